@@ -69,8 +69,8 @@ async fn run() -> Result<()> {
         while let Some(event) = events.next().await {
             let event = match event {
                 ipfs_embed::Event::NewListener(_) => Some(Event::NewListener),
-                ipfs_embed::Event::NewListenAddr(_, addr) => Some(Event::NewListenAddr(addr)),
-                ipfs_embed::Event::ExpiredListenAddr(_, addr) => {
+                ipfs_embed::Event::NewListenAddr(_, addr, _) => Some(Event::NewListenAddr(addr)),
+                ipfs_embed::Event::ExpiredListenAddr(_, addr, _) => {
                     Some(Event::ExpiredListenAddr(addr))
                 }
                 ipfs_embed::Event::ListenerClosed(_) => Some(Event::ListenerClosed),
@@ -79,6 +79,7 @@ async fn run() -> Result<()> {
                     Some(Event::ExpiredExternalAddr(addr))
                 }
                 ipfs_embed::Event::Discovered(peer_id) => Some(Event::Discovered(peer_id)),
+                ipfs_embed::Event::DiscoveredBatch(_) => None,
                 ipfs_embed::Event::Unreachable(peer_id) => Some(Event::Unreachable(peer_id)),
                 ipfs_embed::Event::Connected(peer_id) => Some(Event::Connected(peer_id)),
                 ipfs_embed::Event::Disconnected(peer_id) => Some(Event::Disconnected(peer_id)),
@@ -93,7 +94,7 @@ async fn run() -> Result<()> {
                     Some(info) => Some(Event::PeerInfo(peer, info.into())),
                     None => Some(Event::PeerRemoved(peer)),
                 },
-                ipfs_embed::Event::ListenerError(_, _) => None,
+                ipfs_embed::Event::ListenerError(..) => None,
                 ipfs_embed::Event::DialFailure(p, a, e) => Some(Event::DialFailure(p, a, e)),
                 ipfs_embed::Event::ConnectionEstablished(p, a) => Some(
                     Event::ConnectionEstablished(p, a.get_remote_address().clone()),
@@ -101,7 +102,16 @@ async fn run() -> Result<()> {
                 ipfs_embed::Event::ConnectionClosed(p, a) => {
                     Some(Event::ConnectionClosed(p, a.get_remote_address().clone()))
                 }
+                ipfs_embed::Event::ConnectionRefused(_, _) => None,
+                ipfs_embed::Event::StorageError(_) => None,
+                ipfs_embed::Event::Overloaded => None,
+                ipfs_embed::Event::Recovered => None,
+                ipfs_embed::Event::ResourceLimitApproaching(_) => None,
                 ipfs_embed::Event::AddressChanged(_, _, _) => None,
+                ipfs_embed::Event::ExternalAddressesStable(_) => None,
+                ipfs_embed::Event::MeshPeerAdded(_, _) => None,
+                ipfs_embed::Event::MeshPeerRemoved(_, _) => None,
+                ipfs_embed::Event::PeerIdMismatch(_, _, _) => None,
             };
             if let Some(event) = event {
                 println!("{}", event);
@@ -0,0 +1,117 @@
+//! Export of DAGs as CARv1 files that can be resumed after a failed write.
+use crate::unixfs::varint;
+use fnv::FnvHashSet;
+use libipld::Cid;
+use std::io::{self, Write};
+
+/// Progress of a CAR export, see
+/// [`Ipfs::export_car`](crate::Ipfs::export_car).
+///
+/// A failed export leaves the cursor at the position where writing stopped.
+/// Passing it to another export of the same root continues the file where the
+/// failed one left off, without writing any block twice.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CarCursor {
+    header_written: bool,
+    written: FnvHashSet<Cid>,
+    /// the section that was being written when the writer failed and how many
+    /// of its bytes made it out
+    partial: Option<(Vec<u8>, usize)>,
+}
+
+impl CarCursor {
+    /// The blocks that have been written, including the one being written
+    /// when the export was interrupted.
+    pub fn written(&self) -> impl Iterator<Item = &Cid> + '_ {
+        self.written.iter()
+    }
+
+    pub(crate) fn contains(&self, cid: &Cid) -> bool {
+        self.written.contains(cid)
+    }
+
+    /// Finishes the section a previous export was interrupted in.
+    pub(crate) fn resume(&mut self, w: &mut impl Write) -> io::Result<()> {
+        if let Some((section, pos)) = self.partial.take() {
+            self.write_section(w, section, pos)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn write_header(&mut self, w: &mut impl Write, root: &Cid) -> io::Result<()> {
+        if !self.header_written {
+            let header = header(root);
+            let mut section = Vec::with_capacity(header.len() + 2);
+            varint(&mut section, header.len() as u64);
+            section.extend_from_slice(&header);
+            self.header_written = true;
+            self.write_section(w, section, 0)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn write_block(
+        &mut self,
+        w: &mut impl Write,
+        cid: &Cid,
+        data: &[u8],
+    ) -> io::Result<()> {
+        if self.written.insert(*cid) {
+            let cid = cid.to_bytes();
+            let mut section = Vec::with_capacity(cid.len() + data.len() + 8);
+            varint(&mut section, (cid.len() + data.len()) as u64);
+            section.extend_from_slice(&cid);
+            section.extend_from_slice(data);
+            self.write_section(w, section, 0)?;
+        }
+        Ok(())
+    }
+
+    fn write_section(
+        &mut self,
+        w: &mut impl Write,
+        section: Vec<u8>,
+        mut pos: usize,
+    ) -> io::Result<()> {
+        while pos < section.len() {
+            match w.write(&section[pos..]) {
+                Ok(0) => {
+                    self.partial = Some((section, pos));
+                    return Err(io::ErrorKind::WriteZero.into());
+                }
+                Ok(n) => pos += n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    self.partial = Some((section, pos));
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The dag-cbor encoded `{"roots": [root], "version": 1}`.
+fn header(root: &Cid) -> Vec<u8> {
+    let cid = root.to_bytes();
+    let mut buf = vec![0xa2, 0x65];
+    buf.extend_from_slice(b"roots");
+    // an array with a single link, i.e. tag 42 around the cid bytes prefixed
+    // with the multibase identity prefix
+    buf.extend_from_slice(&[0x81, 0xd8, 0x2a]);
+    let len = cid.len() + 1;
+    if len < 24 {
+        buf.push(0x40 | len as u8);
+    } else if len < 256 {
+        buf.extend_from_slice(&[0x58, len as u8]);
+    } else {
+        buf.push(0x59);
+        buf.extend_from_slice(&(len as u16).to_be_bytes());
+    }
+    buf.push(0);
+    buf.extend_from_slice(&cid);
+    buf.push(0x67);
+    buf.extend_from_slice(b"version");
+    buf.push(0x01);
+    buf
+}
@@ -1,24 +1,46 @@
+use chrono::{DateTime, Utc};
+use fnv::FnvHashMap;
+use futures::channel::mpsc;
 pub use ipfs_sqlite_block_store::TempPin;
 use ipfs_sqlite_block_store::{
-    cache::{CacheTracker, InMemCacheTracker, SqliteCacheTracker},
-    BlockStore, Config, Synchronous,
+    cache::{BlockInfo, CacheTracker, InMemCacheTracker, SqliteCacheTracker, WriteInfo},
+    BlockStore, BlockStoreError, Config, StoreStats, Synchronous,
 };
 use lazy_static::lazy_static;
-use libipld::{codec::References, store::StoreParams, Block, Cid, Ipld, Result};
+use libipld::{
+    codec::References, error::BlockNotFound, store::StoreParams, Block, Cid, Ipld, Result,
+};
 use parking_lot::Mutex;
 use prometheus::{
     core::{Collector, Desc},
     proto::MetricFamily,
-    HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry,
+    HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+};
+use rusqlite::OptionalExtension;
+use std::{
+    convert::TryFrom,
+    fmt::Debug,
+    future::Future,
+    panic::AssertUnwindSafe,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
-use std::{future::Future, path::PathBuf, sync::Arc, time::Duration};
+use thiserror::Error;
 use tracing::info;
 
-use crate::executor::{Executor, JoinHandle};
+use crate::{
+    executor::{panic_message, Executor, JoinHandle},
+    net::{Clock, Jitter, SystemClock},
+    seal::{Sealed, Sealer},
+};
 use std::collections::HashSet;
 
 /// Storage configuration.
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone)]
 pub struct StorageConfig {
     /// The path to use for the block store. If it is `None` an in-memory block
     /// store will be used.
@@ -54,6 +76,9 @@ pub struct StorageConfig {
     /// exactly at this interval, but there will be some drift if gc takes
     /// long.
     pub gc_interval: Duration,
+    /// Spreads the delays between gcs, so that nodes started together don't
+    /// collect garbage at the same time. 10% of the interval by default.
+    pub gc_jitter: Jitter,
     /// The minimum number of blocks to collect in any case.
     ///
     /// Using this parameter, it is possible to guarantee a minimum rate with
@@ -66,6 +91,367 @@ pub struct StorageConfig {
     /// `gc_min_blocks`. But as soon as this duration is exceeded, the
     /// incremental gc will stop doing additional work.
     pub gc_target_duration: Duration,
+    /// What to do when inserting a block whose CID is already stored.
+    pub on_duplicate: DuplicatePolicy,
+    /// Where aliases are kept. If it is `None` they are stored in the block
+    /// store database.
+    pub pin_store: Option<Arc<dyn PinStore>>,
+    /// Encrypts the bytes of inserted blocks with XChaCha20-Poly1305 under
+    /// this key and a random nonce per block. CIDs are still those of the
+    /// plaintext, `get` decrypts and fails with
+    /// [`DecryptionFailed`](crate::DecryptionFailed) if the key doesn't match.
+    ///
+    /// The links of encrypted blocks are stored in the clear next to the
+    /// ciphertext, so that garbage collection and
+    /// [`Ipfs::rebuild_refcounts`](crate::Ipfs::rebuild_refcounts) work
+    /// without the key. Blocks stored before a key was set stay readable.
+    pub encryption_key: Option<[u8; 32]>,
+    /// Compresses the bytes of inserted blocks, before they are encrypted.
+    /// CIDs are still those of the uncompressed bytes and `get` returns them.
+    ///
+    /// Every block records whether it was compressed, so the setting can be
+    /// changed without touching the stored blocks. Blocks that don't get
+    /// smaller are stored uncompressed.
+    pub compression: Option<Compression>,
+}
+
+impl Debug for StorageConfig {
+    /// The encryption key is left out.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StorageConfig")
+            .field("path", &self.path)
+            .field("access_db_path", &self.access_db_path)
+            .field("cache_size_blocks", &self.cache_size_blocks)
+            .field("cache_size_bytes", &self.cache_size_bytes)
+            .field("gc_interval", &self.gc_interval)
+            .field("gc_jitter", &self.gc_jitter)
+            .field("gc_min_blocks", &self.gc_min_blocks)
+            .field("gc_target_duration", &self.gc_target_duration)
+            .field("on_duplicate", &self.on_duplicate)
+            .field("pin_store", &self.pin_store)
+            .field("encryption_key", &self.encryption_key.map(|_| ".."))
+            .field("compression", &self.compression)
+            .finish()
+    }
+}
+
+impl PartialEq for StorageConfig {
+    /// Pin stores are equal if they are the same instance.
+    fn eq(&self, other: &Self) -> bool {
+        let Self {
+            path,
+            access_db_path,
+            cache_size_blocks,
+            cache_size_bytes,
+            gc_interval,
+            gc_jitter,
+            gc_min_blocks,
+            gc_target_duration,
+            on_duplicate,
+            pin_store,
+            encryption_key,
+            compression,
+        } = self;
+        let same_pin_store = match (pin_store, &other.pin_store) {
+            (Some(a), Some(b)) => Arc::as_ptr(a) as *const () == Arc::as_ptr(b) as *const (),
+            (a, b) => a.is_none() && b.is_none(),
+        };
+        *path == other.path
+            && *access_db_path == other.access_db_path
+            && *cache_size_blocks == other.cache_size_blocks
+            && *cache_size_bytes == other.cache_size_bytes
+            && *gc_interval == other.gc_interval
+            && *gc_jitter == other.gc_jitter
+            && *gc_min_blocks == other.gc_min_blocks
+            && *gc_target_duration == other.gc_target_duration
+            && *on_duplicate == other.on_duplicate
+            && same_pin_store
+            && *encryption_key == other.encryption_key
+            && *compression == other.compression
+    }
+}
+
+impl Eq for StorageConfig {}
+
+/// Storage for aliases, the named roots of DAGs that are protected from
+/// garbage collection.
+///
+/// A separate pin store allows keeping aliases in a different database than
+/// the blocks, e.g. one that is shared between nodes. The garbage collector
+/// then reads all aliases from the pin store at the start of each run and
+/// retains their DAGs. Aliases set through a [`Batch`] always go to the block
+/// store database.
+pub trait PinStore: Debug + Send + Sync + 'static {
+    /// Creates, updates or removes an alias.
+    fn alias(&self, alias: &[u8], cid: Option<&Cid>) -> Result<()>;
+    /// Returns the root an alias points to.
+    fn resolve(&self, alias: &[u8]) -> Result<Option<Cid>>;
+    /// Lists all aliases.
+    fn aliases(&self) -> Result<Vec<(Vec<u8>, Cid)>>;
+    /// Sets an alias to `new` only if it currently points to `expected`,
+    /// returning whether it was changed. The check and the update must be
+    /// atomic; pin stores that cannot guarantee this keep the default, which
+    /// fails.
+    fn alias_cas(&self, alias: &[u8], expected: Option<&Cid>, new: Option<&Cid>) -> Result<bool> {
+        let _ = (alias, expected, new);
+        Err(anyhow::anyhow!(
+            "the pin store doesn't support compare-and-swap"
+        ))
+    }
+}
+
+/// Policy for inserts of blocks that are already present in the store.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum DuplicatePolicy {
+    /// Silently accept the insert without touching the stored block.
+    #[default]
+    Ignore,
+    /// Compare the incoming bytes with the stored bytes and fail with
+    /// [`DuplicateMismatch`] if they differ.
+    VerifyBytes,
+    /// Accept the insert and count it in the
+    /// `block_store_duplicate_inserts_total` metric.
+    CountMetric,
+}
+
+/// How the bytes of inserted blocks are compressed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Compression {
+    /// Raw deflate at `level`, from 1 for the fastest to 10 for the smallest
+    /// output.
+    Deflate { level: u8 },
+}
+
+/// A block was inserted whose bytes differ from the stored block with the same
+/// CID.
+#[derive(Debug, Error)]
+#[error("block {0} is already stored with different bytes")]
+pub struct DuplicateMismatch(pub Cid);
+
+/// Error returned when the bytes of a block don't hash to its CID.
+#[derive(Debug, Error)]
+#[error("block {0} is corrupt, its bytes don't match the CID")]
+pub struct Corrupt(pub Cid);
+
+/// How a block was accessed since the store was opened.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AccessInfo {
+    /// Time of the last read, with a granularity of one second.
+    pub last_accessed: DateTime<Utc>,
+    /// Number of reads.
+    pub access_count: u64,
+}
+
+/// Size of the block store, see [`Ipfs::store_size`](crate::Ipfs::store_size).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct StoreSize {
+    /// Total size in bytes of the stored blocks.
+    pub total_bytes: u64,
+    /// Number of stored blocks.
+    pub num_blocks: u64,
+    /// Number of stored blocks that are reachable from an alias.
+    pub num_pinned: u64,
+}
+
+/// Outcome of [`Ipfs::rebuild_refcounts`](crate::Ipfs::rebuild_refcounts).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RefRepairReport {
+    /// Number of blocks whose references were checked.
+    pub blocks: usize,
+    /// Number of blocks whose recorded references were wrong.
+    pub corrected: usize,
+    /// References that were missing, which could get still referenced blocks
+    /// collected.
+    pub added: usize,
+    /// References that don't exist, which kept unreferenced blocks from being
+    /// collected.
+    pub removed: usize,
+}
+
+/// Last computed number of pinned blocks, along with the block count and
+/// aliases it was computed for.
+#[derive(Debug, Default)]
+struct PinnedCount {
+    num_blocks: u64,
+    aliases: Vec<(Vec<u8>, Cid)>,
+    num_pinned: u64,
+}
+
+/// Pinned status of recently looked up blocks. The blocks and aliases
+/// changed since a lookup started are told by `generation`, which every write
+/// through the storage service bumps.
+#[derive(Debug, Default)]
+struct PinnedCache {
+    generation: u64,
+    pinned: FnvHashMap<Cid, bool>,
+}
+
+/// Number of blocks whose pinned status is cached, the cache is cleared once
+/// it is full.
+const PINNED_CACHE_SIZE: usize = 4096;
+
+/// Time `rebuild_refcounts` waits for other connections to finish writing.
+const REPAIR_BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Pages copied by each step of the online backup of a snapshot, writes go
+/// ahead in between.
+const SNAPSHOT_PAGES_PER_STEP: i32 = 256;
+const SNAPSHOT_STEP_PAUSE: Duration = Duration::from_millis(1);
+/// Copies of the block store made before the store is kept locked to make
+/// one that matches the aliases of the pin store.
+const SNAPSHOT_ATTEMPTS: usize = 3;
+
+/// Schema version of the block store the raw queries on its tables are
+/// written against.
+const SCHEMA_VERSION: u32 = 2;
+
+/// Error returned when the tables of the block store have a schema the rebuild
+/// of the references or the removal of a block don't know.
+#[derive(Debug, Error)]
+#[error(
+    "unsupported block store schema version {0}, expected {}",
+    SCHEMA_VERSION
+)]
+pub struct UnsupportedSchema(pub u32);
+
+/// Copies the database of `src` into `dst` with the SQLite online backup, a
+/// few pages at a time. The backup starts over when `src` is written to
+/// between two steps. Returns the data version of `src` before the last step.
+fn backup_stepwise(src: &rusqlite::Connection, dst: &mut rusqlite::Connection) -> Result<i64> {
+    let backup = rusqlite::backup::Backup::new(src, dst)?;
+    loop {
+        let version = data_version(src)?;
+        match backup.step(SNAPSHOT_PAGES_PER_STEP)? {
+            rusqlite::backup::StepResult::Done => return Ok(version),
+            _ => std::thread::sleep(SNAPSHOT_STEP_PAUSE),
+        }
+    }
+}
+
+/// Changes whenever another connection commits to the database of `conn`.
+fn data_version(conn: &rusqlite::Connection) -> Result<i64> {
+    Ok(conn.pragma_query_value(None, "data_version", |row| row.get(0))?)
+}
+
+fn check_schema(txn: &rusqlite::Transaction) -> Result<()> {
+    let version = txn.pragma_query_value(None, "user_version", |row| row.get::<_, u32>(0))?;
+    if version != SCHEMA_VERSION {
+        return Err(UnsupportedSchema(version).into());
+    }
+    Ok(())
+}
+
+/// Number of CIDs looked up per query by `contains_many`, within the
+/// smallest limit on query parameters of sqlite versions.
+const CONTAINS_CHUNK_SIZE: usize = 999;
+
+/// Reads within this time of the previous one don't update the access time.
+const ACCESS_GRANULARITY: Duration = Duration::from_secs(1);
+
+/// Number of blocks whose access info is kept. Once it is reached, the
+/// quarter that was read least recently is forgotten.
+const MAX_ACCESS_INFOS: usize = 65_536;
+
+/// Counts block reads and forwards them to the tracker that decides the
+/// eviction order, throttled to one update per block and `ACCESS_GRANULARITY`.
+#[derive(Debug)]
+struct AccessTracker {
+    inner: Arc<dyn CacheTracker>,
+    access: Mutex<FnvHashMap<Cid, AccessInfo>>,
+}
+
+impl AccessTracker {
+    fn new(inner: Arc<dyn CacheTracker>) -> Self {
+        Self {
+            inner,
+            access: Default::default(),
+        }
+    }
+
+    fn get(&self, cid: &Cid) -> Option<AccessInfo> {
+        self.access.lock().get(cid).copied()
+    }
+}
+
+/// Removes the `n` entries that were read least recently, ties of the
+/// coarse access times are broken by cid.
+fn forget_least_recent(access: &mut FnvHashMap<Cid, AccessInfo>, n: usize) {
+    if n == 0 {
+        return;
+    }
+    if access.len() <= n {
+        access.clear();
+        return;
+    }
+    let mut keys = access
+        .iter()
+        .map(|(cid, info)| (info.last_accessed, *cid))
+        .collect::<Vec<_>>();
+    keys.select_nth_unstable(n - 1);
+    for (_, cid) in &keys[..n] {
+        access.remove(cid);
+    }
+}
+
+impl CacheTracker for AccessTracker {
+    fn blocks_accessed(&self, blocks: Vec<BlockInfo>) {
+        let now = Utc::now();
+        let mut access = self.access.lock();
+        let blocks = blocks
+            .into_iter()
+            .filter(|block| match access.get_mut(block.cid()) {
+                Some(info) => {
+                    info.access_count += 1;
+                    let elapsed = (now - info.last_accessed).to_std().unwrap_or_default();
+                    if elapsed < ACCESS_GRANULARITY {
+                        return false;
+                    }
+                    info.last_accessed = now;
+                    true
+                }
+                None => {
+                    if access.len() >= MAX_ACCESS_INFOS {
+                        forget_least_recent(&mut access, MAX_ACCESS_INFOS / 4);
+                    }
+                    let info = AccessInfo {
+                        last_accessed: now,
+                        access_count: 1,
+                    };
+                    access.insert(*block.cid(), info);
+                    true
+                }
+            })
+            .collect::<Vec<_>>();
+        drop(access);
+        if !blocks.is_empty() {
+            self.inner.blocks_accessed(blocks);
+        }
+    }
+
+    fn blocks_written(&self, blocks: Vec<WriteInfo>) {
+        self.inner.blocks_written(blocks)
+    }
+
+    fn blocks_deleted(&self, blocks: Vec<BlockInfo>) {
+        let mut access = self.access.lock();
+        for block in &blocks {
+            access.remove(block.cid());
+        }
+        drop(access);
+        self.inner.blocks_deleted(blocks)
+    }
+
+    fn sort_ids(&self, ids: &mut [i64]) {
+        self.inner.sort_ids(ids)
+    }
+
+    fn has_persistent_state(&self) -> bool {
+        self.inner.has_persistent_state()
+    }
+
+    fn retain_ids(&self, ids: &[i64]) {
+        self.inner.retain_ids(ids)
+    }
 }
 
 impl StorageConfig {
@@ -82,20 +468,115 @@ impl StorageConfig {
             cache_size_blocks: cache_size,
             cache_size_bytes: u64::MAX,
             gc_interval,
+            gc_jitter: Jitter::default(),
             gc_min_blocks: usize::MAX,
             gc_target_duration: Duration::new(u64::MAX, 1_000_000_000 - 1),
+            on_duplicate: DuplicatePolicy::Ignore,
+            pin_store: None,
+            encryption_key: None,
+            compression: None,
         }
     }
 }
 
+/// Number of consecutive failed writes after which the block store counts as
+/// degraded.
+const DEGRADED_AFTER_FAILURES: usize = 3;
+
+/// Error returned when fetching blocks while the block store is degraded.
+#[derive(Debug, Error)]
+#[error("Writes to the block store keep failing, not fetching blocks.")]
+pub struct StorageDegraded;
+
+/// Tracks whether writes to the block store keep failing, e.g. because the
+/// disk is full.
+#[derive(Default)]
+struct WriteHealth {
+    failures: AtomicUsize,
+    degraded: AtomicBool,
+    subscribers: Mutex<Vec<mpsc::UnboundedSender<String>>>,
+    #[cfg(test)]
+    fail: AtomicBool,
+}
+
+impl WriteHealth {
+    fn record<R>(&self, res: &Result<R>) {
+        match res {
+            Ok(_) => {
+                self.failures.store(0, Ordering::SeqCst);
+                if self.degraded.swap(false, Ordering::SeqCst) {
+                    info!("block store writes succeed again");
+                }
+            }
+            // only errors of the block store itself, not e.g. a duplicate
+            // mismatch
+            Err(err) if err.downcast_ref::<BlockStoreError>().is_some() => {
+                let failures = self.failures.fetch_add(1, Ordering::SeqCst) + 1;
+                if failures >= DEGRADED_AFTER_FAILURES
+                    && !self.degraded.swap(true, Ordering::SeqCst)
+                {
+                    let err = format!("{:#}", err);
+                    tracing::error!("block store writes keep failing: {}", err);
+                    self.subscribers
+                        .lock()
+                        .retain(|tx| tx.unbounded_send(err.clone()).is_ok());
+                }
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+/// File descriptors of a sqlite connection to a file: the database, its
+/// write-ahead log and the shared memory index.
+const FDS_PER_CONNECTION: usize = 3;
+
 struct StorageServiceInner<S: StoreParams> {
     executor: Executor,
-    store: Arc<Mutex<BlockStore<S>>>,
+    store: Arc<Mutex<BlockStore<Sealed<S>>>>,
+    /// the database file of the block store, `None` if it is in memory
+    db_path: Option<PathBuf>,
+    /// read connection for `contains_many`, opened on first use
+    lookups: Mutex<Option<rusqlite::Connection>>,
+    /// file descriptors of the sqlite connections to files
+    file_descriptors: usize,
     gc_target_duration: Duration,
     gc_min_blocks: usize,
+    on_duplicate: DuplicatePolicy,
+    pin_store: Option<Arc<dyn PinStore>>,
+    sealer: Sealer,
+    access: Arc<AccessTracker>,
+    pinned: Mutex<Option<PinnedCount>>,
+    /// answers of `is_pinned`, asked for every inbound bitswap request
+    pinned_cache: Mutex<PinnedCache>,
+    writes: WriteHealth,
+    #[cfg(test)]
+    reads: ReadGate,
     gc_task: Option<JoinHandle<()>>,
 }
 
+/// Holds back `get`s while paused, to test requests that are being served.
+#[cfg(test)]
+#[derive(Default)]
+struct ReadGate {
+    paused: AtomicBool,
+    waiting: AtomicUsize,
+}
+
+#[cfg(test)]
+impl ReadGate {
+    fn wait(&self) {
+        if !self.paused.load(Ordering::SeqCst) {
+            return;
+        }
+        self.waiting.fetch_add(1, Ordering::SeqCst);
+        while self.paused.load(Ordering::SeqCst) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        self.waiting.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 impl<S: StoreParams> Drop for StorageServiceInner<S> {
     fn drop(&mut self) {
         if let Some(t) = self.gc_task.take() {
@@ -129,7 +610,9 @@ where
         let store_config = Config::default()
             .with_size_targets(config.cache_size_blocks, config.cache_size_bytes)
             .with_pragma_synchronous(Synchronous::Normal);
+        let mut file_descriptors = 0;
         let tracker: Arc<dyn CacheTracker> = if let Some(path) = config.access_db_path {
+            file_descriptors += FDS_PER_CONNECTION;
             let path = if path.is_file() {
                 path
             } else {
@@ -140,8 +623,11 @@ where
         } else {
             Arc::new(InMemCacheTracker::new(|access, _| Some(access)))
         };
+        let access = Arc::new(AccessTracker::new(tracker));
+        let tracker: Arc<dyn CacheTracker> = access.clone();
 
         let is_memory = config.path.is_none();
+        let mut db_path = None;
         // create DB connection
         let store = if let Some(path) = config.path {
             let path = if path.is_file() {
@@ -150,43 +636,64 @@ where
                 std::fs::create_dir_all(&path)?;
                 path.join("db")
             };
-            BlockStore::open(path, store_config.with_cache_tracker(tracker))?
+            db_path = Some(path.clone());
+            // the store, the gc and the lookup connection
+            file_descriptors += 3 * FDS_PER_CONNECTION;
+            BlockStore::<Sealed<S>>::open(path, store_config.with_cache_tracker(tracker))?
         } else {
-            BlockStore::memory(store_config.with_cache_tracker(tracker))?
+            BlockStore::<Sealed<S>>::memory(store_config.with_cache_tracker(tracker))?
         };
         let store = Arc::new(Mutex::new(store));
 
         // spawn GC task
         let gc_interval = config.gc_interval;
+        let gc_jitter = config.gc_jitter;
         let gc_min_blocks = config.gc_min_blocks;
         let gc_target_duration = config.gc_target_duration;
         let gc_task = if is_memory {
             let gc = store.clone();
+            let pin_store = config.pin_store.clone();
             executor.spawn(async move {
                 loop {
-                    futures_timer::Delay::new(gc_interval).await;
+                    SystemClock.tick(gc_interval, gc_jitter).await;
                     info!("going for gc!");
-                    gc.lock()
-                        .incremental_gc(gc_min_blocks, gc_target_duration)
-                        .map_err(|e| {
-                            tracing::warn!("failure during incremental gc: {:#}", e);
-                            e
-                        })
-                        .ok();
+                    // a panicking gc run must not stop the following ones
+                    let gc_run = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                        incremental_gc::<Sealed<S>>(
+                            &mut gc.lock(),
+                            pin_store.as_deref(),
+                            gc_min_blocks,
+                            gc_target_duration,
+                        )
+                    }));
+                    match gc_run {
+                        Ok(Ok(_)) => {}
+                        Ok(Err(e)) => tracing::warn!("failure during incremental gc: {:#}", e),
+                        Err(panic) => tracing::error!("gc panicked: {}", panic_message(&*panic)),
+                    }
                 }
             })
         } else {
             let mut gc = store.lock().additional_connection()?;
+            let pin_store = config.pin_store.clone();
             executor.spawn(async move {
                 loop {
-                    futures_timer::Delay::new(gc_interval).await;
+                    SystemClock.tick(gc_interval, gc_jitter).await;
                     info!("going for gc!");
-                    gc.incremental_gc(gc_min_blocks, gc_target_duration)
-                        .map_err(|e| {
-                            tracing::warn!("failure during incremental gc: {:#}", e);
-                            e
-                        })
-                        .ok();
+                    // a panicking gc run must not stop the following ones
+                    let gc_run = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                        incremental_gc::<Sealed<S>>(
+                            &mut gc,
+                            pin_store.as_deref(),
+                            gc_min_blocks,
+                            gc_target_duration,
+                        )
+                    }));
+                    match gc_run {
+                        Ok(Ok(_)) => {}
+                        Ok(Err(e)) => tracing::warn!("failure during incremental gc: {:#}", e),
+                        Err(panic) => tracing::error!("gc panicked: {}", panic_message(&*panic)),
+                    }
                 }
             })
         };
@@ -194,7 +701,23 @@ where
             executor,
             gc_target_duration: config.gc_target_duration,
             gc_min_blocks: config.gc_min_blocks,
+            on_duplicate: config.on_duplicate,
+            pin_store: config.pin_store,
+            sealer: Sealer::new(
+                config.encryption_key.as_ref(),
+                config.compression,
+                S::MAX_BLOCK_SIZE,
+            ),
+            access,
+            pinned: Default::default(),
+            pinned_cache: Default::default(),
+            writes: Default::default(),
+            #[cfg(test)]
+            reads: Default::default(),
             store,
+            db_path,
+            lookups: Default::default(),
+            file_descriptors,
             gc_task: Some(gc_task),
         })
     }
@@ -219,7 +742,11 @@ where
             tracing::warn!(op, "very long storage lock wait time of {:.1}s", t);
         }
         let _timer = QUERY_DURATION.with_label_values(&[op]).start_timer();
-        let mut txn = Batch(lock.transaction());
+        let mut txn = Batch(
+            lock.transaction(),
+            self.inner.on_duplicate,
+            &self.inner.sealer,
+        );
         let res = f(&mut txn);
         if res.is_ok() {
             txn.0.commit()?;
@@ -227,6 +754,218 @@ where
         res
     }
 
+    /// Like `rw`, for operations that write blocks or aliases. Their failures
+    /// put the store into the degraded state, their successes end it.
+    fn write<F: FnOnce(&mut Batch<'_, S>) -> Result<R>, R>(
+        &self,
+        op: &'static str,
+        f: F,
+    ) -> Result<R> {
+        #[cfg(test)]
+        {
+            if self.inner.writes.fail.load(Ordering::SeqCst) {
+                let err = anyhow::anyhow!("simulated write failure");
+                let res = Err(BlockStoreError::Other(err).into());
+                self.inner.writes.record(&res);
+                return res;
+            }
+        }
+        let res = self.rw(op, f);
+        self.pins_changed();
+        self.inner.writes.record(&res);
+        res
+    }
+
+    /// Drops the cached pinned status of all blocks, called after blocks or
+    /// aliases were written.
+    fn pins_changed(&self) {
+        let mut cache = self.inner.pinned_cache.lock();
+        cache.generation = cache.generation.wrapping_add(1);
+        cache.pinned.clear();
+    }
+
+    pub fn snapshot(&self, dir: &Path) -> Result<StoreSnapshot<S>> {
+        let dir = dir.join(format!(
+            "ipfs-embed-snapshot-{:016x}",
+            rand::random::<u64>()
+        ));
+        std::fs::create_dir_all(&dir)?;
+        let mut snapshot = StoreSnapshot {
+            store: None,
+            aliases: None,
+            sealer: self.inner.sealer.clone(),
+            dir,
+        };
+        let path = snapshot.dir.join("db");
+        if let Some(db_path) = &self.inner.db_path {
+            let src = rusqlite::Connection::open(db_path)?;
+            src.busy_timeout(REPAIR_BUSY_TIMEOUT)?;
+            let mut dst = rusqlite::Connection::open(&path)?;
+            if let Some(pin_store) = &self.inner.pin_store {
+                // the aliases are read with the store locked, the copy matches
+                // them if nothing was written since its last step
+                for attempt in 1..=SNAPSHOT_ATTEMPTS {
+                    let last = attempt == SNAPSHOT_ATTEMPTS;
+                    let lock = if last {
+                        Some(self.inner.store.lock())
+                    } else {
+                        None
+                    };
+                    let version = backup_stepwise(&src, &mut dst)?;
+                    let _lock = lock.unwrap_or_else(|| self.inner.store.lock());
+                    if last || data_version(&src)? == version {
+                        snapshot.aliases = Some(pin_store.aliases()?);
+                        break;
+                    }
+                }
+            } else {
+                backup_stepwise(&src, &mut dst)?;
+            }
+        } else {
+            // an in-memory store can only be copied by its own connection
+            let mut lock = self.inner.store.lock();
+            if let Some(pin_store) = &self.inner.pin_store {
+                snapshot.aliases = Some(pin_store.aliases()?);
+            }
+            lock.backup(path.clone())?;
+        }
+        snapshot.store = Some(Mutex::new(BlockStore::<Sealed<S>>::open(
+            path,
+            Config::default(),
+        )?));
+        Ok(snapshot)
+    }
+
+    /// Recomputes the references between all stored blocks from their links
+    /// and rewrites the recorded ones that differ, in a single transaction.
+    ///
+    /// The block store doesn't keep reference counts but the references
+    /// themselves, from which the gc computes what is reachable from the
+    /// aliases. So correcting the references corrects what the gc collects.
+    pub fn rebuild_refcounts(&self) -> Result<RefRepairReport> {
+        let path = self
+            .inner
+            .db_path
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("an in-memory block store can't be repaired"))?;
+        // this connection's transaction keeps out the gc connection, the lock
+        // the writes of the node
+        let _lock = self.inner.store.lock();
+        let mut conn = rusqlite::Connection::open(path)?;
+        conn.busy_timeout(REPAIR_BUSY_TIMEOUT)?;
+        let txn = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+        let report = repair_refs::<Sealed<S>>(&txn)?;
+        txn.commit()?;
+        if report.corrected > 0 {
+            *self.inner.pinned.lock() = None;
+            self.pins_changed();
+        }
+        Ok(report)
+    }
+
+    /// Deletes the block of `cid`, returning whether it was stored and got
+    /// deleted.
+    ///
+    /// Without `force` the block is kept if another stored block links to
+    /// it or it is aliased or temp pinned. With `force` it is deleted
+    /// anyway, along with the aliases naming it. Either way the references
+    /// of the block to its children are removed with it, so they can be
+    /// collected once nothing else references them.
+    pub fn remove_block(&self, cid: &Cid, force: bool) -> Result<bool> {
+        let path =
+            self.inner.db_path.as_ref().ok_or_else(|| {
+                anyhow::anyhow!("can't remove blocks from an in-memory block store")
+            })?;
+        // the block store has no API for deleting a single block, so this
+        // goes through a connection of its own while the store is locked
+        let store = self.inner.store.lock();
+        let mut conn = rusqlite::Connection::open(path)?;
+        conn.busy_timeout(REPAIR_BUSY_TIMEOUT)?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        let txn = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+        check_schema(&txn)?;
+        let block = txn
+            .query_row(
+                "SELECT id, LENGTH(block) FROM cids, blocks ON id = block_id WHERE cid = ?",
+                [cid.to_bytes()],
+                |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)),
+            )
+            .optional()?;
+        let (id, len) = match block {
+            Some(block) => block,
+            None => return Ok(false),
+        };
+        if !force {
+            // checked in the transaction, so no pin added in between is
+            // missed; a block reachable from an alias is linked to by a
+            // stored block or aliased itself
+            let referenced = txn.query_row(
+                "SELECT EXISTS (SELECT 1 FROM refs WHERE child_id = ?1) \
+                 OR EXISTS (SELECT 1 FROM temp_pins WHERE block_id = ?1) \
+                 OR EXISTS (SELECT 1 FROM aliases WHERE block_id = ?1)",
+                [id],
+                |row| row.get::<_, bool>(0),
+            )?;
+            let aliased = match &self.inner.pin_store {
+                Some(pin_store) => pin_store.aliases()?.iter().any(|(_, root)| root == cid),
+                None => false,
+            };
+            if referenced || aliased {
+                return Ok(false);
+            }
+        } else {
+            txn.execute("DELETE FROM aliases WHERE block_id = ?", [id])?;
+        }
+        // cascades to the references of the block, the cid stays as other
+        // blocks and temp pins may still refer to it
+        txn.execute("DELETE FROM blocks WHERE block_id = ?", [id])?;
+        txn.execute("UPDATE stats SET count = count - 1, size = size - ?", [len])?;
+        txn.commit()?;
+        drop(store);
+        self.inner
+            .access
+            .blocks_deleted(vec![BlockInfo::new(id, cid, len as usize)]);
+        *self.inner.pinned.lock() = None;
+        self.pins_changed();
+        Ok(true)
+    }
+
+    /// Whether writes to the block store keep failing.
+    pub fn is_degraded(&self) -> bool {
+        self.inner.writes.degraded.load(Ordering::SeqCst)
+    }
+
+    /// The file descriptors held by the store, some for each connection to a
+    /// database file.
+    pub fn file_descriptors(&self) -> usize {
+        self.inner.file_descriptors
+    }
+
+    /// Returns a stream of the errors that put the store into the degraded
+    /// state, one each time it is entered.
+    pub fn write_errors(&self) -> mpsc::UnboundedReceiver<String> {
+        let (tx, rx) = mpsc::unbounded();
+        self.inner.writes.subscribers.lock().push(tx);
+        rx
+    }
+
+    #[cfg(test)]
+    pub fn fail_writes(&self, fail: bool) {
+        self.inner.writes.fail.store(fail, Ordering::SeqCst);
+    }
+
+    /// Holds back `get`s until called with `false`.
+    #[cfg(test)]
+    pub fn pause_reads(&self, pause: bool) {
+        self.inner.reads.paused.store(pause, Ordering::SeqCst);
+    }
+
+    /// Number of `get`s that are held back.
+    #[cfg(test)]
+    pub fn paused_reads(&self) -> usize {
+        self.inner.reads.waiting.load(Ordering::SeqCst)
+    }
+
     pub fn create_temp_pin(&self) -> Result<TempPin> {
         self.rw("create_temp_pin", |x| x.create_temp_pin())
     }
@@ -247,43 +986,259 @@ where
         self.rw("contains", |x| x.contains(cid))
     }
 
+    /// Checks for each CID whether its block is stored, in the order given.
+    ///
+    /// With a store on disk each chunk of `CONTAINS_CHUNK_SIZE` CIDs is looked
+    /// up with a single `IN (...)` query on a read connection of its own, so
+    /// the storage lock isn't taken. The in-memory block store can only be
+    /// reached through its own API, so each chunk takes the storage lock and
+    /// a transaction once and checks its CIDs one by one.
+    pub fn contains_many(&self, cids: &[Cid]) -> Result<Vec<bool>> {
+        let path = match &self.inner.db_path {
+            Some(path) => path,
+            None => {
+                let mut res = Vec::with_capacity(cids.len());
+                for chunk in cids.chunks(CONTAINS_CHUNK_SIZE) {
+                    self.rw("contains_many", |x| {
+                        for cid in chunk {
+                            res.push(x.contains(cid)?);
+                        }
+                        Ok(())
+                    })?;
+                }
+                return Ok(res);
+            }
+        };
+        let mut conn = self.inner.lookups.lock();
+        if conn.is_none() {
+            let lookups = rusqlite::Connection::open_with_flags(
+                path,
+                rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY
+                    | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )?;
+            lookups.busy_timeout(REPAIR_BUSY_TIMEOUT)?;
+            *conn = Some(lookups);
+        }
+        let conn = conn.as_mut().expect("opened above");
+        let mut res = Vec::with_capacity(cids.len());
+        for chunk in cids.chunks(CONTAINS_CHUNK_SIZE) {
+            QUERIES_TOTAL.with_label_values(&["contains_many"]).inc();
+            let _timer = QUERY_DURATION
+                .with_label_values(&["contains_many"])
+                .start_timer();
+            let keys = chunk.iter().map(|cid| cid.to_bytes()).collect::<Vec<_>>();
+            let query = format!(
+                "SELECT cid FROM cids, blocks ON id = block_id WHERE cid IN ({})",
+                vec!["?"; keys.len()].join(", ")
+            );
+            let found = conn
+                .prepare_cached(&query)?
+                .query_map(rusqlite::params_from_iter(&keys), |row| {
+                    row.get::<_, Vec<u8>>(0)
+                })?
+                .collect::<rusqlite::Result<HashSet<_>>>()?;
+            res.extend(keys.iter().map(|key| found.contains(key)));
+        }
+        Ok(res)
+    }
+
     pub fn get(&self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        #[cfg(test)]
+        self.inner.reads.wait();
         self.rw("get", |x| x.get(cid))
     }
 
+    /// Returns how often and when a stored block was last read since the
+    /// store was opened.
+    pub fn access_info(&self, cid: &Cid) -> Option<AccessInfo> {
+        self.inner.access.get(cid)
+    }
+
     pub fn insert(&self, block: Block<S>) -> Result<()> {
-        self.rw("insert", |x| x.insert(block))
+        self.write("insert", |x| x.insert(block))
     }
 
     pub fn alias(&self, alias: &[u8], cid: Option<&Cid>) -> Result<()> {
-        self.rw("alias", |x| x.alias(alias, cid))
+        if let Some(pin_store) = &self.inner.pin_store {
+            let res = pin_store.alias(alias, cid);
+            self.pins_changed();
+            return res;
+        }
+        self.write("alias", |x| x.alias(alias, cid))
+    }
+
+    pub fn alias_cas(
+        &self,
+        alias: &[u8],
+        expected: Option<&Cid>,
+        new: Option<&Cid>,
+    ) -> Result<bool> {
+        if let Some(pin_store) = &self.inner.pin_store {
+            let res = pin_store.alias_cas(alias, expected, new);
+            self.pins_changed();
+            return res;
+        }
+        self.write("alias_cas", |x| {
+            if x.resolve(alias)?.as_ref() != expected {
+                return Ok(false);
+            }
+            x.alias(alias, new)?;
+            Ok(true)
+        })
+    }
+
+    /// Moves an alias to `new_root` in one transaction and returns its
+    /// previous root.
+    pub fn pin_alias(&self, alias: &[u8], new_root: &Cid) -> Result<Option<Cid>> {
+        if let Some(pin_store) = &self.inner.pin_store {
+            // retry until no concurrent update gets in between
+            loop {
+                let old = pin_store.resolve(alias)?;
+                if pin_store.alias_cas(alias, old.as_ref(), Some(new_root))? {
+                    self.pins_changed();
+                    return Ok(old);
+                }
+            }
+        }
+        self.write("pin_alias", |x| {
+            let old = x.resolve(alias)?;
+            x.alias(alias, Some(new_root))?;
+            Ok(old)
+        })
     }
 
     pub fn aliases(&self) -> Result<Vec<(Vec<u8>, Cid)>> {
+        if let Some(pin_store) = &self.inner.pin_store {
+            return pin_store.aliases();
+        }
         self.rw("aliases", |x| x.aliases())
     }
 
     pub fn resolve(&self, alias: &[u8]) -> Result<Option<Cid>> {
+        if let Some(pin_store) = &self.inner.pin_store {
+            return pin_store.resolve(alias);
+        }
         self.rw("resolve", |x| x.resolve(alias))
     }
 
     pub fn reverse_alias(&self, cid: &Cid) -> Result<Option<HashSet<Vec<u8>>>> {
+        if let Some(pin_store) = &self.inner.pin_store {
+            let aliases = pin_store.aliases()?;
+            return self.rw("reverse_alias", |x| x.reverse_alias_of(cid, aliases));
+        }
         self.rw("reverse_alias", |x| x.reverse_alias(cid))
     }
 
+    /// Returns whether the block is stored and reachable from an alias.
+    ///
+    /// The answer is cached until blocks or aliases are written through the
+    /// storage service, aliases changed in the pin store by others aren't
+    /// seen before that.
+    pub fn is_pinned(&self, cid: &Cid) -> Result<bool> {
+        let generation = {
+            let cache = self.inner.pinned_cache.lock();
+            if let Some(pinned) = cache.pinned.get(cid) {
+                return Ok(*pinned);
+            }
+            cache.generation
+        };
+        let pinned = self
+            .reverse_alias(cid)?
+            .is_some_and(|aliases| !aliases.is_empty());
+        let mut cache = self.inner.pinned_cache.lock();
+        // a write that went ahead in between may have changed the answer
+        if cache.generation == generation {
+            if cache.pinned.len() >= PINNED_CACHE_SIZE {
+                cache.pinned.clear();
+            }
+            cache.pinned.insert(*cid, pinned);
+        }
+        Ok(pinned)
+    }
+
+    /// Returns the size of the store.
+    ///
+    /// Block count and size are maintained by the block store on every insert
+    /// and delete. The pinned count walks the aliased DAGs, it is only
+    /// recomputed when the number of blocks or the aliases changed since the
+    /// last call and isn't part of the exported metrics.
+    pub fn store_size(&self) -> Result<StoreSize> {
+        QUERIES_TOTAL.with_label_values(&["store_size"]).inc();
+        let _timer = QUERY_DURATION
+            .with_label_values(&["store_size"])
+            .start_timer();
+        store_size::<Sealed<S>>(
+            &mut self.inner.store.lock(),
+            self.inner.pin_store.as_deref(),
+            &self.inner.pinned,
+        )
+    }
+
     pub fn missing_blocks(&self, cid: &Cid) -> Result<Vec<Cid>> {
         self.rw("missing_blocks", |x| x.missing_blocks(cid))
     }
 
+    pub fn transaction(
+        &self,
+        f: impl FnOnce(&mut WriteTransaction<S>) -> Result<Cid>,
+    ) -> Result<Cid> {
+        let mut txn = WriteTransaction::new();
+        let root = f(&mut txn)?;
+        let pin_store = self.inner.pin_store.as_deref();
+        let WriteTransaction { blocks, aliases } = txn;
+        // the gc may run on a different connection, so keep the blocks until
+        // they are aliased
+        let _tmp = self.write("transaction", |x| {
+            let mut tmp = x.create_temp_pin()?;
+            for block in blocks {
+                x.insert_pinned(block, &mut tmp)?;
+            }
+            if pin_store.is_none() {
+                for (alias, cid) in &aliases {
+                    x.alias(alias, cid.as_ref())?;
+                }
+            }
+            Ok(tmp)
+        })?;
+        if let Some(pin_store) = pin_store {
+            // the pin store can't take part in the transaction of the block
+            // store, so a failed alias restores the ones set before
+            let mut previous = Vec::with_capacity(aliases.len());
+            for (alias, cid) in &aliases {
+                let res = pin_store.resolve(alias).and_then(|old| {
+                    pin_store.alias(alias, cid.as_ref())?;
+                    Ok(old)
+                });
+                match res {
+                    Ok(old) => previous.push((alias, old)),
+                    Err(err) => {
+                        self.pins_changed();
+                        for (alias, old) in previous.into_iter().rev() {
+                            if let Err(err) = pin_store.alias(alias, old.as_ref()) {
+                                tracing::error!("failed to restore alias: {:#}", err);
+                            }
+                        }
+                        return Err(err);
+                    }
+                }
+            }
+            self.pins_changed();
+        }
+        Ok(root)
+    }
+
     pub fn evict(&self) -> impl Future<Output = Result<()>> {
         let store = self.inner.store.clone();
+        let pin_store = self.inner.pin_store.clone();
         let gc_min_blocks = self.inner.gc_min_blocks;
         let gc_target_duration = self.inner.gc_target_duration;
         let evict = self.inner.executor.spawn_blocking(move || {
-            while !store
-                .lock()
-                .incremental_gc(gc_min_blocks, gc_target_duration)?
-            {
+            while !incremental_gc::<Sealed<S>>(
+                &mut store.lock(),
+                pin_store.as_deref(),
+                gc_min_blocks,
+                gc_target_duration,
+            )? {
                 tracing::trace!("x");
             }
             Ok(())
@@ -300,9 +1255,28 @@ where
         async { Ok(observe_future("flush", flush).await??) }
     }
 
+    pub fn compact(&self) -> impl Future<Output = Result<u64>> {
+        let store = self.inner.store.clone();
+        let compact = self.inner.executor.spawn_blocking(move || -> Result<u64> {
+            let mut store = store.lock();
+            let before = store.get_store_stats()?;
+            store.flush()?;
+            store.vacuum()?;
+            // in WAL mode the database file only shrinks once the vacuumed
+            // pages are checkpointed
+            store.flush()?;
+            let after = store.get_store_stats()?;
+            let size = |stats: &StoreStats| stats.used_pages() * stats.page_size();
+            Ok(size(&before).saturating_sub(size(&after)))
+        });
+        async { observe_future("compact", compact).await? }
+    }
+
     pub fn register_metrics(&self, registry: &Registry) -> Result<()> {
         registry.register(Box::new(QUERIES_TOTAL.clone()))?;
         registry.register(Box::new(QUERY_DURATION.clone()))?;
+        registry.register(Box::new(DUPLICATE_INSERTS.clone()))?;
+        registry.register(Box::new(DUPLICATE_BLOCKS_RECEIVED.clone()))?;
         registry.register(Box::new(SqliteStoreCollector::new(
             self.inner.store.clone(),
         )))?;
@@ -331,6 +1305,146 @@ lazy_static! {
         &["type"],
     )
     .unwrap();
+    pub static ref DUPLICATE_INSERTS: IntCounter = IntCounter::new(
+        "block_store_duplicate_inserts_total",
+        "Number of inserts of blocks that were already stored."
+    )
+    .unwrap();
+    pub static ref DUPLICATE_BLOCKS_RECEIVED: IntCounter = IntCounter::new(
+        "bitswap_duplicate_blocks_received_total",
+        "Number of blocks received via bitswap that were already stored."
+    )
+    .unwrap();
+}
+
+/// Runs one incremental gc pass, retaining the DAGs aliased in `pin_store`
+/// in addition to those aliased in the block store.
+fn incremental_gc<S: StoreParams>(
+    store: &mut BlockStore<S>,
+    pin_store: Option<&dyn PinStore>,
+    min_blocks: usize,
+    target_duration: Duration,
+) -> Result<bool>
+where
+    Ipld: References<S::Codecs>,
+{
+    let _pin = if let Some(pin_store) = pin_store {
+        let mut pin = store.temp_pin();
+        for (_, cid) in pin_store.aliases()? {
+            store.extend_temp_pin(&mut pin, &cid)?;
+        }
+        Some(pin)
+    } else {
+        None
+    };
+    Ok(store.incremental_gc(min_blocks, target_duration)?)
+}
+
+/// Makes the `refs` table of the block store match the links of the stored
+/// blocks.
+fn repair_refs<S: StoreParams>(txn: &rusqlite::Transaction) -> Result<RefRepairReport>
+where
+    Ipld: References<S::Codecs>,
+{
+    check_schema(txn)?;
+    let mut report = RefRepairReport::default();
+    // references of blocks that were deleted
+    report.removed += txn.execute(
+        "DELETE FROM refs WHERE parent_id NOT IN (SELECT block_id FROM blocks)",
+        [],
+    )?;
+    let ids = txn
+        .prepare("SELECT block_id FROM blocks")?
+        .query_map([], |row| row.get::<_, i64>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut get_block = txn.prepare(
+        "SELECT cids.cid, blocks.block FROM blocks \
+         JOIN cids ON cids.id = blocks.block_id WHERE blocks.block_id = ?",
+    )?;
+    let mut get_refs = txn.prepare("SELECT child_id FROM refs WHERE parent_id = ?")?;
+    let mut add_cid = txn.prepare("INSERT OR IGNORE INTO cids (cid) VALUES (?)")?;
+    let mut get_id = txn.prepare("SELECT id FROM cids WHERE cid = ?")?;
+    let mut add_ref = txn.prepare("INSERT INTO refs (parent_id, child_id) VALUES (?, ?)")?;
+    let mut remove_ref = txn.prepare("DELETE FROM refs WHERE parent_id = ? AND child_id = ?")?;
+    for id in ids {
+        let (cid, data) = get_block.query_row([id], |row| {
+            Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Vec<u8>>(1)?))
+        })?;
+        let block = Block::<S>::new_unchecked(Cid::try_from(cid.as_slice())?, data);
+        let mut links = HashSet::new();
+        block.references(&mut links)?;
+        let mut expected = HashSet::with_capacity(links.len());
+        for link in links {
+            let link = link.to_bytes();
+            add_cid.execute([&link])?;
+            expected.insert(get_id.query_row([&link], |row| row.get::<_, i64>(0))?);
+        }
+        let recorded = get_refs
+            .query_map([id], |row| row.get::<_, i64>(0))?
+            .collect::<rusqlite::Result<HashSet<_>>>()?;
+        report.blocks += 1;
+        if recorded == expected {
+            continue;
+        }
+        report.corrected += 1;
+        for child in expected.difference(&recorded) {
+            add_ref.execute([id, *child])?;
+            report.added += 1;
+        }
+        for child in recorded.difference(&expected) {
+            remove_ref.execute([id, *child])?;
+            report.removed += 1;
+        }
+    }
+    Ok(report)
+}
+
+/// Reads the block count and size from the stats of the block store and
+/// counts the pinned blocks unless `cache` holds the count for the current
+/// block count and aliases.
+fn store_size<S: StoreParams>(
+    store: &mut BlockStore<S>,
+    pin_store: Option<&dyn PinStore>,
+    cache: &Mutex<Option<PinnedCount>>,
+) -> Result<StoreSize>
+where
+    Ipld: References<S::Codecs>,
+{
+    let mut txn = store.transaction();
+    let stats = txn.get_store_stats()?;
+    let mut aliases: Vec<(Vec<u8>, Cid)> = match pin_store {
+        Some(pin_store) => pin_store.aliases()?,
+        None => txn.aliases()?,
+    };
+    aliases.sort();
+    let mut cache = cache.lock();
+    let num_pinned = match &*cache {
+        Some(pinned) if pinned.num_blocks == stats.count() && pinned.aliases == aliases => {
+            pinned.num_pinned
+        }
+        _ => {
+            let mut pinned = HashSet::new();
+            for (_, root) in &aliases {
+                for cid in txn.get_descendants::<Vec<_>>(root)? {
+                    if !pinned.contains(&cid) && txn.has_block(&cid)? {
+                        pinned.insert(cid);
+                    }
+                }
+            }
+            let num_pinned = pinned.len() as u64;
+            *cache = Some(PinnedCount {
+                num_blocks: stats.count(),
+                aliases,
+                num_pinned,
+            });
+            num_pinned
+        }
+    };
+    Ok(StoreSize {
+        total_bytes: stats.size(),
+        num_blocks: stats.count(),
+        num_pinned,
+    })
 }
 
 async fn observe_future<T, F>(name: &'static str, query: F) -> Result<T>
@@ -393,8 +1507,100 @@ impl<S: StoreParams> SqliteStoreCollector<S> {
     }
 }
 
+/// Blocks and aliases that are written together, see
+/// [`Ipfs::transaction`](crate::Ipfs::transaction).
+///
+/// Nothing is written until the transaction is committed, so other readers
+/// don't see any of its blocks before that.
+pub struct WriteTransaction<S: StoreParams> {
+    blocks: Vec<Block<S>>,
+    aliases: Vec<(Vec<u8>, Option<Cid>)>,
+}
+
+impl<S: StoreParams> WriteTransaction<S> {
+    fn new() -> Self {
+        Self {
+            blocks: vec![],
+            aliases: vec![],
+        }
+    }
+
+    /// Inserts a block on commit.
+    pub fn insert(&mut self, block: Block<S>) {
+        self.blocks.push(block);
+    }
+
+    /// Creates, updates or removes an alias on commit, after all blocks have
+    /// been inserted.
+    pub fn alias<T: AsRef<[u8]>>(&mut self, alias: T, cid: Option<&Cid>) {
+        self.aliases.push((alias.as_ref().to_vec(), cid.copied()));
+    }
+}
+
+/// A point-in-time view of the block store, see
+/// [`Ipfs::snapshot`](crate::Ipfs::snapshot).
+///
+/// The block store can't keep a read transaction open across calls, so the
+/// snapshot is a copy of the database made with the SQLite online backup.
+/// Writes made after the snapshot was taken are not visible in it. The copy
+/// is deleted when the snapshot is dropped.
+pub struct StoreSnapshot<S: StoreParams> {
+    store: Option<Mutex<BlockStore<Sealed<S>>>>,
+    /// the aliases of the pin store at the time of the snapshot
+    aliases: Option<Vec<(Vec<u8>, Cid)>>,
+    sealer: Sealer,
+    dir: PathBuf,
+}
+
+impl<S: StoreParams> StoreSnapshot<S>
+where
+    Ipld: References<S::Codecs>,
+{
+    fn read<F: FnOnce(&mut Batch<'_, S>) -> Result<R>, R>(&self, f: F) -> Result<R> {
+        let mut lock = self.store.as_ref().unwrap().lock();
+        let mut txn = Batch(lock.transaction(), DuplicatePolicy::Ignore, &self.sealer);
+        f(&mut txn)
+    }
+
+    pub fn iter(&self) -> Result<impl Iterator<Item = Cid>> {
+        self.read(|x| x.iter())
+    }
+
+    pub fn contains(&self, cid: &Cid) -> Result<bool> {
+        self.read(|x| x.contains(cid))
+    }
+
+    pub fn get(&self, cid: &Cid) -> Result<Block<S>> {
+        match self.read(|x| x.get(cid))? {
+            Some(data) => Ok(Block::new_unchecked(*cid, data)),
+            None => Err(BlockNotFound(*cid).into()),
+        }
+    }
+
+    pub fn aliases(&self) -> Result<Vec<(Vec<u8>, Cid)>> {
+        if let Some(aliases) = &self.aliases {
+            return Ok(aliases.clone());
+        }
+        self.read(|x| x.aliases())
+    }
+}
+
+impl<S: StoreParams> Drop for StoreSnapshot<S> {
+    fn drop(&mut self) {
+        // close the connection before deleting its files
+        self.store.take();
+        if let Err(err) = std::fs::remove_dir_all(&self.dir) {
+            tracing::warn!(dir = %self.dir.display(), "failed to delete snapshot: {}", err);
+        }
+    }
+}
+
 /// A handle for performing batch operations on an ipfs storage
-pub struct Batch<'a, S>(ipfs_sqlite_block_store::Transaction<'a, S>);
+pub struct Batch<'a, S>(
+    ipfs_sqlite_block_store::Transaction<'a, Sealed<S>>,
+    DuplicatePolicy,
+    &'a Sealer,
+);
 
 impl<'a, S: StoreParams> Batch<'a, S>
 where
@@ -426,13 +1632,43 @@ where
     }
 
     pub fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
-        Ok(self.0.get_block(cid)?)
+        match self.0.get_block(cid)? {
+            Some(data) => Ok(Some(self.2.open(cid, data)?)),
+            None => Ok(None),
+        }
     }
 
     pub fn insert(&mut self, block: Block<S>) -> Result<()> {
+        self.check_duplicate(&block)?;
+        let block = self.2.seal(block)?;
         Ok(self.0.put_block(block, None)?)
     }
 
+    fn insert_pinned(&mut self, block: Block<S>, pin: &mut TempPin) -> Result<()> {
+        self.check_duplicate(&block)?;
+        let block = self.2.seal(block)?;
+        Ok(self.0.put_block(block, Some(pin))?)
+    }
+
+    fn check_duplicate(&mut self, block: &Block<S>) -> Result<()> {
+        match self.1 {
+            DuplicatePolicy::Ignore => {}
+            DuplicatePolicy::VerifyBytes => {
+                if let Some(data) = self.get(block.cid())? {
+                    if data != block.data() {
+                        return Err(DuplicateMismatch(*block.cid()).into());
+                    }
+                }
+            }
+            DuplicatePolicy::CountMetric => {
+                if self.0.has_block(block.cid())? {
+                    DUPLICATE_INSERTS.inc();
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn resolve(&mut self, alias: &[u8]) -> Result<Option<Cid>> {
         Ok(self.0.resolve(alias)?)
     }
@@ -449,6 +1685,26 @@ where
         Ok(self.0.reverse_alias(cid)?)
     }
 
+    /// Like `reverse_alias`, but for aliases that are stored elsewhere.
+    fn reverse_alias_of(
+        &mut self,
+        cid: &Cid,
+        aliases: Vec<(Vec<u8>, Cid)>,
+    ) -> Result<Option<HashSet<Vec<u8>>>> {
+        // the temp pins gc takes for the pin store keep the CIDs of collected
+        // blocks known, so ask for the block itself
+        if !self.0.has_block(cid)? {
+            return Ok(None);
+        }
+        let mut res = HashSet::new();
+        for (alias, root) in aliases {
+            if self.0.get_descendants::<HashSet<Cid>>(&root)?.contains(cid) {
+                res.insert(alias);
+            }
+        }
+        Ok(Some(res))
+    }
+
     pub fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>> {
         Ok(self.0.get_missing_blocks(cid)?)
     }
@@ -459,7 +1715,10 @@ mod tests {
     use crate::executor::Executor;
 
     use super::*;
+    use crate::seal::DecryptionFailed;
     use libipld::{alias, cbor::DagCborCodec, ipld, multihash::Code, store::DefaultParams};
+    use std::collections::HashMap;
+    use tempdir::TempDir;
 
     fn create_block(ipld: &Ipld) -> Block<DefaultParams> {
         Block::encode(DagCborCodec, Code::Blake3_256, ipld).unwrap()
@@ -536,6 +1795,208 @@ mod tests {
         assert_unpinned!(&store, &d);
     }
 
+    #[async_std::test]
+    async fn test_store_evict_least_recently_used() {
+        tracing_try_init();
+        let store = create_store();
+        let blocks = (0..4).map(|i| create_block(&ipld!(i))).collect::<Vec<_>>();
+        for block in &blocks {
+            store.insert(block.clone()).unwrap();
+        }
+        store.flush().await.unwrap();
+        assert_eq!(store.access_info(blocks[1].cid()), None);
+        store.get(blocks[1].cid()).unwrap();
+        store.get(blocks[3].cid()).unwrap();
+        store.get(blocks[3].cid()).unwrap();
+        assert_eq!(store.access_info(blocks[1].cid()).unwrap().access_count, 1);
+        assert_eq!(store.access_info(blocks[3].cid()).unwrap().access_count, 2);
+        // the cache holds two blocks, so the never accessed ones go first
+        store.evict().await.unwrap();
+        assert_evicted!(store, blocks[0]);
+        assert_unpinned!(store, blocks[1]);
+        assert_evicted!(store, blocks[2]);
+        assert_unpinned!(store, blocks[3]);
+    }
+
+    #[async_std::test]
+    async fn test_store_evict_least_recently_used_size_cap() {
+        tracing_try_init();
+        // room for any number of blocks, but only two of these bytes
+        let blocks = (0..4).map(|i| create_block(&ipld!(i))).collect::<Vec<_>>();
+        let mut config = StorageConfig::new(None, None, 1000, Duration::from_secs(100));
+        config.cache_size_bytes = 2 * blocks[0].data().len() as u64;
+        let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+        for block in &blocks {
+            store.insert(block.clone()).unwrap();
+        }
+        store.flush().await.unwrap();
+        store.get(blocks[0].cid()).unwrap();
+        store.get(blocks[2].cid()).unwrap();
+        store.evict().await.unwrap();
+        assert_unpinned!(store, blocks[0]);
+        assert_evicted!(store, blocks[1]);
+        assert_unpinned!(store, blocks[2]);
+        assert_evicted!(store, blocks[3]);
+    }
+
+    #[test]
+    fn test_access_infos_bounded() {
+        let now = Utc::now();
+        let mut access = (0..8u32)
+            .map(|i| {
+                let info = AccessInfo {
+                    last_accessed: now + chrono::Duration::seconds(i64::from(i)),
+                    access_count: 1,
+                };
+                (*create_block(&ipld!(i)).cid(), info)
+            })
+            .collect::<FnvHashMap<_, _>>();
+        forget_least_recent(&mut access, 2);
+        assert_eq!(access.len(), 6);
+        let oldest = access.values().map(|info| info.last_accessed).min();
+        assert_eq!(oldest, Some(now + chrono::Duration::seconds(2)));
+
+        // entries read at the same time are only removed up to `n`
+        let mut access = (0..8u32)
+            .map(|i| {
+                let info = AccessInfo {
+                    last_accessed: now,
+                    access_count: 1,
+                };
+                (*create_block(&ipld!(i)).cid(), info)
+            })
+            .collect::<FnvHashMap<_, _>>();
+        forget_least_recent(&mut access, 2);
+        assert_eq!(access.len(), 6);
+    }
+
+    #[async_std::test]
+    async fn test_store_size() {
+        tracing_try_init();
+        let store = create_store();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [a.cid()] }));
+        let c = create_block(&ipld!({ "c": [] }));
+        let d = create_block(&ipld!({ "d": [] }));
+        let x = alias!(x).as_bytes().to_vec();
+        let size = |blocks: &[&Block<DefaultParams>]| {
+            blocks.iter().map(|b| b.data().len() as u64).sum::<u64>()
+        };
+        assert_eq!(store.store_size().unwrap(), StoreSize::default());
+        store.insert(a.clone()).unwrap();
+        store.insert(b.clone()).unwrap();
+        store.insert(c.clone()).unwrap();
+        store.alias(&x, Some(b.cid())).unwrap();
+        let expected = StoreSize {
+            total_bytes: size(&[&a, &b, &c]),
+            num_blocks: 3,
+            num_pinned: 2,
+        };
+        assert_eq!(store.store_size().unwrap(), expected);
+        // unchanged blocks and aliases reuse the pinned count
+        assert_eq!(store.inner.pinned.lock().as_ref().unwrap().num_pinned, 2);
+        assert_eq!(store.store_size().unwrap(), expected);
+
+        store.alias(&x, Some(a.cid())).unwrap();
+        store.insert(d.clone()).unwrap();
+        store.flush().await.unwrap();
+        store.evict().await.unwrap();
+        assert_evicted!(&store, &b);
+        assert_evicted!(&store, &c);
+        assert_eq!(
+            store.store_size().unwrap(),
+            StoreSize {
+                total_bytes: size(&[&a, &d]),
+                num_blocks: 2,
+                num_pinned: 1,
+            }
+        );
+    }
+
+    /// Stores every third of 2500 blocks starting with the second, and a
+    /// block linking to the last one, so that its CID is known without its
+    /// block. Returns the CIDs and
+    /// which are stored.
+    fn fill_contains_many(store: &StorageService<DefaultParams>) -> (Vec<Cid>, Vec<bool>) {
+        let blocks = (0..2500)
+            .map(|i| create_block(&ipld!(i)))
+            .collect::<Vec<_>>();
+        for block in blocks.iter().skip(1).step_by(3) {
+            store.insert(block.clone()).unwrap();
+        }
+        let last = blocks.last().unwrap().cid();
+        assert!(!store.contains(last).unwrap());
+        store
+            .insert(create_block(&ipld!({ "link": last })))
+            .unwrap();
+        let cids = blocks.iter().map(|b| *b.cid()).collect::<Vec<_>>();
+        let expected = (0..2500).map(|i| i % 3 == 1).collect::<Vec<_>>();
+        (cids, expected)
+    }
+
+    #[test]
+    fn test_store_contains_many() {
+        let store = create_store();
+        let (cids, expected) = fill_contains_many(&store);
+        assert_eq!(store.contains_many(&cids).unwrap(), expected);
+        assert_eq!(store.contains_many(&[]).unwrap(), Vec::<bool>::new());
+    }
+
+    #[test]
+    fn test_store_contains_many_file() {
+        let tmp = TempDir::new("contains_many").unwrap();
+        let config = StorageConfig::new(
+            Some(tmp.path().to_owned()),
+            None,
+            10_000,
+            Duration::from_secs(100),
+        );
+        let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+        let (cids, expected) = fill_contains_many(&store);
+        // the queries don't go through the block store
+        let _lock = store.inner.store.lock();
+        assert_eq!(store.contains_many(&cids).unwrap(), expected);
+        assert_eq!(store.contains_many(&[]).unwrap(), Vec::<bool>::new());
+    }
+
+    #[test]
+    fn test_store_alias_cas() {
+        let store = create_store();
+        let a = create_block(&ipld!(0));
+        let b = create_block(&ipld!(1));
+        let c = create_block(&ipld!(2));
+        let x = alias!(x).as_bytes().to_vec();
+        assert!(!store.alias_cas(&x, Some(a.cid()), Some(b.cid())).unwrap());
+        assert!(store.alias_cas(&x, None, Some(a.cid())).unwrap());
+        assert_eq!(store.resolve(&x).unwrap(), Some(*a.cid()));
+
+        let barrier = Arc::new(std::sync::Barrier::new(2));
+        let threads = [b, c]
+            .iter()
+            .map(|new| {
+                let store = store.clone();
+                let barrier = barrier.clone();
+                let (x, a, new) = (x.clone(), *a.cid(), *new.cid());
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    let swapped = store.alias_cas(&x, Some(&a), Some(&new)).unwrap();
+                    (swapped, new)
+                })
+            })
+            .collect::<Vec<_>>();
+        let results = threads
+            .into_iter()
+            .map(|t| t.join().unwrap())
+            .collect::<Vec<_>>();
+        let winners = results
+            .iter()
+            .filter(|(swapped, _)| *swapped)
+            .map(|(_, new)| *new)
+            .collect::<Vec<_>>();
+        assert_eq!(winners.len(), 1);
+        assert_eq!(store.resolve(&x).unwrap(), Some(winners[0]));
+    }
+
     #[async_std::test]
     #[allow(clippy::many_single_char_names)]
     async fn test_store_unpin() {
@@ -592,4 +2053,320 @@ mod tests {
         assert_unpinned!(&store, &a);
         assert_unpinned!(&store, &b);
     }
+
+    #[async_std::test]
+    async fn test_store_compact() {
+        tracing_try_init();
+        let tmp = TempDir::new("compact").unwrap();
+        let config = StorageConfig::new(
+            Some(tmp.path().to_owned()),
+            None,
+            0,
+            Duration::from_secs(100),
+        );
+        let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+        let db = tmp.path().join("db");
+        for i in 0..1000 {
+            let ipld = Ipld::List(vec![Ipld::Integer(i), Ipld::Bytes(vec![0; 1024])]);
+            store.insert(create_block(&ipld)).unwrap();
+        }
+        store.flush().await.unwrap();
+        let before = std::fs::metadata(&db).unwrap().len();
+        store.evict().await.unwrap();
+        assert!(store.iter().unwrap().next().is_none());
+        store.compact().await.unwrap();
+        let after = std::fs::metadata(&db).unwrap().len();
+        assert!(after < before, "{} < {}", after, before);
+    }
+
+    #[derive(Debug, Default)]
+    struct MemPinStore(Mutex<HashMap<Vec<u8>, Cid>>);
+
+    impl PinStore for MemPinStore {
+        fn alias(&self, alias: &[u8], cid: Option<&Cid>) -> Result<()> {
+            let mut aliases = self.0.lock();
+            if let Some(cid) = cid {
+                aliases.insert(alias.to_vec(), *cid);
+            } else {
+                aliases.remove(alias);
+            }
+            Ok(())
+        }
+
+        fn resolve(&self, alias: &[u8]) -> Result<Option<Cid>> {
+            Ok(self.0.lock().get(alias).copied())
+        }
+
+        fn aliases(&self) -> Result<Vec<(Vec<u8>, Cid)>> {
+            Ok(self
+                .0
+                .lock()
+                .iter()
+                .map(|(alias, cid)| (alias.clone(), *cid))
+                .collect())
+        }
+    }
+
+    #[async_std::test]
+    async fn test_store_external_pins() {
+        tracing_try_init();
+        let pins = Arc::new(MemPinStore::default());
+        let mut config = StorageConfig::new(None, None, 0, Duration::from_secs(100));
+        config.pin_store = Some(pins.clone());
+        let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [a.cid()] }));
+        let c = create_block(&ipld!({ "c": [] }));
+        let x = alias!(x).as_bytes().to_vec();
+        store.insert(a.clone()).unwrap();
+        store.insert(b.clone()).unwrap();
+        store.insert(c.clone()).unwrap();
+        store.alias(&x, Some(b.cid())).unwrap();
+        assert_eq!(pins.resolve(&x).unwrap(), Some(*b.cid()));
+        assert_eq!(store.resolve(&x).unwrap(), Some(*b.cid()));
+        store.flush().await.unwrap();
+        store.evict().await.unwrap();
+        assert_pinned!(&store, &a);
+        assert_pinned!(&store, &b);
+        assert_evicted!(&store, &c);
+        assert!(store.get(a.cid()).unwrap().is_some());
+
+        store.alias(&x, None).unwrap();
+        store.evict().await.unwrap();
+        assert_evicted!(&store, &a);
+        assert_evicted!(&store, &b);
+    }
+
+    #[test]
+    fn test_remove_block_external_pins() {
+        let tmp = TempDir::new("remove_block").unwrap();
+        let pins = Arc::new(MemPinStore::default());
+        let mut config = StorageConfig::new(
+            Some(tmp.path().to_owned()),
+            None,
+            0,
+            Duration::from_secs(100),
+        );
+        config.pin_store = Some(pins);
+        let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let x = alias!(x).as_bytes().to_vec();
+        store.insert(a.clone()).unwrap();
+        store.alias(&x, Some(a.cid())).unwrap();
+        assert!(!store.remove_block(a.cid(), false).unwrap());
+        assert!(store.contains(a.cid()).unwrap());
+
+        store.alias(&x, None).unwrap();
+        assert!(store.remove_block(a.cid(), false).unwrap());
+        assert!(!store.contains(a.cid()).unwrap());
+    }
+
+    #[test]
+    fn test_store_is_pinned_cached() {
+        let pins = Arc::new(MemPinStore::default());
+        let mut config = StorageConfig::new(None, None, 0, Duration::from_secs(100));
+        config.pin_store = Some(pins);
+        let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [a.cid()] }));
+        let x = alias!(x).as_bytes().to_vec();
+        let cached = |cid: &Cid| store.inner.pinned_cache.lock().pinned.get(cid).copied();
+        assert!(!store.is_pinned(a.cid()).unwrap());
+        assert_eq!(cached(a.cid()), Some(false));
+
+        store.insert(a.clone()).unwrap();
+        assert_eq!(cached(a.cid()), None);
+        store.insert(b.clone()).unwrap();
+        store.alias(&x, Some(b.cid())).unwrap();
+        assert!(store.is_pinned(a.cid()).unwrap());
+        assert_eq!(cached(a.cid()), Some(true));
+
+        store.alias(&x, None).unwrap();
+        assert_eq!(cached(a.cid()), None);
+        assert!(!store.is_pinned(a.cid()).unwrap());
+
+        store
+            .transaction(|txn| {
+                txn.alias(&x, Some(b.cid()));
+                Ok(*b.cid())
+            })
+            .unwrap();
+        assert!(store.is_pinned(a.cid()).unwrap());
+    }
+
+    #[test]
+    fn test_storage_config_eq() {
+        let config = StorageConfig::new(None, None, 0, Duration::from_secs(100));
+        let pins: Arc<dyn PinStore> = Arc::new(MemPinStore::default());
+        let mut a = config.clone();
+        a.pin_store = Some(pins.clone());
+        let mut b = config.clone();
+        b.pin_store = Some(pins);
+        let mut c = config.clone();
+        c.pin_store = Some(Arc::new(MemPinStore::default()));
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_ne!(a, config);
+        assert_eq!(config, config.clone());
+        let mut d = config.clone();
+        d.compression = Some(Compression::Deflate { level: 6 });
+        assert_ne!(d, config);
+    }
+
+    /// Fails to set the alias `fail`.
+    #[derive(Debug, Default)]
+    struct FailingPinStore(MemPinStore);
+
+    impl PinStore for FailingPinStore {
+        fn alias(&self, alias: &[u8], cid: Option<&Cid>) -> Result<()> {
+            if alias == b"fail" {
+                return Err(anyhow::anyhow!("can't set alias"));
+            }
+            self.0.alias(alias, cid)
+        }
+
+        fn resolve(&self, alias: &[u8]) -> Result<Option<Cid>> {
+            self.0.resolve(alias)
+        }
+
+        fn aliases(&self) -> Result<Vec<(Vec<u8>, Cid)>> {
+            self.0.aliases()
+        }
+    }
+
+    #[async_std::test]
+    async fn test_store_transaction_external_pins() {
+        tracing_try_init();
+        let pins = Arc::new(FailingPinStore::default());
+        let mut config = StorageConfig::new(None, None, 0, Duration::from_secs(100));
+        config.pin_store = Some(pins.clone());
+        let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [] }));
+        store.insert(a.clone()).unwrap();
+        store.alias(b"x", Some(a.cid())).unwrap();
+
+        let res = store.transaction(|txn| {
+            txn.insert(b.clone());
+            txn.alias(b"x", Some(b.cid()));
+            txn.alias(b"y", Some(b.cid()));
+            txn.alias(b"fail", Some(b.cid()));
+            Ok(*b.cid())
+        });
+        assert!(res.is_err());
+        // the aliases set before the failed one are restored
+        assert_eq!(pins.resolve(b"x").unwrap(), Some(*a.cid()));
+        assert_eq!(pins.resolve(b"y").unwrap(), None);
+        store.evict().await.unwrap();
+        assert_pinned!(&store, &a);
+        assert_evicted!(&store, &b);
+
+        let root = store
+            .transaction(|txn| {
+                txn.insert(b.clone());
+                txn.alias(b"y", Some(b.cid()));
+                Ok(*b.cid())
+            })
+            .unwrap();
+        assert_eq!(pins.resolve(b"y").unwrap(), Some(root));
+        store.evict().await.unwrap();
+        assert_pinned!(&store, &b);
+    }
+
+    #[async_std::test]
+    async fn test_store_verify_duplicate() {
+        let mut config = StorageConfig::new(None, None, 2, Duration::from_secs(100));
+        config.on_duplicate = DuplicatePolicy::VerifyBytes;
+        let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+        let a = create_block(&ipld!(0));
+        store.insert(a.clone()).unwrap();
+        store.insert(a.clone()).unwrap();
+        let b = Block::new_unchecked(*a.cid(), create_block(&ipld!(1)).data().to_vec());
+        let err = store.insert(b).unwrap_err();
+        assert!(err.downcast_ref::<DuplicateMismatch>().is_some());
+        assert_eq!(store.get(a.cid()).unwrap().as_deref(), Some(a.data()));
+    }
+
+    #[async_std::test]
+    async fn test_store_encryption() {
+        tracing_try_init();
+        let tmp = TempDir::new("encryption").unwrap();
+        let open = |key: Option<[u8; 32]>| {
+            let mut config = StorageConfig::new(
+                Some(tmp.path().to_owned()),
+                None,
+                0,
+                Duration::from_secs(100),
+            );
+            config.encryption_key = key;
+            StorageService::<DefaultParams>::open(config, Executor::new()).unwrap()
+        };
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [a.cid()] }));
+        let c = create_block(&ipld!({ "c": [] }));
+        let x = alias!(x).as_bytes().to_vec();
+
+        let store = open(None);
+        store.insert(a.clone()).unwrap();
+        drop(store);
+
+        let store = open(Some([1; 32]));
+        store.insert(b.clone()).unwrap();
+        store.insert(c.clone()).unwrap();
+        store.alias(&x, Some(b.cid())).unwrap();
+        assert_eq!(store.get(a.cid()).unwrap().as_deref(), Some(a.data()));
+        assert_eq!(store.get(b.cid()).unwrap().as_deref(), Some(b.data()));
+        drop(store);
+
+        let store = open(Some([2; 32]));
+        let err = store.get(b.cid()).unwrap_err();
+        assert_eq!(err.downcast_ref::<DecryptionFailed>().unwrap().0, *b.cid());
+        assert_eq!(store.get(a.cid()).unwrap().as_deref(), Some(a.data()));
+        // the links of encrypted blocks are tracked without the key
+        assert_eq!(store.rebuild_refcounts().unwrap().corrected, 0);
+        store.flush().await.unwrap();
+        store.evict().await.unwrap();
+        assert_pinned!(&store, &a);
+        assert_pinned!(&store, &b);
+        assert_evicted!(&store, &c);
+    }
+
+    #[async_std::test]
+    async fn test_store_compression() {
+        tracing_try_init();
+        let tmp = TempDir::new("compression").unwrap();
+        let open = |compression: Option<Compression>, key: Option<[u8; 32]>| {
+            let mut config = StorageConfig::new(
+                Some(tmp.path().to_owned()),
+                None,
+                0,
+                Duration::from_secs(100),
+            );
+            config.compression = compression;
+            config.encryption_key = key;
+            StorageService::<DefaultParams>::open(config, Executor::new()).unwrap()
+        };
+        let text = "all work and no play makes jack a dull boy. ".repeat(100);
+        let a = create_block(&ipld!({ "a": text.clone() }));
+        let b = create_block(&ipld!({ "b": text, "a": a.cid() }));
+        let x = alias!(x).as_bytes().to_vec();
+
+        let store = open(Some(Compression::Deflate { level: 6 }), None);
+        store.insert(a.clone()).unwrap();
+        store.alias(&x, Some(a.cid())).unwrap();
+        assert!(store.store_size().unwrap().total_bytes < a.data().len() as u64);
+        drop(store);
+
+        let store = open(Some(Compression::Deflate { level: 6 }), Some([1; 32]));
+        store.insert(b.clone()).unwrap();
+        store.alias(&x, Some(b.cid())).unwrap();
+        drop(store);
+
+        let store = open(None, Some([1; 32]));
+        assert_eq!(store.get(a.cid()).unwrap().as_deref(), Some(a.data()));
+        assert_eq!(store.get(b.cid()).unwrap().as_deref(), Some(b.data()));
+        let size = store.store_size().unwrap();
+        assert!(size.total_bytes < (a.data().len() + b.data().len()) as u64);
+        assert_eq!(size.num_pinned, 2);
+    }
 }
@@ -10,7 +10,7 @@
 
 use futures::{Future, FutureExt};
 use pin_project::pin_project;
-use std::{pin::Pin, task::Poll};
+use std::{any::Any, panic::AssertUnwindSafe, pin::Pin, task::Poll};
 
 #[derive(Clone)]
 pub enum Executor {
@@ -65,6 +65,26 @@ impl Executor {
     }
 }
 
+/// Runs `task` to completion, logging a panic instead of letting it take down
+/// the executor thread. Returns `false` if the task panicked.
+pub(crate) async fn supervise<F: Future<Output = ()>>(name: &str, task: F) -> bool {
+    match AssertUnwindSafe(task).catch_unwind().await {
+        Ok(()) => true,
+        Err(panic) => {
+            tracing::error!("{} panicked: {}", name, panic_message(&*panic));
+            false
+        }
+    }
+}
+
+pub(crate) fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(|s| s.as_str()))
+        .unwrap_or("unknown cause")
+}
+
 impl Default for Executor {
     fn default() -> Self {
         Self::new()
@@ -0,0 +1,226 @@
+//! Application request/response protocols that share the connections and the
+//! address book of the node.
+//!
+//! All application protocols are multiplexed over a single libp2p protocol:
+//! every request carries the name under which the receiving side registered
+//! its handler. The node itself uses it to introduce peers to each other.
+use async_trait::async_trait;
+use fnv::FnvHashSet;
+use futures::{
+    channel::oneshot,
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+};
+use libp2p::{
+    core::upgrade::{read_length_prefixed, write_length_prefixed},
+    request_response::{ProtocolName, RequestResponseCodec},
+    Multiaddr, PeerId,
+};
+use serde_json::json;
+use std::{io, time::Duration};
+
+/// Protocol names are short, anything longer is a broken request.
+const MAX_PROTOCOL_NAME: usize = 256;
+
+/// Name of the built-in protocol for sharing the addresses of a peer, which
+/// can't be registered by applications.
+pub(crate) const INTRODUCE: &str = "/ipfs-embed/introduce";
+
+pub(crate) fn encode_introduction(peer: &PeerId, addrs: &[Multiaddr]) -> Vec<u8> {
+    let addrs = addrs
+        .iter()
+        .map(|addr| addr.to_string())
+        .collect::<Vec<_>>();
+    json!({ "peer": peer.to_string(), "addrs": addrs })
+        .to_string()
+        .into_bytes()
+}
+
+/// Parses an introduction, skipping addresses that are not valid.
+pub(crate) fn decode_introduction(data: &[u8]) -> anyhow::Result<(PeerId, Vec<Multiaddr>)> {
+    let json: serde_json::Value = serde_json::from_slice(data)?;
+    let peer = json
+        .get("peer")
+        .and_then(|peer| peer.as_str())
+        .ok_or_else(|| anyhow::anyhow!("introduction without peer"))?
+        .parse()?;
+    let addrs = json
+        .get("addrs")
+        .and_then(|addrs| addrs.as_array())
+        .map(|addrs| {
+            addrs
+                .iter()
+                .filter_map(|addr| addr.as_str()?.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok((peer, addrs))
+}
+
+/// Which peers may tell us the addresses of other peers via
+/// [`Ipfs::introduce_peer`](crate::Ipfs::introduce_peer).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum IntroductionPolicy {
+    /// Ignore all introductions, the default.
+    Refuse,
+    /// Accept introductions from these peers only.
+    From(FnvHashSet<PeerId>),
+    /// Accept introductions from any peer.
+    Any,
+}
+
+impl IntroductionPolicy {
+    pub fn accepts(&self, peer: &PeerId) -> bool {
+        match self {
+            Self::Refuse => false,
+            Self::From(peers) => peers.contains(peer),
+            Self::Any => true,
+        }
+    }
+}
+
+impl Default for IntroductionPolicy {
+    fn default() -> Self {
+        Self::Refuse
+    }
+}
+
+/// Configuration of the application protocols, see
+/// [`Ipfs::register_protocol`](crate::Ipfs::register_protocol).
+#[derive(Clone, Debug)]
+pub struct AppProtocolConfig {
+    /// Maximum size in bytes of a request or response payload.
+    pub max_message_size: usize,
+    /// Time after which a request without response fails.
+    pub request_timeout: Duration,
+    /// Maximum number of requests queued for a handler that hasn't taken
+    /// them yet. Further requests are dropped, failing at the requester.
+    pub max_pending_requests: usize,
+}
+
+impl Default for AppProtocolConfig {
+    fn default() -> Self {
+        Self {
+            max_message_size: 1024 * 1024,
+            request_timeout: Duration::from_secs(10),
+            max_pending_requests: 64,
+        }
+    }
+}
+
+/// A request of an application protocol received from a peer.
+#[derive(Debug)]
+pub struct AppRequest {
+    peer: PeerId,
+    data: Vec<u8>,
+    response: oneshot::Sender<Vec<u8>>,
+}
+
+impl AppRequest {
+    pub(crate) fn new(peer: PeerId, data: Vec<u8>) -> (Self, oneshot::Receiver<Vec<u8>>) {
+        let (response, rx) = oneshot::channel();
+        (
+            Self {
+                peer,
+                data,
+                response,
+            },
+            rx,
+        )
+    }
+
+    /// The peer that sent the request.
+    pub fn peer(&self) -> PeerId {
+        self.peer
+    }
+
+    /// The request payload.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Answers the request. Dropping a request without answering it fails the
+    /// request on the sending side.
+    pub fn respond(self, data: Vec<u8>) {
+        self.response.send(data).ok();
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct AppProtocol;
+
+impl ProtocolName for AppProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/ipfs-embed/app/1.0.0"
+    }
+}
+
+/// A request as it goes over the wire.
+#[derive(Debug)]
+pub(crate) struct AppMessage {
+    /// name of the application protocol
+    pub protocol: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct AppCodec {
+    max_message_size: usize,
+}
+
+impl AppCodec {
+    pub fn new(max_message_size: usize) -> Self {
+        Self { max_message_size }
+    }
+}
+
+#[async_trait]
+impl RequestResponseCodec for AppCodec {
+    type Protocol = AppProtocol;
+    type Request = AppMessage;
+    type Response = Vec<u8>;
+
+    async fn read_request<T>(&mut self, _: &AppProtocol, io: &mut T) -> io::Result<AppMessage>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let protocol = read_length_prefixed(io, MAX_PROTOCOL_NAME).await?;
+        let protocol = String::from_utf8(protocol)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let data = read_length_prefixed(io, self.max_message_size).await?;
+        Ok(AppMessage { protocol, data })
+    }
+
+    async fn read_response<T>(&mut self, _: &AppProtocol, io: &mut T) -> io::Result<Vec<u8>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_length_prefixed(io, self.max_message_size).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &AppProtocol,
+        io: &mut T,
+        req: AppMessage,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, req.protocol).await?;
+        write_length_prefixed(io, req.data).await?;
+        io.close().await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &AppProtocol,
+        io: &mut T,
+        res: Vec<u8>,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, res).await?;
+        io.close().await
+    }
+}
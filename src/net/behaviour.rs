@@ -1,7 +1,15 @@
 use crate::{
     net::{
+        app_protocol::{self, AppCodec, AppMessage, AppProtocol, AppRequest},
         config::NetworkConfig,
-        peers::{AddressBook, Event},
+        identify_request::{IdentifyCodec, IdentifyProtocol},
+        ledger::{Ledger, PendingBlocks, RecentWants, Served, ServedStore},
+        peers::{
+            AddressBook, AddressBookConfig, Event, EventHandler, InboundFilter, ReconnectPolicy,
+            SwarmEvents, TooManySubscribers,
+        },
+        redial::Redials,
+        serve_queue::FairBitswap,
     },
     variable::Writer,
     AddressSource, PeerInfo,
@@ -11,32 +19,50 @@ use futures::channel::{
     mpsc::{self, UnboundedSender},
     oneshot,
 };
-use libipld::{store::StoreParams, Cid, DefaultParams, Result};
+use libipld::{error::BlockNotFound, store::StoreParams, Cid, DefaultParams, Result};
 #[cfg(feature = "async_global")]
 use libp2p::mdns::async_io::Behaviour as Mdns;
 #[cfg(all(feature = "tokio", not(feature = "async_global")))]
 use libp2p::mdns::tokio::Behaviour as Mdns;
 use libp2p::{
-    core::ConnectedPoint,
+    core::{transport::ListenerId, ConnectedPoint},
     gossipsub,
     gossipsub::{
-        Event as GossipsubEvent, IdentTopic, Message as GossipsubMessage, MessageAuthenticity,
+        Event as GossipsubEvent, IdentTopic, Message as GossipsubMessage, MessageAcceptance,
+        MessageAuthenticity,
     },
     identify,
     kad::{
-        record::{store::MemoryStore, Key, Record},
+        record::{
+            store::{MemoryStore, RecordStore},
+            Key, Record,
+        },
         AddProviderOk, BootstrapOk, GetClosestPeersOk, GetProvidersOk, GetRecordOk, Kademlia,
-        KademliaEvent, PeerRecord, ProgressStep, PutRecordOk, QueryResult, Quorum, K_VALUE,
+        KademliaConfig, KademliaEvent, Mode, PeerRecord, ProgressStep, PutRecordOk, QueryResult,
+        Quorum, K_VALUE,
     },
     mdns, ping,
-    swarm::{behaviour::toggle::Toggle, ConnectionError, ConnectionHandler, NetworkBehaviour},
-    Multiaddr, PeerId,
+    request_response::{
+        ProtocolSupport, RequestId, RequestResponse, RequestResponseConfig, RequestResponseEvent,
+        RequestResponseMessage, ResponseChannel,
+    },
+    swarm::{
+        behaviour::toggle::Toggle, ConnectionError, ConnectionHandler, ConnectionId,
+        NetworkBehaviour,
+    },
+    Multiaddr, PeerId, StreamProtocol,
 };
 use libp2p_bitswap::{Bitswap, BitswapEvent, BitswapStore};
 use libp2p_broadcast::{Broadcast, BroadcastEvent, Topic};
 use std::{collections::HashSet, sync::Arc, time::Duration};
 use thiserror::Error;
 
+/// The Kademlia defaults for re-replicating and republishing records and for
+/// republishing provider records, spread with `NetworkConfig::jitter`.
+const KAD_REPLICATION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const KAD_PUBLICATION_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+const KAD_PROVIDER_PUBLICATION_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct QueryId(InnerQueryId);
 
@@ -58,24 +84,42 @@ impl From<libp2p::kad::QueryId> for QueryId {
     }
 }
 
+/// Progress of a sync query.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FetchProgress {
+    /// The amount of subtrees to sync. If it is syncing a linked list, it
+    /// will always be 1.
+    pub missing: usize,
+    /// The providers of the query that are currently connected, i.e. that
+    /// blocks can be received from. Bitswap doesn't report which of them
+    /// sent a particular block.
+    pub active_providers: Vec<PeerId>,
+}
+
 /// An event of a sync query.
 #[derive(Debug)]
 pub enum SyncEvent {
-    /// Signals that the sync query made progress and counts the amount of
-    /// subtrees to sync. If it is syncing a linked list, it will always be
-    /// 1.
-    Progress { missing: usize },
+    /// Signals that the sync query made progress, emitted whenever a block
+    /// arrives.
+    Progress(FetchProgress),
     /// Signals completion of the sync query and if it was completed
     /// successfully.
     Complete(Result<()>),
 }
 
 pub type GetChannel = oneshot::Receiver<Result<()>>;
+pub type AppHandlers = FnvHashMap<String, mpsc::Sender<AppRequest>>;
+pub type AppRequests = FnvHashMap<RequestId, oneshot::Sender<Result<Vec<u8>>>>;
+/// The inbound app protocol requests that have not been answered yet.
+pub type AppServing = FnvHashSet<RequestId>;
+pub type IdentifyRequests = FnvHashMap<RequestId, oneshot::Sender<Result<PeerInfo>>>;
 pub type SyncChannel = mpsc::UnboundedReceiver<SyncEvent>;
 
 pub enum QueryChannel {
-    Get(oneshot::Sender<Result<()>>),
-    Sync(mpsc::UnboundedSender<SyncEvent>),
+    /// a block request, its providers and everyone waiting for it
+    Get(Cid, Vec<PeerId>, Vec<oneshot::Sender<Result<()>>>),
+    /// a sync query, its root and its providers
+    Sync(Cid, Vec<PeerId>, mpsc::UnboundedSender<SyncEvent>),
     Bootstrap(oneshot::Sender<Result<()>>),
     #[allow(dead_code)]
     GetClosestPeers(oneshot::Sender<Result<Vec<PeerId>>>),
@@ -95,6 +139,80 @@ pub enum GossipEvent {
     Unsubscribed(PeerId),
 }
 
+/// A pubsub message that is validated before it is delivered to the
+/// subscribers, see [`Ipfs::subscribe_validated`](crate::Ipfs::subscribe_validated).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PubsubMessage {
+    /// The peer that published the message.
+    pub source: PeerId,
+    /// The peer the message was received from.
+    pub propagation_source: PeerId,
+    pub topic: String,
+    pub data: Arc<[u8]>,
+}
+
+/// The outcome of validating a [`PubsubMessage`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValidationResult {
+    /// The message is delivered to the subscribers and forwarded.
+    Accept,
+    /// The message is dropped and the peer it was received from is penalized
+    /// if gossipsub peer scoring is enabled.
+    Reject,
+    /// The message is dropped without penalizing anyone.
+    Ignore,
+}
+
+impl From<ValidationResult> for MessageAcceptance {
+    fn from(result: ValidationResult) -> Self {
+        match result {
+            ValidationResult::Accept => Self::Accept,
+            ValidationResult::Reject => Self::Reject,
+            ValidationResult::Ignore => Self::Ignore,
+        }
+    }
+}
+
+/// Decides whether a message of a topic is delivered and forwarded.
+pub struct Validator(Box<dyn Fn(&PubsubMessage) -> ValidationResult + Send>);
+
+impl Validator {
+    pub fn new(validator: impl Fn(&PubsubMessage) -> ValidationResult + Send + 'static) -> Self {
+        Self(Box::new(validator))
+    }
+}
+
+impl std::fmt::Debug for Validator {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_tuple("Validator").finish()
+    }
+}
+
+/// The validators of the subscribed topics.
+#[derive(Debug, Default)]
+pub struct Validators {
+    /// whether gossipsub waits for messages to be validated before forwarding
+    /// them, in which case every message needs to be reported
+    enabled: bool,
+    topics: FnvHashMap<String, Validator>,
+}
+
+impl Validators {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            topics: Default::default(),
+        }
+    }
+
+    fn validate(&self, msg: &PubsubMessage) -> ValidationResult {
+        self.topics
+            .get(&msg.topic)
+            .map(|validator| (validator.0)(msg))
+            .unwrap_or(ValidationResult::Accept)
+    }
+}
+
 pub(crate) type MyHandlerError = <<<NetworkBackendBehaviour<DefaultParams> as NetworkBehaviour>
     ::ConnectionHandler as IntoConnectionHandler>::Handler as ConnectionHandler>::Error;
 
@@ -105,9 +223,11 @@ pub struct NetworkBackendBehaviour<P: StoreParams> {
     mdns: Toggle<Mdns>,
     ping: Toggle<ping::Behaviour>,
     identify: Toggle<identify::Behaviour>,
-    bitswap: Toggle<Bitswap<P>>,
+    identify_request: Toggle<RequestResponse<IdentifyCodec>>,
+    bitswap: Toggle<FairBitswap<P>>,
     gossipsub: Toggle<gossipsub::Behaviour>,
     broadcast: Toggle<Broadcast>,
+    app: Toggle<RequestResponse<AppCodec>>,
 }
 
 impl<P: StoreParams> NetworkBackendBehaviour<P> {
@@ -115,7 +235,10 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         match event {
             mdns::Event::Discovered(list) => {
                 for (peer_id, addr) in list {
-                    self.add_address(&peer_id, addr, AddressSource::Mdns);
+                    // keep the DHT free of addresses we can't route to
+                    if self.peers.accepts_mdns(&addr) {
+                        self.add_address(&peer_id, addr, AddressSource::Mdns);
+                    }
                 }
             }
             mdns::Event::Expired(_) => {
@@ -133,6 +256,22 @@ pub struct DisabledProtocol(&'static str);
 #[error("Trying to use kad before bootstrap completed successfully.")]
 pub struct NotBootstrapped;
 
+#[derive(Debug, Error)]
+#[error("The DHT was disabled in `NetworkConfig`.")]
+pub struct DhtDisabled;
+
+#[derive(Debug, Error)]
+#[error("The node is draining and doesn't accept new work.")]
+pub struct Draining;
+
+#[derive(Debug, Error)]
+#[error("Too many bitswap queries are running.")]
+pub struct Overloaded;
+
+#[derive(Debug, Error)]
+#[error("Peer {0} is not connected.")]
+pub struct NotConnected(pub PeerId);
+
 #[derive(Debug, Error)]
 #[error("{0:?}")]
 pub struct KadStoreError(pub libp2p::kad::record::store::Error);
@@ -298,15 +437,31 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
     ) {
         match event {
             BitswapEvent::Progress(id, missing) => {
-                if let Some(QueryChannel::Sync(ch)) = queries.get(&id.into()) {
-                    ch.unbounded_send(SyncEvent::Progress { missing }).ok();
+                if let Some(QueryChannel::Sync(_, providers, ch)) = queries.get(&id.into()) {
+                    let active_providers = providers
+                        .iter()
+                        .filter(|peer| self.peers.is_connected(peer))
+                        .copied()
+                        .collect();
+                    ch.unbounded_send(SyncEvent::Progress(FetchProgress {
+                        missing,
+                        active_providers,
+                    }))
+                    .ok();
                 }
             }
             BitswapEvent::Complete(id, result) => match queries.remove(&id.into()) {
-                Some(QueryChannel::Get(ch)) => {
-                    ch.send(result).ok();
+                Some(QueryChannel::Get(cid, _, waiters)) => {
+                    for ch in waiters {
+                        ch.send(copy_result(&result)).ok();
+                    }
+                    // requests for the same block asking other providers
+                    // are done as well
+                    if result.is_ok() {
+                        self.block_received(&cid, queries);
+                    }
                 }
-                Some(QueryChannel::Sync(ch)) => {
+                Some(QueryChannel::Sync(_, _, ch)) => {
                     ch.unbounded_send(SyncEvent::Complete(result)).ok();
                 }
                 _ => {}
@@ -315,6 +470,166 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
     }
 }
 
+/// Copies the result of a block request for one of its waiters. Errors other
+/// than `BlockNotFound` are passed on as their message.
+fn copy_result(result: &Result<()>) -> Result<()> {
+    match result {
+        Ok(()) => Ok(()),
+        Err(err) => match err.downcast_ref::<BlockNotFound>() {
+            Some(BlockNotFound(cid)) => Err(BlockNotFound(*cid).into()),
+            None => Err(anyhow::anyhow!("{:#}", err)),
+        },
+    }
+}
+
+impl<P: StoreParams> NetworkBackendBehaviour<P> {
+    /// Hands an incoming request to the handler registered for its protocol.
+    /// Returns the channel on which the handler answers, the request is
+    /// dropped if there is no handler or the node is draining.
+    pub fn inject_app_event(
+        &mut self,
+        event: RequestResponseEvent<AppMessage, Vec<u8>>,
+        handlers: &mut AppHandlers,
+        requests: &mut AppRequests,
+        serving: &mut AppServing,
+        draining: bool,
+    ) -> Option<(oneshot::Receiver<Vec<u8>>, ResponseChannel<Vec<u8>>)> {
+        match event {
+            RequestResponseEvent::Message { peer, message } => match message {
+                RequestResponseMessage::Request {
+                    request_id,
+                    request,
+                    channel,
+                } => {
+                    if draining {
+                        // dropping the channel fails the request
+                        tracing::debug!("draining, dropping app request from {}", peer);
+                        return None;
+                    }
+                    // every request ends with the response sent or an inbound
+                    // failure, also when the channel is dropped
+                    serving.insert(request_id);
+                    let AppMessage { protocol, data } = request;
+                    if protocol == app_protocol::INTRODUCE {
+                        self.inject_introduction(&peer, &data);
+                        self.respond(channel, vec![]);
+                        return None;
+                    }
+                    let (request, rx) = AppRequest::new(peer, data);
+                    match handlers.get_mut(&protocol).map(|h| h.try_send(request)) {
+                        Some(Ok(())) => return Some((rx, channel)),
+                        Some(Err(err)) if err.is_full() => {
+                            tracing::debug!(
+                                "handler of {} is busy, dropping request from {}",
+                                protocol,
+                                peer
+                            );
+                            return None;
+                        }
+                        Some(Err(_)) => {
+                            handlers.remove(&protocol);
+                        }
+                        None => {}
+                    }
+                    tracing::debug!("no handler for request of {} from {}", protocol, peer);
+                }
+                RequestResponseMessage::Response {
+                    request_id,
+                    response,
+                } => {
+                    if let Some(tx) = requests.remove(&request_id) {
+                        tx.send(Ok(response)).ok();
+                    }
+                }
+            },
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                if let Some(tx) = requests.remove(&request_id) {
+                    tx.send(Err(anyhow::anyhow!(
+                        "request to {} failed: {:?}",
+                        peer,
+                        error
+                    )))
+                    .ok();
+                }
+            }
+            RequestResponseEvent::InboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                serving.remove(&request_id);
+                tracing::trace!("request from {} failed: {:?}", peer, error);
+            }
+            RequestResponseEvent::ResponseSent { request_id, .. } => {
+                serving.remove(&request_id);
+            }
+        }
+        None
+    }
+
+    pub fn register_protocol(
+        &mut self,
+        protocol: String,
+        max_pending: usize,
+        handlers: &mut AppHandlers,
+    ) -> Result<mpsc::Receiver<AppRequest>> {
+        if self.app.as_ref().is_none() {
+            return Err(DisabledProtocol("app").into());
+        }
+        if protocol == app_protocol::INTRODUCE {
+            return Err(anyhow::anyhow!("protocol {} is reserved", protocol));
+        }
+        if let Some(handler) = handlers.get(&protocol) {
+            if !handler.is_closed() {
+                return Err(anyhow::anyhow!(
+                    "protocol {} is already registered",
+                    protocol
+                ));
+            }
+        }
+        let (tx, rx) = mpsc::channel(max_pending);
+        handlers.insert(protocol, tx);
+        Ok(rx)
+    }
+
+    pub fn request(
+        &mut self,
+        peer: &PeerId,
+        protocol: String,
+        data: Vec<u8>,
+        requests: &mut AppRequests,
+        tx: oneshot::Sender<Result<Vec<u8>>>,
+    ) {
+        if let Some(app) = self.app.as_mut() {
+            let id = app.send_request(peer, AppMessage { protocol, data });
+            requests.insert(id, tx);
+        } else {
+            tx.send(Err(DisabledProtocol("app").into())).ok();
+        }
+    }
+
+    /// Adds the addresses another peer shared with us via `introduce_peer`,
+    /// if `NetworkConfig::introductions` accepts them from that peer.
+    fn inject_introduction(&mut self, from: &PeerId, data: &[u8]) {
+        match app_protocol::decode_introduction(data) {
+            Ok((peer, addrs)) => self.peers.add_introduced(from, &peer, addrs),
+            Err(err) => tracing::debug!("invalid introduction from {}: {:#}", from, err),
+        }
+    }
+
+    pub fn respond(&mut self, channel: ResponseChannel<Vec<u8>>, data: Vec<u8>) {
+        if let Some(app) = self.app.as_mut() {
+            if app.send_response(channel, data).is_err() {
+                tracing::trace!("requester went away before the response was sent");
+            }
+        }
+    }
+}
+
 impl<P: StoreParams> NetworkBackendBehaviour<P> {
     pub fn inject_ping_event(&mut self, event: ping::Event) {
         // Don't really need to do anything here as ping handles disconnecting
@@ -349,6 +664,66 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
             self.peers.set_info(&peer_id, info);
         }
     }
+
+    /// Asks a connected peer for its identify info now.
+    pub fn refresh_peer_info(
+        &mut self,
+        peer: &PeerId,
+        requests: &mut IdentifyRequests,
+        tx: oneshot::Sender<Result<PeerInfo>>,
+    ) {
+        let identify = match self.identify_request.as_mut() {
+            Some(identify) => identify,
+            None => {
+                tx.send(Err(DisabledProtocol("identify").into())).ok();
+                return;
+            }
+        };
+        if !self.peers.is_connected(peer) {
+            tx.send(Err(NotConnected(*peer).into())).ok();
+            return;
+        }
+        let id = identify.send_request(peer, ());
+        requests.insert(id, tx);
+    }
+
+    pub fn inject_identify_request_event(
+        &mut self,
+        event: RequestResponseEvent<(), identify::Info>,
+        requests: &mut IdentifyRequests,
+    ) {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Response {
+                        request_id,
+                        response,
+                    },
+            } => {
+                self.peers.set_info(&peer, response);
+                if let Some(tx) = requests.remove(&request_id) {
+                    let info = self.peers.info(&peer).ok_or(NotConnected(peer));
+                    tx.send(info.map_err(Into::into)).ok();
+                }
+            }
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                if let Some(tx) = requests.remove(&request_id) {
+                    tx.send(Err(anyhow::anyhow!(
+                        "identify request to {} failed: {:?}",
+                        peer,
+                        error
+                    )))
+                    .ok();
+                }
+            }
+            _ => {}
+        }
+    }
 }
 
 #[derive(Debug, Error)]
@@ -360,6 +735,8 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         &mut self,
         event: GossipsubEvent,
         subscriptions: &mut FnvHashMap<String, Vec<mpsc::UnboundedSender<GossipEvent>>>,
+        validators: &Validators,
+        mesh: &mut FnvHashMap<String, FnvHashSet<PeerId>>,
     ) {
         match event {
             GossipsubEvent::Message {
@@ -371,13 +748,33 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
                         ..
                     },
                 propagation_source,
-                ..
+                message_id,
             } => {
-                self.notify_subscribers(
-                    &topic.to_string(),
-                    GossipEvent::Message(source.unwrap_or(propagation_source), data.into()),
-                    subscriptions,
-                );
+                let msg = PubsubMessage {
+                    source: source.unwrap_or(propagation_source),
+                    propagation_source,
+                    topic: topic.to_string(),
+                    data: data.into(),
+                };
+                let result = validators.validate(&msg);
+                if validators.enabled {
+                    if let Some(gossipsub) = self.gossipsub.as_mut() {
+                        if let Err(err) = gossipsub.report_message_validation_result(
+                            &message_id,
+                            &propagation_source,
+                            result.into(),
+                        ) {
+                            tracing::trace!("forwarding message failed with {:?}", err);
+                        }
+                    }
+                }
+                if result == ValidationResult::Accept {
+                    self.notify_subscribers(
+                        &msg.topic,
+                        GossipEvent::Message(msg.source, msg.data),
+                        subscriptions,
+                    );
+                }
             }
             GossipsubEvent::Subscribed { peer_id, topic, .. } => {
                 self.peers
@@ -388,6 +785,7 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
                     GossipEvent::Subscribed(peer_id),
                     subscriptions,
                 );
+                self.mesh_changes(topic.as_str(), mesh);
             }
             GossipsubEvent::Unsubscribed { peer_id, topic, .. } => {
                 self.peers
@@ -397,10 +795,57 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
                     GossipEvent::Unsubscribed(peer_id),
                     subscriptions,
                 );
+                self.mesh_changes(topic.as_str(), mesh);
             }
             GossipsubEvent::GossipsubNotSupported { .. } => {}
         }
     }
+
+    /// Compares the gossipsub mesh of `topic` with the previously seen
+    /// `mesh`, emitting an event for every peer that was grafted or pruned
+    /// since.
+    ///
+    /// Gossipsub doesn't emit events for mesh changes. Subscriptions change
+    /// the mesh right away, so this is called for the topic of every
+    /// subscription, while the grafts and prunes of the heartbeat are picked
+    /// up by `mesh_heartbeat`.
+    pub fn mesh_changes(&mut self, topic: &str, mesh: &mut FnvHashMap<String, FnvHashSet<PeerId>>) {
+        let current = match self.gossipsub.as_ref() {
+            Some(gossipsub) => gossipsub
+                .mesh_peers(&IdentTopic::new(topic).hash())
+                .copied()
+                .collect::<FnvHashSet<_>>(),
+            None => return,
+        };
+        let previous = mesh.remove(topic).unwrap_or_default();
+        for peer in current.difference(&previous) {
+            self.peers
+                .notify(Event::MeshPeerAdded(topic.to_owned(), *peer));
+        }
+        for peer in previous.difference(&current) {
+            self.peers
+                .notify(Event::MeshPeerRemoved(topic.to_owned(), *peer));
+        }
+        if !current.is_empty() {
+            mesh.insert(topic.to_owned(), current);
+        }
+    }
+
+    /// Checks the mesh of every topic for changes, to be called at the
+    /// interval of the gossipsub heartbeat.
+    pub fn mesh_heartbeat(&mut self, mesh: &mut FnvHashMap<String, FnvHashSet<PeerId>>) {
+        let topics = match self.gossipsub.as_ref() {
+            Some(gossipsub) => gossipsub
+                .topics()
+                .map(|topic| topic.to_string())
+                .chain(mesh.keys().cloned())
+                .collect::<FnvHashSet<_>>(),
+            None => return,
+        };
+        for topic in topics {
+            self.mesh_changes(&topic, mesh);
+        }
+    }
 }
 
 impl<P: StoreParams> NetworkBackendBehaviour<P> {
@@ -408,11 +853,25 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         &mut self,
         event: BroadcastEvent,
         subscriptions: &mut FnvHashMap<String, Vec<mpsc::UnboundedSender<GossipEvent>>>,
+        validators: &Validators,
     ) {
         match event {
             BroadcastEvent::Received(peer_id, topic, data) => {
-                let topic = std::str::from_utf8(&topic).unwrap();
-                self.notify_subscribers(topic, GossipEvent::Message(peer_id, data), subscriptions);
+                // broadcast messages are not forwarded, so validating them only
+                // decides whether they are delivered
+                let msg = PubsubMessage {
+                    source: peer_id,
+                    propagation_source: peer_id,
+                    topic: std::str::from_utf8(&topic).unwrap().to_string(),
+                    data,
+                };
+                if validators.validate(&msg) == ValidationResult::Accept {
+                    self.notify_subscribers(
+                        &msg.topic,
+                        GossipEvent::Message(peer_id, msg.data),
+                        subscriptions,
+                    );
+                }
             }
             BroadcastEvent::Subscribed(peer_id, topic) => {
                 if let Ok(topic) = std::str::from_utf8(&topic) {
@@ -443,6 +902,9 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         listeners: Writer<FnvHashSet<Multiaddr>>,
         peers: Writer<FnvHashMap<PeerId, PeerInfo>>,
         external: Writer<Vec<AddressRecord>>,
+        ledgers: Writer<FnvHashMap<PeerId, Ledger>>,
+        wants: Writer<RecentWants>,
+        pending: PendingBlocks,
     ) -> Result<Self> {
         let node_key = libp2p::identity::Keypair::Ed25519(config.node_key.clone());
         let node_name = config.node_name.clone();
@@ -452,13 +914,36 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         } else {
             None
         };
-        let kad = if let Some(config) = config.kad.take() {
-            let kad_store = MemoryStore::with_config(peer_id, config);
-            Some(Kademlia::new(peer_id, kad_store))
+        let kad = if let Some(kad_config) = config.kad.take() {
+            let kad_store = MemoryStore::with_config(peer_id, kad_config);
+            let mut kad_config = KademliaConfig::default();
+            kad_config.set_replication_factor(config.kad_replication_factor);
+            let jitter = config.jitter;
+            kad_config.set_replication_interval(Some(jitter.apply(KAD_REPLICATION_INTERVAL)));
+            kad_config.set_publication_interval(Some(jitter.apply(KAD_PUBLICATION_INTERVAL)));
+            kad_config.set_provider_publication_interval(Some(
+                jitter.apply(KAD_PROVIDER_PUBLICATION_INTERVAL),
+            ));
+            if let Some(protocol) = &config.kad_protocol {
+                let name = StreamProtocol::try_from_owned(protocol.clone())
+                    .map_err(|err| anyhow::anyhow!("invalid kad protocol {}: {}", protocol, err))?;
+                kad_config.set_protocol_names(vec![name]);
+            }
+            let mut kad = Kademlia::with_config(peer_id, kad_store, kad_config);
+            kad.set_mode(match config.kad_mode {
+                KadMode::Auto => None,
+                KadMode::Client => Some(Mode::Client),
+                KadMode::Server => Some(Mode::Server),
+            });
+            Some(kad)
         } else {
             None
         };
-        let ping = config.ping.take().map(ping::Behaviour::new);
+        let ping_interval = config.jitter.apply(config.ping_interval);
+        let ping = config
+            .ping
+            .take()
+            .map(|ping| ping::Behaviour::new(ping.with_interval(ping_interval)));
         let identify = if let Some(mut config) = config.identify.take() {
             config.local_public_key = node_key.public();
             config.agent_version = node_name;
@@ -466,37 +951,97 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         } else {
             None
         };
-        let gossipsub = if let Some(config) = config.gossipsub.take() {
+        let identify_request = identify.as_ref().map(|_| {
+            RequestResponse::new(
+                IdentifyCodec,
+                std::iter::once((IdentifyProtocol, ProtocolSupport::Outbound)),
+                RequestResponseConfig::default(),
+            )
+        });
+        let gossipsub = if let Some(mut gossipsub_config) = config.gossipsub.take() {
+            if let Some(mesh) = &config.gossipsub_mesh {
+                gossipsub_config = mesh.apply(gossipsub_config)?;
+            }
             let gossipsub =
-                gossipsub::Behaviour::new(MessageAuthenticity::Signed(node_key), config)
+                gossipsub::Behaviour::new(MessageAuthenticity::Signed(node_key), gossipsub_config)
                     .map_err(|err| anyhow::anyhow!("{}", err))?;
             Some(gossipsub)
         } else {
             None
         };
         let broadcast = config.broadcast.take().map(Broadcast::new);
-        let bitswap = config
-            .bitswap
-            .take()
-            .map(|config| Bitswap::new(config, store));
-        Ok(Self {
-            peers: AddressBook::new(
-                peer_id,
-                config.port_reuse,
-                config.enable_loopback,
-                config.keep_alive,
+        let max_serve_queue = config.max_serve_queue_per_peer;
+        let max_pending = config.max_pending_block_bytes;
+        let bitswap = config.bitswap.take().map(|config| {
+            let served = Served::default();
+            let store = ServedStore::new(store, served.clone());
+            FairBitswap::new(
+                Bitswap::new(config, store),
+                max_serve_queue,
+                served,
+                ledgers,
+                wants,
+                max_pending,
+                pending,
+            )
+        });
+        let app = config.app_protocol.take().map(|config| {
+            let mut rr_config = RequestResponseConfig::default();
+            rr_config.set_request_timeout(config.request_timeout);
+            RequestResponse::new(
+                AppCodec::new(config.max_message_size),
+                std::iter::once((AppProtocol, ProtocolSupport::Full)),
+                rr_config,
+            )
+        });
+        let mut behaviour = Self {
+            peers: AddressBook::new(AddressBookConfig {
+                local_peer_id: peer_id,
+                port_reuse: config.port_reuse,
+                enable_loopback: config.enable_loopback,
+                keep_alive: config.keep_alive,
+                external_port_map: config.external_port_map.clone(),
+                redials: Redials::new(config.redial_backoff, config.max_redials),
+                external_quiet_period: config.external_addrs_quiet_period,
+                normalize: config.normalize_address,
+                tie_break: config.tie_break_connections,
+                mdns_filter: config.mdns_interface_filter.clone(),
+                discovery_batch: config.discovery_batch_interval,
+                dial_budget: config.dial_budget,
+                max_subscribers: config.max_event_subscribers,
                 listeners,
                 peers,
                 external,
-            ),
+            }),
             mdns: mdns.into(),
             kad: kad.into(),
             ping: ping.into(),
             identify: identify.into(),
+            identify_request: identify_request.into(),
             bitswap: bitswap.into(),
             gossipsub: gossipsub.into(),
             broadcast: broadcast.into(),
-        })
+            app: app.into(),
+        };
+        behaviour
+            .peers
+            .set_external_addr_policy(config.external_addr_policy.clone());
+        behaviour
+            .peers
+            .set_connection_gate(config.connection_gate.clone());
+        behaviour
+            .peers
+            .set_trust_remote_loopback(config.trust_remote_loopback);
+        behaviour.peers.set_fd_soft_limit(config.fd_soft_limit);
+        behaviour
+            .peers
+            .set_introductions(config.introductions.clone());
+        behaviour.peers.set_jitter(config.jitter);
+        behaviour.peers.set_clock(config.clock.clone());
+        for (peer, addrs) in config.static_peers.drain(..) {
+            behaviour.add_static_peer(peer, addrs, config.static_peer_reconnect);
+        }
+        Ok(behaviour)
     }
 
     pub fn add_address(&mut self, peer_id: &PeerId, addr: Multiaddr, source: AddressSource) {
@@ -506,6 +1051,24 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         self.peers.add_address(peer_id, addr, source);
     }
 
+    pub fn add_static_peer(
+        &mut self,
+        peer_id: PeerId,
+        addrs: Vec<Multiaddr>,
+        reconnect: ReconnectPolicy,
+    ) {
+        if let Some(kad) = self.kad.as_mut() {
+            for addr in &addrs {
+                kad.add_address(&peer_id, addr.clone());
+            }
+        }
+        self.peers.add_static_peer(peer_id, addrs, reconnect);
+    }
+
+    pub fn remove_static_peer(&mut self, peer_id: &PeerId) {
+        self.peers.remove_static_peer(peer_id);
+    }
+
     pub fn remove_address(&mut self, peer_id: &PeerId, addr: &Multiaddr) {
         self.peers.remove_address(peer_id, addr);
         if let Some(kad) = self.kad.as_mut() {
@@ -517,10 +1080,34 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         self.peers.prune_peers(min_age);
     }
 
+    pub fn prune_stale_peers(&mut self, max_age: Duration) {
+        self.peers.prune_stale_peers(max_age);
+    }
+
+    pub fn load_peers(&mut self, peers: Vec<(PeerId, PeerInfo)>) {
+        self.peers.load_peers(peers);
+    }
+
     pub fn dial(&mut self, peer_id: &PeerId) {
         self.peers.dial(peer_id);
     }
 
+    pub fn storage_error(&mut self, err: String) {
+        self.peers.notify(Event::StorageError(err));
+    }
+
+    pub fn listening_on(&mut self, listener: ListenerId, addr: Multiaddr) {
+        self.peers.listening_on(listener, addr);
+    }
+
+    pub fn set_inbound_filter(&mut self, filter: InboundFilter) {
+        self.peers.set_boxed_inbound_filter(filter);
+    }
+
+    pub fn set_event_handler(&mut self, handler: EventHandler) {
+        self.peers.set_boxed_event_handler(handler);
+    }
+
     pub fn dial_address(&mut self, peer_id: &PeerId, addr: Multiaddr) {
         self.peers.dial_address(peer_id, addr);
     }
@@ -528,12 +1115,13 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
     pub(crate) fn connection_closed(
         &mut self,
         peer: PeerId,
+        id: ConnectionId,
         cp: ConnectedPoint,
         num_established: u32,
         error: Option<ConnectionError<MyHandlerError>>,
     ) {
         self.peers
-            .connection_closed(peer, cp, num_established, error);
+            .connection_closed(peer, id, cp, num_established, error);
     }
 
     pub fn bootstrap(
@@ -541,17 +1129,42 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         queries: &mut FnvHashMap<QueryId, QueryChannel>,
         tx: oneshot::Sender<Result<()>>,
     ) {
-        if let Some(kad) = self.kad.as_mut() {
-            match kad.bootstrap() {
-                Ok(id) => {
-                    queries.insert(id.into(), QueryChannel::Bootstrap(tx));
-                }
-                Err(err) => {
-                    tx.send(Err(err.into())).ok();
-                }
+        match self.dht(true).and_then(|kad| Ok(kad.bootstrap()?)) {
+            Ok(id) => {
+                queries.insert(id.into(), QueryChannel::Bootstrap(tx));
+            }
+            Err(err) => {
+                tx.send(Err(err)).ok();
+            }
+        }
+    }
+
+    /// The Kademlia behaviour for a DHT operation. Fails with `DhtDisabled`
+    /// if `NetworkConfig::kad` is `None`, and with `NotBootstrapped` for
+    /// operations that need a bootstrapped routing table before it is.
+    fn dht(&mut self, bootstrap_complete: bool) -> Result<&mut Kademlia<MemoryStore>> {
+        let kad = self.kad.as_mut().ok_or(DhtDisabled)?;
+        if !bootstrap_complete {
+            return Err(NotBootstrapped.into());
+        }
+        Ok(kad)
+    }
+
+    /// Looks up the peers closest to `peer`, adding them to the routing table.
+    pub fn get_closest_peers(
+        &mut self,
+        peer: PeerId,
+        queries: &mut FnvHashMap<QueryId, QueryChannel>,
+        tx: oneshot::Sender<Result<Vec<PeerId>>>,
+    ) {
+        match self.dht(true) {
+            Ok(kad) => {
+                let id = kad.get_closest_peers(peer);
+                queries.insert(id.into(), QueryChannel::GetClosestPeers(tx));
+            }
+            Err(err) => {
+                tx.send(Err(err)).ok();
             }
-        } else {
-            tx.send(Err(NotBootstrapped.into())).ok();
         }
     }
 
@@ -583,19 +1196,17 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         queries: &mut FnvHashMap<QueryId, QueryChannel>,
         tx: oneshot::Sender<Result<()>>,
     ) {
-        if bootstrap_complete {
-            if let Some(kad) = self.kad.as_mut() {
-                match kad.start_providing(key) {
-                    Ok(id) => {
-                        queries.insert(id.into(), QueryChannel::StartProviding(tx));
-                    }
-                    Err(err) => {
-                        tx.send(Err(KadStoreError(err).into())).ok();
-                    }
-                }
+        let res = self.dht(bootstrap_complete).and_then(|kad| {
+            kad.start_providing(key)
+                .map_err(|err| KadStoreError(err).into())
+        });
+        match res {
+            Ok(id) => {
+                queries.insert(id.into(), QueryChannel::StartProviding(tx));
+            }
+            Err(err) => {
+                tx.send(Err(err)).ok();
             }
-        } else {
-            tx.send(Err(NotBootstrapped.into())).ok();
         }
     }
 
@@ -612,16 +1223,28 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         queries: &mut FnvHashMap<QueryId, QueryChannel>,
         tx: oneshot::Sender<Result<HashSet<PeerId>>>,
     ) {
-        if bootstrap_complete {
-            if let Some(kad) = self.kad.as_mut() {
+        match self.dht(bootstrap_complete) {
+            Ok(kad) => {
                 let id = kad.get_providers(key);
                 queries.insert(id.into(), QueryChannel::GetProviders(HashSet::new(), tx));
             }
-        } else {
-            tx.send(Err(NotBootstrapped.into())).ok();
+            Err(err) => {
+                tx.send(Err(err)).ok();
+            }
         }
     }
 
+    /// The providers of `key` whose records are stored on this node.
+    pub fn local_providers(&mut self, key: &Key) -> Result<HashSet<PeerId>> {
+        let kad = self.dht(true)?;
+        Ok(kad
+            .store_mut()
+            .providers(key)
+            .into_iter()
+            .map(|record| record.provider)
+            .collect())
+    }
+
     pub fn get_record(
         &mut self,
         key: Key,
@@ -630,8 +1253,8 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         queries: &mut FnvHashMap<QueryId, QueryChannel>,
         tx: oneshot::Sender<Result<Vec<PeerRecord>>>,
     ) {
-        if bootstrap_complete {
-            if let Some(kad) = self.kad.as_mut() {
+        match self.dht(bootstrap_complete) {
+            Ok(kad) => {
                 let quorum = match quorum {
                     Quorum::One => 1,
                     Quorum::Majority => K_VALUE.get() / 2 + 1,
@@ -641,8 +1264,9 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
                 let id = kad.get_record(key);
                 queries.insert(id.into(), QueryChannel::GetRecord(quorum, vec![], tx));
             }
-        } else {
-            tx.send(Err(NotBootstrapped.into())).ok();
+            Err(err) => {
+                tx.send(Err(err)).ok();
+            }
         }
     }
 
@@ -654,19 +1278,17 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         queries: &mut FnvHashMap<QueryId, QueryChannel>,
         tx: oneshot::Sender<Result<()>>,
     ) {
-        if bootstrap_complete {
-            if let Some(kad) = self.kad.as_mut() {
-                match kad.put_record(record, quorum) {
-                    Ok(id) => {
-                        queries.insert(id.into(), QueryChannel::PutRecord(tx));
-                    }
-                    Err(err) => {
-                        tx.send(Err(KadStoreError(err).into())).ok();
-                    }
-                }
+        let res = self.dht(bootstrap_complete).and_then(|kad| {
+            kad.put_record(record, quorum)
+                .map_err(|err| KadStoreError(err).into())
+        });
+        match res {
+            Ok(id) => {
+                queries.insert(id.into(), QueryChannel::PutRecord(tx));
+            }
+            Err(err) => {
+                tx.send(Err(err)).ok();
             }
-        } else {
-            tx.send(Err(NotBootstrapped.into())).ok();
         }
     }
 
@@ -679,11 +1301,18 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
     pub fn subscribe(
         &mut self,
         topic: &str,
+        validator: Option<Validator>,
         subscriptions: &mut FnvHashMap<String, Vec<mpsc::UnboundedSender<GossipEvent>>>,
+        validators: &mut Validators,
     ) -> Result<mpsc::UnboundedReceiver<GossipEvent>> {
         if self.gossipsub.as_ref().is_none() && self.broadcast.as_ref().is_none() {
             return Err(DisabledProtocol("gossipsub and broadcast").into());
         }
+        if validator.is_some() && self.gossipsub.as_ref().is_some() && !validators.enabled {
+            return Err(anyhow::anyhow!(
+                "validating messages requires a `GossipsubConfig` built with `validate_messages()`"
+            ));
+        }
         let (tx, rx) = mpsc::unbounded();
         if let Some(subscribers) = subscriptions.get_mut(topic) {
             subscribers.push(tx);
@@ -691,6 +1320,8 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
             let gossip_topic = IdentTopic::new(topic);
             let broadcast_topic = Topic::new(gossip_topic.hash().as_str().as_ref());
             subscriptions.insert(gossip_topic.hash().as_str().to_string(), vec![tx]);
+            // the validator of a previous subscription of the topic
+            validators.topics.remove(topic);
             if let Some(gossipsub) = self.gossipsub.as_mut() {
                 gossipsub
                     .subscribe(&gossip_topic)
@@ -700,6 +1331,9 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
                 broadcast.subscribe(broadcast_topic);
             }
         }
+        if let Some(validator) = validator {
+            validators.topics.insert(topic.to_string(), validator);
+        }
         Ok(rx)
     }
 
@@ -765,10 +1399,20 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         providers: impl Iterator<Item = PeerId>,
         queries: &mut FnvHashMap<QueryId, QueryChannel>,
     ) -> (GetChannel, QueryId) {
-        let bitswap = self.bitswap.as_mut().expect("bitswap enabled");
         let (tx, rx) = oneshot::channel();
-        let id = bitswap.get(cid, providers);
-        queries.insert(id.into(), QueryChannel::Get(tx));
+        let providers = providers.collect::<Vec<_>>();
+        // join a request for the same block that is still running and asks
+        // all of the providers
+        if let Some(id) = self.joinable(&cid, &providers, queries) {
+            if let Some(QueryChannel::Get(_, _, waiters)) = queries.get_mut(&id) {
+                tracing::trace!(cid = %cid, "joining running block request");
+                waiters.push(tx);
+                return (rx, id);
+            }
+        }
+        let bitswap = self.bitswap.as_mut().expect("bitswap enabled");
+        let id = bitswap.get(cid, providers.iter().copied());
+        queries.insert(id.into(), QueryChannel::Get(cid, providers, vec![tx]));
         (rx, id.into())
     }
 
@@ -781,19 +1425,174 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
     ) -> (SyncChannel, QueryId) {
         let bitswap = self.bitswap.as_mut().expect("bitswap enabled");
         let (tx, rx) = mpsc::unbounded();
-        let id = bitswap.sync(cid, providers, missing);
-        queries.insert(id.into(), QueryChannel::Sync(tx));
+        let id = bitswap.sync(cid, providers.clone(), missing);
+        queries.insert(id.into(), QueryChannel::Sync(cid, providers, tx));
         (rx, id.into())
     }
 
+    /// Stops serving new bitswap requests, new app protocol requests are
+    /// dropped by `inject_app_event` while draining.
+    pub fn drain(&mut self) {
+        if let Some(bitswap) = self.bitswap.as_mut() {
+            bitswap.drain();
+        }
+    }
+
+    /// Number of bitswap queries that are running.
+    pub fn fetches(&self, queries: &FnvHashMap<QueryId, QueryChannel>) -> usize {
+        queries
+            .values()
+            .filter(|query| matches!(query, QueryChannel::Get(..) | QueryChannel::Sync(..)))
+            .count()
+    }
+
+    /// The blocks requested and the roots synced by the running bitswap
+    /// queries.
+    pub fn wantlist(&self, queries: &FnvHashMap<QueryId, QueryChannel>) -> Vec<Cid> {
+        queries
+            .values()
+            .filter_map(|query| match query {
+                QueryChannel::Get(cid, ..) | QueryChannel::Sync(cid, ..) => Some(*cid),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Whether a block request for `cid` is running that a new one asking
+    /// `providers` would join.
+    pub fn is_fetching(
+        &self,
+        cid: &Cid,
+        providers: &[PeerId],
+        queries: &FnvHashMap<QueryId, QueryChannel>,
+    ) -> bool {
+        self.joinable(cid, providers, queries).is_some()
+    }
+
+    /// The running block request for `cid` that asks all of `providers`.
+    fn joinable(
+        &self,
+        cid: &Cid,
+        providers: &[PeerId],
+        queries: &FnvHashMap<QueryId, QueryChannel>,
+    ) -> Option<QueryId> {
+        queries.iter().find_map(|(id, query)| match query {
+            QueryChannel::Get(c, running, _)
+                if c == cid && providers.iter().all(|peer| running.contains(peer)) =>
+            {
+                Some(*id)
+            }
+            _ => None,
+        })
+    }
+
+    /// Cancels the oldest running sync, which fails with [`Overloaded`].
+    /// Returns `false` if no sync is running.
+    pub fn shed_sync(&mut self, queries: &mut FnvHashMap<QueryId, QueryChannel>) -> bool {
+        let oldest = queries
+            .iter()
+            .filter_map(|(id, query)| match (id, query) {
+                (QueryId(InnerQueryId::Bitswap(id)), QueryChannel::Sync(..)) => Some(*id),
+                _ => None,
+            })
+            .min();
+        let id = match oldest {
+            Some(id) => id,
+            None => return false,
+        };
+        tracing::debug!("overloaded, shedding sync {:?}", id);
+        if let Some(QueryChannel::Sync(_, _, ch)) = queries.remove(&id.into()) {
+            ch.unbounded_send(SyncEvent::Complete(Err(Overloaded.into())))
+                .ok();
+        }
+        self.bitswap.as_mut().unwrap().cancel(id);
+        true
+    }
+
+    /// Drops inbound bitswap requests while `overloaded`.
+    pub fn set_overloaded(&mut self, overloaded: bool) {
+        if let Some(bitswap) = self.bitswap.as_mut() {
+            bitswap.set_overloaded(overloaded);
+        }
+    }
+
+    pub fn notify(&mut self, event: Event) {
+        self.peers.notify(event);
+    }
+
+    pub fn reserve_fds(&mut self, fds: usize) {
+        self.peers.reserve_fds(fds);
+    }
+
+    /// Whether bitswap or app protocol requests of other peers are still
+    /// being answered, or blocks or app protocol responses are still being
+    /// fetched.
+    pub fn busy(
+        &self,
+        queries: &FnvHashMap<QueryId, QueryChannel>,
+        app_requests: &AppRequests,
+        app_serving: &AppServing,
+    ) -> bool {
+        let serving = self
+            .bitswap
+            .as_ref()
+            .map(|bitswap| bitswap.in_flight() > 0)
+            .unwrap_or_default();
+        serving
+            || !app_serving.is_empty()
+            || !app_requests.is_empty()
+            || queries
+                .values()
+                .any(|query| matches!(query, QueryChannel::Get(..) | QueryChannel::Sync(..)))
+    }
+
+    /// Completes the block requests for `cid` once bitswap stored the block,
+    /// e.g. received for a sync, and cancels their requests to the providers
+    /// that haven't answered yet.
+    pub fn block_received(&mut self, cid: &Cid, queries: &mut FnvHashMap<QueryId, QueryChannel>) {
+        let ids = queries
+            .iter()
+            .filter_map(|(id, query)| match query {
+                QueryChannel::Get(c, ..) if c == cid => Some(*id),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        for id in ids {
+            if let Some(QueryChannel::Get(_, _, waiters)) = queries.remove(&id) {
+                tracing::trace!(cid = %cid, "block received, cancelling its request");
+                for ch in waiters {
+                    ch.send(Ok(())).ok();
+                }
+            }
+            if let QueryId(InnerQueryId::Bitswap(id)) = id {
+                self.bitswap.as_mut().unwrap().cancel(id);
+            }
+        }
+    }
+
     pub fn cancel(&mut self, id: QueryId, queries: &mut FnvHashMap<QueryId, QueryChannel>) {
+        if let Some(QueryChannel::Get(_, _, waiters)) = queries.get_mut(&id) {
+            // the request is shared, keep it for the waiters that are left
+            waiters.retain(|ch| !ch.is_canceled());
+            if !waiters.is_empty() {
+                return;
+            }
+        }
         queries.remove(&id);
         if let QueryId(InnerQueryId::Bitswap(id)) = id {
             self.bitswap.as_mut().unwrap().cancel(id);
         }
     }
 
-    pub fn swarm_events(&mut self, tx: UnboundedSender<Event>) {
+    pub fn swarm_events(&mut self, tx: UnboundedSender<Event>) -> Result<(), TooManySubscribers> {
         self.peers.swarm_events(tx)
     }
+
+    pub fn swarm_events_for_peers(
+        &mut self,
+        peers: FnvHashSet<PeerId>,
+        peerless: bool,
+    ) -> Result<SwarmEvents, TooManySubscribers> {
+        self.peers.swarm_events_for_peers(peers, peerless)
+    }
 }
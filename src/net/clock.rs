@@ -0,0 +1,150 @@
+//! The time as seen by the address book.
+//!
+//! Dial budgets, redials, discovery batches and the ages of addresses are
+//! measured with a [`Clock`], so that tests can drive them with a
+//! [`MockClock`] instead of sleeping. The intervals of periodic tasks are
+//! spread with a [`Jitter`].
+use chrono::{DateTime, Utc};
+use futures::{channel::oneshot, future::BoxFuture, FutureExt};
+use parking_lot::Mutex;
+use rand::Rng;
+use std::{fmt::Debug, sync::Arc, time::Duration};
+
+/// A source of the current time and of timers, see
+/// [`NetworkConfig::clock`](crate::net::NetworkConfig::clock).
+pub trait Clock: Debug + Send + Sync + 'static {
+    fn now(&self) -> DateTime<Utc>;
+
+    /// A future that completes once `duration` has passed.
+    fn delay(&self, duration: Duration) -> BoxFuture<'static, ()>;
+
+    /// A timer for the next run of a task that runs every `interval`.
+    fn tick(&self, interval: Duration, jitter: Jitter) -> BoxFuture<'static, ()> {
+        self.delay(jitter.apply(interval))
+    }
+}
+
+/// Randomly lengthens or shortens the intervals of periodic tasks by up to a
+/// fraction of the interval, so that the tasks of nodes that were started
+/// together drift apart instead of causing bursts at the same time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Jitter(f64);
+
+impl Jitter {
+    /// Keeps the intervals as they are.
+    pub const NONE: Self = Self(0.0);
+
+    /// The largest fraction, so that a spread interval neither shrinks to
+    /// nothing nor outlasts what it refreshes, e.g. a DHT record republished
+    /// a day after it was stored expires after 36 hours.
+    pub const MAX_FRACTION: f64 = 0.25;
+
+    /// Spreads the intervals by up to `fraction` in both directions, clamped
+    /// to between 0 and [`MAX_FRACTION`](Self::MAX_FRACTION).
+    pub fn new(fraction: f64) -> Self {
+        if fraction.is_nan() {
+            return Self::NONE;
+        }
+        Self(fraction.clamp(0.0, Self::MAX_FRACTION))
+    }
+
+    pub fn fraction(&self) -> f64 {
+        self.0
+    }
+
+    /// The interval until the next run.
+    pub fn apply(&self, interval: Duration) -> Duration {
+        if self.0 == 0.0 {
+            return interval;
+        }
+        let factor = 1.0 + self.0 * rand::thread_rng().gen_range(-1.0..=1.0);
+        interval.mul_f64(factor)
+    }
+}
+
+// the fraction is never NaN, see `new`
+impl Eq for Jitter {}
+
+impl Default for Jitter {
+    /// 10% of the interval.
+    fn default() -> Self {
+        Self(0.1)
+    }
+}
+
+/// The system clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn delay(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        futures_timer::Delay::new(duration).boxed()
+    }
+}
+
+#[derive(Debug)]
+struct MockState {
+    now: DateTime<Utc>,
+    timers: Vec<(DateTime<Utc>, oneshot::Sender<()>)>,
+}
+
+/// A clock that only moves when it is advanced, firing the timers that are
+/// due.
+#[derive(Clone, Debug)]
+pub struct MockClock(Arc<Mutex<MockState>>);
+
+impl MockClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self(Arc::new(Mutex::new(MockState {
+            now,
+            timers: vec![],
+        })))
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.0.lock();
+        state.now = add(state.now, duration).unwrap_or(DateTime::<Utc>::MAX_UTC);
+        let now = state.now;
+        let (due, pending) = state
+            .timers
+            .drain(..)
+            .partition::<Vec<_>, _>(|(at, _)| *at <= now);
+        state.timers = pending;
+        drop(state);
+        for (_, tx) in due {
+            tx.send(()).ok();
+        }
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new(Utc::now())
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.0.lock().now
+    }
+
+    fn delay(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let mut state = self.0.lock();
+        let (tx, rx) = oneshot::channel();
+        let at = add(state.now, duration).unwrap_or(DateTime::<Utc>::MAX_UTC);
+        if at <= state.now {
+            tx.send(()).ok();
+        } else {
+            state.timers.push((at, tx));
+        }
+        rx.map(|_| ()).boxed()
+    }
+}
+
+fn add(time: DateTime<Utc>, duration: Duration) -> Option<DateTime<Utc>> {
+    time.checked_add_signed(chrono::Duration::from_std(duration).ok()?)
+}
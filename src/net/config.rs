@@ -1,5 +1,20 @@
+use super::{
+    app_protocol::{AppProtocolConfig, IntroductionPolicy},
+    clock::{Clock, Jitter, SystemClock},
+    peers::{AllowAll, ConfirmImmediately, ConnectionGate, ExternalAddrPolicy, ReconnectPolicy},
+    upnp::{PortMapper, Upnp},
+};
 use crate::config::*;
-use libp2p::identity::ed25519::Keypair;
+use fnv::FnvHashMap;
+use libp2p::{identity::ed25519::Keypair, kad::K_VALUE, Multiaddr};
+use std::{
+    fs,
+    io::{self, Write},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 /// Network configuration.
 #[derive(Debug)]
@@ -7,6 +22,12 @@ pub struct NetworkConfig {
     /// Enable adding loopback addresses to the address book. Should be
     /// enabled during testing and disabled in production.
     pub enable_loopback: bool,
+    /// Trust loopback addresses that peers advertise via identify or that
+    /// are learned from the DHT. A remote peer's loopback address points at
+    /// ourselves rather than the peer, so with `false` loopback addresses
+    /// are only added from mDNS or by the user, even if `enable_loopback` is
+    /// set. Defaults to `true`, so that local test networks keep working.
+    pub trust_remote_loopback: bool,
     /// Enable binding to the listen port number when dialling peers
     /// instead of using a random outgoing port. While this may allow
     /// stricter firewall confiuration or shorter peer lists when interacting
@@ -17,6 +38,11 @@ pub struct NetworkConfig {
     pub node_name: String,
     /// Node key.
     pub node_key: Keypair,
+    /// File the node key is kept in. If it exists the key is loaded from it
+    /// and replaces `node_key`, otherwise `node_key` is written to it, so that
+    /// the peer id stays the same across restarts. The file is only readable
+    /// by the owner.
+    pub keypair_path: Option<PathBuf>,
     /// Pre shared key.
     pub psk: Option<[u8; 32]>,
     /// Dns config. If no dns config is provided the system
@@ -24,22 +50,179 @@ pub struct NetworkConfig {
     pub dns: Option<DnsConfig>,
     /// Mdns config.
     pub mdns: Option<MdnsConfig>,
-    /// Kad config.
+    /// Only accept addresses discovered via mDNS that lie within one of these
+    /// subnets, e.g. to ignore interfaces of a multihomed machine that peers
+    /// can't be reached on. All addresses are accepted if this is `None`.
+    pub mdns_interface_filter: Option<Vec<IpNet>>,
+    /// Kad config. Without it the DHT doesn't run: `provide`, `providers`,
+    /// records and bootstrap fail with `DhtDisabled` and blocks are only
+    /// exchanged with peers found by other means, e.g. mDNS.
     pub kad: Option<KadConfig>,
+    /// Whether to answer DHT queries from other peers.
+    pub kad_mode: KadMode,
+    /// Number of peers that provider records and records are stored on.
+    pub kad_replication_factor: NonZeroUsize,
+    /// Protocol name of the DHT, e.g. `/myapp/kad/1.0.0` for a private DHT
+    /// whose nodes neither answer nor query the nodes of the public IPFS DHT.
+    /// `/ipfs/kad/1.0.0` if `None`.
+    pub kad_protocol: Option<String>,
+    /// How `Ipfs::bootstrap` fills the routing table.
+    pub bootstrap_strategy: BootstrapStrategy,
+    /// Maximum number of provider records `Ipfs::provide_many` publishes at
+    /// once.
+    pub provide_concurrency: usize,
     /// Ping config.
     pub ping: Option<PingConfig>,
+    /// Interval of the pings measuring the round trip times, spread with
+    /// `jitter`. Replaces the interval of `ping`, 15 seconds by default.
+    pub ping_interval: Duration,
     /// Identify config. Note that the `node_name` and
     /// `node_key` will overwrite the `local_public_key` and
     /// the `agent_version`.
     pub identify: Option<IdentifyConfig>,
     /// Gossipsub config.
     pub gossipsub: Option<GossipsubConfig>,
+    /// Mesh parameters that override those of the gossipsub config, e.g. to
+    /// trade bandwidth for delivery on topics with bursty fan-out.
+    pub gossipsub_mesh: Option<GossipsubMesh>,
     /// Broadcast config.
     pub broadcast: Option<BroadcastConfig>,
     /// Bitswap config.
     pub bitswap: Option<BitswapConfig>,
+    /// Maximum number of bitswap requests of a single peer that wait to be
+    /// served, so that a peer flooding us with requests can't delay serving
+    /// everyone else. Up to as many further requests of that peer are held
+    /// back and served in turns with those of other peers as the queued ones
+    /// are answered, beyond that they fail. Dropped requests are counted in
+    /// `bitswap_serve_queue_full`. Unlimited if `None`, which is the default.
+    pub max_serve_queue_per_peer: Option<usize>,
+    /// Maximum number of bytes of received blocks waiting to be written to
+    /// the block store. Until its block arrives every bitswap request counts
+    /// as a block of `StoreParams::MAX_BLOCK_SIZE`, further requests are held
+    /// back until the blocks are stored, so at least one maximum block size
+    /// is needed to fetch more than one block at a time. The pending bytes
+    /// are reported by `Ipfs::pending_block_bytes` and in
+    /// `bitswap_pending_block_bytes`. Unlimited if `None`, which is the
+    /// default.
+    pub max_pending_block_bytes: Option<usize>,
+    /// Maximum number of bitswap queries, i.e. block requests and syncs,
+    /// running at once. Requests for a block that is already being fetched
+    /// join the running query and aren't counted. Unlimited if `None`, which
+    /// is the default.
+    pub max_fetches: Option<usize>,
+    /// What to do while `max_fetches` bitswap queries are running.
+    pub overload_policy: OverloadPolicy,
+    /// Application protocol config, see
+    /// [`Ipfs::register_protocol`](crate::Ipfs::register_protocol).
+    /// Disabled if `None`, which is the default.
+    pub app_protocol: Option<AppProtocolConfig>,
+    /// Which peers may add addresses of other peers to our address book via
+    /// [`Ipfs::introduce_peer`](crate::Ipfs::introduce_peer). Introduced
+    /// addresses are probed before they are used and count as advertised by
+    /// a remote peer, see `trust_remote_loopback`. At most 32 addresses are
+    /// accepted from a peer while it is connected. None by default.
+    pub introductions: IntroductionPolicy,
+    /// Answer bitswap requests for any stored block. When disabled only blocks
+    /// reachable from an alias are served, cached blocks are reported as
+    /// missing.
+    pub serve_unpinned: bool,
     /// Keep explicitly dialed and incoming connections open indefinitely
     pub keep_alive: bool,
+    /// Initial delay before redialing a peer after a failed dial that looks
+    /// like a TCP simultaneous open; doubled with every further attempt.
+    pub redial_backoff: Duration,
+    /// Number of redials after which a peer is reported as unreachable.
+    pub max_redials: u32,
+    /// How long the set of external addresses must remain unchanged before
+    /// `Event::ExternalAddressesStable` is emitted.
+    pub external_addrs_quiet_period: Duration,
+    /// Report newly discovered peers in an `Event::DiscoveredBatch` at most
+    /// once per interval instead of one `Event::Discovered` each, e.g. to not
+    /// overwhelm consumers when many peers show up on a busy LAN.
+    pub discovery_batch_interval: Option<Duration>,
+    /// Time after which dialing a peer is given up and it is reported as
+    /// `Event::Unreachable`, no matter how many of its addresses are still
+    /// being tried. The addresses of a peer are dialed concurrently, up to
+    /// eight at a time, so without a budget a peer with many slow addresses
+    /// takes a multiple of the connection timeout to fail.
+    pub dial_budget: Option<Duration>,
+    /// Maximum number of open swarm event subscriptions. Every subscriber
+    /// gets its own copy of each event, so a consumer that keeps subscribing
+    /// without dropping the streams makes every event more expensive. 256 by
+    /// default.
+    pub max_event_subscribers: Option<usize>,
+    /// Maximum number of open fetch sessions, see
+    /// [`Ipfs::session`](crate::Ipfs::session). Each keeps the providers
+    /// found for its blocks, so an app opening sessions without dropping
+    /// them would grow without bound. 64 by default.
+    pub max_sessions: usize,
+    /// Peers that are dialed on startup and redialed whenever the last
+    /// connection to them closes or dialing them fails, e.g. a curated set
+    /// of peers shipped with the node. Their addresses are added as
+    /// `AddressSource::User`. Unlike the DHT bootstrap nodes these peers are
+    /// kept connected.
+    pub static_peers: Vec<(PeerId, Vec<Multiaddr>)>,
+    /// How the `static_peers` are redialed, by default with a backoff from
+    /// 5 seconds to 5 minutes. Peers added with
+    /// [`Ipfs::add_static_peer`](crate::Ipfs::add_static_peer) bring their
+    /// own policy. Banning a peer stops redialing it.
+    pub static_peer_reconnect: ReconnectPolicy,
+    /// Decides for each peer address whether `/p2p/<peer>` is appended to it
+    /// when it is added to the address book. Addresses of transports that
+    /// need the peer id elsewhere, or none at all, can be exempted here. By
+    /// default all addresses are normalized.
+    pub normalize_address: fn(&Multiaddr) -> bool,
+    /// When connections to a peer exist in both directions, e.g. after both
+    /// sides dialed at the same time, close those that were not dialed by the
+    /// peer with the smaller id (comparing the id bytes). Both sides then
+    /// agree on the surviving connection.
+    pub tie_break_connections: bool,
+    /// Delegated routing HTTP endpoint that is asked for providers when the
+    /// DHT doesn't know any or is disabled, by `Ipfs::providers` and the
+    /// `FetchSource::Dht` step of fetches. Only `http` URLs are supported,
+    /// requests time out after 10 seconds and responses are limited to 1 MiB.
+    pub delegated_router: Option<Url>,
+    /// Static port mappings from internal (listen) ports to the ports under
+    /// which they are reachable from the outside, e.g. due to manual port
+    /// forwarding. Listen addresses on a mapped port are advertised as
+    /// external addresses with the port component rewritten.
+    pub external_port_map: FnvHashMap<u16, u16>,
+    /// Try to open each TCP listen port on the local router and advertise the
+    /// resulting external address. Only IPv4 listen addresses of the local
+    /// network are mapped, loopback and link-local ones are not. Mappings are
+    /// renewed before their lease expires and removed when the listener is
+    /// closed or the node shut down; if the router doesn't support this the
+    /// node carries on without it.
+    pub enable_upnp: bool,
+    /// The gateway used for port mapping when `enable_upnp` is set, defaults
+    /// to UPnP IGD.
+    pub port_mapper: Arc<dyn PortMapper>,
+    /// Decides when an address observed for us by peers is reported as
+    /// `Event::NewExternalAddr`, e.g. [`ConfirmAfter`](crate::ConfirmAfter)
+    /// to wait for several peers to agree behind a carrier-grade NAT.
+    /// [`ConfirmImmediately`] by default.
+    pub external_addr_policy: Arc<dyn ExternalAddrPolicy>,
+    /// Vetoes dials and connections, e.g. to combine block and allow lists
+    /// with subnet filters in one place. [`AllowAll`] by default.
+    pub connection_gate: Arc<dyn ConnectionGate>,
+    /// Soft limit on the file descriptors used by connections, listeners and
+    /// the block store, which are counted coarsely. Once it is reached,
+    /// connections are closed to stay below it, those to static peers last
+    /// and otherwise those to badly reputed and idle peers first, see
+    /// `Event::ResourceLimitApproaching`. The count is exported as the
+    /// `peers_file_descriptors` gauge. Unlimited if `None`, which is the
+    /// default.
+    pub fd_soft_limit: Option<usize>,
+    /// The time that dial budgets, redials, discovery batches and address
+    /// ages are measured with, e.g. a [`MockClock`](crate::net::MockClock) to test them without
+    /// sleeping. [`SystemClock`] by default.
+    pub clock: Arc<dyn Clock>,
+    /// Spreads the intervals of periodic tasks, i.e. port mapping renewals,
+    /// provide retries, static peer redials, discovery batches, pings, DHT
+    /// record republishing and the compaction of event subscribers, so that
+    /// nodes started together don't run them in lockstep. 10% of the
+    /// interval by default.
+    pub jitter: Jitter,
 }
 
 /// `DNS` configuration.
@@ -59,6 +242,155 @@ pub enum DnsConfig {
     },
 }
 
+/// Kademlia operating mode.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KadMode {
+    /// Act as a client while we have no confirmed external address, i.e. we're
+    /// presumably not publicly reachable, and as a server otherwise.
+    Auto,
+    /// Only issue queries, don't advertise the DHT protocol to other peers.
+    Client,
+    /// Advertise the DHT protocol and answer queries from other peers.
+    Server,
+}
+
+impl Default for KadMode {
+    fn default() -> Self {
+        Self::Auto
+    }
+}
+
+/// How the routing table is filled when bootstrapping the DHT, see
+/// [`Ipfs::bootstrap_with`](crate::Ipfs::bootstrap_with).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BootstrapStrategy {
+    /// The Kademlia bootstrap, which looks up our own peer ID and refreshes
+    /// the buckets it can.
+    Default,
+    /// The Kademlia bootstrap followed by looking up this many random peer
+    /// IDs. Random IDs are mostly far from ours, so this fills the far
+    /// buckets of a sparse network.
+    RandomWalk { rounds: usize },
+    /// The Kademlia bootstrap followed by looking up the given peers, filling
+    /// the buckets around them.
+    Targeted(Vec<PeerId>),
+}
+
+impl Default for BootstrapStrategy {
+    fn default() -> Self {
+        Self::Default
+    }
+}
+
+impl BootstrapStrategy {
+    /// The peer IDs to look up in addition to the Kademlia bootstrap.
+    pub(crate) fn lookups(&self) -> Vec<PeerId> {
+        match self {
+            Self::Default => vec![],
+            Self::RandomWalk { rounds } => (0..*rounds).map(|_| PeerId::random()).collect(),
+            Self::Targeted(peers) => peers.clone(),
+        }
+    }
+}
+
+/// What to do while the node runs as many bitswap queries as
+/// [`NetworkConfig::max_fetches`] allows. Reaching the limit is reported as
+/// [`Event::Overloaded`](crate::net::Event::Overloaded), falling below it
+/// again as [`Event::Recovered`](crate::net::Event::Recovered).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OverloadPolicy {
+    /// New fetches wait until running ones complete, then start in the order
+    /// they were issued.
+    PauseFetches,
+    /// Syncs make room for block requests: a new block request cancels the
+    /// oldest running sync, which fails with
+    /// [`Overloaded`](crate::net::Overloaded). New syncs, and block requests
+    /// while no sync is running, fail with `Overloaded` as well.
+    ShedLowPriority,
+    /// New fetches start regardless, but the bitswap requests of other peers
+    /// are dropped until the node recovers, so that serving them doesn't add
+    /// to the load.
+    RejectInbound,
+}
+
+impl Default for OverloadPolicy {
+    fn default() -> Self {
+        Self::PauseFetches
+    }
+}
+
+/// The Kademlia settings a node runs with, see
+/// [`Ipfs::kad_config`](crate::Ipfs::kad_config).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct KadSettings {
+    pub mode: KadMode,
+    pub replication_factor: NonZeroUsize,
+}
+
+/// Gossipsub mesh parameters, see [`NetworkConfig::gossipsub_mesh`]. The
+/// defaults are those of gossipsub.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GossipsubMesh {
+    /// Number of peers in the mesh of a topic that is aimed for.
+    pub mesh_n: usize,
+    /// Fewer peers than this in a mesh makes the heartbeat add more.
+    pub mesh_n_low: usize,
+    /// More peers than this in a mesh makes the heartbeat remove some.
+    pub mesh_n_high: usize,
+    /// Share of the peers outside of the mesh that gossip is emitted to.
+    pub gossip_factor: f64,
+    /// Interval of the heartbeat that maintains the meshes and emits gossip.
+    pub heartbeat_interval: Duration,
+}
+
+impl Default for GossipsubMesh {
+    fn default() -> Self {
+        Self {
+            mesh_n: 6,
+            mesh_n_low: 5,
+            mesh_n_high: 12,
+            gossip_factor: 0.25,
+            heartbeat_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+impl GossipsubMesh {
+    /// Sets the mesh parameters in `config`, failing unless
+    /// `mesh_n_low <= mesh_n <= mesh_n_high`. Gossipsub also keeps at most
+    /// half of a mesh for outbound peers, that minimum is lowered as needed
+    /// for small meshes.
+    pub(crate) fn apply(&self, config: GossipsubConfig) -> anyhow::Result<GossipsubConfig> {
+        if !(self.mesh_n_low <= self.mesh_n && self.mesh_n <= self.mesh_n_high) {
+            anyhow::bail!(
+                "invalid gossipsub mesh: mesh_n_low {} <= mesh_n {} <= mesh_n_high {} doesn't hold",
+                self.mesh_n_low,
+                self.mesh_n,
+                self.mesh_n_high
+            );
+        }
+        if !(0.0..=1.0).contains(&self.gossip_factor) {
+            anyhow::bail!(
+                "invalid gossipsub gossip_factor {}, must be between 0 and 1",
+                self.gossip_factor
+            );
+        }
+        let outbound_min = config
+            .mesh_outbound_min()
+            .min(self.mesh_n_low)
+            .min(self.mesh_n / 2);
+        GossipsubConfigBuilder::from(config)
+            .mesh_n(self.mesh_n)
+            .mesh_n_low(self.mesh_n_low)
+            .mesh_n_high(self.mesh_n_high)
+            .mesh_outbound_min(outbound_min)
+            .gossip_factor(self.gossip_factor)
+            .heartbeat_interval(self.heartbeat_interval)
+            .build()
+            .map_err(|err| anyhow::anyhow!("invalid gossipsub mesh: {}", err))
+    }
+}
+
 impl NetworkConfig {
     /// Creates a new network configuration.
     pub fn new(node_key: Keypair) -> Self {
@@ -71,19 +403,56 @@ impl NetworkConfig {
         );
         Self {
             enable_loopback: true,
+            trust_remote_loopback: true,
             port_reuse: true,
             node_name,
             node_key,
+            keypair_path: None,
             psk: None,
             dns: None,
             mdns: Some(MdnsConfig::default()),
+            mdns_interface_filter: None,
             kad: Some(KadConfig::default()),
+            kad_mode: KadMode::Auto,
+            kad_replication_factor: K_VALUE,
+            kad_protocol: None,
+            bootstrap_strategy: BootstrapStrategy::Default,
+            provide_concurrency: 8,
             ping: None,
+            ping_interval: Duration::from_secs(15),
             identify: Some(identify),
             gossipsub: Some(GossipsubConfig::default()),
+            gossipsub_mesh: None,
             broadcast: Some(BroadcastConfig::default()),
             bitswap: Some(BitswapConfig::default()),
+            max_serve_queue_per_peer: None,
+            max_pending_block_bytes: None,
+            max_fetches: None,
+            overload_policy: OverloadPolicy::PauseFetches,
+            app_protocol: None,
+            introductions: IntroductionPolicy::default(),
+            serve_unpinned: true,
             keep_alive: false,
+            redial_backoff: Duration::from_millis(100),
+            max_redials: 5,
+            external_addrs_quiet_period: Duration::from_secs(10),
+            discovery_batch_interval: None,
+            dial_budget: None,
+            max_event_subscribers: Some(256),
+            max_sessions: 64,
+            static_peers: vec![],
+            static_peer_reconnect: ReconnectPolicy::default(),
+            normalize_address: |_| true,
+            tie_break_connections: false,
+            delegated_router: None,
+            external_port_map: Default::default(),
+            enable_upnp: false,
+            port_mapper: Arc::new(Upnp),
+            external_addr_policy: Arc::new(ConfirmImmediately),
+            connection_gate: Arc::new(AllowAll),
+            fd_soft_limit: None,
+            clock: Arc::new(SystemClock),
+            jitter: Jitter::default(),
         }
     }
 }
@@ -93,3 +462,48 @@ impl Default for NetworkConfig {
         Self::new(Keypair::generate())
     }
 }
+
+/// Loads the ed25519 keypair stored at `path`, or stores `generated` there if
+/// the file doesn't exist yet. The key is written to a temporary file that is
+/// renamed into place, so a crash never leaves a truncated key behind.
+pub(crate) fn load_or_store_keypair(path: &Path, generated: &Keypair) -> io::Result<Keypair> {
+    match fs::read(path) {
+        Ok(mut bytes) => {
+            return Keypair::decode(&mut bytes).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid keypair in {}: {}", path.display(), err),
+                )
+            })
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = match options.open(&tmp) {
+        Ok(file) => file,
+        // left behind by an interrupted attempt, its mode may be wider, so it
+        // is narrowed before the key is written
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+            let file = fs::OpenOptions::new()
+                .write(true)
+                .truncate(true)
+                .open(&tmp)?;
+            #[cfg(unix)]
+            file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+            file
+        }
+        Err(err) => return Err(err),
+    };
+    file.write_all(&generated.encode())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(generated.clone())
+}
@@ -0,0 +1,175 @@
+//! Identify requests on demand.
+//!
+//! The identify behaviour only asks a peer for its info after connecting and
+//! then periodically. Asking for it is a plain request response exchange:
+//! the dialer opens a stream and the remote answers with its info, so the
+//! request is sent via a request response behaviour that supports outbound
+//! requests only, leaving the inbound ones to the identify behaviour.
+use async_trait::async_trait;
+use futures::io::{AsyncRead, AsyncWrite};
+use libp2p::{
+    core::upgrade::read_length_prefixed,
+    identify::{self, PROTOCOL_NAME},
+    identity::PublicKey,
+    request_response::{ProtocolName, RequestResponseCodec},
+    Multiaddr,
+};
+use std::{convert::TryFrom, io};
+
+/// The maximum size of an identify message, like in libp2p-identify.
+const MAX_MESSAGE_SIZE: usize = 4096;
+
+#[derive(Clone, Debug)]
+pub(crate) struct IdentifyProtocol;
+
+impl ProtocolName for IdentifyProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        PROTOCOL_NAME
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct IdentifyCodec;
+
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "identify requests are answered by the identify behaviour",
+    )
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_varint(buf: &mut &[u8]) -> io::Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = buf
+            .split_first()
+            .ok_or_else(|| invalid_data("truncated varint"))?;
+        *buf = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid_data("varint too long"))
+}
+
+fn read_bytes<'a>(buf: &mut &'a [u8], len: u64) -> io::Result<&'a [u8]> {
+    let len = usize::try_from(len).map_err(|_| invalid_data("truncated field"))?;
+    if buf.len() < len {
+        return Err(invalid_data("truncated field"));
+    }
+    let (bytes, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(bytes)
+}
+
+fn read_string(bytes: &[u8]) -> io::Result<String> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| invalid_data("invalid string"))
+}
+
+/// Decodes the `Identify` protobuf message. Invalid listen addresses are
+/// skipped, like libp2p-identify does.
+pub(crate) fn decode_info(mut buf: &[u8]) -> io::Result<identify::Info> {
+    let mut public_key = None;
+    let mut protocol_version = String::new();
+    let mut agent_version = String::new();
+    let mut listen_addrs = vec![];
+    let mut protocols = vec![];
+    let mut observed_addr = Multiaddr::empty();
+    while !buf.is_empty() {
+        let key = read_varint(&mut buf)?;
+        let bytes = match key & 7 {
+            0 => {
+                read_varint(&mut buf)?;
+                continue;
+            }
+            1 => {
+                read_bytes(&mut buf, 8)?;
+                continue;
+            }
+            2 => {
+                let len = read_varint(&mut buf)?;
+                read_bytes(&mut buf, len)?
+            }
+            5 => {
+                read_bytes(&mut buf, 4)?;
+                continue;
+            }
+            _ => return Err(invalid_data("unsupported wire type")),
+        };
+        match key >> 3 {
+            1 => {
+                let key = PublicKey::from_protobuf_encoding(bytes)
+                    .map_err(|_| invalid_data("invalid public key"))?;
+                public_key = Some(key);
+            }
+            2 => listen_addrs.extend(Multiaddr::try_from(bytes.to_vec()).ok()),
+            3 => protocols.push(read_string(bytes)?),
+            4 => {
+                observed_addr = Multiaddr::try_from(bytes.to_vec())
+                    .map_err(|_| invalid_data("invalid observed address"))?;
+            }
+            5 => protocol_version = read_string(bytes)?,
+            6 => agent_version = read_string(bytes)?,
+            _ => {}
+        }
+    }
+    Ok(identify::Info {
+        public_key: public_key.ok_or_else(|| invalid_data("missing public key"))?,
+        protocol_version,
+        agent_version,
+        listen_addrs,
+        protocols,
+        observed_addr,
+    })
+}
+
+#[async_trait]
+impl RequestResponseCodec for IdentifyCodec {
+    type Protocol = IdentifyProtocol;
+    type Request = ();
+    type Response = identify::Info;
+
+    async fn read_request<T>(&mut self, _: &IdentifyProtocol, _: &mut T) -> io::Result<()>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        Err(unsupported())
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &IdentifyProtocol,
+        io: &mut T,
+    ) -> io::Result<identify::Info>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let msg = read_length_prefixed(io, MAX_MESSAGE_SIZE).await?;
+        decode_info(&msg)
+    }
+
+    async fn write_request<T>(&mut self, _: &IdentifyProtocol, _: &mut T, _: ()) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        // opening the stream is the request
+        Ok(())
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &IdentifyProtocol,
+        _: &mut T,
+        _: identify::Info,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        Err(unsupported())
+    }
+}
@@ -0,0 +1,286 @@
+//! Accounting of the blocks exchanged with each peer via bitswap.
+//!
+//! libp2p-bitswap doesn't tell which peer a block was sent to or received
+//! from, so the messages are inspected as they pass between the connection
+//! handlers and the bitswap behaviour, see
+//! [`FairBitswap`](super::serve_queue::FairBitswap). The block sent in a
+//! response is looked up by bitswap on its own thread; its size is recorded
+//! by wrapping the store. The received blocks are counted until they are
+//! stored, see [`PendingBlocks`].
+use fnv::FnvHashMap;
+use lazy_static::lazy_static;
+use libipld::{Block, Cid, Result};
+use libp2p::PeerId;
+use libp2p_bitswap::BitswapStore;
+use parking_lot::Mutex;
+use prometheus::IntGauge;
+use std::{
+    collections::VecDeque,
+    io,
+    sync::Arc,
+    task::Waker,
+    time::{Duration, Instant},
+};
+
+/// Number of inbound bitswap requests remembered by [`RecentWants`].
+const RECENT_WANTS: usize = 4096;
+
+/// How long a received block counts as pending if it is never stored, see
+/// [`PendingBlocks::bytes`].
+pub(crate) const PENDING_BLOCK_EXPIRY: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    pub static ref PENDING_BLOCK_BYTES: IntGauge = IntGauge::new(
+        "bitswap_pending_block_bytes",
+        "Bytes of the blocks received via bitswap that are waiting to be stored."
+    )
+    .unwrap();
+}
+
+/// The blocks exchanged with a peer via bitswap while it is connected, see
+/// [`Ipfs::bitswap_ledger`](crate::Ipfs::bitswap_ledger).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Ledger {
+    pub blocks_sent: u64,
+    pub bytes_sent: u64,
+    pub blocks_received: u64,
+    pub bytes_received: u64,
+}
+
+impl Ledger {
+    /// Bytes sent per byte received, computed like go-ipfs does. A peer that
+    /// only downloads from us has a large ratio.
+    pub fn debt_ratio(&self) -> f64 {
+        self.bytes_sent as f64 / (self.bytes_received as f64 + 1.0)
+    }
+}
+
+/// The blocks most recently asked for by connected peers, oldest first, see
+/// [`Ipfs::requesters`](crate::Ipfs::requesters).
+#[derive(Debug)]
+pub(crate) struct RecentWants {
+    max: usize,
+    wants: VecDeque<(Cid, PeerId)>,
+}
+
+impl RecentWants {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            wants: VecDeque::with_capacity(max),
+        }
+    }
+
+    /// Records a request, forgetting the oldest one if full.
+    pub fn push(&mut self, cid: Cid, peer: PeerId) {
+        if self.max == 0 {
+            return;
+        }
+        if self.wants.len() >= self.max {
+            self.wants.pop_front();
+        }
+        self.wants.push_back((cid, peer));
+    }
+
+    /// The peers that asked for `cid`, most recent first.
+    pub fn requesters(&self, cid: &Cid) -> Vec<PeerId> {
+        let mut peers = Vec::new();
+        for (_, peer) in self.wants.iter().rev().filter(|(c, _)| c == cid) {
+            if !peers.contains(peer) {
+                peers.push(*peer);
+            }
+        }
+        peers
+    }
+
+    /// Forgets the requests of a peer that is no longer connected.
+    pub fn remove_peer(&mut self, peer: &PeerId) {
+        self.wants.retain(|(_, p)| p != peer);
+    }
+}
+
+impl Default for RecentWants {
+    fn default() -> Self {
+        Self::new(RECENT_WANTS)
+    }
+}
+
+#[derive(Debug, Default)]
+struct ServedBlock {
+    /// block requests waiting for their response
+    requests: usize,
+    /// the size of the block if the store had it
+    len: Option<usize>,
+}
+
+/// The blocks being served, so that their sizes can be attributed to the
+/// requesting peers once the responses are sent.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Served(Arc<Mutex<FnvHashMap<Cid, ServedBlock>>>);
+
+impl Served {
+    pub fn requested(&self, cid: Cid) {
+        self.0.lock().entry(cid).or_default().requests += 1;
+    }
+
+    fn looked_up(&self, cid: &Cid, len: usize) {
+        if let Some(block) = self.0.lock().get_mut(cid) {
+            block.len = Some(len);
+        }
+    }
+
+    /// Ends a block request, returning the size of the block if it was
+    /// found.
+    pub fn done(&self, cid: &Cid) -> Option<usize> {
+        let mut served = self.0.lock();
+        let block = served.get_mut(cid)?;
+        block.requests -= 1;
+        let len = block.len;
+        if block.requests == 0 {
+            served.remove(cid);
+        }
+        len
+    }
+}
+
+/// A bitswap store that records the sizes of the blocks it hands out.
+pub(crate) struct ServedStore<S> {
+    inner: S,
+    served: Served,
+}
+
+impl<S> ServedStore<S> {
+    pub fn new(inner: S, served: Served) -> Self {
+        Self { inner, served }
+    }
+}
+
+impl<S: BitswapStore> BitswapStore for ServedStore<S> {
+    type Params = S::Params;
+
+    fn contains(&mut self, cid: &Cid) -> Result<bool> {
+        self.inner.contains(cid)
+    }
+
+    fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        let data = self.inner.get(cid)?;
+        if let Some(data) = &data {
+            self.served.looked_up(cid, data.len());
+        }
+        Ok(data)
+    }
+
+    fn insert(&mut self, block: &Block<Self::Params>) -> Result<()> {
+        self.inner.insert(block)
+    }
+
+    fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>> {
+        self.inner.missing_blocks(cid)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+    /// when each block was received and its size, in the order bitswap hands
+    /// the blocks to the store
+    blocks: VecDeque<(Instant, usize)>,
+    bytes: usize,
+    /// the task waiting for blocks to be stored
+    waker: Option<Waker>,
+}
+
+impl Pending {
+    /// Forgets the `n` oldest blocks.
+    fn forget(&mut self, n: usize) {
+        let bytes: usize = self.blocks.drain(..n).map(|(_, len)| len).sum();
+        self.bytes -= bytes;
+        PENDING_BLOCK_BYTES.sub(bytes as i64);
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        PENDING_BLOCK_BYTES.sub(self.bytes as i64);
+    }
+}
+
+/// The blocks received via bitswap that are waiting to be stored by its
+/// database thread, see
+/// [`NetworkConfig::max_pending_block_bytes`](crate::net::NetworkConfig::max_pending_block_bytes).
+///
+/// Bitswap doesn't tell when it drops a received block, e.g. one that
+/// doesn't match its CID. As blocks are stored in the order they were
+/// received, those received before a stored block are forgotten with it.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PendingBlocks(Arc<Mutex<Pending>>);
+
+impl PendingBlocks {
+    pub fn received(&self, len: usize) {
+        let mut pending = self.0.lock();
+        pending.blocks.push_back((Instant::now(), len));
+        pending.bytes += len;
+        PENDING_BLOCK_BYTES.add(len as i64);
+    }
+
+    /// Forgets the oldest pending block of `len` bytes and those received
+    /// before it, waking the task waiting for it.
+    pub fn stored(&self, len: usize) {
+        let mut pending = self.0.lock();
+        if let Some(i) = pending.blocks.iter().position(|(_, l)| *l == len) {
+            pending.forget(i + 1);
+        }
+        if let Some(waker) = pending.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Bytes of the pending blocks, forgetting those received more than
+    /// [`PENDING_BLOCK_EXPIRY`] before `now`, and when the oldest one left
+    /// expires.
+    pub fn bytes(&self, now: Instant) -> (usize, Option<Instant>) {
+        let mut pending = self.0.lock();
+        let expired = pending
+            .blocks
+            .iter()
+            .take_while(|(received, _)| now.duration_since(*received) >= PENDING_BLOCK_EXPIRY)
+            .count();
+        pending.forget(expired);
+        let expiry = pending
+            .blocks
+            .front()
+            .map(|(received, _)| *received + PENDING_BLOCK_EXPIRY);
+        (pending.bytes, expiry)
+    }
+
+    /// Wakes the task of `waker` once the next block was stored.
+    pub fn register(&self, waker: &Waker) {
+        self.0.lock().waker = Some(waker.clone());
+    }
+}
+
+/// Measures an encoded bitswap request or response, whose first byte is the
+/// message type.
+#[derive(Debug, Default)]
+pub(crate) struct MessageLen {
+    pub ty: Option<u8>,
+    pub len: usize,
+}
+
+impl MessageLen {
+    /// The type of block requests and responses.
+    pub const BLOCK: u8 = 1;
+}
+
+impl io::Write for MessageLen {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.ty.is_none() {
+            self.ty = buf.first().copied();
+        }
+        self.len += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -1,21 +1,50 @@
 mod address_handler;
+mod app_protocol;
 mod behaviour;
+mod clock;
 mod config;
+mod identify_request;
+mod ledger;
 mod peer_info;
 mod peers;
+mod redial;
+mod router;
+mod serve_queue;
 #[cfg(test)]
 mod tests;
+mod upnp;
 
 pub use self::{
-    behaviour::{GossipEvent, QueryId, SyncEvent},
-    config::{DnsConfig, NetworkConfig},
-    peer_info::{AddressSource, ConnectionFailure, Direction, PeerInfo, Rtt},
-    peers::{register_metrics, Event, SwarmEvents},
+    app_protocol::{AppProtocolConfig, AppRequest, IntroductionPolicy},
+    behaviour::{
+        DhtDisabled, Draining, FetchProgress, GossipEvent, NotConnected, Overloaded, PubsubMessage,
+        QueryId, SyncEvent, ValidationResult,
+    },
+    clock::{Clock, Jitter, MockClock, SystemClock},
+    config::{
+        BootstrapStrategy, DnsConfig, GossipsubMesh, KadMode, KadSettings, NetworkConfig,
+        OverloadPolicy,
+    },
+    ledger::Ledger,
+    peer_info::{AddressSource, ConnectionFailure, Direction, Endpoint, PeerInfo, Reputation, Rtt},
+    peers::{
+        register_metrics, AllowAll, ConfirmAfter, ConfirmImmediately, ConnectionGate, Event,
+        EventHandler, ExternalAddrPolicy, GateStage, InboundFilter, ListenAddrKind,
+        ReconnectPolicy, Resource, SwarmEvents, TooManySubscribers, Transport,
+    },
+    upnp::{PortMapper, Upnp},
 };
 
-use self::behaviour::{GetChannel, NetworkBackendBehaviour, QueryChannel, SyncChannel};
+use self::{
+    behaviour::{
+        AppHandlers, AppRequests, AppServing, GetChannel, IdentifyRequests,
+        NetworkBackendBehaviour, NotBootstrapped, QueryChannel, SyncChannel, Validator, Validators,
+    },
+    config::load_or_store_keypair,
+    ledger::{PendingBlocks, RecentWants},
+};
 use crate::{
-    executor::{Executor, JoinHandle},
+    executor::{supervise, Executor, JoinHandle},
     variable::{Reader, Writer},
 };
 use anyhow::anyhow;
@@ -28,9 +57,9 @@ use futures::{
     },
     future::{self, Either},
     stream::{Stream, StreamExt},
-    FutureExt,
+    FutureExt, SinkExt,
 };
-use libipld::{error::BlockNotFound, store::StoreParams, Cid, Result};
+use libipld::{error::BlockNotFound, store::StoreParams, Block, Cid, Result};
 #[cfg(feature = "async_global")]
 use libp2p::dns::DnsConfig as Dns;
 #[cfg(all(feature = "tokio", not(feature = "async_global")))]
@@ -42,7 +71,7 @@ use libp2p::tcp::tokio::Transport as TcpTransport;
 use libp2p::{
     core::{
         either::EitherTransport,
-        transport::{ListenerId, Transport},
+        transport::{ListenerId, Transport as _},
         upgrade::{SelectUpgrade, Version},
     },
     dns::DnsErr,
@@ -51,26 +80,35 @@ use libp2p::{
     mplex::MplexConfig,
     noise::{self, NoiseConfig, X25519Spec},
     pnet::{PnetConfig, PreSharedKey},
+    request_response::ResponseChannel,
     swarm::{AddressRecord, AddressScore, Swarm, SwarmBuilder, SwarmEvent},
     tcp::Config as TcpConfig,
     yamux::YamuxConfig,
     Multiaddr, PeerId,
 };
 use libp2p_bitswap::BitswapStore;
+use serde_json::{json, Value};
 use std::{
-    collections::HashSet,
+    collections::{HashSet, VecDeque},
+    convert::TryFrom,
     future::Future,
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
-    time::Duration,
+    time::{Duration, Instant},
 };
+use url::Url;
 use void::unreachable;
 
+/// How often a provider record publish is retried by `provide_many`.
+const PROVIDE_RETRIES: usize = 2;
+/// The delay before the first retry, doubled for each further one.
+const PROVIDE_BACKOFF: Duration = Duration::from_millis(500);
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum ListenerEvent {
-    NewListenAddr(Multiaddr),
-    ExpiredListenAddr(Multiaddr),
+    NewListenAddr(Multiaddr, ListenAddrKind),
+    ExpiredListenAddr(Multiaddr, ListenAddrKind),
     ListenFailed(Multiaddr, String),
 }
 
@@ -80,17 +118,29 @@ pub enum NetworkCommand {
     AddExternalAddress(Multiaddr),
     AddAddress(PeerId, Multiaddr),
     AddAddresses(Vec<(PeerId, Multiaddr)>),
+    AddDiscoveredAddresses(Vec<(PeerId, Multiaddr)>, AddressSource),
     RemoveAddress(PeerId, Multiaddr),
     PrunePeers(Duration),
+    PruneStalePeers(Duration),
+    LoadPeers(Vec<(PeerId, PeerInfo)>),
+    #[cfg(test)]
+    Panic,
     Dial(PeerId),
     DialAddress(PeerId, Multiaddr),
+    AddStaticPeer(PeerId, Vec<Multiaddr>, ReconnectPolicy),
     Ban(PeerId),
     Unban(PeerId),
+    SetInboundFilter(InboundFilter),
+    SetEventHandler(EventHandler),
+    StorageError(String),
+    ReserveFds(usize),
     Bootstrap(
         Vec<(PeerId, Multiaddr)>,
         oneshot::Sender<anyhow::Result<()>>,
     ),
+    GetClosestPeers(PeerId, oneshot::Sender<anyhow::Result<Vec<PeerId>>>),
     Providers(Key, oneshot::Sender<anyhow::Result<HashSet<PeerId>>>),
+    LocalProviders(Key, oneshot::Sender<anyhow::Result<HashSet<PeerId>>>),
     Provide(Key, oneshot::Sender<anyhow::Result<()>>),
     Unprovide(Key),
     GetRecord(
@@ -102,14 +152,77 @@ pub enum NetworkCommand {
     RemoveRecord(Key),
     Subscribe(
         String,
+        Option<Validator>,
         oneshot::Sender<anyhow::Result<UnboundedReceiver<GossipEvent>>>,
     ),
     Publish(String, Vec<u8>, oneshot::Sender<anyhow::Result<()>>),
     Broadcast(String, Vec<u8>, oneshot::Sender<anyhow::Result<()>>),
+    RegisterProtocol(
+        String,
+        oneshot::Sender<anyhow::Result<Receiver<AppRequest>>>,
+    ),
+    Request(
+        PeerId,
+        String,
+        Vec<u8>,
+        oneshot::Sender<anyhow::Result<Vec<u8>>>,
+    ),
+    Respond(ResponseChannel<Vec<u8>>, Vec<u8>),
     Get(Cid, Vec<PeerId>, oneshot::Sender<GetQuery>),
     Sync(Cid, Vec<PeerId>, Vec<Cid>, oneshot::Sender<SyncQuery>),
-    SwarmEvents(oneshot::Sender<SwarmEvents>),
+    SwarmEvents(oneshot::Sender<Result<SwarmEvents, TooManySubscribers>>),
+    SwarmEventsForPeers(
+        FnvHashSet<PeerId>,
+        bool,
+        oneshot::Sender<Result<SwarmEvents, TooManySubscribers>>,
+    ),
     CancelQuery(QueryId),
+    BlockReceived(Cid),
+    Wantlist(oneshot::Sender<Vec<Cid>>),
+    Drain(oneshot::Sender<()>),
+    RefreshPeerInfo(PeerId, oneshot::Sender<Result<PeerInfo>>),
+}
+
+/// Tells the swarm task about every block bitswap stored, so that requests
+/// for it to other providers can be cancelled, and that it is no longer
+/// pending.
+struct ReceivedBlocks<S> {
+    store: S,
+    cmd: Sender<NetworkCommand>,
+    pending: PendingBlocks,
+}
+
+impl<S: BitswapStore> BitswapStore for ReceivedBlocks<S> {
+    type Params = S::Params;
+
+    fn contains(&mut self, cid: &Cid) -> Result<bool> {
+        self.store.contains(cid)
+    }
+
+    fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        self.store.get(cid)
+    }
+
+    fn insert(&mut self, block: &Block<Self::Params>) -> Result<()> {
+        let res = self.store.insert(block);
+        // bitswap drops the block if it can't be stored
+        self.pending.stored(block.data().len());
+        res?;
+        if let Err(err) = self
+            .cmd
+            .try_send(NetworkCommand::BlockReceived(*block.cid()))
+        {
+            // the request then completes once its own provider answers
+            if !err.is_disconnected() {
+                tracing::debug!("cannot report received block: {}", err.into_send_error());
+            }
+        }
+        Ok(())
+    }
+
+    fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>> {
+        self.store.missing_blocks(cid)
+    }
 }
 
 #[derive(Clone)]
@@ -118,10 +231,24 @@ pub struct NetworkService {
     peers: Reader<FnvHashMap<PeerId, PeerInfo>>,
     listeners: Reader<FnvHashSet<Multiaddr>>,
     external: Reader<Vec<AddressRecord>>,
+    ledgers: Reader<FnvHashMap<PeerId, Ledger>>,
+    wants: Reader<RecentWants>,
+    pending: PendingBlocks,
     public_key: PublicKey,
     peer_id: PeerId,
     node_name: String,
     cmd: Sender<NetworkCommand>,
+    /// cleared once the swarm task panicked
+    driver_alive: Reader<bool>,
+    /// set once `drain` was called, new fetches fail from then on
+    draining: Writer<bool>,
+    delegated_router: Option<Url>,
+    kad: Option<KadSettings>,
+    bootstrap_strategy: BootstrapStrategy,
+    provide_concurrency: usize,
+    clock: Arc<dyn Clock>,
+    jitter: Jitter,
+    executor: Executor,
     _swarm_task: Arc<JoinHandle<()>>,
 }
 
@@ -141,7 +268,7 @@ type TransportError = libp2p::core::transport::timeout::TransportTimeoutError<
 >;
 
 /// if this fails compilation, also change peers::is_sim_open()
-fn assert_transport_error_type<T: Transport<Error = U>, U>(_: &T) {}
+fn assert_transport_error_type<T: libp2p::core::Transport<Error = U>, U>(_: &T) {}
 
 impl NetworkService {
     pub async fn new<S: BitswapStore>(
@@ -149,10 +276,47 @@ impl NetworkService {
         store: S,
         executor: Executor,
     ) -> Result<Self> {
+        if let Some(path) = config.keypair_path.as_ref() {
+            config.node_key = load_or_store_keypair(path, &config.node_key)?;
+        }
         let public_key = config.node_key.public();
         let peer_id =
             PeerId::from_public_key(&libp2p::core::PublicKey::Ed25519(public_key.clone()));
         let node_name = config.node_name.clone();
+        let delegated_router = config.delegated_router.clone();
+        let bootstrap_strategy = config.bootstrap_strategy.clone();
+        let provide_concurrency = config.provide_concurrency;
+        let clock = config.clock.clone();
+        let jitter = config.jitter;
+        let fetch_limit = FetchLimit::new(config.max_fetches, config.overload_policy);
+        let kad = if config.kad.is_some() {
+            Some(KadSettings {
+                mode: config.kad_mode,
+                replication_factor: config.kad_replication_factor,
+            })
+        } else {
+            None
+        };
+        let validate_messages = config
+            .gossipsub
+            .as_ref()
+            .map(|config| config.validate_messages())
+            .unwrap_or_default();
+        let max_pending_requests = config
+            .app_protocol
+            .as_ref()
+            .map(|config| config.max_pending_requests)
+            .unwrap_or_default();
+        let port_mapper = if config.enable_upnp {
+            Some(config.port_mapper.clone())
+        } else {
+            None
+        };
+        let mesh_interval = match (&config.gossipsub, &config.gossipsub_mesh) {
+            (Some(_), Some(mesh)) => Some(mesh.heartbeat_interval),
+            (Some(gossipsub), None) => Some(gossipsub.heartbeat_interval()),
+            (None, _) => None,
+        };
 
         let peers = Writer::new(FnvHashMap::default());
         let peers2 = peers.reader();
@@ -160,8 +324,27 @@ impl NetworkService {
         let listeners2 = listeners.reader();
         let external = Writer::new(vec![]);
         let external2 = external.reader();
-        let behaviour =
-            NetworkBackendBehaviour::new(&mut config, store, listeners, peers, external)?;
+        let ledgers = Writer::new(FnvHashMap::default());
+        let ledgers2 = ledgers.reader();
+        let wants = Writer::new(RecentWants::default());
+        let wants2 = wants.reader();
+        let pending = PendingBlocks::default();
+        let (cmd_tx, cmd_rx) = mpsc::channel(100);
+        let store = ReceivedBlocks {
+            store,
+            cmd: cmd_tx.clone(),
+            pending: pending.clone(),
+        };
+        let behaviour = NetworkBackendBehaviour::new(
+            &mut config,
+            store,
+            listeners,
+            peers,
+            external,
+            ledgers,
+            wants,
+            pending.clone(),
+        )?;
 
         let tcp = {
             let transport =
@@ -262,28 +445,67 @@ impl NetworkService {
 
         let bootstrapped = Writer::new(false);
         let bootstrapped2 = bootstrapped.reader();
-        let (cmd_tx, cmd_rx) = mpsc::channel(100);
-        let swarm_task = executor.spawn(poll_swarm(
+        let driver = poll_swarm(
             cmd_rx,
             cmd_tx.clone(),
             swarm,
             executor.clone(),
             bootstrapped,
-        ));
+            SwarmOptions {
+                port_mapper,
+                mesh_interval,
+                clock: clock.clone(),
+                jitter,
+                validate_messages,
+                max_pending_requests,
+                fetch_limit,
+            },
+        );
+        let driver_alive = Writer::new(true);
+        let driver_alive2 = driver_alive.reader();
+        // The swarm state is lost in a panic, so instead of restarting the
+        // driver all further commands fail because the receiver is gone.
+        let swarm_task = executor.spawn(async move {
+            if !supervise("network driver", driver).await {
+                *driver_alive.write() = false;
+            }
+        });
 
         Ok(Self {
             bootstrapped: bootstrapped2,
             peers: peers2,
             listeners: listeners2,
             external: external2,
+            ledgers: ledgers2,
+            wants: wants2,
+            pending,
             public_key,
             peer_id,
             node_name,
             cmd: cmd_tx,
+            driver_alive: driver_alive2,
+            draining: Writer::new(false),
+            delegated_router,
+            kad,
+            bootstrap_strategy,
+            provide_concurrency,
+            clock,
+            jitter,
+            executor,
             _swarm_task: Arc::new(swarm_task),
         })
     }
 
+    /// Whether the background task driving the swarm is still running.
+    pub fn is_running(&self) -> bool {
+        self.driver_alive.get() && !self.cmd.is_closed()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn inject_panic(&mut self) {
+        self.cmd(NetworkCommand::Panic);
+    }
+
     pub fn local_public_key(&self) -> PublicKey {
         self.public_key.clone()
     }
@@ -297,20 +519,24 @@ impl NetworkService {
     }
 
     fn cmd(&mut self, msg: NetworkCommand) -> Option<(NetworkCommand, &'static str)> {
-        Self::handle_send_result(self.cmd.try_send(msg))
+        let res = self.cmd.try_send(msg);
+        self.handle_send_result(res)
     }
 
     fn cmd_shared(&self, msg: NetworkCommand) -> Option<(NetworkCommand, &'static str)> {
-        Self::handle_send_result(self.cmd.clone().try_send(msg))
+        self.handle_send_result(self.cmd.clone().try_send(msg))
     }
 
     fn handle_send_result(
+        &self,
         res: Result<(), TrySendError<NetworkCommand>>,
     ) -> Option<(NetworkCommand, &'static str)> {
         match res {
             Ok(_) => None,
             Err(err) => {
-                let reason = if err.is_disconnected() {
+                let reason = if err.is_disconnected() && !self.driver_alive.get() {
+                    "network driver panicked"
+                } else if err.is_disconnected() {
                     "receiver went away"
                 } else {
                     "channel is full"
@@ -365,22 +591,82 @@ impl NetworkService {
         self.cmd(NetworkCommand::PrunePeers(min_age));
     }
 
+    pub fn prune_stale_peers(&mut self, max_age: Duration) {
+        self.cmd(NetworkCommand::PruneStalePeers(max_age));
+    }
+
+    pub fn save_peers(&self) -> Vec<u8> {
+        self.peers.project(peer_info::save_peers)
+    }
+
+    pub fn load_peers(&mut self, data: &[u8]) -> Result<()> {
+        let peers = peer_info::load_peers(data)?;
+        self.cmd(NetworkCommand::LoadPeers(peers));
+        Ok(())
+    }
+
     pub fn dial(&mut self, peer: PeerId) {
         self.cmd(NetworkCommand::Dial(peer));
     }
 
+    /// Dials the peer unless it is connected already, for callers that only
+    /// hold a shared reference.
+    pub fn connect(&self, peer: &PeerId) {
+        if !self.is_connected(peer) {
+            self.cmd_shared(NetworkCommand::Dial(*peer));
+        }
+    }
+
     pub fn dial_address(&mut self, peer: PeerId, addr: Multiaddr) {
         self.cmd(NetworkCommand::DialAddress(peer, addr));
     }
 
+    pub fn add_static_peer(
+        &mut self,
+        peer: PeerId,
+        addrs: Vec<Multiaddr>,
+        reconnect: ReconnectPolicy,
+    ) {
+        self.cmd(NetworkCommand::AddStaticPeer(peer, addrs, reconnect));
+    }
+
     pub fn ban(&mut self, peer: PeerId) {
         self.cmd(NetworkCommand::Ban(peer));
     }
 
+    /// Reports the errors of the block store as `Event::StorageError`. The
+    /// task only holds on to the command channel, so it doesn't keep the swarm
+    /// (which owns the block store) alive.
+    pub fn forward_storage_errors(&self, errors: impl Stream<Item = String> + Send + 'static) {
+        let cmd = self.cmd.clone();
+        self.executor
+            .spawn(errors.for_each(move |err| {
+                let mut cmd = cmd.clone();
+                async move {
+                    cmd.send(NetworkCommand::StorageError(err)).await.ok();
+                }
+            }))
+            .detach();
+    }
+
+    /// Accounts the file descriptors of the block store towards
+    /// `NetworkConfig::fd_soft_limit`.
+    pub fn reserve_fds(&mut self, fds: usize) {
+        self.cmd(NetworkCommand::ReserveFds(fds));
+    }
+
     pub fn unban(&mut self, peer: PeerId) {
         self.cmd(NetworkCommand::Unban(peer));
     }
 
+    pub fn set_inbound_filter(&mut self, filter: InboundFilter) {
+        self.cmd(NetworkCommand::SetInboundFilter(filter));
+    }
+
+    pub fn set_event_handler(&mut self, handler: EventHandler) {
+        self.cmd(NetworkCommand::SetEventHandler(handler));
+    }
+
     pub fn peers(&self) -> Vec<PeerId> {
         self.peers.project(|peers| peers.keys().copied().collect())
     }
@@ -398,6 +684,28 @@ impl NetworkService {
         })
     }
 
+    pub fn connected_peers(&self) -> Vec<PeerId> {
+        self.peers.project(|peers| {
+            peers
+                .iter()
+                .filter(|(_, info)| !info.connections.is_empty())
+                .map(|(peer, _)| *peer)
+                .collect()
+        })
+    }
+
+    pub fn peers_with_protocol(&self, protocol: &str) -> Vec<PeerId> {
+        self.peers.project(|peers| {
+            peers
+                .iter()
+                .filter(|(_, info)| {
+                    !info.connections.is_empty() && info.protocols().any(|p| p == protocol)
+                })
+                .map(|(peer, _)| *peer)
+                .collect()
+        })
+    }
+
     pub fn is_connected(&self, peer: &PeerId) -> bool {
         *peer == self.local_peer_id()
             || self.peers.project(|peers| {
@@ -412,17 +720,109 @@ impl NetworkService {
         self.peers.project(|peers| peers.get(peer).cloned())
     }
 
+    pub fn bitswap_ledger(&self, peer: &PeerId) -> Option<Ledger> {
+        self.ledgers.project(|ledgers| ledgers.get(peer).copied())
+    }
+
+    pub fn requesters(&self, cid: &Cid) -> Vec<PeerId> {
+        self.wants.project(|wants| wants.requesters(cid))
+    }
+
+    pub fn pending_block_bytes(&self) -> usize {
+        self.pending.bytes(Instant::now()).0
+    }
+
+    /// The blocks being fetched, asked from the swarm task when called.
+    pub fn wantlist(&self) -> impl Future<Output = Result<Vec<Cid>>> {
+        let (tx, rx) = oneshot::channel();
+        if let Some((_, err)) = self.cmd_shared(NetworkCommand::Wantlist(tx)) {
+            return future::ready(Err(anyhow!("{}", err))).left_future();
+        }
+        async { Ok(rx.await?) }.right_future()
+    }
+
+    pub fn debug_dump(&self) -> impl Future<Output = Result<Value>> {
+        let mut peers = self.peers.project(|peers| {
+            peers
+                .iter()
+                .map(|(peer, info)| peer_info::debug_peer(peer, info))
+                .collect::<Vec<_>>()
+        });
+        peers.sort_by(|a, b| a["id"].as_str().cmp(&b["id"].as_str()));
+        let connections = self
+            .connections()
+            .into_iter()
+            .map(|(peer, addr, dt, direction)| {
+                json!({
+                    "peer": peer.to_string(),
+                    "address": addr.to_string(),
+                    "since": dt.to_rfc3339(),
+                    "direction": format!("{:?}", direction),
+                })
+            })
+            .collect::<Vec<_>>();
+        let external_addresses = self
+            .external_addresses()
+            .into_iter()
+            .map(|record| {
+                let score = match record.score {
+                    AddressScore::Finite(score) => json!(score),
+                    AddressScore::Infinite => json!("infinite"),
+                };
+                json!({ "address": record.addr.to_string(), "score": score })
+            })
+            .collect::<Vec<_>>();
+        let mut dump = json!({
+            "peer_id": self.peer_id.to_string(),
+            "peers": peers,
+            "connections": connections,
+            "listeners": self.listeners().iter().map(|a| a.to_string()).collect::<Vec<_>>(),
+            "external_addresses": external_addresses,
+        });
+        let wantlist = self.wantlist();
+        async move {
+            let wantlist = wantlist.await?;
+            dump["wantlist"] = json!(wantlist.iter().map(|c| c.to_string()).collect::<Vec<_>>());
+            Ok(dump)
+        }
+    }
+
     pub fn bootstrap(
         &mut self,
         peers: Vec<(PeerId, Multiaddr)>,
+    ) -> impl Future<Output = Result<()>> {
+        let strategy = self.bootstrap_strategy.clone();
+        self.bootstrap_with(peers, strategy)
+    }
+
+    pub fn bootstrap_with(
+        &mut self,
+        peers: Vec<(PeerId, Multiaddr)>,
+        strategy: BootstrapStrategy,
     ) -> impl Future<Output = Result<()>> {
         let (tx, rx) = oneshot::channel();
         if let Some((_, err)) = self.cmd(NetworkCommand::Bootstrap(peers, tx)) {
             return future::ready(Err(anyhow!("{}", err))).left_future();
         }
         tracing::debug!("started bootstrap");
+        // the lookups run alongside the bootstrap, they only need the peers
+        // it starts from
+        let mut lookups = vec![];
+        for peer in strategy.lookups() {
+            let (tx, rx) = oneshot::channel();
+            if let Some((_, err)) = self.cmd(NetworkCommand::GetClosestPeers(peer, tx)) {
+                return future::ready(Err(anyhow!("{}", err))).left_future();
+            }
+            lookups.push(rx);
+        }
         async {
             rx.await??;
+            for res in future::join_all(lookups).await {
+                // a lookup that didn't find anyone doesn't fail the bootstrap
+                if let Ok(Err(err)) = res {
+                    tracing::debug!("bootstrap lookup failed: {:#}", err);
+                }
+            }
             tracing::debug!("boostrap complete");
             Ok(())
         }
@@ -447,7 +847,74 @@ impl NetworkService {
 
     pub fn providers(&mut self, key: Key) -> impl Future<Output = Result<HashSet<PeerId>>> {
         let (tx, rx) = oneshot::channel();
-        if let Some((_, err)) = self.cmd(NetworkCommand::Providers(key, tx)) {
+        let dht = if let Some((_, err)) = self.cmd(NetworkCommand::Providers(key.clone(), tx)) {
+            future::ready(Err(anyhow!("{}", err))).left_future()
+        } else {
+            async { rx.await? }.right_future()
+        };
+        let router = self.delegated_router.clone();
+        let executor = self.executor.clone();
+        let mut cmd = self.cmd.clone();
+        async move {
+            let res = dht.await;
+            let router = match router {
+                Some(router) => router,
+                None => return res,
+            };
+            match &res {
+                Ok(providers) if !providers.is_empty() => return res,
+                Ok(_) => {}
+                Err(err) => tracing::debug!("dht provider lookup failed: {:#}", err),
+            }
+            // only content keys can be looked up
+            let cid = match Cid::try_from(key.to_vec()) {
+                Ok(cid) => cid,
+                Err(_) => return res,
+            };
+            let providers = executor
+                .spawn_blocking(move || router::find_providers(&router, &cid))
+                .await??;
+            tracing::debug!(
+                "delegated router found {} providers of {}",
+                providers.len(),
+                cid
+            );
+            let addresses = providers
+                .iter()
+                .flat_map(|(peer, addrs)| addrs.iter().map(move |addr| (*peer, addr.clone())))
+                .collect();
+            cmd.send(NetworkCommand::AddDiscoveredAddresses(
+                addresses,
+                AddressSource::Kad,
+            ))
+            .await
+            .ok();
+            Ok(providers.into_iter().map(|(peer, _)| peer).collect())
+        }
+    }
+
+    /// Fetches the raw bytes of a block of at most `max_size` bytes from an
+    /// HTTP gateway.
+    pub fn fetch_from_gateway(
+        &self,
+        gateway: &Url,
+        cid: Cid,
+        max_size: usize,
+    ) -> impl Future<Output = Result<Vec<u8>>> {
+        let gateway = gateway.clone();
+        let task = self
+            .executor
+            .spawn_blocking(move || router::fetch_from_gateway(&gateway, &cid, max_size));
+        async move { task.await? }
+    }
+
+    pub fn kad_config(&self) -> Option<KadSettings> {
+        self.kad
+    }
+
+    pub fn local_providers(&mut self, key: Key) -> impl Future<Output = Result<HashSet<PeerId>>> {
+        let (tx, rx) = oneshot::channel();
+        if let Some((_, err)) = self.cmd(NetworkCommand::LocalProviders(key, tx)) {
             return future::ready(Err(anyhow!("{}", err))).left_future();
         }
         async { rx.await? }.right_future()
@@ -461,6 +928,29 @@ impl NetworkService {
         async { rx.await? }.right_future()
     }
 
+    /// Number of provider records `provide_many` publishes at once.
+    pub fn provide_concurrency(&self) -> usize {
+        self.provide_concurrency.max(1)
+    }
+
+    /// Provides a key, retrying failed publishes with backoff.
+    pub async fn provide_with_backoff(mut self, key: Key) -> Result<()> {
+        let mut backoff = PROVIDE_BACKOFF;
+        for _ in 0..PROVIDE_RETRIES {
+            match self.provide(key.clone()).await {
+                Ok(()) => return Ok(()),
+                // retrying doesn't help with these
+                Err(err) if err.is::<DhtDisabled>() || err.is::<NotBootstrapped>() => {
+                    return Err(err)
+                }
+                Err(err) => tracing::debug!("providing failed, retrying: {:#}", err),
+            }
+            self.clock.tick(backoff, self.jitter).await;
+            backoff *= 2;
+        }
+        self.provide(key).await
+    }
+
     pub fn unprovide(&mut self, key: Key) -> Result<()> {
         if let Some((_, err)) = self.cmd(NetworkCommand::Unprovide(key)) {
             return Err(anyhow!("{}", err));
@@ -504,7 +994,20 @@ impl NetworkService {
         topic: String,
     ) -> impl Future<Output = Result<impl Stream<Item = GossipEvent>>> {
         let (tx, rx) = oneshot::channel();
-        if let Some((_, err)) = self.cmd(NetworkCommand::Subscribe(topic, tx)) {
+        if let Some((_, err)) = self.cmd(NetworkCommand::Subscribe(topic, None, tx)) {
+            return future::ready(Err(anyhow!("{}", err))).left_future();
+        }
+        async { rx.await? }.right_future()
+    }
+
+    pub fn subscribe_validated(
+        &mut self,
+        topic: String,
+        validator: impl Fn(&PubsubMessage) -> ValidationResult + Send + 'static,
+    ) -> impl Future<Output = Result<impl Stream<Item = GossipEvent>>> {
+        let (tx, rx) = oneshot::channel();
+        let validator = Some(Validator::new(validator));
+        if let Some((_, err)) = self.cmd(NetworkCommand::Subscribe(topic, validator, tx)) {
             return future::ready(Err(anyhow!("{}", err))).left_future();
         }
         async { rx.await? }.right_future()
@@ -526,8 +1029,60 @@ impl NetworkService {
         async { rx.await? }.right_future()
     }
 
+    pub fn register_protocol(
+        &mut self,
+        protocol: String,
+    ) -> impl Future<Output = Result<impl Stream<Item = AppRequest>>> {
+        let (tx, rx) = oneshot::channel();
+        if let Some((_, err)) = self.cmd(NetworkCommand::RegisterProtocol(protocol, tx)) {
+            return future::ready(Err(anyhow!("{}", err))).left_future();
+        }
+        async { rx.await? }.right_future()
+    }
+
+    pub fn request(
+        &mut self,
+        peer: PeerId,
+        protocol: String,
+        data: Vec<u8>,
+    ) -> impl Future<Output = Result<Vec<u8>>> {
+        if *self.draining.read() {
+            return future::ready(Err(Draining.into())).left_future();
+        }
+        let (tx, rx) = oneshot::channel();
+        if let Some((_, err)) = self.cmd(NetworkCommand::Request(peer, protocol, data, tx)) {
+            return future::ready(Err(anyhow!("{}", err))).left_future();
+        }
+        async { rx.await? }.right_future()
+    }
+
+    pub fn refresh_peer_info(&mut self, peer: PeerId) -> impl Future<Output = Result<PeerInfo>> {
+        let (tx, rx) = oneshot::channel();
+        if let Some((_, err)) = self.cmd(NetworkCommand::RefreshPeerInfo(peer, tx)) {
+            return future::ready(Err(anyhow!("{}", err))).left_future();
+        }
+        async { rx.await? }.right_future()
+    }
+
+    pub fn introduce_peer(
+        &mut self,
+        target: PeerId,
+        peer: PeerId,
+        addrs: Vec<Multiaddr>,
+    ) -> impl Future<Output = Result<()>> {
+        let data = app_protocol::encode_introduction(&peer, &addrs);
+        let response = self.request(target, app_protocol::INTRODUCE.to_owned(), data);
+        async move {
+            response.await?;
+            Ok(())
+        }
+    }
+
     // This cannot take `&mut self` due to trait constraints, so it needs to use the less efficient cmd_shared.
     pub fn get(&self, cid: Cid, providers: Vec<PeerId>) -> impl Future<Output = Result<GetQuery>> {
+        if *self.draining.read() {
+            return future::ready(Err(Draining.into())).left_future();
+        }
         let (tx, rx) = oneshot::channel();
         if let Some((_, err)) = self.cmd_shared(NetworkCommand::Get(cid, providers, tx)) {
             return future::ready(Err(anyhow!("{}", err))).left_future();
@@ -542,6 +1097,9 @@ impl NetworkService {
         providers: Vec<PeerId>,
         missing: Vec<Cid>,
     ) -> impl Future<Output = Result<SyncQuery>> {
+        if *self.draining.read() {
+            return future::ready(Err(Draining.into())).left_future();
+        }
         if missing.is_empty() {
             return future::ready(Ok(SyncQuery::ready(Ok(())))).left_future();
         }
@@ -556,13 +1114,49 @@ impl NetworkService {
         async { Ok(rx.await?) }.right_future()
     }
 
+    /// Stops accepting bitswap and app protocol requests, new fetches and new
+    /// app protocol requests to other peers. The returned future completes
+    /// once the requests and fetches in flight are done.
+    pub fn drain(&self) -> impl Future<Output = Result<()>> {
+        *self.draining.write() = true;
+        let (tx, rx) = oneshot::channel();
+        if let Some((_, err)) = self.cmd_shared(NetworkCommand::Drain(tx)) {
+            return future::ready(Err(anyhow!("{}", err))).left_future();
+        }
+        async { Ok(rx.await?) }.right_future()
+    }
+
     pub fn swarm_events(&mut self) -> impl Future<Output = Result<SwarmEvents>> {
         let (tx, rx) = oneshot::channel();
         if let Some((_, err)) = self.cmd(NetworkCommand::SwarmEvents(tx)) {
             return future::ready(Err(anyhow!("{}", err))).left_future();
         }
-        async { Ok(rx.await?) }.right_future()
+        async { Ok(rx.await??) }.right_future()
     }
+
+    pub fn swarm_events_for_peers(
+        &mut self,
+        peers: FnvHashSet<PeerId>,
+        peerless: bool,
+    ) -> impl Future<Output = Result<SwarmEvents>> {
+        let (tx, rx) = oneshot::channel();
+        if let Some((_, err)) = self.cmd(NetworkCommand::SwarmEventsForPeers(peers, peerless, tx)) {
+            return future::ready(Err(anyhow!("{}", err))).left_future();
+        }
+        async { Ok(rx.await??) }.right_future()
+    }
+}
+
+/// The settings of the swarm task taken from the `NetworkConfig`.
+struct SwarmOptions {
+    port_mapper: Option<Arc<dyn PortMapper>>,
+    /// the gossipsub heartbeat interval, if gossipsub is enabled
+    mesh_interval: Option<Duration>,
+    clock: Arc<dyn Clock>,
+    jitter: Jitter,
+    validate_messages: bool,
+    max_pending_requests: usize,
+    fetch_limit: FetchLimit,
 }
 
 async fn poll_swarm<P: StoreParams>(
@@ -571,15 +1165,56 @@ async fn poll_swarm<P: StoreParams>(
     mut swarm: Swarm<NetworkBackendBehaviour<P>>,
     executor: Executor,
     bootstrapped: Writer<bool>,
+    options: SwarmOptions,
 ) {
+    let SwarmOptions {
+        port_mapper,
+        mesh_interval,
+        clock,
+        jitter,
+        validate_messages,
+        max_pending_requests,
+        mut fetch_limit,
+    } = options;
     let mut subscriptions =
         FnvHashMap::<String, Vec<mpsc::UnboundedSender<GossipEvent>>>::default();
+    let mut validators = Validators::new(validate_messages);
     let mut queries = FnvHashMap::<QueryId, QueryChannel>::default();
+    let mut port_mappings = FnvHashMap::<Multiaddr, upnp::PortMapping>::default();
+    let mut mesh = FnvHashMap::<String, FnvHashSet<PeerId>>::default();
+    let mut app_handlers = AppHandlers::default();
+    let mut app_requests = AppRequests::default();
+    let mut app_serving = AppServing::default();
+    let mut identify_requests = IdentifyRequests::default();
+    let mut draining = false;
+    let mut drained = Vec::<oneshot::Sender<()>>::new();
+    let mut mesh_heartbeat = mesh_interval.map(|interval| clock.tick(interval, jitter));
     loop {
         match future::select(
             future::poll_fn(|cx| {
                 tracing::trace!("polling swarm ({:?})", std::thread::current().id());
-                swarm.poll_next_unpin(cx)
+                let res = swarm.poll_next_unpin(cx);
+                if let (Some(interval), Some(heartbeat)) = (mesh_interval, mesh_heartbeat.as_mut())
+                {
+                    if heartbeat.poll_unpin(cx).is_ready() {
+                        swarm.behaviour_mut().mesh_heartbeat(&mut mesh);
+                        *heartbeat = clock.tick(interval, jitter);
+                        // register the next tick with the waker
+                        let _ = heartbeat.poll_unpin(cx);
+                    }
+                }
+                // answered requests don't surface as swarm events, so this is
+                // checked on every poll
+                if !drained.is_empty()
+                    && !swarm
+                        .behaviour()
+                        .busy(&queries, &app_requests, &app_serving)
+                {
+                    for tx in drained.drain(..) {
+                        tx.send(()).ok();
+                    }
+                }
+                res
             }),
             cmd_rx.next(),
         )
@@ -592,15 +1227,36 @@ async fn poll_swarm<P: StoreParams>(
             Either::Left((Some(cmd), _)) => match cmd {
                 SwarmEvent::ConnectionClosed {
                     peer_id,
+                    connection_id,
                     endpoint,
                     num_established,
                     cause,
                 } => swarm.behaviour_mut().connection_closed(
                     peer_id,
+                    connection_id,
                     endpoint,
                     num_established,
                     cause,
                 ),
+                SwarmEvent::NewListenAddr { address, .. } => {
+                    let local = upnp::socket_addr(&address).filter(upnp::is_mappable);
+                    if let (Some(mapper), Some(local)) = (&port_mapper, local) {
+                        let mapping = upnp::PortMapping::new(
+                            &executor,
+                            mapper.clone(),
+                            local,
+                            *swarm.local_peer_id(),
+                            cmd_tx.clone(),
+                            clock.clone(),
+                            jitter,
+                        );
+                        port_mappings.insert(address, mapping);
+                    }
+                }
+                SwarmEvent::ExpiredListenAddr { address, .. } => {
+                    // dropping the mapping removes it from the gateway
+                    port_mappings.remove(&address);
+                }
                 SwarmEvent::Behaviour(event) => {
                     let swarm = swarm.behaviour_mut();
                     match event {
@@ -623,14 +1279,44 @@ async fn poll_swarm<P: StoreParams>(
                         behaviour::NetworkBackendBehaviourEvent::Identify(e) => {
                             swarm.inject_id_event(e);
                         }
+                        behaviour::NetworkBackendBehaviourEvent::IdentifyRequest(e) => {
+                            swarm.inject_identify_request_event(e, &mut identify_requests);
+                        }
                         behaviour::NetworkBackendBehaviourEvent::Bitswap(e) => {
                             swarm.inject_bitswap_event(e, &mut queries);
                         }
                         behaviour::NetworkBackendBehaviourEvent::Gossipsub(e) => {
-                            swarm.inject_gossip_event(e, &mut subscriptions);
+                            swarm.inject_gossip_event(
+                                e,
+                                &mut subscriptions,
+                                &validators,
+                                &mut mesh,
+                            );
                         }
                         behaviour::NetworkBackendBehaviourEvent::Broadcast(e) => {
-                            swarm.inject_broadcast_event(e, &mut subscriptions);
+                            swarm.inject_broadcast_event(e, &mut subscriptions, &validators);
+                        }
+                        behaviour::NetworkBackendBehaviourEvent::App(e) => {
+                            let pending = swarm.inject_app_event(
+                                e,
+                                &mut app_handlers,
+                                &mut app_requests,
+                                &mut app_serving,
+                                draining,
+                            );
+                            if let Some((response, channel)) = pending {
+                                let mut cmd_tx = cmd_tx.clone();
+                                executor
+                                    .spawn(async move {
+                                        if let Ok(data) = response.await {
+                                            cmd_tx
+                                                .send(NetworkCommand::Respond(channel, data))
+                                                .await
+                                                .ok();
+                                        }
+                                    })
+                                    .detach();
+                            }
                         }
                     }
                 }
@@ -643,11 +1329,20 @@ async fn poll_swarm<P: StoreParams>(
             Either::Right((Some(cmd), _)) => match cmd {
                 NetworkCommand::ListenOn(addr, response) => {
                     let (tx, rx) = mpsc::unbounded();
-                    swarm.behaviour_mut().swarm_events(tx);
+                    if let Err(err) = swarm.behaviour_mut().swarm_events(tx) {
+                        // the listener events are forwarded through a subscription
+                        response
+                            .unbounded_send(ListenerEvent::ListenFailed(addr, err.to_string()))
+                            .ok();
+                        continue;
+                    }
                     match swarm.listen_on(addr.clone()) {
-                        Ok(listener) => executor
-                            .spawn(forward_listener_events(listener, response, rx))
-                            .detach(),
+                        Ok(listener) => {
+                            swarm.behaviour_mut().listening_on(listener, addr);
+                            executor
+                                .spawn(forward_listener_events(listener, response, rx))
+                                .detach();
+                        }
                         Err(error) => {
                             response
                                 .unbounded_send(ListenerEvent::ListenFailed(
@@ -673,24 +1368,56 @@ async fn poll_swarm<P: StoreParams>(
                             .add_address(&peer, addr, AddressSource::User);
                     }
                 }
+                NetworkCommand::AddDiscoveredAddresses(addresses, source) => {
+                    for (peer, addr) in addresses {
+                        swarm.behaviour_mut().add_address(&peer, addr, source);
+                    }
+                }
                 NetworkCommand::RemoveAddress(peer, addr) => {
                     swarm.behaviour_mut().remove_address(&peer, &addr);
                 }
                 NetworkCommand::PrunePeers(min_age) => {
                     swarm.behaviour_mut().prune_peers(min_age);
                 }
+                NetworkCommand::PruneStalePeers(max_age) => {
+                    swarm.behaviour_mut().prune_stale_peers(max_age);
+                }
+                NetworkCommand::LoadPeers(peers) => {
+                    swarm.behaviour_mut().load_peers(peers);
+                }
+                #[cfg(test)]
+                NetworkCommand::Panic => panic!("injected panic"),
                 NetworkCommand::Dial(peer) => {
                     swarm.behaviour_mut().dial(&peer);
                 }
                 NetworkCommand::DialAddress(peer, addr) => {
                     swarm.behaviour_mut().dial_address(&peer, addr);
                 }
+                NetworkCommand::AddStaticPeer(peer, addrs, reconnect) => {
+                    swarm
+                        .behaviour_mut()
+                        .add_static_peer(peer, addrs, reconnect);
+                }
                 NetworkCommand::Ban(peer) => {
+                    // the closed connections must not be redialed
+                    swarm.behaviour_mut().remove_static_peer(&peer);
                     swarm.ban_peer_id(peer);
                 }
+                NetworkCommand::StorageError(err) => {
+                    swarm.behaviour_mut().storage_error(err);
+                }
+                NetworkCommand::ReserveFds(fds) => {
+                    swarm.behaviour_mut().reserve_fds(fds);
+                }
                 NetworkCommand::Unban(peer) => {
                     swarm.unban_peer_id(peer);
                 }
+                NetworkCommand::SetInboundFilter(filter) => {
+                    swarm.behaviour_mut().set_inbound_filter(filter);
+                }
+                NetworkCommand::SetEventHandler(handler) => {
+                    swarm.behaviour_mut().set_event_handler(handler);
+                }
                 NetworkCommand::Bootstrap(initial, tx) => {
                     let swarm = swarm.behaviour_mut();
                     for (peer, addr) in initial {
@@ -699,12 +1426,20 @@ async fn poll_swarm<P: StoreParams>(
                     }
                     swarm.bootstrap(&mut queries, tx);
                 }
+                NetworkCommand::GetClosestPeers(peer, tx) => {
+                    swarm
+                        .behaviour_mut()
+                        .get_closest_peers(peer, &mut queries, tx);
+                }
                 NetworkCommand::Providers(key, tx) => {
                     let bootstrap_complete = *bootstrapped.read();
                     swarm
                         .behaviour_mut()
                         .providers(key, bootstrap_complete, &mut queries, tx);
                 }
+                NetworkCommand::LocalProviders(key, tx) => {
+                    tx.send(swarm.behaviour_mut().local_providers(&key)).ok();
+                }
                 NetworkCommand::Provide(key, tx) => {
                     let bootstrap_complete = *bootstrapped.read();
                     swarm
@@ -737,9 +1472,13 @@ async fn poll_swarm<P: StoreParams>(
                 NetworkCommand::RemoveRecord(key) => {
                     swarm.behaviour_mut().remove_record(&key);
                 }
-                NetworkCommand::Subscribe(topic, tx) => {
-                    tx.send(swarm.behaviour_mut().subscribe(&topic, &mut subscriptions))
-                        .ok();
+                NetworkCommand::Subscribe(topic, validator, tx) => {
+                    let swarm = swarm.behaviour_mut();
+                    let res =
+                        swarm.subscribe(&topic, validator, &mut subscriptions, &mut validators);
+                    // joining a topic grafts the peers known to be subscribed
+                    swarm.mesh_changes(&topic, &mut mesh);
+                    tx.send(res).ok();
                 }
                 NetworkCommand::Publish(topic, msg, tx) => {
                     tx.send(swarm.behaviour_mut().publish(&topic, msg)).ok();
@@ -747,42 +1486,236 @@ async fn poll_swarm<P: StoreParams>(
                 NetworkCommand::Broadcast(topic, msg, tx) => {
                     tx.send(swarm.behaviour_mut().broadcast(&topic, msg)).ok();
                 }
-                NetworkCommand::Get(cid, providers, tx) => {
-                    let (rx, id) =
-                        swarm
-                            .behaviour_mut()
-                            .get(cid, providers.into_iter(), &mut queries);
-                    tx.send(GetQuery {
-                        swarm: cmd_tx.clone(),
-                        id,
-                        rx,
-                    })
+                NetworkCommand::RegisterProtocol(protocol, tx) => {
+                    tx.send(swarm.behaviour_mut().register_protocol(
+                        protocol,
+                        max_pending_requests,
+                        &mut app_handlers,
+                    ))
                     .ok();
                 }
-                NetworkCommand::Sync(cid, providers, missing, tx) => {
-                    let (rx, id) = swarm.behaviour_mut().sync(
-                        cid,
-                        providers,
-                        missing.into_iter(),
-                        &mut queries,
-                    );
-                    tx.send(SyncQuery {
-                        swarm: Some(cmd_tx.clone()),
-                        id: Some(id),
-                        rx,
-                    })
-                    .ok();
+                NetworkCommand::Request(peer, protocol, data, tx) => {
+                    swarm
+                        .behaviour_mut()
+                        .request(&peer, protocol, data, &mut app_requests, tx);
                 }
+                NetworkCommand::Respond(channel, data) => {
+                    swarm.behaviour_mut().respond(channel, data);
+                }
+                NetworkCommand::Get(cid, providers, tx) => fetch_limit.fetch(
+                    &mut swarm,
+                    &mut queries,
+                    &cmd_tx,
+                    Fetch::Get(cid, providers, tx),
+                ),
+                NetworkCommand::Sync(cid, providers, missing, tx) => fetch_limit.fetch(
+                    &mut swarm,
+                    &mut queries,
+                    &cmd_tx,
+                    Fetch::Sync(cid, providers, missing, tx),
+                ),
                 NetworkCommand::SwarmEvents(result) => {
                     let (tx, rx) = mpsc::unbounded();
-                    swarm.behaviour_mut().swarm_events(tx);
-                    result.send(SwarmEvents::new(rx)).ok();
+                    let events = swarm
+                        .behaviour_mut()
+                        .swarm_events(tx)
+                        .map(|()| SwarmEvents::new(rx));
+                    result.send(events).ok();
+                }
+                NetworkCommand::SwarmEventsForPeers(peers, peerless, result) => {
+                    let events = swarm
+                        .behaviour_mut()
+                        .swarm_events_for_peers(peers, peerless);
+                    result.send(events).ok();
                 }
                 NetworkCommand::CancelQuery(id) => {
                     swarm.behaviour_mut().cancel(id, &mut queries);
                 }
+                NetworkCommand::BlockReceived(cid) => {
+                    swarm.behaviour_mut().block_received(&cid, &mut queries);
+                }
+                NetworkCommand::Wantlist(tx) => {
+                    tx.send(swarm.behaviour().wantlist(&queries)).ok();
+                }
+                NetworkCommand::Drain(tx) => {
+                    swarm.behaviour_mut().drain();
+                    draining = true;
+                    drained.push(tx);
+                }
+                NetworkCommand::RefreshPeerInfo(peer, tx) => {
+                    swarm
+                        .behaviour_mut()
+                        .refresh_peer_info(&peer, &mut identify_requests, tx);
+                }
             },
         }
+        // fetches start on commands and complete on swarm events, so the
+        // limit is checked after either
+        fetch_limit.update(&mut swarm, &mut queries, &cmd_tx);
+    }
+}
+
+/// A block request or sync that is subject to the fetch limit.
+enum Fetch {
+    Get(Cid, Vec<PeerId>, oneshot::Sender<GetQuery>),
+    Sync(Cid, Vec<PeerId>, Vec<Cid>, oneshot::Sender<SyncQuery>),
+}
+
+impl Fetch {
+    /// Whether the caller gave up waiting for the query.
+    fn is_canceled(&self) -> bool {
+        match self {
+            Self::Get(_, _, tx) => tx.is_canceled(),
+            Self::Sync(_, _, _, tx) => tx.is_canceled(),
+        }
+    }
+}
+
+/// Applies [`NetworkConfig::max_fetches`] and the overload policy.
+struct FetchLimit {
+    max: Option<usize>,
+    policy: OverloadPolicy,
+    overloaded: bool,
+    /// fetches waiting for a free slot, see [`OverloadPolicy::PauseFetches`]
+    paused: VecDeque<Fetch>,
+}
+
+impl FetchLimit {
+    fn new(max: Option<usize>, policy: OverloadPolicy) -> Self {
+        Self {
+            max,
+            policy,
+            overloaded: false,
+            paused: Default::default(),
+        }
+    }
+
+    /// Whether `fetch` would have to wait for a free slot.
+    fn is_full<P: StoreParams>(
+        &self,
+        swarm: &Swarm<NetworkBackendBehaviour<P>>,
+        queries: &FnvHashMap<QueryId, QueryChannel>,
+        fetch: &Fetch,
+    ) -> bool {
+        let max = match self.max {
+            Some(max) => max,
+            None => return false,
+        };
+        let behaviour = swarm.behaviour();
+        match fetch {
+            Fetch::Get(cid, providers, _) if behaviour.is_fetching(cid, providers, queries) => {
+                false
+            }
+            _ => behaviour.fetches(queries) >= max,
+        }
+    }
+
+    fn fetch<P: StoreParams>(
+        &mut self,
+        swarm: &mut Swarm<NetworkBackendBehaviour<P>>,
+        queries: &mut FnvHashMap<QueryId, QueryChannel>,
+        cmd_tx: &Sender<NetworkCommand>,
+        fetch: Fetch,
+    ) {
+        // paused fetches start in order, so new ones queue up behind them
+        if !self.paused.is_empty() || self.is_full(swarm, queries, &fetch) {
+            match (self.policy, &fetch) {
+                (OverloadPolicy::PauseFetches, _) => {
+                    tracing::debug!("overloaded, pausing fetch");
+                    self.paused.push_back(fetch);
+                    return;
+                }
+                (OverloadPolicy::ShedLowPriority, Fetch::Get(..))
+                    if swarm.behaviour_mut().shed_sync(queries) => {}
+                (OverloadPolicy::ShedLowPriority, _) => {
+                    tracing::debug!("overloaded, rejecting fetch");
+                    match fetch {
+                        Fetch::Get(_, _, tx) => {
+                            tx.send(GetQuery::ready(cmd_tx.clone(), Err(Overloaded.into())))
+                                .ok();
+                        }
+                        Fetch::Sync(_, _, _, tx) => {
+                            tx.send(SyncQuery::ready(Err(Overloaded.into()))).ok();
+                        }
+                    }
+                    return;
+                }
+                (OverloadPolicy::RejectInbound, _) => {}
+            }
+        }
+        Self::start(swarm, queries, cmd_tx, fetch);
+    }
+
+    fn start<P: StoreParams>(
+        swarm: &mut Swarm<NetworkBackendBehaviour<P>>,
+        queries: &mut FnvHashMap<QueryId, QueryChannel>,
+        cmd_tx: &Sender<NetworkCommand>,
+        fetch: Fetch,
+    ) {
+        match fetch {
+            Fetch::Get(cid, providers, tx) => {
+                let (rx, id) = swarm
+                    .behaviour_mut()
+                    .get(cid, providers.into_iter(), queries);
+                tx.send(GetQuery {
+                    swarm: cmd_tx.clone(),
+                    id: Some(id),
+                    rx,
+                })
+                .ok();
+            }
+            Fetch::Sync(cid, providers, missing, tx) => {
+                let (rx, id) =
+                    swarm
+                        .behaviour_mut()
+                        .sync(cid, providers, missing.into_iter(), queries);
+                tx.send(SyncQuery {
+                    swarm: Some(cmd_tx.clone()),
+                    id: Some(id),
+                    rx,
+                })
+                .ok();
+            }
+        }
+    }
+
+    /// Starts the paused fetches that fit and reports whether the node is
+    /// overloaded.
+    fn update<P: StoreParams>(
+        &mut self,
+        swarm: &mut Swarm<NetworkBackendBehaviour<P>>,
+        queries: &mut FnvHashMap<QueryId, QueryChannel>,
+        cmd_tx: &Sender<NetworkCommand>,
+    ) {
+        let max = match self.max {
+            Some(max) => max,
+            None => return,
+        };
+        while let Some(fetch) = self.paused.front() {
+            if fetch.is_canceled() {
+                self.paused.pop_front();
+                continue;
+            }
+            if self.is_full(swarm, queries, fetch) {
+                break;
+            }
+            let fetch = self.paused.pop_front().unwrap();
+            Self::start(swarm, queries, cmd_tx, fetch);
+        }
+        let overloaded = swarm.behaviour().fetches(queries) >= max || !self.paused.is_empty();
+        if overloaded == self.overloaded {
+            return;
+        }
+        self.overloaded = overloaded;
+        let behaviour = swarm.behaviour_mut();
+        if self.policy == OverloadPolicy::RejectInbound {
+            behaviour.set_overloaded(overloaded);
+        }
+        behaviour.notify(if overloaded {
+            Event::Overloaded
+        } else {
+            Event::Recovered
+        });
     }
 }
 
@@ -793,14 +1726,14 @@ fn forward_listener_events(
 ) -> impl Future<Output = ()> {
     rx.take_while(move |event| match event {
         Event::ListenerClosed(id) if *id == listener => future::ready(false),
-        Event::NewListenAddr(id, addr) if *id == listener => future::ready(
+        Event::NewListenAddr(id, addr, kind) if *id == listener => future::ready(
             response
-                .unbounded_send(ListenerEvent::NewListenAddr(addr.clone()))
+                .unbounded_send(ListenerEvent::NewListenAddr(addr.clone(), *kind))
                 .is_ok(),
         ),
-        Event::ExpiredListenAddr(id, addr) if *id == listener => future::ready(
+        Event::ExpiredListenAddr(id, addr, kind) if *id == listener => future::ready(
             response
-                .unbounded_send(ListenerEvent::ExpiredListenAddr(addr.clone()))
+                .unbounded_send(ListenerEvent::ExpiredListenAddr(addr.clone(), *kind))
                 .is_ok(),
         ),
         _ => future::ready(true),
@@ -811,10 +1744,27 @@ fn forward_listener_events(
 #[derive(Debug)]
 pub struct GetQuery {
     swarm: Sender<NetworkCommand>,
-    id: QueryId,
+    id: Option<QueryId>,
     rx: GetChannel,
 }
 
+impl GetQuery {
+    fn ready(swarm: Sender<NetworkCommand>, res: Result<()>) -> Self {
+        let (tx, rx) = oneshot::channel();
+        tx.send(res).ok();
+        Self {
+            swarm,
+            id: None,
+            rx,
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn query_id(&self) -> Option<QueryId> {
+        self.id
+    }
+}
+
 impl Future for GetQuery {
     type Output = Result<()>;
 
@@ -829,9 +1779,11 @@ impl Future for GetQuery {
 
 impl Drop for GetQuery {
     fn drop(&mut self) {
-        if let Err(err) = self.swarm.try_send(NetworkCommand::CancelQuery(self.id)) {
-            if !err.is_disconnected() {
-                tracing::warn!("cannot cancel dropped GetQuery: {}", err.into_send_error());
+        if let Some(id) = self.id.take() {
+            if let Err(err) = self.swarm.try_send(NetworkCommand::CancelQuery(id)) {
+                if !err.is_disconnected() {
+                    tracing::warn!("cannot cancel dropped GetQuery: {}", err.into_send_error());
+                }
             }
         }
     }
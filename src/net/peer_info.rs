@@ -1,8 +1,10 @@
+use anyhow::{anyhow, Result};
 use chrono::{DateTime, Utc};
 use fnv::FnvHashMap;
 use libp2p::{
-    core::ConnectedPoint, multiaddr::Protocol, swarm::DialError, Multiaddr, TransportError,
+    core::ConnectedPoint, multiaddr::Protocol, swarm::DialError, Multiaddr, PeerId, TransportError,
 };
+use serde_json::{json, Value};
 use std::{
     borrow::Cow, cmp::Ordering, collections::VecDeque, error::Error, fmt::Write, io, time::Duration,
 };
@@ -15,8 +17,48 @@ pub struct PeerInfo {
     pub(crate) listeners: Vec<Multiaddr>,
     pub(crate) addresses: FnvHashMap<Multiaddr, (AddressSource, DateTime<Utc>)>,
     pub(crate) connections: FnvHashMap<Multiaddr, (DateTime<Utc>, Direction)>,
+    /// our own address that the peer dialed, per inbound connection
+    pub(crate) local_addresses: FnvHashMap<Multiaddr, Multiaddr>,
     failures: VecDeque<ConnectionFailure>,
     rtt: Option<Rtt>,
+    reputation: Reputation,
+    /// identify information was restored and not yet confirmed by the peer
+    pub(crate) stale: bool,
+}
+
+/// How connecting to a peer went so far, kept across restarts when peers are
+/// saved and loaded.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Reputation {
+    successes: u64,
+    failures: u64,
+    last_interaction: Option<DateTime<Utc>>,
+}
+
+impl Reputation {
+    /// Number of established connections.
+    pub fn successes(&self) -> u64 {
+        self.successes
+    }
+
+    /// Number of failed dials and connections closed with an error.
+    pub fn failures(&self) -> u64 {
+        self.failures
+    }
+
+    /// Time of the last success or failure.
+    pub fn last_interaction(&self) -> Option<DateTime<Utc>> {
+        self.last_interaction
+    }
+
+    /// Whether connecting to the peer failed more often than it succeeded.
+    pub fn is_bad(&self) -> bool {
+        self.failures > self.successes
+    }
+
+    fn is_empty(&self) -> bool {
+        self.last_interaction.is_none()
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -34,6 +76,37 @@ impl From<&ConnectedPoint> for Direction {
     }
 }
 
+/// A connection endpoint described without libp2p types.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Endpoint {
+    /// address of the remote side of the connection
+    pub addr: Multiaddr,
+    pub direction: Direction,
+    /// our own address that the peer dialed, only known for inbound
+    /// connections
+    pub local_addr: Option<Multiaddr>,
+}
+
+impl From<&ConnectedPoint> for Endpoint {
+    fn from(cp: &ConnectedPoint) -> Self {
+        match cp {
+            ConnectedPoint::Dialer { address, .. } => Self {
+                addr: address.clone(),
+                direction: Direction::Outbound,
+                local_addr: None,
+            },
+            ConnectedPoint::Listener {
+                local_addr,
+                send_back_addr,
+            } => Self {
+                addr: send_back_addr.clone(),
+                direction: Direction::Inbound,
+                local_addr: Some(local_addr.clone()),
+            },
+        }
+    }
+}
+
 impl PeerInfo {
     pub fn protocol_version(&self) -> Option<&str> {
         self.protocol_version.as_deref()
@@ -47,6 +120,13 @@ impl PeerInfo {
         self.protocols.iter().map(|s| &**s)
     }
 
+    /// Whether the protocol version, agent version, protocols and listeners
+    /// were restored from saved peers and have not been confirmed by identify
+    /// since.
+    pub fn is_stale(&self) -> bool {
+        self.stale
+    }
+
     pub fn listen_addresses(&self) -> impl Iterator<Item = &Multiaddr> {
         self.listeners.iter()
     }
@@ -63,6 +143,16 @@ impl PeerInfo {
         self.connections.iter().map(|(a, (dt, dir))| (a, *dt, *dir))
     }
 
+    /// Our address that the peer used to reach us on its most recent inbound
+    /// connection.
+    pub fn local_address(&self) -> Option<&Multiaddr> {
+        self.local_addresses
+            .iter()
+            .filter_map(|(remote, local)| Some((self.connections.get(remote)?.0, local)))
+            .max_by_key(|(dt, _)| *dt)
+            .map(|(_, local)| local)
+    }
+
     pub fn rtt(&self) -> Option<Duration> {
         self.rtt.map(|x| x.current)
     }
@@ -71,15 +161,32 @@ impl PeerInfo {
         self.rtt
     }
 
-    pub(crate) fn set_rtt(&mut self, rtt: Option<Duration>) {
+    pub(crate) fn set_rtt(&mut self, rtt: Option<Duration>, now: DateTime<Utc>) {
         if let Some(duration) = rtt {
             if let Some(ref mut rtt) = self.rtt {
-                rtt.register(duration);
+                rtt.register(duration, now);
             } else {
-                self.rtt = Some(Rtt::new(duration));
+                self.rtt = Some(Rtt::new(duration, now));
             }
         } else if let Some(ref mut rtt) = self.rtt {
-            rtt.register_failure();
+            rtt.register_failure(now);
+        }
+    }
+
+    pub fn reputation(&self) -> Reputation {
+        self.reputation
+    }
+
+    pub(crate) fn record_success(&mut self, now: DateTime<Utc>) {
+        self.reputation.successes += 1;
+        self.reputation.last_interaction = Some(now);
+    }
+
+    /// Takes the saved reputation unless the peer was interacted with since
+    /// the start.
+    pub(crate) fn restore_reputation(&mut self, saved: Reputation) {
+        if self.reputation.is_empty() {
+            self.reputation = saved;
         }
     }
 
@@ -96,6 +203,8 @@ impl PeerInfo {
         if self.failures.len() > 9 {
             self.failures.pop_back();
         }
+        self.reputation.failures += 1;
+        self.reputation.last_interaction = Some(f.time);
         if probe_result
             && self
                 .addresses
@@ -129,9 +238,14 @@ impl PeerInfo {
             .map(|x| x.0)
     }
 
-    pub(crate) fn ingest_address(&mut self, addr: Multiaddr, source: AddressSource) -> bool {
+    pub(crate) fn ingest_address(
+        &mut self,
+        addr: Multiaddr,
+        source: AddressSource,
+        now: DateTime<Utc>,
+    ) -> bool {
         if let Some((src, dt)) = self.addresses.get_mut(&addr) {
-            *dt = Utc::now();
+            *dt = now;
             match source.cmp(src) {
                 Ordering::Less => false,
                 Ordering::Equal => false,
@@ -142,7 +256,7 @@ impl PeerInfo {
             }
         } else {
             debug_assert!(matches!(addr.iter().last(), Some(Protocol::P2p(_))));
-            self.addresses.insert(addr, (source, Utc::now()));
+            self.addresses.insert(addr, (source, now));
             source.is_to_probe()
         }
     }
@@ -155,20 +269,23 @@ pub struct Rtt {
     decay_10: Duration,
     failures: u32,
     failure_rate: u32,
+    updated: DateTime<Utc>,
 }
 
 impl Rtt {
-    pub fn new(current: Duration) -> Self {
+    pub fn new(current: Duration, now: DateTime<Utc>) -> Self {
         Self {
             current,
             decay_3: current,
             decay_10: current,
             failures: 0,
             failure_rate: 0,
+            updated: now,
         }
     }
 
-    pub fn register(&mut self, current: Duration) {
+    pub fn register(&mut self, current: Duration, now: DateTime<Utc>) {
+        self.updated = now;
         self.current = current;
         self.decay_3 = self.decay_3 * 7 / 10 + current * 3 / 10;
         self.decay_10 = self.decay_10 * 9 / 10 + current / 10;
@@ -176,7 +293,8 @@ impl Rtt {
         self.failure_rate = self.failure_rate * 99 / 100;
     }
 
-    pub fn register_failure(&mut self) {
+    pub fn register_failure(&mut self, now: DateTime<Utc>) {
+        self.updated = now;
         self.failures += 1;
         // failures decay at 1% rate, failure_rate is 1_000_000 for only failures
         self.failure_rate = self.failure_rate * 99 / 100 + 10_000;
@@ -212,11 +330,18 @@ impl Rtt {
     pub fn failure_rate(&self) -> u32 {
         self.failure_rate
     }
+
+    /// Time of the last registered rtt or failure.
+    pub fn updated(&self) -> DateTime<Utc> {
+        self.updated
+    }
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord)]
 pub enum AddressSource {
     Incoming,
+    /// told by a peer via [`Ipfs::introduce_peer`](crate::Ipfs::introduce_peer)
+    Introduced,
     Listen,
     Kad,
     Mdns,
@@ -226,6 +351,21 @@ pub enum AddressSource {
 }
 
 impl AddressSource {
+    const ALL: [AddressSource; 8] = [
+        AddressSource::Incoming,
+        AddressSource::Introduced,
+        AddressSource::Listen,
+        AddressSource::Kad,
+        AddressSource::Mdns,
+        AddressSource::Candidate,
+        AddressSource::User,
+        AddressSource::Dial,
+    ];
+
+    pub(crate) fn name(&self) -> String {
+        format!("{:?}", self)
+    }
+
     pub fn is_confirmed(&self) -> bool {
         matches!(
             self,
@@ -235,7 +375,8 @@ impl AddressSource {
     pub fn is_to_probe(&self) -> bool {
         matches!(
             self,
-            AddressSource::Listen
+            AddressSource::Introduced
+                | AddressSource::Listen
                 | AddressSource::Kad
                 | AddressSource::Mdns
                 | AddressSource::Candidate
@@ -244,6 +385,163 @@ impl AddressSource {
     pub fn is_to_translate(&self) -> bool {
         matches!(self, AddressSource::Incoming)
     }
+    /// Whether the address was advertised by a remote peer, whose loopback
+    /// addresses aren't reachable by us.
+    pub fn is_remote(&self) -> bool {
+        matches!(
+            self,
+            AddressSource::Introduced | AddressSource::Listen | AddressSource::Kad
+        )
+    }
+}
+
+/// Serializes the addresses, identify information and reputation of `peers`
+/// as JSON.
+pub(crate) fn save_peers(peers: &FnvHashMap<PeerId, PeerInfo>) -> Vec<u8> {
+    let peers = peers
+        .iter()
+        .map(|(peer, info)| {
+            json!({
+                "id": peer.to_string(),
+                "protocol_version": info.protocol_version,
+                "agent_version": info.agent_version,
+                "protocols": info.protocols,
+                "listeners": info.listeners.iter().map(|a| a.to_string()).collect::<Vec<_>>(),
+                "addresses": info
+                    .addresses
+                    .iter()
+                    .map(|(addr, (source, dt))| {
+                        json!([addr.to_string(), source.name(), dt.to_rfc3339()])
+                    })
+                    .collect::<Vec<_>>(),
+                "reputation": {
+                    "successes": info.reputation.successes,
+                    "failures": info.reputation.failures,
+                    "last_interaction": info.reputation.last_interaction.map(|dt| dt.to_rfc3339()),
+                },
+            })
+        })
+        .collect::<Vec<_>>();
+    serde_json::to_vec(&peers).expect("json values are serializable")
+}
+
+/// Describes a peer for [`Ipfs::debug_dump`](crate::Ipfs::debug_dump), with
+/// everything we know about its addresses and connections.
+pub(crate) fn debug_peer(peer: &PeerId, info: &PeerInfo) -> Value {
+    json!({
+        "id": peer.to_string(),
+        "protocol_version": info.protocol_version,
+        "agent_version": info.agent_version,
+        "protocols": info.protocols,
+        "listeners": info.listeners.iter().map(|a| a.to_string()).collect::<Vec<_>>(),
+        "addresses": info
+            .addresses
+            .iter()
+            .map(|(addr, (source, dt))| {
+                json!({
+                    "address": addr.to_string(),
+                    "source": source.name(),
+                    "since": dt.to_rfc3339(),
+                })
+            })
+            .collect::<Vec<_>>(),
+        "connections": info
+            .connections
+            .iter()
+            .map(|(addr, (dt, direction))| {
+                json!({
+                    "address": addr.to_string(),
+                    "since": dt.to_rfc3339(),
+                    "direction": format!("{:?}", direction),
+                })
+            })
+            .collect::<Vec<_>>(),
+        "rtt": info.rtt.map(|rtt| {
+            json!({
+                "current_ms": rtt.current.as_secs_f64() * 1000.0,
+                "decay_3_ms": rtt.decay_3.as_secs_f64() * 1000.0,
+                "decay_10_ms": rtt.decay_10.as_secs_f64() * 1000.0,
+                "failures": rtt.failures,
+                "failure_rate": rtt.failure_rate,
+                "updated": rtt.updated.to_rfc3339(),
+            })
+        }),
+        "reputation": {
+            "successes": info.reputation.successes,
+            "failures": info.reputation.failures,
+            "last_interaction": info.reputation.last_interaction.map(|dt| dt.to_rfc3339()),
+        },
+        "stale": info.stale,
+    })
+}
+
+/// Parses peers serialized with [`save_peers`], marking their identify
+/// information as stale.
+pub(crate) fn load_peers(data: &[u8]) -> Result<Vec<(PeerId, PeerInfo)>> {
+    fn strings(value: &Value) -> Vec<&str> {
+        value
+            .as_array()
+            .map(|values| values.iter().filter_map(|v| v.as_str()).collect())
+            .unwrap_or_default()
+    }
+    fn address(value: &Value) -> Result<(Multiaddr, (AddressSource, DateTime<Utc>))> {
+        let (addr, source, dt) = match strings(value)[..] {
+            [addr, source, dt] => (addr, source, dt),
+            _ => return Err(anyhow!("malformed address {}", value)),
+        };
+        let source = AddressSource::ALL
+            .iter()
+            .find(|s| s.name() == source)
+            .ok_or_else(|| anyhow!("unknown address source {}", source))?;
+        let dt = DateTime::parse_from_rfc3339(dt)?.with_timezone(&Utc);
+        Ok((addr.parse()?, (*source, dt)))
+    }
+    fn reputation(value: &Value) -> Result<Reputation> {
+        Ok(Reputation {
+            successes: value["successes"].as_u64().unwrap_or_default(),
+            failures: value["failures"].as_u64().unwrap_or_default(),
+            last_interaction: value["last_interaction"]
+                .as_str()
+                .map(|dt| {
+                    Ok::<_, anyhow::Error>(DateTime::parse_from_rfc3339(dt)?.with_timezone(&Utc))
+                })
+                .transpose()?,
+        })
+    }
+    let json: Value = serde_json::from_slice(data)?;
+    let peers = json
+        .as_array()
+        .ok_or_else(|| anyhow!("saved peers are not an array"))?;
+    peers
+        .iter()
+        .map(|peer| {
+            let id = peer["id"]
+                .as_str()
+                .ok_or_else(|| anyhow!("saved peer without id"))?
+                .parse()?;
+            let info = PeerInfo {
+                protocol_version: peer["protocol_version"].as_str().map(Into::into),
+                agent_version: peer["agent_version"].as_str().map(Into::into),
+                protocols: strings(&peer["protocols"])
+                    .into_iter()
+                    .map(Into::into)
+                    .collect(),
+                listeners: strings(&peer["listeners"])
+                    .into_iter()
+                    .map(|a| a.parse())
+                    .collect::<Result<_, _>>()?,
+                addresses: peer["addresses"]
+                    .as_array()
+                    .map(|addrs| addrs.iter().map(address).collect::<Result<_>>())
+                    .transpose()?
+                    .unwrap_or_default(),
+                reputation: reputation(&peer["reputation"])?,
+                stale: true,
+                ..Default::default()
+            };
+            Ok((id, info))
+        })
+        .collect()
 }
 
 #[test]
@@ -283,7 +581,7 @@ fn without_peer(a: &Multiaddr) -> Cow<'_, Multiaddr> {
 }
 
 impl ConnectionFailure {
-    pub(crate) fn dial(addr: Multiaddr, error: &DialError) -> Self {
+    pub(crate) fn dial(addr: Multiaddr, error: &DialError, time: DateTime<Utc>) -> Self {
         let display = match error {
             DialError::ConnectionIo(e) => format!("I/O error: {}", e),
             DialError::Transport(e) => {
@@ -304,37 +602,46 @@ impl ConnectionFailure {
         Self {
             kind: ConnectionFailureKind::DialError,
             addr: without_peer(&addr).into_owned(),
-            time: Utc::now(),
+            time,
             display,
             debug: format!("{:?}", error),
         }
     }
 
-    pub(crate) fn transport(addr: Multiaddr, error: &TransportError<std::io::Error>) -> Self {
+    pub(crate) fn transport(
+        addr: Multiaddr,
+        error: &TransportError<std::io::Error>,
+        time: DateTime<Utc>,
+    ) -> Self {
         Self {
             kind: ConnectionFailureKind::DialError,
             addr: without_peer(&addr).into_owned(),
-            time: Utc::now(),
+            time,
             display: format!("transport error: {}", D(error)),
             debug: format!("{:?}", error),
         }
     }
 
-    pub(crate) fn us(addr: Multiaddr, display: String, debug: String) -> Self {
+    pub(crate) fn us(addr: Multiaddr, display: String, debug: String, time: DateTime<Utc>) -> Self {
         Self {
             kind: ConnectionFailureKind::WeDisconnected,
             addr,
-            time: Utc::now(),
+            time,
             display,
             debug,
         }
     }
 
-    pub(crate) fn them(addr: Multiaddr, display: String, debug: String) -> Self {
+    pub(crate) fn them(
+        addr: Multiaddr,
+        display: String,
+        debug: String,
+        time: DateTime<Utc>,
+    ) -> Self {
         Self {
             kind: ConnectionFailureKind::PeerDisconnected,
             addr,
-            time: Utc::now(),
+            time,
             display,
             debug,
         }
@@ -1,7 +1,10 @@
 use super::{
     address_handler::IntoAddressHandler,
+    app_protocol::IntroductionPolicy,
     behaviour::MyHandlerError,
-    peer_info::{AddressSource, Direction, PeerInfo},
+    clock::{Clock, Jitter, SystemClock},
+    peer_info::{AddressSource, Direction, Endpoint, PeerInfo},
+    redial::{Redial, Redials},
 };
 use crate::{net::peer_info::ConnectionFailure, variable::Writer};
 use anyhow::Result;
@@ -13,11 +16,11 @@ use futures::{
     stream::{FuturesUnordered, Stream},
     FutureExt, StreamExt,
 };
-use futures_timer::Delay;
+use ipnet::IpNet;
 use lazy_static::lazy_static;
 use libp2p::{
     core::{
-        connection::ConnectedPoint,
+        connection::{ConnectedPoint, ConnectionId},
         transport::{timeout::TransportTimeoutError, ListenerId},
     },
     dns::DnsErr,
@@ -26,32 +29,82 @@ use libp2p::{
     swarm::{
         derive_prelude::FromSwarm,
         dial_opts::{DialOpts, PeerCondition},
-        ConnectionError, DialError, NetworkBehaviour, PollParameters, ToSwarm,
+        AddressScore, CloseConnection, ConnectionError, DialError, NetworkBehaviour,
+        PollParameters, ToSwarm,
     },
     Multiaddr, PeerId, TransportError,
 };
-use prometheus::{IntCounter, IntGauge, Registry};
+use prometheus::{IntCounter, IntCounterVec, IntGauge, Opts, Registry};
 use std::{
     borrow::Cow,
     collections::VecDeque,
     convert::TryInto,
+    fmt::Debug,
     io::ErrorKind,
     net::IpAddr,
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
     time::Duration,
 };
+use thiserror::Error;
 
+/// How often closed swarm event subscribers are dropped.
+const COMPACT_SUBSCRIBERS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Delay before a static peer is first redialed after losing its last
+/// connection or failing to connect, see [`ReconnectPolicy`].
+const STATIC_PEER_REDIAL: Duration = Duration::from_secs(5);
+
+/// Number of addresses a peer may introduce while it is connected.
+const MAX_INTRODUCED_ADDRS: usize = 32;
+
+/// Number of our addresses whose observations are remembered.
+const MAX_OBSERVED_ADDRS: usize = 64;
+/// Number of peers remembered per observed address, the count passed to the
+/// [`ExternalAddrPolicy`] doesn't go beyond it.
+const MAX_OBSERVERS: usize = 32;
+/// How long the observations of an address that isn't confirmed are kept
+/// after the last one.
+const OBSERVATION_TTL: Duration = Duration::from_secs(600);
+/// How often the observations are pruned.
+const PRUNE_OBSERVATIONS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Error returned when subscribing to the swarm events while the maximum
+/// number of subscribers, see
+/// [`NetworkConfig::max_event_subscribers`](crate::NetworkConfig::max_event_subscribers),
+/// are still open.
+#[derive(Debug, Error)]
+#[error("too many swarm event subscribers, at most {0} are allowed")]
+pub struct TooManySubscribers(pub usize);
+
+/// Reason given to the swarm for denying an inbound connection before the
+/// handshake, see `Event::ConnectionRefused` and `Event::ConnectionGated`.
+#[derive(Debug, Error)]
+#[error("inbound connection from {0} denied")]
+struct InboundDenied(Multiaddr);
+
+/// An event of the swarm event stream.
+///
+/// Every subscriber receives the events in the order they happened, a
+/// subscriber restricted to some peers receives a subsequence of that order.
+/// The first connection to a peer that wasn't known with a confirmed address
+/// yields `Discovered` (or a `DiscoveredBatch` containing the peer), `NewInfo`,
+/// `Connected` and `ConnectionEstablished`, in this order.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Event {
     /// a new listener has been created
     NewListener(ListenerId),
     /// the given listener started listening on this address
-    NewListenAddr(ListenerId, Multiaddr),
+    NewListenAddr(ListenerId, Multiaddr, ListenAddrKind),
     /// the given listener stopped listening on this address
-    ExpiredListenAddr(ListenerId, Multiaddr),
-    /// the given listener experienced an error
-    ListenerError(ListenerId, String),
+    ExpiredListenAddr(ListenerId, Multiaddr, ListenAddrKind),
+    /// the given listener experienced an error, with the address it was
+    /// created for if known
+    ListenerError(ListenerId, Option<Multiaddr>, String),
+    /// the given listener was bound to this port, e.g. the one the OS chose
+    /// for a listen address with port 0
+    ListenPortBound(ListenerId, u16, Transport),
     /// the given listener was closed
     ListenerClosed(ListenerId),
     /// we received an observed address for ourselves from a peer
@@ -59,18 +112,35 @@ pub enum Event {
     /// an address observed earlier for ourselves has been retired since it was
     /// not refreshed
     ExpiredExternalAddr(Multiaddr),
+    /// no external address has appeared or expired for the configured quiet
+    /// period, these are all current external addresses
+    ExternalAddressesStable(Vec<Multiaddr>),
     /// an address was added for the given peer, following a successful dailling
     /// attempt
     Discovered(PeerId),
+    /// the peers discovered since the last batch, emitted instead of
+    /// `Discovered` when discovery events are batched
+    DiscoveredBatch(Vec<PeerId>),
     /// a dialling attempt for the given peer has failed
     DialFailure(PeerId, Multiaddr, String),
     /// a peer could not be reached by any known address
     Unreachable(PeerId),
+    /// dialling the given peer at the given address reached a peer with the
+    /// second id instead, e.g. due to a stale address or an impersonation
+    /// attempt
+    PeerIdMismatch(PeerId, PeerId, Multiaddr),
     /// a new connection has been opened to the given peer
     ConnectionEstablished(PeerId, ConnectedPoint),
     /// a connection to the given peer has been closed
     // FIXME add termination reason
     ConnectionClosed(PeerId, ConnectedPoint),
+    /// an inbound connection from the given address was denied before the
+    /// handshake since the inbound filter rejected it
+    ConnectionRefused(Multiaddr),
+    /// the connection gate vetoed a dial of the given peer or address, denied
+    /// an inbound connection from the given address before the handshake, or
+    /// closed a connection to the given peer right away
+    ConnectionGated(Option<PeerId>, Option<Multiaddr>, GateStage),
     /// the given peer signaled that its address has changed
     AddressChanged(PeerId, ConnectedPoint, ConnectedPoint),
     /// we are now connected to the given peer
@@ -81,9 +151,162 @@ pub enum Event {
     Subscribed(PeerId, String),
     /// the given peer unsubscribed from the given gossipsub or broadcast topic
     Unsubscribed(PeerId, String),
+    /// the given peer was grafted into our gossipsub mesh for the given topic,
+    /// reported at the latest one gossipsub heartbeat later
+    MeshPeerAdded(String, PeerId),
+    /// the given peer was pruned from our gossipsub mesh for the given topic
+    MeshPeerRemoved(String, PeerId),
     Bootstrapped,
     /// the peer-info for the given peer has been updated with new information
     NewInfo(PeerId),
+    /// writes to the block store keep failing with the given error
+    StorageError(String),
+    /// as many bitswap queries are running as `NetworkConfig::max_fetches`
+    /// allows, further fetches are handled according to the overload policy
+    Overloaded,
+    /// fewer bitswap queries are running than `NetworkConfig::max_fetches`
+    /// allows and no fetch waits for a free slot
+    Recovered,
+    /// the use of the given resource reached its soft limit, connections are
+    /// being closed to stay below it
+    ResourceLimitApproaching(Resource),
+}
+
+impl Event {
+    /// The peer this event is about, if any.
+    pub fn peer_id(&self) -> Option<PeerId> {
+        match self {
+            Self::Discovered(peer)
+            | Self::DialFailure(peer, _, _)
+            | Self::Unreachable(peer)
+            | Self::PeerIdMismatch(peer, _, _)
+            | Self::ConnectionEstablished(peer, _)
+            | Self::ConnectionClosed(peer, _)
+            | Self::AddressChanged(peer, _, _)
+            | Self::Connected(peer)
+            | Self::Disconnected(peer)
+            | Self::Subscribed(peer, _)
+            | Self::Unsubscribed(peer, _)
+            | Self::MeshPeerAdded(_, peer)
+            | Self::MeshPeerRemoved(_, peer)
+            | Self::NewInfo(peer) => Some(*peer),
+            Self::ConnectionGated(peer, _, _) => *peer,
+            Self::ConnectionRefused(_)
+            | Self::NewListener(_)
+            | Self::NewListenAddr(..)
+            | Self::ExpiredListenAddr(..)
+            | Self::ListenerError(..)
+            | Self::ListenPortBound(..)
+            | Self::ListenerClosed(_)
+            | Self::NewExternalAddr(_)
+            | Self::ExpiredExternalAddr(_)
+            | Self::ExternalAddressesStable(_)
+            | Self::DiscoveredBatch(_)
+            | Self::StorageError(_)
+            | Self::Overloaded
+            | Self::Recovered
+            | Self::ResourceLimitApproaching(_)
+            | Self::Bootstrapped => None,
+        }
+    }
+
+    /// The endpoint of a connection event, for `AddressChanged` the new one.
+    pub fn endpoint(&self) -> Option<Endpoint> {
+        match self {
+            Self::ConnectionEstablished(_, cp)
+            | Self::ConnectionClosed(_, cp)
+            | Self::AddressChanged(_, _, cp) => Some(cp.into()),
+            _ => None,
+        }
+    }
+}
+
+/// Restricts a subscriber of the swarm events to a set of peers.
+#[derive(Debug)]
+struct PeerFilter {
+    peers: FnvHashSet<PeerId>,
+    /// whether to deliver events that are not about any peer
+    peerless: bool,
+}
+
+impl PeerFilter {
+    fn matches(&self, event: &Event) -> bool {
+        match event.peer_id() {
+            Some(peer) => self.peers.contains(&peer),
+            None => self.peerless,
+        }
+    }
+}
+
+/// Classification of a listen address, derived from its `Multiaddr`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ListenAddrKind {
+    /// a listener bound to a loopback, private or unspecified address
+    Direct,
+    /// an address reachable only via a relay (contains `/p2p-circuit`)
+    Relay,
+    /// a listener bound to a globally routable address
+    External,
+}
+
+impl ListenAddrKind {
+    pub fn of(addr: &Multiaddr) -> Self {
+        if addr.iter().any(|p| matches!(p, Protocol::P2pCircuit)) {
+            return Self::Relay;
+        }
+        let global = match addr.iter().next() {
+            Some(Protocol::Ip4(ip)) => {
+                !(ip.is_loopback()
+                    || ip.is_private()
+                    || ip.is_link_local()
+                    || ip.is_unspecified()
+                    || ip.is_broadcast()
+                    || ip.is_documentation()
+                    // shared address space (RFC 6598), used for carrier-grade NAT
+                    || (ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64))
+            }
+            Some(Protocol::Ip6(ip)) => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    // unique local fc00::/7 and link local fe80::/10
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80)
+            }
+            _ => false,
+        };
+        if global {
+            Self::External
+        } else {
+            Self::Direct
+        }
+    }
+}
+
+/// The transport of a listen address.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Transport {
+    Tcp,
+    /// websockets on top of TCP
+    WebSocket,
+    Quic,
+}
+
+impl Transport {
+    /// The port and transport of a concrete listen address.
+    pub fn of(addr: &Multiaddr) -> Option<(u16, Self)> {
+        let mut iter = addr.iter().skip(1);
+        match (iter.next()?, iter.next()) {
+            (Protocol::Tcp(port), Some(Protocol::Ws(_) | Protocol::Wss(_))) => {
+                Some((port, Self::WebSocket))
+            }
+            (Protocol::Tcp(port), _) => Some((port, Self::Tcp)),
+            (Protocol::Udp(port), Some(Protocol::Quic | Protocol::QuicV1)) => {
+                Some((port, Self::Quic))
+            }
+            _ => None,
+        }
+    }
 }
 
 lazy_static! {
@@ -99,6 +322,11 @@ lazy_static! {
         IntGauge::new("peers_connected", "Number of connected peers.").unwrap();
     pub static ref CONNECTIONS: IntGauge =
         IntGauge::new("peers_connections", "Number of connections.").unwrap();
+    pub static ref FILE_DESCRIPTORS: IntGauge = IntGauge::new(
+        "peers_file_descriptors",
+        "Estimated number of file descriptors used by connections, listeners and the block store."
+    )
+    .unwrap();
     pub static ref LISTENER_ERROR: IntCounter = IntCounter::new(
         "peers_listener_error",
         "Number of non fatal listener errors."
@@ -111,10 +339,79 @@ lazy_static! {
     .unwrap();
     pub static ref DIAL_FAILURE: IntCounter =
         IntCounter::new("peers_dial_failure", "Number of dial failures.").unwrap();
+    pub static ref ADDRESS_UNREACHABLE: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "peers_address_unreachable",
+            "Number of addresses that the transport couldn't reach, labelled by address source."
+        ),
+        &["address_source"],
+    )
+    .unwrap();
+    pub static ref ADDRESS_DIAL_FAILURE: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "peers_address_dial_failure",
+            "Number of failed dials of an address, labelled by address source."
+        ),
+        &["address_source"],
+    )
+    .unwrap();
+    pub static ref DIAL_SUCCESS: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "peers_dial_success",
+            "Number of connections established by dialling, labelled by address source."
+        ),
+        &["address_source"],
+    )
+    .unwrap();
+    pub static ref OVERSIZED_IDENTIFY: IntCounter = IntCounter::new(
+        "peers_oversized_identify",
+        "Number of identify infos truncated before storing them."
+    )
+    .unwrap();
 }
 
 const SIM_OPEN_RETRIES: u8 = 10;
 
+/// Limits on what is stored of the identify info of a peer.
+const MAX_IDENTIFY_PROTOCOLS: usize = 128;
+const MAX_IDENTIFY_LISTENERS: usize = 64;
+const MAX_IDENTIFY_STRING: usize = 256;
+
+/// Truncates `s` to at most `max` bytes on a char boundary, returning whether
+/// it was too long.
+fn truncate_str(s: &mut String, max: usize) -> bool {
+    if s.len() <= max {
+        return false;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    s.truncate(end);
+    true
+}
+
+/// Caps the identify info a peer sent, so that a peer can't make us store
+/// arbitrary amounts of data. Returns whether anything was cut off.
+fn cap_identify(info: &mut identify::Info) -> bool {
+    let mut capped = truncate_str(&mut info.protocol_version, MAX_IDENTIFY_STRING);
+    capped |= truncate_str(&mut info.agent_version, MAX_IDENTIFY_STRING);
+    capped |= info.protocols.len() > MAX_IDENTIFY_PROTOCOLS;
+    info.protocols.truncate(MAX_IDENTIFY_PROTOCOLS);
+    for protocol in &mut info.protocols {
+        capped |= truncate_str(protocol, MAX_IDENTIFY_STRING);
+    }
+    capped |= info.listen_addrs.len() > MAX_IDENTIFY_LISTENERS;
+    info.listen_addrs.truncate(MAX_IDENTIFY_LISTENERS);
+    capped
+}
+
+/// The `address_source` label of the dial metrics for an address of a peer.
+fn source_label(info: Option<&PeerInfo>, addr: &Multiaddr) -> String {
+    info.and_then(|info| info.addresses.get(addr))
+        .map_or_else(|| "Unknown".to_owned(), |(source, _)| source.name())
+}
+
 #[inline]
 pub(crate) fn normalize_addr(addr: &mut Multiaddr, peer: &PeerId) {
     if let Some(Protocol::P2p(_)) = addr.iter().last() {
@@ -134,6 +431,20 @@ fn normalize_addr_ref<'a>(addr: &'a Multiaddr, peer: &PeerId) -> Cow<'a, Multiad
     }
 }
 
+/// Like `normalize_addr_ref`, but leaves addresses alone for which `normalize`
+/// returns false.
+fn normalize_addr_with<'a>(
+    addr: &'a Multiaddr,
+    peer: &PeerId,
+    normalize: fn(&Multiaddr) -> bool,
+) -> Cow<'a, Multiaddr> {
+    if normalize(addr) {
+        normalize_addr_ref(addr, peer)
+    } else {
+        Cow::Borrowed(addr)
+    }
+}
+
 fn without_peer_id(addr: &Multiaddr) -> Multiaddr {
     let mut addr = addr.clone();
     if let Some(Protocol::P2p(_)) = addr.iter().last() {
@@ -146,21 +457,22 @@ fn normalize_connected_point(
     cp: &ConnectedPoint,
     local: &PeerId,
     remote: &PeerId,
+    normalize: fn(&Multiaddr) -> bool,
 ) -> ConnectedPoint {
     match cp {
         ConnectedPoint::Dialer {
             address,
             role_override,
         } => ConnectedPoint::Dialer {
-            address: normalize_addr_ref(address, remote).into_owned(),
+            address: normalize_addr_with(address, remote, normalize).into_owned(),
             role_override: *role_override,
         },
         ConnectedPoint::Listener {
             local_addr,
             send_back_addr,
         } => ConnectedPoint::Listener {
-            local_addr: normalize_addr_ref(local_addr, local).into_owned(),
-            send_back_addr: normalize_addr_ref(send_back_addr, remote).into_owned(),
+            local_addr: normalize_addr_with(local_addr, local, normalize).into_owned(),
+            send_back_addr: normalize_addr_with(send_back_addr, remote, normalize).into_owned(),
         },
     }
 }
@@ -187,40 +499,375 @@ impl MultiaddrExt for Multiaddr {
     }
 }
 
+/// Decides whether an inbound connection from a remote address is accepted.
+pub struct InboundFilter(Box<dyn Fn(&Multiaddr) -> bool + Send>);
+
+impl InboundFilter {
+    pub fn new(filter: impl Fn(&Multiaddr) -> bool + Send + 'static) -> Self {
+        Self(Box::new(filter))
+    }
+}
+
+/// Called synchronously for every event emitted by the address book, see
+/// [`AddressBook::set_event_handler`].
+pub struct EventHandler(Box<dyn Fn(&Event) + Send>);
+
+impl EventHandler {
+    pub fn new(handler: impl Fn(&Event) + Send + 'static) -> Self {
+        Self(Box::new(handler))
+    }
+}
+
+impl std::fmt::Debug for EventHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_tuple("EventHandler").finish()
+    }
+}
+
+impl std::fmt::Debug for InboundFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_tuple("InboundFilter").finish()
+    }
+}
+
+/// The peers that observed one of our addresses.
+#[derive(Debug)]
+struct Observation {
+    peers: FnvHashSet<PeerId>,
+    last_seen: DateTime<Utc>,
+}
+
+/// Decides when an address that peers observed for us is trusted enough to
+/// be reported as `Event::NewExternalAddr`.
+///
+/// Addresses that no peer observed, e.g. those added by hand or through port
+/// mapping, are not subject to the policy.
+pub trait ExternalAddrPolicy: Debug + Send + Sync + 'static {
+    /// Whether `addr` is confirmed after `observation_count` distinct peers
+    /// reported it.
+    fn should_confirm(&self, addr: &Multiaddr, observation_count: usize) -> bool;
+}
+
+/// Confirms external addresses on the first observation, e.g. on a server
+/// with a public address.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConfirmImmediately;
+
+impl ExternalAddrPolicy for ConfirmImmediately {
+    fn should_confirm(&self, _addr: &Multiaddr, _observation_count: usize) -> bool {
+        true
+    }
+}
+
+/// Confirms external addresses once the given number of distinct peers
+/// observed them, e.g. behind a carrier-grade NAT. At most 32 peers are
+/// counted.
+#[derive(Clone, Copy, Debug)]
+pub struct ConfirmAfter(pub usize);
+
+impl ExternalAddrPolicy for ConfirmAfter {
+    fn should_confirm(&self, _addr: &Multiaddr, observation_count: usize) -> bool {
+        observation_count >= self.0
+    }
+}
+
+/// Decides which connections are allowed, consulted at every stage of a
+/// connection. The hooks default to allowing everything, so a gate only
+/// implements the stages it cares about.
+pub trait ConnectionGate: Debug + Send + Sync + 'static {
+    /// Whether to dial `peer`, at `addr` if the dial is for a specific
+    /// address. `peer` is `None` for dials of an address whose peer is not
+    /// known yet.
+    fn intercept_dial(&self, _peer: Option<&PeerId>, _addr: Option<&Multiaddr>) -> bool {
+        true
+    }
+
+    /// Whether to accept an inbound connection from `remote_addr`, asked
+    /// before the handshake.
+    fn intercept_inbound(&self, _remote_addr: &Multiaddr) -> bool {
+        true
+    }
+
+    /// Whether to keep a connection once the peer on the other side proved
+    /// its identity.
+    fn intercept_peer_id(&self, _peer: &PeerId, _endpoint: &ConnectedPoint) -> bool {
+        true
+    }
+}
+
+/// The stage of a connection at which a [`ConnectionGate`] rejected it.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum GateStage {
+    Dial,
+    Inbound,
+    PeerId,
+}
+
+/// A resource of the node that is used up by connections, see
+/// [`Event::ResourceLimitApproaching`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Resource {
+    /// See `NetworkConfig::fd_soft_limit`.
+    FileDescriptors,
+}
+
+/// How a static peer is redialed after losing its last connection or
+/// failing to connect, see `NetworkConfig::static_peer_reconnect`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ReconnectPolicy {
+    /// Delay before the first redial, doubled with every further attempt.
+    pub backoff: Duration,
+    /// Upper bound of the delay between redials.
+    pub max_backoff: Duration,
+    /// Number of redials after which the peer is given up on until it
+    /// connects again. Unlimited if `None`.
+    pub max_attempts: Option<u32>,
+}
+
+impl ReconnectPolicy {
+    /// The delay before redial number `attempt`, counting from zero.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.backoff
+            .checked_mul(1 << attempt.min(31))
+            .unwrap_or(Duration::MAX)
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for ReconnectPolicy {
+    /// Redials after 5 seconds, backing off to 5 minutes, forever.
+    fn default() -> Self {
+        Self {
+            backoff: STATIC_PEER_REDIAL,
+            max_backoff: STATIC_PEER_REDIAL * 60,
+            max_attempts: None,
+        }
+    }
+}
+
+/// A connection gate allowing all connections.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AllowAll;
+
+impl ConnectionGate for AllowAll {}
+
+/// The settings and shared state of an [`AddressBook`], mostly taken from
+/// the [`NetworkConfig`](crate::NetworkConfig). The defaults turn all
+/// optional behaviour off.
+#[derive(Debug)]
+pub struct AddressBookConfig {
+    pub local_peer_id: PeerId,
+    pub port_reuse: bool,
+    pub enable_loopback: bool,
+    pub keep_alive: bool,
+    pub external_port_map: FnvHashMap<u16, u16>,
+    pub redials: Redials,
+    pub external_quiet_period: Duration,
+    pub normalize: fn(&Multiaddr) -> bool,
+    pub tie_break: bool,
+    pub mdns_filter: Option<Vec<IpNet>>,
+    pub discovery_batch: Option<Duration>,
+    pub dial_budget: Option<Duration>,
+    pub max_subscribers: Option<usize>,
+    pub listeners: Writer<FnvHashSet<Multiaddr>>,
+    pub peers: Writer<FnvHashMap<PeerId, PeerInfo>>,
+    pub external: Writer<Vec<AddressRecord>>,
+}
+
+impl Default for AddressBookConfig {
+    fn default() -> Self {
+        Self {
+            local_peer_id: PeerId::random(),
+            port_reuse: false,
+            enable_loopback: false,
+            keep_alive: false,
+            external_port_map: Default::default(),
+            redials: Default::default(),
+            external_quiet_period: Duration::from_secs(10),
+            normalize: |_| true,
+            tie_break: false,
+            mdns_filter: None,
+            discovery_batch: None,
+            dial_budget: None,
+            max_subscribers: None,
+            listeners: Writer::new(Default::default()),
+            peers: Writer::new(Default::default()),
+            external: Writer::new(Default::default()),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct AddressBook {
     port_reuse: bool,
     enable_loopback: bool,
+    /// `NetworkConfig::trust_remote_loopback`
+    trust_remote_loopback: bool,
     keep_alive: bool,
+    external_port_map: FnvHashMap<u16, u16>,
+    redials: Redials,
     local_peer_id: PeerId,
     listeners: Writer<FnvHashSet<Multiaddr>>,
     peers: Writer<FnvHashMap<PeerId, PeerInfo>>,
     external: Writer<Vec<AddressRecord>>,
     refresh_external: bool,
-    event_stream: Vec<mpsc::UnboundedSender<Event>>,
+    external_quiet_period: Duration,
+    /// whether to append `/p2p/<peer>` to an address
+    normalize: fn(&Multiaddr) -> bool,
+    tie_break: bool,
+    /// subnets that addresses discovered via mDNS must be in
+    mdns_filter: Option<Vec<IpNet>>,
+    /// interval at which discovered peers are reported in batches
+    discovery_batch: Option<Duration>,
+    /// peers discovered since the last `DiscoveredBatch`
+    discovered: Vec<PeerId>,
+    /// fires when the next `DiscoveredBatch` is due
+    discovered_flush: Option<BoxFuture<'static, ()>>,
+    /// number of outstanding dials requested via `dial` or `dial_address`
+    pending_dials: FnvHashMap<PeerId, usize>,
+    /// time after which a dial of all addresses of a peer is given up
+    dial_budget: Option<Duration>,
+    /// fire when the dial budget of a peer is used up
+    dial_deadlines: FnvHashMap<PeerId, BoxFuture<'static, ()>>,
+    /// peers reported `Unreachable` when their dial budget was used up, whose
+    /// dial failure is still to come
+    over_budget: FnvHashSet<PeerId>,
+    /// peers that are kept connected, see `NetworkConfig::static_peers`
+    static_peers: FnvHashMap<PeerId, ReconnectPolicy>,
+    /// scheduled redials of disconnected static peers
+    static_redials: FnvHashMap<PeerId, BoxFuture<'static, ()>>,
+    /// redials of static peers since they were last connected
+    static_attempts: FnvHashMap<PeerId, u32>,
+    inbound_filter: Option<InboundFilter>,
+    /// connections closed by the connection gate once the peer was known
+    refused: FnvHashSet<ConnectionId>,
+    /// the addresses listeners were created for, to tell which one failed
+    listen_requests: FnvHashMap<ListenerId, Multiaddr>,
+    /// ports already reported in `ListenPortBound`, e.g. a listener on
+    /// `0.0.0.0` has one listen address per interface with the same port
+    bound_ports: FnvHashSet<(ListenerId, u16)>,
+    /// established connections per peer
+    conns: FnvHashMap<PeerId, Vec<(ConnectionId, ConnectedPoint)>>,
+    /// connections closed for tie-breaking whose `ConnectionClosed` has been
+    /// emitted already
+    tie_broken: FnvHashSet<ConnectionId>,
+    /// connections closed at the file descriptor limit whose
+    /// `ConnectionClosed` has been emitted already
+    trimmed: FnvHashSet<ConnectionId>,
+    /// open listeners
+    open_listeners: usize,
+    /// file descriptors of the block store, see `reserve_fds`
+    reserved_fds: usize,
+    /// the file descriptors at which connections are trimmed, see
+    /// `NetworkConfig::fd_soft_limit`
+    fd_soft_limit: Option<usize>,
+    /// the file descriptors last added to the `FILE_DESCRIPTORS` gauge
+    fds_reported: usize,
+    /// whether `ResourceLimitApproaching` was emitted since the file
+    /// descriptors last fell well below the soft limit
+    fd_limit_reached: bool,
+    /// current external addresses as reported in `ExternalAddressesStable`
+    external_addrs: Vec<Multiaddr>,
+    external_addr_policy: Arc<dyn ExternalAddrPolicy>,
+    connection_gate: Arc<dyn ConnectionGate>,
+    /// the peers that observed each of our addresses
+    observations: FnvHashMap<Multiaddr, Observation>,
+    /// fires when the observations of unconfirmed addresses are next pruned
+    prune_observations: BoxFuture<'static, ()>,
+    /// external addresses of the swarm the policy didn't confirm yet
+    unconfirmed: FnvHashSet<Multiaddr>,
+    /// fires once the external addresses haven't changed for the quiet period
+    external_stable: Option<BoxFuture<'static, ()>>,
+    max_subscribers: Option<usize>,
+    /// fires when the closed subscribers are next dropped
+    compact_subscribers: BoxFuture<'static, ()>,
+    /// `NetworkConfig::jitter`
+    jitter: Jitter,
+    /// the time for timers and address ages, see `NetworkConfig::clock`
+    clock: Arc<dyn Clock>,
+    /// `NetworkConfig::introductions`
+    introductions: IntroductionPolicy,
+    /// number of addresses introduced by each connected peer
+    introduced: FnvHashMap<PeerId, usize>,
+    /// called inline for every event before it is sent to the subscribers
+    event_handler: Option<EventHandler>,
+    event_stream: Vec<(mpsc::UnboundedSender<Event>, Option<PeerFilter>)>,
     pub(crate) actions: VecDeque<ToSwarm<void::Void, IntoAddressHandler>>,
     deferred: FuturesUnordered<BoxFuture<'static, ToSwarm<void::Void, IntoAddressHandler>>>,
 }
 
 impl AddressBook {
-    pub fn new(
-        local_peer_id: PeerId,
-        port_reuse: bool,
-        enable_loopback: bool,
-        keep_alive: bool,
-        listeners: Writer<FnvHashSet<Multiaddr>>,
-        peers: Writer<FnvHashMap<PeerId, PeerInfo>>,
-        external: Writer<Vec<AddressRecord>>,
-    ) -> Self {
+    pub fn new(config: AddressBookConfig) -> Self {
+        let AddressBookConfig {
+            local_peer_id,
+            port_reuse,
+            enable_loopback,
+            keep_alive,
+            external_port_map,
+            redials,
+            external_quiet_period,
+            normalize,
+            tie_break,
+            mdns_filter,
+            discovery_batch,
+            dial_budget,
+            max_subscribers,
+            listeners,
+            peers,
+            external,
+        } = config;
         Self {
             port_reuse,
             enable_loopback,
+            trust_remote_loopback: true,
             keep_alive,
+            external_port_map,
+            redials,
             local_peer_id,
             listeners,
             peers,
             external,
             refresh_external: true,
+            external_quiet_period,
+            normalize,
+            tie_break,
+            mdns_filter,
+            discovery_batch,
+            discovered: Default::default(),
+            discovered_flush: None,
+            pending_dials: Default::default(),
+            dial_budget,
+            dial_deadlines: Default::default(),
+            over_budget: Default::default(),
+            static_peers: Default::default(),
+            static_redials: Default::default(),
+            static_attempts: Default::default(),
+            inbound_filter: None,
+            refused: Default::default(),
+            listen_requests: Default::default(),
+            bound_ports: Default::default(),
+            conns: Default::default(),
+            tie_broken: Default::default(),
+            trimmed: Default::default(),
+            open_listeners: 0,
+            reserved_fds: 0,
+            fd_soft_limit: None,
+            fds_reported: 0,
+            fd_limit_reached: false,
+            external_addrs: Default::default(),
+            external_addr_policy: Arc::new(ConfirmImmediately),
+            connection_gate: Arc::new(AllowAll),
+            observations: Default::default(),
+            prune_observations: SystemClock.delay(PRUNE_OBSERVATIONS_INTERVAL),
+            unconfirmed: Default::default(),
+            external_stable: None,
+            max_subscribers,
+            compact_subscribers: SystemClock.delay(COMPACT_SUBSCRIBERS_INTERVAL),
+            jitter: Jitter::NONE,
+            clock: Arc::new(SystemClock),
+            introductions: Default::default(),
+            introduced: Default::default(),
+            event_handler: None,
             event_stream: Default::default(),
             actions: Default::default(),
             deferred: Default::default(),
@@ -231,13 +878,272 @@ impl AddressBook {
         &self.local_peer_id
     }
 
+    /// Whether an address is one of our own listen or external addresses.
+    /// Listeners on wildcard addresses report every concrete address they
+    /// are bound to, so those are the ones compared.
+    fn is_own_address(&self, address: &Multiaddr) -> bool {
+        let address = without_peer_id(address);
+        self.listeners.read().contains(&address)
+            || self
+                .external_addrs
+                .iter()
+                .any(|external| without_peer_id(external) == address)
+    }
+
+    /// The addresses a dial of `peer` tries, leaving out our own addresses
+    /// that other peers may have passed on as theirs.
+    pub(crate) fn dial_addresses(&self, peer: &PeerId) -> Vec<Multiaddr> {
+        if let Some(info) = self.peers.read().get(peer) {
+            info.confirmed_addresses()
+                .filter(|addr| !self.is_own_address(addr))
+                .cloned()
+                .collect()
+        } else {
+            vec![]
+        }
+    }
+
+    /// Whether an address discovered via mDNS lies within the configured
+    /// subnets; addresses without an IP are rejected when a filter is set.
+    pub fn accepts_mdns(&self, address: &Multiaddr) -> bool {
+        let filter = match &self.mdns_filter {
+            Some(filter) => filter,
+            None => return true,
+        };
+        let ip = address.iter().find_map(|p| match p {
+            Protocol::Ip4(ip) => Some(IpAddr::V4(ip)),
+            Protocol::Ip6(ip) => Some(IpAddr::V6(ip)),
+            _ => None,
+        });
+        ip.map_or(false, |ip| filter.iter().any(|net| net.contains(&ip)))
+    }
+
+    /// Records the address a listener was created for, which is reported
+    /// alongside its errors.
+    pub fn listening_on(&mut self, listener: ListenerId, addr: Multiaddr) {
+        self.listen_requests.insert(listener, addr);
+    }
+
+    /// Sets the filter deciding which inbound connections are accepted, based
+    /// on the address of the remote side. Rejected connections are denied
+    /// before the handshake and reported as `Event::ConnectionRefused`.
+    pub fn set_inbound_filter(&mut self, filter: impl Fn(&Multiaddr) -> bool + Send + 'static) {
+        self.inbound_filter = Some(InboundFilter::new(filter));
+    }
+
+    pub(crate) fn set_boxed_inbound_filter(&mut self, filter: InboundFilter) {
+        self.inbound_filter = Some(filter);
+    }
+
+    /// Sets the policy deciding when addresses observed by peers are
+    /// confirmed as external, see `NetworkConfig::external_addr_policy`.
+    /// Addresses observed before keep their count of observers and are judged
+    /// by the new policy when they are observed again.
+    pub fn set_external_addr_policy(&mut self, policy: Arc<dyn ExternalAddrPolicy>) {
+        self.external_addr_policy = policy;
+    }
+
+    /// Measures time with `clock` from now on, see `NetworkConfig::clock`.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.compact_subscribers = clock.tick(COMPACT_SUBSCRIBERS_INTERVAL, self.jitter);
+        self.prune_observations = clock.tick(PRUNE_OBSERVATIONS_INTERVAL, self.jitter);
+        self.clock = clock;
+    }
+
+    /// Spreads the intervals of periodic tasks, see `NetworkConfig::jitter`.
+    pub fn set_jitter(&mut self, jitter: Jitter) {
+        self.jitter = jitter;
+    }
+
+    /// Whether loopback addresses advertised by peers are added, see
+    /// `NetworkConfig::trust_remote_loopback`.
+    pub fn set_trust_remote_loopback(&mut self, trust: bool) {
+        self.trust_remote_loopback = trust;
+    }
+
+    /// Which peers may introduce others, see `NetworkConfig::introductions`.
+    pub fn set_introductions(&mut self, policy: IntroductionPolicy) {
+        self.introductions = policy;
+    }
+
+    /// Adds the addresses of `peer` that `from` told us about, if the
+    /// introduction policy allows it and `from` hasn't introduced
+    /// `MAX_INTRODUCED_ADDRS` already.
+    pub fn add_introduced(&mut self, from: &PeerId, peer: &PeerId, addrs: Vec<Multiaddr>) {
+        if !self.introductions.accepts(from) {
+            tracing::debug!("ignoring introduction of {} from {}", peer, from);
+            return;
+        }
+        let count = self.introduced.entry(*from).or_default();
+        let accepted = MAX_INTRODUCED_ADDRS.saturating_sub(*count).min(addrs.len());
+        *count += accepted;
+        if accepted < addrs.len() {
+            tracing::debug!("{} introduced too many addresses, ignoring some", from);
+        }
+        tracing::debug!("{} introduced {} at {:?}", from, peer, &addrs[..accepted]);
+        for addr in addrs.into_iter().take(accepted) {
+            self.add_address(peer, addr, AddressSource::Introduced);
+        }
+    }
+
+    /// Closes connections once the file descriptors in use reach `limit`, see
+    /// `NetworkConfig::fd_soft_limit`.
+    pub fn set_fd_soft_limit(&mut self, limit: Option<usize>) {
+        self.fd_soft_limit = limit;
+        self.limit_fds();
+    }
+
+    /// Accounts file descriptors held outside of the swarm, i.e. by the block
+    /// store.
+    pub fn reserve_fds(&mut self, fds: usize) {
+        self.reserved_fds += fds;
+        self.limit_fds();
+    }
+
+    /// A coarse estimate of the file descriptors in use: one per connection
+    /// and listener and the ones reserved for the block store.
+    pub fn file_descriptors(&self) -> usize {
+        let conns = self.conns.values().map(Vec::len).sum::<usize>();
+        conns + self.open_listeners + self.reserved_fds
+    }
+
+    /// Updates the `FILE_DESCRIPTORS` gauge and trims connections if the soft
+    /// limit is reached. Connections to static peers are closed last. Of the
+    /// others, those to peers with a bad reputation go first, then those to
+    /// the peers we interacted with least recently and, among equally idle
+    /// ones, those with the highest round trip time.
+    fn limit_fds(&mut self) {
+        if let Some(limit) = self.fd_soft_limit {
+            if self.file_descriptors() >= limit {
+                if !self.fd_limit_reached {
+                    self.fd_limit_reached = true;
+                    self.notify(Event::ResourceLimitApproaching(Resource::FileDescriptors));
+                }
+                self.trim_connections(limit);
+            } else if self.file_descriptors() < limit - limit / 10 {
+                self.fd_limit_reached = false;
+            }
+        }
+        let fds = self.file_descriptors();
+        FILE_DESCRIPTORS.add(fds as i64 - self.fds_reported as i64);
+        self.fds_reported = fds;
+    }
+
+    /// Closes connections until fewer than `limit` file descriptors are used.
+    fn trim_connections(&mut self, limit: usize) {
+        let peers = self.peers.read();
+        let mut candidates = self
+            .conns
+            .iter()
+            .flat_map(|(peer, conns)| conns.iter().map(move |(id, _)| (*peer, *id)))
+            .map(|(peer, id)| {
+                let info = peers.get(&peer);
+                let reputation = info.map(|info| info.reputation()).unwrap_or_default();
+                // peers without rtt haven't proven to be cheap either
+                let rtt = info.and_then(|info| info.rtt()).unwrap_or(Duration::MAX);
+                (
+                    self.static_peers.contains_key(&peer),
+                    !reputation.is_bad(),
+                    reputation.last_interaction(),
+                    std::cmp::Reverse(rtt),
+                    peer,
+                    id,
+                )
+            })
+            .collect::<Vec<_>>();
+        drop(peers);
+        candidates.sort();
+        let excess = self.file_descriptors() + 1 - limit;
+        for (_, _, _, _, peer, id) in candidates.into_iter().take(excess) {
+            let conns = self.conns.get_mut(&peer).unwrap();
+            let pos = conns.iter().position(|(c, _)| *c == id).unwrap();
+            let (_, cp) = conns.remove(pos);
+            if conns.is_empty() {
+                self.conns.remove(&peer);
+            }
+            tracing::debug!(peer = %peer, addr = %cp.get_remote_address(),
+                "file descriptor limit reached, closing connection");
+            self.actions.push_back(ToSwarm::CloseConnection {
+                peer_id: peer,
+                connection: CloseConnection::One(id),
+            });
+            self.trimmed.insert(id);
+            self.notify(Event::ConnectionClosed(peer, cp));
+        }
+    }
+
+    /// Sets the gate that all dials and connections have to pass, rejections
+    /// are reported as `Event::ConnectionGated`.
+    pub fn set_connection_gate(&mut self, gate: Arc<dyn ConnectionGate>) {
+        self.connection_gate = gate;
+    }
+
+    /// Asks the connection gate whether a dial may go out, forgetting the dial
+    /// if it may not.
+    pub(crate) fn dial_gated(&mut self, action: &ToSwarm<void::Void, IntoAddressHandler>) -> bool {
+        let (peer, handler) = match action {
+            ToSwarm::Dial { opts, handler } => (opts.get_peer_id(), handler),
+            _ => return false,
+        };
+        let addr = match handler {
+            IntoAddressHandler(Some((addr, _)), _) => Some(addr),
+            IntoAddressHandler(None, _) => None,
+        };
+        if self.connection_gate.intercept_dial(peer.as_ref(), addr) {
+            return false;
+        }
+        tracing::debug!(peer = ?peer, addr = ?addr, "connection gate rejected dial");
+        let addr = addr.cloned();
+        if let Some(peer) = peer {
+            self.dial_finished(&peer);
+        }
+        self.notify(Event::ConnectionGated(peer, addr, GateStage::Dial));
+        true
+    }
+
+    /// Counts one of the pending dials of `peer` as done without a
+    /// connection, forgetting its budget once none are left.
+    fn dial_finished(&mut self, peer: &PeerId) {
+        if let Some(pending) = self.pending_dials.get_mut(peer) {
+            *pending -= 1;
+            if *pending == 0 {
+                self.pending_dials.remove(peer);
+                self.dial_deadlines.remove(peer);
+            }
+        }
+    }
+
+    /// Sets a handler that is called for every event on the swarm task, before
+    /// the event is sent to the subscribers of `swarm_events`, which saves the
+    /// channel hop for latency sensitive logic. The handler holds up the whole
+    /// network while it runs, so it must be fast and must not block. Replaces
+    /// a previously set handler.
+    pub fn set_event_handler(&mut self, handler: Box<dyn Fn(&Event) + Send>) {
+        self.event_handler = Some(EventHandler(handler));
+    }
+
+    pub(crate) fn set_boxed_event_handler(&mut self, handler: EventHandler) {
+        self.event_handler = Some(handler);
+    }
+
     pub fn dial(&mut self, peer: &PeerId) {
         if peer == self.local_peer_id() {
             tracing::error!("attempting to dial self");
             return;
         }
         tracing::debug!("request dialing {}", peer);
-        let handler = self.new_handler();
+        *self.pending_dials.entry(*peer).or_default() += 1;
+        if let Some(budget) = self.dial_budget {
+            self.over_budget.remove(peer);
+            self.dial_deadlines
+                .entry(*peer)
+                .or_insert_with(|| self.clock.delay(budget));
+        }
+        let handler = if self.static_peers.contains_key(peer) {
+            IntoAddressHandler(None, true)
+        } else {
+            self.new_handler()
+        };
         self.actions.push_back(ToSwarm::Dial {
             opts: DialOpts::peer_id(*peer).build(),
             handler,
@@ -249,7 +1155,7 @@ impl AddressBook {
             tracing::error!("attempting to dial self");
             return;
         }
-        let target = normalize_addr_ref(&addr, peer);
+        let target = normalize_addr_with(&addr, peer, self.normalize);
         let mut peers = self.peers.write();
         let info = peers.entry(*peer).or_default();
         if info.connections.contains_key(target.as_ref()) {
@@ -258,7 +1164,12 @@ impl AddressBook {
             return;
         }
         drop(peers);
+        if self.is_own_address(&addr) {
+            tracing::debug!(peer = %peer, addr = %&addr, "not dialing own address");
+            return;
+        }
         tracing::debug!(peer = %peer, addr = %&addr, "request dialing");
+        *self.pending_dials.entry(*peer).or_default() += 1;
         let handler = IntoAddressHandler(
             Some((target.into_owned(), SIM_OPEN_RETRIES + 1)),
             self.keep_alive,
@@ -269,11 +1180,119 @@ impl AddressBook {
         });
     }
 
-    pub fn add_address(&mut self, peer: &PeerId, mut address: Multiaddr, source: AddressSource) {
-        if peer == self.local_peer_id() {
+    /// Adds a peer that is dialed right away and redialed according to
+    /// `reconnect` whenever it gets disconnected.
+    pub fn add_static_peer(
+        &mut self,
+        peer: PeerId,
+        addrs: Vec<Multiaddr>,
+        reconnect: ReconnectPolicy,
+    ) {
+        if peer == self.local_peer_id {
+            return;
+        }
+        for addr in addrs {
+            self.add_address(&peer, addr, AddressSource::User);
+        }
+        self.static_peers.insert(peer, reconnect);
+        self.dial(&peer);
+    }
+
+    /// Stops redialing `peer`, e.g. because it was banned. Its connections
+    /// are no longer kept alive once they are reestablished.
+    pub fn remove_static_peer(&mut self, peer: &PeerId) {
+        self.static_peers.remove(peer);
+        self.static_redials.remove(peer);
+        self.static_attempts.remove(peer);
+        self.redials.reset(peer);
+    }
+
+    /// Schedules a redial if `peer` is a static peer.
+    fn redial_static(&mut self, peer: PeerId) {
+        let policy = match self.static_peers.get(&peer) {
+            Some(policy) => *policy,
+            None => return,
+        };
+        if self.static_redials.contains_key(&peer) {
             return;
         }
-        if !self.enable_loopback && address.is_loopback() {
+        let attempts = self.static_attempts.entry(peer).or_default();
+        if policy
+            .max_attempts
+            .map(|max| *attempts >= max)
+            .unwrap_or_default()
+        {
+            tracing::debug!(peer = %peer, "giving up redialing static peer");
+            return;
+        }
+        let delay = policy.delay(*attempts);
+        *attempts += 1;
+        tracing::debug!(peer = %peer, "scheduling redial of static peer in {:?}", delay);
+        self.static_redials
+            .insert(peer, self.clock.tick(delay, self.jitter));
+    }
+
+    /// Queues the redials whose backoff has passed.
+    pub(crate) fn poll_deferred(&mut self, cx: &mut Context) {
+        while let Poll::Ready(Some(action)) = self.deferred.poll_next_unpin(cx) {
+            if let ToSwarm::Dial { opts, .. } = &action {
+                if let Some(peer) = opts.get_peer_id() {
+                    self.redials.fired(&peer);
+                }
+            }
+            self.actions.push_back(action);
+        }
+    }
+
+    /// Number of peers with redials since their last connection.
+    #[cfg(test)]
+    pub(crate) fn redialed_peers(&self) -> usize {
+        self.redials.peers()
+    }
+
+    /// Redials the static peers whose redial delay has passed.
+    pub(crate) fn poll_static_redials(&mut self, cx: &mut Context) {
+        let due = self
+            .static_redials
+            .iter_mut()
+            .filter_map(|(peer, delay)| delay.poll_unpin(cx).is_ready().then(|| *peer))
+            .collect::<Vec<_>>();
+        for peer in due {
+            self.static_redials.remove(&peer);
+            self.dial(&peer);
+        }
+    }
+
+    /// The peers with dials requested via [`dial`](Self::dial) or
+    /// [`dial_address`](Self::dial_address) that have neither connected nor
+    /// failed yet.
+    pub fn pending_dials(&self) -> impl Iterator<Item = &PeerId> {
+        self.pending_dials.keys()
+    }
+
+    /// Whether an address of `peer` learned from `source` passes the loopback
+    /// and mDNS subnet filters and isn't one of our own addresses.
+    fn accepts_address(&self, peer: &PeerId, address: &Multiaddr, source: AddressSource) -> bool {
+        if address.is_loopback()
+            && (!self.enable_loopback || !self.trust_remote_loopback && source.is_remote())
+        {
+            tracing::trace!(peer = %peer, "ignoring loopback address {} from {:?}", address, source);
+            return false;
+        }
+        if source == AddressSource::Mdns && !self.accepts_mdns(address) {
+            tracing::trace!(peer = %peer, "ignoring mDNS address {} outside of the allowed subnets", address);
+            return false;
+        }
+        if self.is_own_address(address) {
+            tracing::debug!(peer = %peer, addr = %address,
+                "ignoring peer address from unreachable scope");
+            return false;
+        }
+        true
+    }
+
+    pub fn add_address(&mut self, peer: &PeerId, mut address: Multiaddr, source: AddressSource) {
+        if peer == self.local_peer_id() || !self.accepts_address(peer, &address, source) {
             return;
         }
         let discovered = self
@@ -282,45 +1301,44 @@ impl AddressBook {
             .get(peer)
             .filter(|info| info.confirmed_addresses().next().is_some())
             .is_none();
-        let addr_full = match normalize_addr_ref(&address, peer) {
-            Cow::Borrowed(a) => {
-                let ret = a.clone();
-                address.pop();
-                ret
+        let addr_full = if !(self.normalize)(&address) {
+            address.clone()
+        } else {
+            match normalize_addr_ref(&address, peer) {
+                Cow::Borrowed(a) => {
+                    let ret = a.clone();
+                    address.pop();
+                    ret
+                }
+                Cow::Owned(a) => a,
             }
-            Cow::Owned(a) => a,
         };
-        let is_listener = self.listeners.read().contains(&address);
-        if !is_listener {
-            // addr_full is with peerId, address is guaranteed without
-            tracing::debug!(peer = %peer, "adding address {} from {:?}", address, source);
-            let mut peers = self.peers.write();
-            let info = peers.entry(*peer).or_default();
-            let result = info.ingest_address(addr_full.clone(), source)
-                && !info.connections.contains_key(&addr_full);
-            drop(peers);
-            if result {
-                self.actions.push_back(ToSwarm::Dial {
-                    opts: DialOpts::peer_id(*peer)
-                        .condition(PeerCondition::Always)
-                        .addresses(vec![address])
-                        .build(),
-                    handler: IntoAddressHandler(Some((addr_full, SIM_OPEN_RETRIES + 1)), false),
-                });
-            }
-            if discovered && source.is_confirmed() {
-                self.notify(Event::Discovered(*peer));
-            }
-            self.notify(Event::NewInfo(*peer));
-        } else {
-            tracing::debug!(peer = %peer, addr = %&address,
-                "ignoring peer address from unreachable scope");
+        // addr_full is with peerId, address is guaranteed without
+        tracing::debug!(peer = %peer, "adding address {} from {:?}", address, source);
+        let now = self.clock.now();
+        let mut peers = self.peers.write();
+        let info = peers.entry(*peer).or_default();
+        let result = info.ingest_address(addr_full.clone(), source, now)
+            && !info.connections.contains_key(&addr_full);
+        drop(peers);
+        if result {
+            self.actions.push_back(ToSwarm::Dial {
+                opts: DialOpts::peer_id(*peer)
+                    .condition(PeerCondition::Always)
+                    .addresses(vec![address])
+                    .build(),
+                handler: IntoAddressHandler(Some((addr_full, SIM_OPEN_RETRIES + 1)), false),
+            });
         }
+        if discovered && source.is_confirmed() {
+            self.discovered(*peer);
+        }
+        self.notify(Event::NewInfo(*peer));
     }
 
     pub fn remove_address(&mut self, peer: &PeerId, address: &Multiaddr) {
         if let Some(info) = self.peers.write().get_mut(peer) {
-            let address = normalize_addr_ref(address, peer);
+            let address = normalize_addr_with(address, peer, self.normalize);
             tracing::trace!("removing address {}", address);
             info.addresses.remove(&address);
         }
@@ -328,7 +1346,7 @@ impl AddressBook {
 
     pub fn prune_peers(&mut self, min_age: Duration) {
         let _span = tracing::trace_span!("prune_peers").entered();
-        let now = Utc::now();
+        let now = self.clock.now();
         let mut remove = Vec::new();
         'l: for (peer, info) in self.peers.read().iter() {
             if info.connections().next().is_some() {
@@ -358,7 +1376,92 @@ impl AddressBook {
             remove.push(*peer);
         }
         for peer in remove {
-            self.peers.write().remove(&peer);
+            self.remove_peer(&peer);
+            self.notify(Event::NewInfo(peer));
+        }
+    }
+
+    /// Forgets `peer`, keeping the `peers_discovered` gauge in step.
+    fn remove_peer(&mut self, peer: &PeerId) {
+        self.redials.reset(peer);
+        if let Some(info) = self.peers.write().remove(peer) {
+            if info.confirmed_addresses().next().is_some() {
+                DISCOVERED.dec();
+            }
+        }
+    }
+
+    /// Serializes all known peers with their addresses and identify information.
+    pub fn save_peers(&self) -> Vec<u8> {
+        super::peer_info::save_peers(&self.peers.read())
+    }
+
+    /// Adds previously saved peers. Addresses are merged into those already
+    /// known with the source they were saved with, leaving out those that
+    /// `add_address` would reject, while the stale identify information is
+    /// only used for peers that have not been identified yet.
+    pub fn load_peers(&mut self, saved: Vec<(PeerId, PeerInfo)>) {
+        let _span = tracing::trace_span!("load_peers").entered();
+        for (peer, saved) in saved {
+            if peer == self.local_peer_id {
+                continue;
+            }
+            let addresses = saved
+                .addresses
+                .iter()
+                .filter(|(addr, (source, _))| self.accepts_address(&peer, addr, *source))
+                .map(|(addr, entry)| {
+                    let addr = normalize_addr_with(addr, &peer, self.normalize).into_owned();
+                    (addr, *entry)
+                })
+                .collect::<Vec<_>>();
+            let mut peers = self.peers.write();
+            let info = peers.entry(peer).or_default();
+            let discovered = info.confirmed_addresses().next().is_none();
+            for (addr, entry) in addresses {
+                info.addresses.entry(addr).or_insert(entry);
+            }
+            if info.agent_version.is_none() && info.protocols.is_empty() {
+                info.protocol_version = saved.protocol_version;
+                info.agent_version = saved.agent_version;
+                info.protocols = saved.protocols;
+                info.listeners = saved.listeners;
+                info.stale = true;
+            }
+            info.restore_reputation(saved.reputation());
+            let discovered = discovered && info.confirmed_addresses().next().is_some();
+            drop(peers);
+            if discovered {
+                self.discovered(peer);
+            }
+            self.notify(Event::NewInfo(peer));
+        }
+    }
+
+    /// Removes peers without connections whose most recently seen address is
+    /// older than `max_age`, as opposed to `prune_peers` this also removes
+    /// peers with confirmed addresses.
+    pub fn prune_stale_peers(&mut self, max_age: Duration) {
+        let _span = tracing::trace_span!("prune_stale_peers").entered();
+        let now = self.clock.now();
+        let stale = self
+            .peers
+            .read()
+            .iter()
+            .filter(|(_, info)| {
+                info.connections().next().is_none()
+                    && info
+                        .addresses()
+                        .map(|(_, _, dt)| dt)
+                        .max()
+                        .map_or(true, |dt| diff_time(dt, now) > max_age)
+            })
+            .map(|(peer, _)| *peer)
+            .collect::<Vec<_>>();
+        for peer in stale {
+            tracing::trace!(peer = %peer, "pruning stale");
+            self.remove_peer(&peer);
+            self.notify(Event::Unreachable(peer));
             self.notify(Event::NewInfo(peer));
         }
     }
@@ -366,6 +1469,7 @@ impl AddressBook {
     pub fn connection_closed(
         &mut self,
         peer: PeerId,
+        id: ConnectionId,
         conn: ConnectedPoint,
         num_established: u32,
         error: Option<ConnectionError<MyHandlerError>>,
@@ -373,8 +1477,12 @@ impl AddressBook {
         use libp2p::core::either::EitherError::*;
         use ConnectionError::Handler as ConnHandler;
 
-        let conn = normalize_connected_point(&conn, &self.local_peer_id, &peer);
+        let conn = normalize_connected_point(&conn, &self.local_peer_id, &peer, self.normalize);
         let addr = conn.get_remote_address();
+        if self.refused.remove(&id) {
+            // never reported as established
+            return;
+        }
 
         let debug = format!("{:?}", error);
         let (reason, peer_closed) = match error {
@@ -403,52 +1511,130 @@ impl AddressBook {
             reason
         );
 
+        if let Some(conns) = self.conns.get_mut(&peer) {
+            conns.retain(|(c, _)| *c != id);
+            if conns.is_empty() {
+                self.conns.remove(&peer);
+            }
+        }
+        self.limit_fds();
+        let tie_broken = self.tie_broken.remove(&id);
+        let trimmed = self.trimmed.remove(&id);
+        let already_reported = tie_broken || trimmed;
+
+        let now = self.clock.now();
         let mut peers = self.peers.write();
         let entry = peers.entry(peer).or_default();
         entry.connections.remove(addr);
-        let addr_no_peer = without_peer_id(addr);
-        let failure = if peer_closed {
-            ConnectionFailure::them(addr_no_peer, reason, debug)
-        } else {
-            ConnectionFailure::us(addr_no_peer, reason, debug)
-        };
-        entry.push_failure(addr, failure, false);
+        entry.local_addresses.remove(addr);
+        if !already_reported {
+            let addr_no_peer = without_peer_id(addr);
+            let failure = if peer_closed {
+                ConnectionFailure::them(addr_no_peer, reason, debug, now)
+            } else {
+                ConnectionFailure::us(addr_no_peer, reason, debug, now)
+            };
+            entry.push_failure(addr, failure, false);
+        }
         drop(peers);
 
-        self.notify(Event::ConnectionClosed(peer, conn));
+        if !already_reported {
+            self.notify(Event::ConnectionClosed(peer, conn));
+        }
         if num_established == 0 {
+            self.introduced.remove(&peer);
+            self.redials.reset(&peer);
             self.notify(Event::Disconnected(peer));
+            self.redial_static(peer);
         }
         self.notify(Event::NewInfo(peer));
     }
 
+    /// Closes the connections to `peer` in one direction if there are
+    /// connections in both, keeping those dialed by the peer whose id bytes
+    /// are smaller. Since both sides apply the same rule, they agree on the
+    /// surviving connection regardless of the order in which they learned of
+    /// them.
+    fn break_tie(&mut self, peer: PeerId) {
+        if !self.tie_break {
+            return;
+        }
+        let conns = match self.conns.get_mut(&peer) {
+            Some(conns) => conns,
+            None => return,
+        };
+        let both = conns.iter().any(|(_, cp)| cp.is_dialer())
+            && conns.iter().any(|(_, cp)| !cp.is_dialer());
+        if !both {
+            return;
+        }
+        let keep_outbound = self.local_peer_id.to_bytes() < peer.to_bytes();
+        let (dropped, kept) = conns
+            .drain(..)
+            .partition::<Vec<_>, _>(|(_, cp)| cp.is_dialer() != keep_outbound);
+        *conns = kept;
+        for (id, cp) in dropped {
+            tracing::debug!(peer = %peer, addr = %cp.get_remote_address(),
+                "closing duplicate connection");
+            self.actions.push_back(ToSwarm::CloseConnection {
+                peer_id: peer,
+                connection: CloseConnection::One(id),
+            });
+            self.tie_broken.insert(id);
+            self.notify(Event::ConnectionClosed(peer, cp));
+        }
+    }
+
     #[cfg(test)]
     pub fn peers(&self) -> Vec<PeerId> {
         self.peers.read().keys().copied().collect()
     }
 
-    #[cfg(test)]
+    pub fn is_connected(&self, peer_id: &PeerId) -> bool {
+        self.peers
+            .read()
+            .get(peer_id)
+            .map(|info| !info.connections.is_empty())
+            .unwrap_or_default()
+    }
+
     pub fn info(&self, peer_id: &PeerId) -> Option<PeerInfo> {
         self.peers.read().get(peer_id).cloned()
     }
 
+    /// Returns our address that the peer dialed on its most recent inbound
+    /// connection.
+    pub fn local_address_for(&self, peer: &PeerId) -> Option<Multiaddr> {
+        self.peers
+            .read()
+            .get(peer)
+            .and_then(|info| info.local_address().cloned())
+    }
+
     pub fn set_rtt(&mut self, peer_id: &PeerId, rtt: Option<Duration>) {
+        let now = self.clock.now();
         let mut peers = self.peers.write();
         if let Some(info) = peers.get_mut(peer_id) {
-            info.set_rtt(rtt);
+            info.set_rtt(rtt, now);
             drop(peers);
             self.notify(Event::NewInfo(*peer_id));
         }
     }
 
-    pub fn set_info(&mut self, peer_id: &PeerId, identify: identify::Info) {
+    pub fn set_info(&mut self, peer_id: &PeerId, mut identify: identify::Info) {
         let _span = tracing::trace_span!("set_info", peer = %peer_id).entered();
+        if cap_identify(&mut identify) {
+            tracing::debug!(peer = %peer_id, "truncated oversized identify info");
+            OVERSIZED_IDENTIFY.inc();
+        }
+        self.observed_addr(*peer_id, identify.observed_addr.clone());
         let mut peers = self.peers.write();
         if let Some(info) = peers.get_mut(peer_id) {
             info.protocol_version = Some(identify.protocol_version);
             info.agent_version = Some(identify.agent_version);
             info.protocols = identify.protocols;
             info.listeners = identify.listen_addrs;
+            info.stale = false;
 
             let listen_port = info
                 .listeners
@@ -509,7 +1695,8 @@ impl AddressBook {
 
             info.addresses.retain(|_a, (s, _dt)| !s.is_to_translate());
 
-            let loopback = self.enable_loopback;
+            let loopback = self.enable_loopback && self.trust_remote_loopback;
+            let now = self.clock.now();
             translated.extend(
                 info.listeners
                     .iter()
@@ -520,14 +1707,14 @@ impl AddressBook {
             for addr in translated {
                 let mut tcp = addr.clone();
                 tcp.pop();
-                if self.listeners.read().contains(&tcp) {
+                if self.is_own_address(&tcp) {
                     // diallling our own listener somehow breaks the Swarm
                     tracing::trace!("not adding self-addr {}", tcp);
                     continue;
                 }
                 tracing::debug!(peer = %peer_id, addr = %&tcp,
                     "adding address derived from Identify");
-                if info.ingest_address(addr.clone(), AddressSource::Listen) {
+                if info.ingest_address(addr.clone(), AddressSource::Listen, now) {
                     // no point trying to dial if we’re already connected and port_reuse==true since
                     // a second connection is fundamentally impossible in this
                     // case
@@ -535,7 +1722,7 @@ impl AddressBook {
                         // this will offer the address as soon as the Swarm asks for one for this
                         // peer, leading to a dial attempt that will answer
                         // the question
-                        info.ingest_address(addr, AddressSource::Candidate);
+                        info.ingest_address(addr, AddressSource::Candidate, now);
                     } else {
                         self.actions.push_back(ToSwarm::Dial {
                             opts: DialOpts::peer_id(*peer_id)
@@ -552,14 +1739,91 @@ impl AddressBook {
         }
     }
 
-    pub fn swarm_events(&mut self, tx: UnboundedSender<Event>) {
-        self.event_stream.push(tx);
+    /// Makes room for one more subscriber, dropping the closed ones first
+    /// if the limit is reached.
+    fn reserve_subscriber(&mut self) -> Result<(), TooManySubscribers> {
+        if let Some(max) = self.max_subscribers {
+            if self.event_stream.len() >= max {
+                self.event_stream.retain(|(tx, _)| !tx.is_closed());
+            }
+            if self.event_stream.len() >= max {
+                return Err(TooManySubscribers(max));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn swarm_events(&mut self, tx: UnboundedSender<Event>) -> Result<(), TooManySubscribers> {
+        self.reserve_subscriber()?;
+        self.event_stream.push((tx, None));
+        Ok(())
+    }
+
+    /// Subscribes to the events concerning the given peers; events without a
+    /// peer are only delivered if `peerless` is set.
+    pub fn swarm_events_for_peers(
+        &mut self,
+        peers: FnvHashSet<PeerId>,
+        peerless: bool,
+    ) -> Result<SwarmEvents, TooManySubscribers> {
+        self.reserve_subscriber()?;
+        let (tx, rx) = mpsc::unbounded();
+        self.event_stream
+            .push((tx, Some(PeerFilter { peers, peerless })));
+        Ok(SwarmEvents::new(rx))
+    }
+
+    /// Number of swarm event subscribers, including closed ones that haven't
+    /// been dropped yet.
+    #[cfg(test)]
+    pub(crate) fn subscriber_count(&self) -> usize {
+        self.event_stream.len()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn observed_addrs(&self) -> usize {
+        self.observations.len()
+    }
+
+    /// Forgets the observations of addresses that weren't confirmed and
+    /// weren't observed for a while.
+    pub(crate) fn poll_prune_observations(&mut self, cx: &mut Context) {
+        if self.prune_observations.poll_unpin(cx).is_ready() {
+            let ttl = chrono::Duration::from_std(OBSERVATION_TTL).unwrap();
+            let cutoff = self.clock.now() - ttl;
+            let external = &self.external_addrs;
+            self.observations.retain(|addr, observation| {
+                external.contains(addr) || observation.last_seen > cutoff
+            });
+            self.prune_observations = self.clock.tick(PRUNE_OBSERVATIONS_INTERVAL, self.jitter);
+            // register the reset timer with the waker
+            let _ = self.prune_observations.poll_unpin(cx);
+        }
+    }
+
+    /// Drops closed subscribers every now and then, as `notify` only notices
+    /// them when there is an event to deliver.
+    pub(crate) fn poll_compact_subscribers(&mut self, cx: &mut Context) {
+        if self.compact_subscribers.poll_unpin(cx).is_ready() {
+            self.event_stream.retain(|(tx, _)| !tx.is_closed());
+            self.compact_subscribers = self.clock.tick(COMPACT_SUBSCRIBERS_INTERVAL, self.jitter);
+            // register the reset timer with the waker
+            let _ = self.compact_subscribers.poll_unpin(cx);
+        }
     }
 
     pub fn notify(&mut self, event: Event) {
         tracing::trace!("{:?}", event);
-        self.event_stream
-            .retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+        if let Some(handler) = &self.event_handler {
+            (handler.0)(&event);
+        }
+        self.event_stream.retain(|(tx, filter)| {
+            if filter.as_ref().map_or(true, |f| f.matches(&event)) {
+                tx.unbounded_send(event.clone()).is_ok()
+            } else {
+                !tx.is_closed()
+            }
+        });
     }
 
     pub(crate) fn dial_failure(
@@ -574,6 +1838,19 @@ impl AddressBook {
             tracing::debug!("dial failure without peer ID: {}", error);
             return;
         };
+        self.dial_finished(&peer_id);
+        if !matches!(error, DialError::DialPeerConditionFalse(_)) {
+            DIAL_FAILURE.inc();
+        }
+        if let DialError::WrongPeerId { obtained, endpoint } = error {
+            let addr = match &handler {
+                IntoAddressHandler(Some((addr, _)), _) => addr.clone(),
+                _ => endpoint.get_remote_address().clone(),
+            };
+            tracing::warn!(expected = %peer_id, presented = %obtained, addr = %addr,
+                "dialled peer presented a different peer ID");
+            self.notify(Event::PeerIdMismatch(peer_id, *obtained, addr));
+        }
         let mut peer = self.peers.write();
         if let Some(info) = peer.get_mut(&peer_id) {
             if let IntoAddressHandler(Some((addr, retries)), keep_alive) = handler {
@@ -582,7 +1859,8 @@ impl AddressBook {
                 let wrong_peer = matches!(error, DialError::WrongPeerId { .. });
                 let probe_result =
                     transport || wrong_peer || matches!(error, DialError::ConnectionIo(_));
-                let failure = ConnectionFailure::dial(without_peer_id(&addr), error);
+                let failure =
+                    ConnectionFailure::dial(without_peer_id(&addr), error, self.clock.now());
                 let is_sim_open = if let DialError::Transport(v) = error {
                     v.iter().any(|(_, e)| is_sim_open(e))
                 } else {
@@ -592,53 +1870,80 @@ impl AddressBook {
                 let error = error.to_string();
                 tracing::debug!(addr = %&addr, error = %&error, active = probe_result,
                     "validation dial failure");
+                let source = source_label(Some(&*info), &addr);
+                ADDRESS_DIAL_FAILURE.with_label_values(&[&source]).inc();
+                if transport {
+                    ADDRESS_REACH_FAILURE.inc();
+                    ADDRESS_UNREACHABLE.with_label_values(&[&source]).inc();
+                }
                 info.push_failure(&addr, failure, probe_result);
                 if wrong_peer {
                     // we know who we dialled and we know someone else answered => kill the address
                     // regardless of whether it was confirmed
                     info.addresses.remove(&addr);
                 }
+                let mut give_up = false;
                 if is_sim_open && retries > 0 {
                     // TCP simultaneous open leads to both sides being initiator in the Noise
                     // handshake, which yields this particular error
-                    if retries == SIM_OPEN_RETRIES + 1 {
-                        tracing::debug!("scheduling redial after presumed TCP simultaneous open");
+                    match self.redials.schedule(peer_id) {
+                        Redial::After(backoff) => {
+                            if retries == SIM_OPEN_RETRIES + 1 {
+                                tracing::debug!(
+                                    "scheduling redial after presumed TCP simultaneous open"
+                                );
+                            }
+                            let action = ToSwarm::Dial {
+                                opts: DialOpts::peer_id(peer_id)
+                                    .addresses(vec![addr.clone()])
+                                    .build(),
+                                handler: IntoAddressHandler(
+                                    Some((addr.clone(), retries - 1)),
+                                    keep_alive,
+                                ),
+                            };
+                            self.deferred.push(
+                                self.clock
+                                    .delay(backoff + self.redial_delay())
+                                    .map(move |_| action)
+                                    .boxed(),
+                            );
+                        }
+                        Redial::Pending => {}
+                        Redial::GiveUp => give_up = true,
                     }
-                    let action = ToSwarm::Dial {
-                        opts: DialOpts::peer_id(peer_id)
-                            .addresses(vec![addr.clone()])
-                            .build(),
-                        handler: IntoAddressHandler(Some((addr.clone(), retries - 1)), keep_alive),
-                    };
-                    self.deferred
-                        .push(Delay::new(self.redial_delay()).map(move |_| action).boxed());
+                } else {
+                    self.redials.reset(&peer_id);
                 }
                 drop(peer);
 
                 self.notify(Event::DialFailure(peer_id, addr, error));
+                if give_up {
+                    self.dial_gave_up(peer_id);
+                }
                 self.notify(Event::NewInfo(peer_id));
             } else if let DialError::Transport(v) = error {
                 let mut events = Vec::with_capacity(v.len());
-                let mut deferred = Vec::new();
+                let mut sim_open = Vec::new();
                 for (addr, error) in v {
                     let is_sim_open = is_sim_open(error);
-                    let failure = ConnectionFailure::transport(without_peer_id(addr), error);
+                    let failure = ConnectionFailure::transport(
+                        without_peer_id(addr),
+                        error,
+                        self.clock.now(),
+                    );
                     let error = format!("{:?}", error);
                     tracing::debug!(addr = %&addr, error = %&error, "non-validation dial failure");
-                    info.push_failure(normalize_addr_ref(addr, &peer_id).as_ref(), failure, true);
+                    let normalized = normalize_addr_with(addr, &peer_id, self.normalize);
+                    let source = source_label(Some(&*info), &normalized);
+                    ADDRESS_DIAL_FAILURE.with_label_values(&[&source]).inc();
+                    ADDRESS_REACH_FAILURE.inc();
+                    ADDRESS_UNREACHABLE.with_label_values(&[&source]).inc();
+                    info.push_failure(normalized.as_ref(), failure, true);
                     // TCP simultaneous open leads to both sides being initiator in the Noise
                     // handshake, which yields this particular error
                     if is_sim_open {
-                        tracing::debug!("scheduling redial after presumed TCP simultaneous open");
-                        deferred.push(ToSwarm::Dial {
-                            opts: DialOpts::peer_id(peer_id)
-                                .addresses(vec![addr.clone()])
-                                .build(),
-                            handler: IntoAddressHandler(
-                                Some((addr.clone(), SIM_OPEN_RETRIES)),
-                                self.keep_alive,
-                            ),
-                        })
+                        sim_open.push(addr.clone());
                     }
                     events.push(Event::DialFailure(peer_id, addr.clone(), error));
                 }
@@ -646,12 +1951,35 @@ impl AddressBook {
                 for event in events {
                     self.notify(event);
                 }
-                if deferred.is_empty() {
-                    self.notify(Event::Unreachable(peer_id));
-                }
-                for action in deferred {
-                    self.deferred
-                        .push(Delay::new(self.redial_delay()).map(move |_| action).boxed());
+                let redial = if sim_open.is_empty() {
+                    self.redials.reset(&peer_id);
+                    Redial::GiveUp
+                } else {
+                    self.redials.schedule(peer_id)
+                };
+                match redial {
+                    Redial::After(backoff) => {
+                        tracing::debug!("scheduling redial after presumed TCP simultaneous open");
+                        for addr in sim_open {
+                            let action = ToSwarm::Dial {
+                                opts: DialOpts::peer_id(peer_id)
+                                    .addresses(vec![addr.clone()])
+                                    .build(),
+                                handler: IntoAddressHandler(
+                                    Some((addr, SIM_OPEN_RETRIES)),
+                                    self.keep_alive,
+                                ),
+                            };
+                            self.deferred.push(
+                                self.clock
+                                    .delay(backoff + self.redial_delay())
+                                    .map(move |_| action)
+                                    .boxed(),
+                            );
+                        }
+                    }
+                    Redial::Pending => {}
+                    Redial::GiveUp => self.dial_gave_up(peer_id),
                 }
                 self.notify(Event::NewInfo(peer_id));
             } else if let DialError::DialPeerConditionFalse(d) = error {
@@ -660,7 +1988,7 @@ impl AddressBook {
                 drop(peer);
                 tracing::debug!(peer = %peer_id, error = %error, "dial failure");
                 if !matches!(error, DialError::Banned | DialError::LocalPeerId) {
-                    self.notify(Event::Unreachable(peer_id));
+                    self.dial_gave_up(peer_id);
                 }
             }
         } else {
@@ -668,6 +1996,133 @@ impl AddressBook {
         }
     }
 
+    /// Emits `ExternalAddressesStable` once the quiet period after the last
+    /// change of the external addresses has passed.
+    pub(crate) fn poll_external_stable(&mut self, cx: &mut Context) {
+        if let Some(delay) = self.external_stable.as_mut() {
+            if delay.poll_unpin(cx).is_ready() {
+                self.external_stable = None;
+                tracing::debug!("external addresses stable: {:?}", self.external_addrs);
+                self.notify(Event::ExternalAddressesStable(self.external_addrs.clone()));
+            }
+        }
+    }
+
+    fn discovered(&mut self, peer: PeerId) {
+        DISCOVERED.inc();
+        match self.discovery_batch {
+            Some(interval) => {
+                if !self.discovered.contains(&peer) {
+                    self.discovered.push(peer);
+                }
+                if self.discovered_flush.is_none() {
+                    self.discovered_flush = Some(self.clock.tick(interval, self.jitter));
+                }
+            }
+            None => self.notify(Event::Discovered(peer)),
+        }
+    }
+
+    /// Reports a peer as `Unreachable` unless its dial budget did so already.
+    fn dial_gave_up(&mut self, peer: PeerId) {
+        self.dial_deadlines.remove(&peer);
+        if !self.over_budget.remove(&peer) {
+            self.notify(Event::Unreachable(peer));
+        }
+        self.redial_static(peer);
+    }
+
+    /// Gives up dialing peers whose dial budget is used up. The dials still
+    /// running are not aborted, but their failure is not reported again.
+    pub(crate) fn poll_dial_budgets(&mut self, cx: &mut Context) {
+        let expired = self
+            .dial_deadlines
+            .iter_mut()
+            .filter_map(|(peer, delay)| delay.poll_unpin(cx).is_ready().then(|| *peer))
+            .collect::<Vec<_>>();
+        for peer in expired {
+            tracing::debug!(peer = %peer, "dial budget used up");
+            self.dial_deadlines.remove(&peer);
+            self.pending_dials.remove(&peer);
+            self.over_budget.insert(peer);
+            self.notify(Event::Unreachable(peer));
+            self.redial_static(peer);
+        }
+    }
+
+    /// Emits the peers discovered since the last batch once the batch
+    /// interval has passed.
+    pub(crate) fn poll_discovered(&mut self, cx: &mut Context) {
+        if let Some(delay) = self.discovered_flush.as_mut() {
+            if delay.poll_unpin(cx).is_ready() {
+                self.flush_discovered();
+            }
+        }
+    }
+
+    fn flush_discovered(&mut self) {
+        self.discovered_flush = None;
+        let peers = std::mem::take(&mut self.discovered);
+        self.notify(Event::DiscoveredBatch(peers));
+    }
+
+    /// Records that `peer` observed us at `addr`, confirming the address if
+    /// the swarm already reported it and the policy now approves.
+    fn observed_addr(&mut self, peer: PeerId, mut addr: Multiaddr) {
+        normalize_addr(&mut addr, self.local_peer_id());
+        let now = self.clock.now();
+        if !self.observations.contains_key(&addr) && self.observations.len() >= MAX_OBSERVED_ADDRS {
+            // make room by forgetting the address that wasn't confirmed and
+            // was observed least recently
+            let external = &self.external_addrs;
+            let oldest = self
+                .observations
+                .iter()
+                .filter(|(addr, _)| !external.contains(addr))
+                .min_by_key(|(_, observation)| observation.last_seen)
+                .map(|(addr, _)| addr.clone());
+            match oldest {
+                Some(oldest) => {
+                    self.observations.remove(&oldest);
+                }
+                None => return,
+            }
+        }
+        let observation = self
+            .observations
+            .entry(addr.clone())
+            .or_insert_with(|| Observation {
+                peers: Default::default(),
+                last_seen: now,
+            });
+        observation.last_seen = now;
+        if observation.peers.len() < MAX_OBSERVERS {
+            observation.peers.insert(peer);
+        }
+        let count = observation.peers.len();
+        if self.unconfirmed.contains(&addr)
+            && self.external_addr_policy.should_confirm(&addr, count)
+        {
+            self.unconfirmed.remove(&addr);
+            self.confirm_external_addr(addr);
+        }
+    }
+
+    fn confirm_external_addr(&mut self, addr: Multiaddr) {
+        self.external_addrs_changed();
+        tracing::trace!("new external addr {}", addr);
+        EXTERNAL_ADDRS.inc();
+        if !self.external_addrs.contains(&addr) {
+            self.external_addrs.push(addr.clone());
+        }
+        self.notify(Event::NewExternalAddr(addr));
+    }
+
+    fn external_addrs_changed(&mut self) {
+        self.refresh_external = true;
+        self.external_stable = Some(self.clock.delay(self.external_quiet_period));
+    }
+
     fn redial_delay(&self) -> Duration {
         Duration::from_secs(1) * rand::random::<u32>() / u32::MAX
             + if self.port_reuse {
@@ -685,9 +2140,16 @@ pub fn register_metrics(registry: &Registry) -> Result<()> {
     registry.register(Box::new(DISCOVERED.clone()))?;
     registry.register(Box::new(CONNECTED.clone()))?;
     registry.register(Box::new(CONNECTIONS.clone()))?;
+    registry.register(Box::new(FILE_DESCRIPTORS.clone()))?;
     registry.register(Box::new(LISTENER_ERROR.clone()))?;
     registry.register(Box::new(ADDRESS_REACH_FAILURE.clone()))?;
     registry.register(Box::new(DIAL_FAILURE.clone()))?;
+    registry.register(Box::new(ADDRESS_UNREACHABLE.clone()))?;
+    registry.register(Box::new(ADDRESS_DIAL_FAILURE.clone()))?;
+    registry.register(Box::new(DIAL_SUCCESS.clone()))?;
+    registry.register(Box::new(OVERSIZED_IDENTIFY.clone()))?;
+    registry.register(Box::new(super::serve_queue::SERVE_QUEUE_FULL.clone()))?;
+    registry.register(Box::new(super::ledger::PENDING_BLOCK_BYTES.clone()))?;
     Ok(())
 }
 
@@ -705,6 +2167,17 @@ fn ip_port(m: &Multiaddr) -> Option<(IpAddr, u16)> {
     Some((addr, port))
 }
 
+/// Rewrites the TCP or UDP port of the given address according to the mapping,
+/// returning `None` if the port is not mapped.
+fn map_port(addr: &Multiaddr, map: &FnvHashMap<u16, u16>) -> Option<Multiaddr> {
+    let (idx, mapped) = addr.iter().enumerate().find_map(|(idx, p)| match p {
+        Protocol::Tcp(port) => map.get(&port).map(|p| (idx, Protocol::Tcp(*p))),
+        Protocol::Udp(port) => map.get(&port).map(|p| (idx, Protocol::Udp(*p))),
+        _ => None,
+    })?;
+    addr.replace(idx, |_| Some(mapped))
+}
+
 fn diff_time(former: DateTime<Utc>, latter: DateTime<Utc>) -> Duration {
     latter
         .signed_duration_since(former)
@@ -749,8 +2222,29 @@ impl NetworkBehaviour for AddressBook {
         &mut self,
         _connection_id: libp2p::swarm::ConnectionId,
         _local_addr: &Multiaddr,
-        _remote_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
     ) -> std::result::Result<(), libp2p::swarm::ConnectionDenied> {
+        if let Some(filter) = &self.inbound_filter {
+            if !(filter.0)(remote_addr) {
+                tracing::debug!(addr = %remote_addr, "refusing inbound connection");
+                self.notify(Event::ConnectionRefused(remote_addr.clone()));
+                return Err(libp2p::swarm::ConnectionDenied::new(InboundDenied(
+                    remote_addr.clone(),
+                )));
+            }
+        }
+        if !self.connection_gate.intercept_inbound(remote_addr) {
+            tracing::debug!(addr = %remote_addr, "connection gate denied inbound connection");
+            self.notify(Event::ConnectionGated(
+                None,
+                Some(remote_addr.clone()),
+                GateStage::Inbound,
+            ));
+            return Err(libp2p::swarm::ConnectionDenied::new(InboundDenied(
+                remote_addr.clone(),
+            )));
+        }
+        Ok(())
     }
 
     fn handle_established_inbound_connection(
@@ -764,10 +2258,13 @@ impl NetworkBehaviour for AddressBook {
     fn handle_pending_outbound_connection(
         &mut self,
         _connection_id: libp2p::swarm::ConnectionId,
-        _maybe_peer: Option<PeerId>,
+        maybe_peer: Option<PeerId>,
         _addresses: &[Multiaddr],
         _effective_role: libp2p::core::Endpoint,
     ) -> std::result::Result<Vec<Multiaddr>, libp2p::swarm::ConnectionDenied> {
+        Ok(maybe_peer
+            .map(|peer| self.dial_addresses(&peer))
+            .unwrap_or_default())
     }
     fn handle_established_outbound_connection(
         &mut self,
@@ -787,48 +2284,110 @@ impl NetworkBehaviour for AddressBook {
             self.refresh_external = false;
             *self.external.write() = params.external_addresses().collect();
         }
-        if let Some(action) = self.actions.pop_front() {
-            Poll::Ready(action)
-        } else if !self.deferred.is_empty() {
-            self.deferred.poll_next_unpin(cx).map(|p| p.unwrap())
-        } else {
-            Poll::Pending
+        self.poll_external_stable(cx);
+        self.poll_discovered(cx);
+        self.poll_dial_budgets(cx);
+        self.poll_compact_subscribers(cx);
+        self.poll_prune_observations(cx);
+        self.poll_static_redials(cx);
+        self.poll_deferred(cx);
+        while let Some(action) = self.actions.pop_front() {
+            if !self.dial_gated(&action) {
+                return Poll::Ready(action);
+            }
         }
+        Poll::Pending
     }
 
     fn on_swarm_event(&mut self, event: FromSwarm<Self::ConnectionHandler>) {
         match event {
             FromSwarm::ConnectionEstablished(c) => {
-                let conn = normalize_connected_point(c.endpoint, &self.local_peer_id, &c.peer_id);
+                let conn = normalize_connected_point(
+                    c.endpoint,
+                    &self.local_peer_id,
+                    &c.peer_id,
+                    self.normalize,
+                );
                 let address = conn.get_remote_address();
+                if !self.connection_gate.intercept_peer_id(&c.peer_id, &conn) {
+                    tracing::debug!(addr = %address, "connection gate closed connection");
+                    self.actions.push_back(ToSwarm::CloseConnection {
+                        peer_id: c.peer_id,
+                        connection: CloseConnection::One(c.connection_id),
+                    });
+                    self.refused.insert(c.connection_id);
+                    if conn.is_dialer() {
+                        // the dial is over, even though it got us no connection
+                        self.dial_finished(&c.peer_id);
+                    }
+                    self.notify(Event::ConnectionGated(
+                        Some(c.peer_id),
+                        Some(address.clone()),
+                        GateStage::PeerId,
+                    ));
+                    return;
+                }
                 tracing::debug!(
                     addr = %address,
                     out = conn.is_dialer(),
                     "connection established"
                 );
                 let src = if conn.is_dialer() {
+                    let source = source_label(self.peers.read().get(&c.peer_id), address);
+                    DIAL_SUCCESS.with_label_values(&[&source]).inc();
                     AddressSource::Dial
                 } else {
                     AddressSource::Incoming
                 };
                 self.add_address(&c.peer_id, address.clone(), src);
-                self.peers
-                    .write()
-                    .entry(c.peer_id)
-                    .or_default()
-                    .connections
-                    .insert(address.clone(), (Utc::now(), Direction::from(&conn)));
+                let mut peers = self.peers.write();
+                let info = peers.entry(c.peer_id).or_default();
+                info.connections
+                    .insert(address.clone(), (self.clock.now(), Direction::from(&conn)));
+                info.record_success(self.clock.now());
+                if let ConnectedPoint::Listener { local_addr, .. } = &conn {
+                    info.local_addresses
+                        .insert(address.clone(), without_peer_id(local_addr));
+                }
+                drop(peers);
+                self.redials.reset(&c.peer_id);
+                self.pending_dials.remove(&c.peer_id);
+                self.dial_deadlines.remove(&c.peer_id);
+                self.over_budget.remove(&c.peer_id);
+                self.static_redials.remove(&c.peer_id);
+                self.static_attempts.remove(&c.peer_id);
+                if self.discovered.contains(&c.peer_id) {
+                    // a batched discovery must not be reported after the
+                    // connection to the peer
+                    self.flush_discovered();
+                }
                 if c.other_established == 0 {
                     self.notify(Event::Connected(c.peer_id));
                 }
+                self.conns
+                    .entry(c.peer_id)
+                    .or_default()
+                    .push((c.connection_id, conn.clone()));
                 self.notify(Event::ConnectionEstablished(c.peer_id, conn));
+                self.break_tie(c.peer_id);
+                self.limit_fds();
             }
             FromSwarm::ConnectionClosed(_) => {
                 // handled via external SwarmEvent since that is the only way to get the reason
             }
             FromSwarm::AddressChange(a) => {
-                let old = normalize_connected_point(a.old, &self.local_peer_id, &a.peer_id);
-                let new = normalize_connected_point(a.new, &self.local_peer_id, &a.peer_id);
+                let old = normalize_connected_point(
+                    a.old,
+                    &self.local_peer_id,
+                    &a.peer_id,
+                    self.normalize,
+                );
+                let new = normalize_connected_point(
+                    a.new,
+                    &self.local_peer_id,
+                    &a.peer_id,
+                    self.normalize,
+                );
                 let old_addr = old.get_remote_address();
                 let new_addr = new.get_remote_address();
                 tracing::debug!(
@@ -848,8 +2407,21 @@ impl NetworkBehaviour for AddressBook {
                 entry.connections.remove(old_addr);
                 entry
                     .connections
-                    .insert(new_addr.clone(), (Utc::now(), Direction::from(&new)));
+                    .insert(new_addr.clone(), (self.clock.now(), Direction::from(&new)));
+                entry.local_addresses.remove(old_addr);
+                if let ConnectedPoint::Listener { local_addr, .. } = &new {
+                    entry
+                        .local_addresses
+                        .insert(new_addr.clone(), without_peer_id(local_addr));
+                }
                 drop(peers);
+                if let Some(conns) = self.conns.get_mut(&a.peer_id) {
+                    for (id, cp) in conns.iter_mut() {
+                        if *id == a.connection_id {
+                            *cp = new.clone();
+                        }
+                    }
+                }
 
                 self.notify(Event::AddressChanged(a.peer_id, old, new));
             }
@@ -858,14 +2430,32 @@ impl NetworkBehaviour for AddressBook {
             FromSwarm::NewListener(l) => {
                 tracing::trace!("listener {:?}: created", l.listener_id);
                 LISTENERS.inc();
+                self.open_listeners += 1;
                 self.notify(Event::NewListener(l.listener_id));
+                self.limit_fds();
             }
             FromSwarm::NewListenAddr(l) => {
                 tracing::trace!("listener {:?}: new listen addr {}", l.listener_id, l.addr);
                 if self.listeners.write().insert(l.addr.clone()) {
                     LISTEN_ADDRS.inc();
                 }
-                self.notify(Event::NewListenAddr(l.listener_id, l.addr.clone()));
+                if let Some(address) = map_port(l.addr, &self.external_port_map) {
+                    tracing::debug!("advertising {} for listen addr {}", address, l.addr);
+                    self.actions.push_back(ToSwarm::ReportObservedAddr {
+                        address,
+                        score: AddressScore::Infinite,
+                    });
+                }
+                self.notify(Event::NewListenAddr(
+                    l.listener_id,
+                    l.addr.clone(),
+                    ListenAddrKind::of(l.addr),
+                ));
+                if let Some((port, transport)) = Transport::of(l.addr) {
+                    if port != 0 && self.bound_ports.insert((l.listener_id, port)) {
+                        self.notify(Event::ListenPortBound(l.listener_id, port, transport));
+                    }
+                }
             }
             FromSwarm::ExpiredListenAddr(l) => {
                 tracing::trace!(
@@ -876,13 +2466,18 @@ impl NetworkBehaviour for AddressBook {
                 if self.listeners.write().remove(l.addr) {
                     LISTEN_ADDRS.dec();
                 }
-                self.notify(Event::ExpiredListenAddr(l.listener_id, l.addr.clone()));
+                self.notify(Event::ExpiredListenAddr(
+                    l.listener_id,
+                    l.addr.clone(),
+                    ListenAddrKind::of(l.addr),
+                ));
             }
             FromSwarm::ListenerError(l) => {
                 let err = format!("{:#}", l.err);
                 tracing::trace!("listener {:?}: listener error {}", l.listener_id, err);
                 LISTENER_ERROR.inc();
-                self.notify(Event::ListenerError(l.listener_id, err));
+                let addr = self.listen_requests.get(&l.listener_id).cloned();
+                self.notify(Event::ListenerError(l.listener_id, addr, err));
             }
             FromSwarm::ListenerClosed(l) => {
                 tracing::trace!(
@@ -891,22 +2486,38 @@ impl NetworkBehaviour for AddressBook {
                     l.reason
                 );
                 LISTENERS.dec();
+                self.open_listeners = self.open_listeners.saturating_sub(1);
+                self.listen_requests.remove(&l.listener_id);
+                self.bound_ports.retain(|(id, _)| *id != l.listener_id);
                 self.notify(Event::ListenerClosed(l.listener_id));
             }
             FromSwarm::NewExternalAddr(a) => {
-                self.refresh_external = true;
                 let mut addr = a.addr.clone();
                 normalize_addr(&mut addr, self.local_peer_id());
-                tracing::trace!("new external addr {}", addr);
-                EXTERNAL_ADDRS.inc();
-                self.notify(Event::NewExternalAddr(addr));
+                let confirmed = match self.observations.get(&addr) {
+                    Some(observation) => self
+                        .external_addr_policy
+                        .should_confirm(&addr, observation.peers.len()),
+                    None => true,
+                };
+                if confirmed {
+                    self.confirm_external_addr(addr);
+                } else {
+                    tracing::trace!("unconfirmed external addr {}", addr);
+                    self.unconfirmed.insert(addr);
+                }
             }
             FromSwarm::ExpiredExternalAddr(a) => {
-                self.refresh_external = true;
                 let mut addr = a.addr.clone();
                 normalize_addr(&mut addr, self.local_peer_id());
+                self.observations.remove(&addr);
+                if self.unconfirmed.remove(&addr) {
+                    return;
+                }
+                self.external_addrs_changed();
                 tracing::trace!("expired external addr {}", addr);
                 EXTERNAL_ADDRS.dec();
+                self.external_addrs.retain(|a| a != &addr);
                 self.notify(Event::ExpiredExternalAddr(addr));
             }
         }
@@ -0,0 +1,81 @@
+use fnv::FnvHashMap;
+use libp2p::PeerId;
+use std::time::Duration;
+
+/// Outcome of asking the scheduler for a redial.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Redial {
+    /// dial again after this delay
+    After(Duration),
+    /// a redial of this peer is already queued
+    Pending,
+    /// the maximum number of redials has been reached
+    GiveUp,
+}
+
+/// Schedules redials of peers after failed dial attempts.
+///
+/// Redials of the same peer are spaced with exponential backoff and at most one
+/// is queued at any time; after `max_attempts` redials without an established
+/// connection the peer is given up on.
+#[derive(Debug)]
+pub(crate) struct Redials {
+    backoff: Duration,
+    max_attempts: u32,
+    /// number of redials so far and whether one is currently queued
+    peers: FnvHashMap<PeerId, (u32, bool)>,
+}
+
+impl Redials {
+    pub fn new(backoff: Duration, max_attempts: u32) -> Self {
+        Self {
+            backoff,
+            max_attempts,
+            peers: Default::default(),
+        }
+    }
+
+    /// Registers a failed dial of `peer` that should be retried.
+    pub fn schedule(&mut self, peer: PeerId) -> Redial {
+        let (attempts, pending) = self.peers.entry(peer).or_default();
+        if *pending {
+            return Redial::Pending;
+        }
+        if *attempts >= self.max_attempts {
+            self.peers.remove(&peer);
+            return Redial::GiveUp;
+        }
+        let delay = self
+            .backoff
+            .checked_mul(1 << (*attempts).min(31))
+            .unwrap_or(Duration::MAX);
+        *attempts += 1;
+        *pending = true;
+        Redial::After(delay)
+    }
+
+    /// The queued redial of `peer` has been handed to the swarm.
+    pub fn fired(&mut self, peer: &PeerId) {
+        if let Some((_, pending)) = self.peers.get_mut(peer) {
+            *pending = false;
+        }
+    }
+
+    /// A connection to `peer` has been established, or it is no longer
+    /// redialed, so start over.
+    pub fn reset(&mut self, peer: &PeerId) {
+        self.peers.remove(peer);
+    }
+
+    /// Number of peers with redials so far.
+    #[cfg(test)]
+    pub fn peers(&self) -> usize {
+        self.peers.len()
+    }
+}
+
+impl Default for Redials {
+    fn default() -> Self {
+        Self::new(Duration::from_millis(100), 5)
+    }
+}
@@ -0,0 +1,133 @@
+//! Provider lookups via a delegated routing HTTP endpoint as specified in
+//! IPIP-337, and block retrieval from HTTP gateways.
+use anyhow::{anyhow, Result};
+use libipld::Cid;
+use libp2p::{Multiaddr, PeerId};
+use std::{
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+use url::Url;
+
+/// Time a request may take from connecting to the end of the response.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Maximum size of the status line and headers of a response.
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+/// Maximum size of a provider lookup response.
+const MAX_PROVIDERS_SIZE: usize = 1024 * 1024;
+
+/// Asks the router at `base` for the providers of `cid`.
+///
+/// This blocks until the response has been received, and only plain `http`
+/// URLs are supported.
+pub(crate) fn find_providers(base: &Url, cid: &Cid) -> Result<Vec<(PeerId, Vec<Multiaddr>)>> {
+    let path = format!("/routing/v1/providers/{}", cid);
+    match http_get(base, &path, "application/json", MAX_PROVIDERS_SIZE)? {
+        (200, body) => parse_providers(std::str::from_utf8(&body)?),
+        (404, _) => Ok(vec![]),
+        (status, _) => Err(anyhow!("delegated router responded with status {}", status)),
+    }
+}
+
+/// Sends a GET request for `path` below `base` and returns the status and the
+/// body of the response. Fails if the response takes longer than `TIMEOUT` or
+/// its body is larger than `max_size` bytes.
+pub(crate) fn http_get(
+    base: &Url,
+    path: &str,
+    accept: &str,
+    max_size: usize,
+) -> Result<(u16, Vec<u8>)> {
+    if base.scheme() != "http" {
+        return Err(anyhow!("unsupported url scheme {}", base.scheme()));
+    }
+    let host = base
+        .host_str()
+        .ok_or_else(|| anyhow!("url {} has no host", base))?;
+    let port = base.port_or_known_default().unwrap_or(80);
+    let path = format!("{}{}", base.path().trim_end_matches('/'), path);
+    let deadline = Instant::now() + TIMEOUT;
+    let addr = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow!("host {} not found", host))?;
+    let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    // HTTP/1.0 keeps the server from using chunked transfer encoding
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: {}\r\n\r\n",
+        path, host, accept
+    )?;
+    let mut response = vec![];
+    let mut buf = [0; 8192];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return Err(anyhow!("request to {} timed out", base));
+        }
+        stream.set_read_timeout(Some(remaining))?;
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        response.extend_from_slice(&buf[..n]);
+        if response.len() > MAX_HEAD_SIZE + max_size {
+            return Err(anyhow!("response from {} is too large", base));
+        }
+    }
+    let split = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| anyhow!("malformed response from {}", base))?;
+    let head = std::str::from_utf8(&response[..split])?;
+    let status = head
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| anyhow!("malformed response from {}", base))?;
+    let body = response.split_off(split + 4);
+    if body.len() > max_size {
+        return Err(anyhow!("response from {} is too large", base));
+    }
+    Ok((status, body))
+}
+
+/// Fetches the raw bytes of the block `cid` from the HTTP gateway at `base`,
+/// failing if they are more than `max_size`. The caller must verify that they
+/// match the CID.
+pub(crate) fn fetch_from_gateway(base: &Url, cid: &Cid, max_size: usize) -> Result<Vec<u8>> {
+    let path = format!("/ipfs/{}?format=raw", cid);
+    match http_get(base, &path, "application/vnd.ipld.raw", max_size)? {
+        (200, body) => Ok(body),
+        (status, _) => Err(anyhow!("gateway responded with status {}", status)),
+    }
+}
+
+/// Extracts peer ids and addresses from the `Providers` of a response,
+/// skipping records without a valid peer id.
+pub(crate) fn parse_providers(body: &str) -> Result<Vec<(PeerId, Vec<Multiaddr>)>> {
+    let json: serde_json::Value = serde_json::from_str(body)?;
+    let providers = match json.get("Providers").and_then(|p| p.as_array()) {
+        Some(providers) => providers,
+        None => return Ok(vec![]),
+    };
+    Ok(providers
+        .iter()
+        .filter_map(|provider| {
+            let peer = provider.get("ID")?.as_str()?.parse::<PeerId>().ok()?;
+            let addrs = provider
+                .get("Addrs")
+                .and_then(|addrs| addrs.as_array())
+                .map(|addrs| {
+                    addrs
+                        .iter()
+                        .filter_map(|addr| addr.as_str()?.parse().ok())
+                        .collect()
+                })
+                .unwrap_or_default();
+            Some((peer, addrs))
+        })
+        .collect())
+}
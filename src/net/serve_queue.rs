@@ -0,0 +1,614 @@
+//! Limits the number of bitswap requests of a single peer that wait to be
+//! served.
+//!
+//! Bitswap answers requests in the order they arrive, so a peer sending
+//! thousands of requests delays everyone else's. Requests beyond the limit
+//! are held back before they reach bitswap and handed to it in turns, one
+//! request of each peer at a time, as the earlier ones are answered. Once as
+//! many requests again are held back for a peer, its further ones are
+//! dropped, which the sending peer sees as a failed request.
+//!
+//! The requests and responses passing through are also accounted in the
+//! [`Ledger`] of the peer.
+//!
+//! Outbound requests are held back while the received blocks waiting to be
+//! stored could exceed
+//! [`NetworkConfig::max_pending_block_bytes`](crate::net::NetworkConfig::max_pending_block_bytes).
+use super::ledger::{Ledger, MessageLen, PendingBlocks, RecentWants, Served};
+use crate::variable::Writer;
+use fnv::{FnvHashMap, FnvHashSet};
+use futures::FutureExt;
+use futures_timer::Delay;
+use lazy_static::lazy_static;
+use libipld::{store::StoreParams, Cid};
+use libp2p::{
+    core::{connection::ConnectionId, either::EitherOutput},
+    request_response::{
+        handler::{RequestProtocol, RequestResponseHandlerEvent},
+        RequestId, RequestResponseCodec,
+    },
+    swarm::{
+        derive_prelude::{ConnectionClosed, FromSwarm},
+        ConnectionHandler, IntoConnectionHandler, NetworkBehaviour, NetworkBehaviourAction,
+        NotifyHandler, PollParameters,
+    },
+    Multiaddr, PeerId,
+};
+use libp2p_bitswap::{Bitswap, BitswapEvent};
+use prometheus::IntCounter;
+use std::{
+    collections::VecDeque,
+    hash::Hash,
+    ops::{Deref, DerefMut},
+    task::{Context, Poll},
+    time::Instant,
+};
+
+lazy_static! {
+    pub static ref SERVE_QUEUE_FULL: IntCounter = IntCounter::new(
+        "bitswap_serve_queue_full",
+        "Number of bitswap requests dropped because the peer had too many queued."
+    )
+    .unwrap();
+}
+
+/// What [`ServeQueues::admit`] did with a request.
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) enum Admission<E> {
+    /// the request is to be served now
+    Admitted(E),
+    /// the request is held back until [`ServeQueues::next`] returns it
+    Deferred,
+    /// the peer has too many requests held back already
+    Rejected,
+}
+
+/// The inbound requests of each peer that have not been answered yet, by the
+/// connection they arrived on.
+#[derive(Debug)]
+pub(crate) struct ServeQueues<I, E> {
+    max: Option<usize>,
+    /// requests that are being served
+    queued: FnvHashMap<PeerId, FnvHashSet<(ConnectionId, I)>>,
+    /// requests held back until the earlier ones of the peer are answered
+    deferred: FnvHashMap<PeerId, VecDeque<(ConnectionId, I, E)>>,
+    /// the peers with deferred requests, in the order they get their turn
+    turns: VecDeque<PeerId>,
+}
+
+impl<I: Copy + Eq + Hash, E> ServeQueues<I, E> {
+    pub fn new(max: Option<usize>) -> Self {
+        Self {
+            max,
+            queued: Default::default(),
+            deferred: Default::default(),
+            turns: Default::default(),
+        }
+    }
+
+    fn is_full(&self, peer: &PeerId) -> bool {
+        let queued = self.queued.get(peer).map(|queued| queued.len());
+        self.max
+            .map(|max| queued.unwrap_or_default() >= max)
+            .unwrap_or_default()
+    }
+
+    /// Queues a request, it is deferred if the peer already has `max`
+    /// requests queued and rejected if `max` more are deferred.
+    pub fn admit(&mut self, peer: PeerId, conn: ConnectionId, id: I, event: E) -> Admission<E> {
+        if !self.is_full(&peer) {
+            self.queued.entry(peer).or_default().insert((conn, id));
+            return Admission::Admitted(event);
+        }
+        let deferred = self.deferred.entry(peer).or_default();
+        if self
+            .max
+            .map(|max| deferred.len() >= max)
+            .unwrap_or_default()
+        {
+            return Admission::Rejected;
+        }
+        if deferred.is_empty() {
+            self.turns.push_back(peer);
+        }
+        deferred.push_back((conn, id, event));
+        Admission::Deferred
+    }
+
+    /// Returns the next deferred request that can be served, taking one
+    /// request of each peer in turn.
+    pub fn next(&mut self) -> Option<(PeerId, ConnectionId, E)> {
+        for _ in 0..self.turns.len() {
+            let peer = self.turns.pop_front()?;
+            if self.is_full(&peer) {
+                self.turns.push_back(peer);
+                continue;
+            }
+            let deferred = match self.deferred.get_mut(&peer) {
+                Some(deferred) => deferred,
+                None => continue,
+            };
+            let (conn, id, event) = match deferred.pop_front() {
+                Some(request) => request,
+                None => continue,
+            };
+            if deferred.is_empty() {
+                self.deferred.remove(&peer);
+            } else {
+                self.turns.push_back(peer);
+            }
+            self.queued.entry(peer).or_default().insert((conn, id));
+            return Some((peer, conn, event));
+        }
+        None
+    }
+
+    /// Removes a request that was answered or failed, also while deferred.
+    pub fn done(&mut self, peer: &PeerId, conn: ConnectionId, id: &I) {
+        if let Some(queued) = self.queued.get_mut(peer) {
+            queued.remove(&(conn, *id));
+            if queued.is_empty() {
+                self.queued.remove(peer);
+            }
+        }
+        if let Some(deferred) = self.deferred.get_mut(peer) {
+            deferred.retain(|(c, i, _)| (*c, i) != (conn, id));
+            if deferred.is_empty() {
+                self.deferred.remove(peer);
+                self.turns.retain(|p| p != peer);
+            }
+        }
+    }
+
+    /// Number of requests of all peers that have not been answered yet.
+    pub fn in_flight(&self) -> usize {
+        let queued: usize = self.queued.values().map(|queued| queued.len()).sum();
+        let deferred: usize = self.deferred.values().map(|deferred| deferred.len()).sum();
+        queued + deferred
+    }
+
+    /// Forgets the requests that arrived on a connection that was closed.
+    pub fn remove_connection(&mut self, peer: &PeerId, conn: ConnectionId) {
+        if let Some(queued) = self.queued.get_mut(peer) {
+            queued.retain(|(c, _)| *c != conn);
+            if queued.is_empty() {
+                self.queued.remove(peer);
+            }
+        }
+        if let Some(deferred) = self.deferred.get_mut(peer) {
+            deferred.retain(|(c, _, _)| *c != conn);
+            if deferred.is_empty() {
+                self.deferred.remove(peer);
+                self.turns.retain(|p| p != peer);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub fn queued(&self, peer: &PeerId) -> usize {
+        self.queued
+            .get(peer)
+            .map(|queued| queued.len())
+            .unwrap_or_default()
+    }
+
+    #[cfg(test)]
+    pub fn deferred(&self, peer: &PeerId) -> usize {
+        self.deferred
+            .get(peer)
+            .map(|deferred| deferred.len())
+            .unwrap_or_default()
+    }
+}
+
+enum Inbound {
+    Request(RequestId),
+    Done(RequestId),
+}
+
+/// Connection handler events that start or end an inbound request. The
+/// bitswap handler is a request response handler, which is combined with the
+/// handler of the legacy protocol if the `compat` feature of `libp2p-bitswap`
+/// is enabled. Legacy requests aren't limited.
+trait InboundEvent {
+    fn inbound(&self) -> Option<Inbound>;
+}
+
+impl<C: RequestResponseCodec> InboundEvent for RequestResponseHandlerEvent<C> {
+    fn inbound(&self) -> Option<Inbound> {
+        match self {
+            RequestResponseHandlerEvent::Request { request_id, .. } => {
+                Some(Inbound::Request(*request_id))
+            }
+            RequestResponseHandlerEvent::ResponseSent(id)
+            | RequestResponseHandlerEvent::ResponseOmission(id)
+            | RequestResponseHandlerEvent::InboundTimeout(id) => Some(Inbound::Done(*id)),
+            _ => None,
+        }
+    }
+}
+
+impl<A: InboundEvent, B> InboundEvent for EitherOutput<A, B> {
+    fn inbound(&self) -> Option<Inbound> {
+        match self {
+            EitherOutput::First(event) => event.inbound(),
+            EitherOutput::Second(_) => None,
+        }
+    }
+}
+
+/// The request response part of a connection handler event, to account the
+/// bitswap messages with or without the legacy protocol.
+trait RequestResponseEvent {
+    type Codec: RequestResponseCodec;
+
+    fn request_response(&self) -> Option<&RequestResponseHandlerEvent<Self::Codec>>;
+}
+
+impl<C: RequestResponseCodec> RequestResponseEvent for RequestResponseHandlerEvent<C> {
+    type Codec = C;
+
+    fn request_response(&self) -> Option<&RequestResponseHandlerEvent<C>> {
+        Some(self)
+    }
+}
+
+impl<A: RequestResponseEvent, B> RequestResponseEvent for EitherOutput<A, B> {
+    type Codec = A::Codec;
+
+    fn request_response(&self) -> Option<&RequestResponseHandlerEvent<A::Codec>> {
+        match self {
+            EitherOutput::First(event) => event.request_response(),
+            EitherOutput::Second(_) => None,
+        }
+    }
+}
+
+/// Connection handler commands that send a request. Requests of the legacy
+/// protocol aren't held back.
+trait OutboundRequest {
+    fn is_request(&self) -> bool;
+}
+
+impl<C: RequestResponseCodec> OutboundRequest for RequestProtocol<C> {
+    fn is_request(&self) -> bool {
+        true
+    }
+}
+
+impl<A: OutboundRequest, B> OutboundRequest for EitherOutput<A, B> {
+    fn is_request(&self) -> bool {
+        match self {
+            EitherOutput::First(command) => command.is_request(),
+            EitherOutput::Second(_) => false,
+        }
+    }
+}
+
+type BitswapHandler<P> = <Bitswap<P> as NetworkBehaviour>::ConnectionHandler;
+type BitswapHandlerEvent<P> =
+    <<BitswapHandler<P> as IntoConnectionHandler>::Handler as ConnectionHandler>::OutEvent;
+type BitswapAction<P> = NetworkBehaviourAction<BitswapEvent, BitswapHandler<P>>;
+
+/// Bitswap with a limit on the queued requests of each peer, see
+/// [`NetworkConfig::max_serve_queue_per_peer`](crate::net::NetworkConfig::max_serve_queue_per_peer).
+pub struct FairBitswap<P: StoreParams> {
+    inner: Bitswap<P>,
+    queues: ServeQueues<RequestId, BitswapHandlerEvent<P>>,
+    /// refuse all new requests, see [`Ipfs::drain`](crate::Ipfs::drain)
+    draining: bool,
+    /// refuse new requests while overloaded, see
+    /// [`OverloadPolicy::RejectInbound`](crate::net::OverloadPolicy::RejectInbound)
+    overloaded: bool,
+    /// the blocks requested by peers that are being served
+    served: Served,
+    block_requests: FnvHashMap<(PeerId, RequestId), Cid>,
+    ledgers: Writer<FnvHashMap<PeerId, Ledger>>,
+    wants: Writer<RecentWants>,
+    max_pending: Option<usize>,
+    /// the received blocks bitswap hasn't stored yet
+    pending: PendingBlocks,
+    /// the outbound requests of each connection waiting for their response
+    requests: FnvHashMap<ConnectionId, usize>,
+    /// outbound requests held back while too many bytes may be pending
+    held: VecDeque<BitswapAction<P>>,
+    /// wakes the task once the oldest pending block expires
+    expiry: Option<Delay>,
+}
+
+impl<P: StoreParams> FairBitswap<P> {
+    /// `served` must be shared with the store `inner` serves blocks from,
+    /// see [`ServedStore`](super::ledger::ServedStore), and `pending` with
+    /// the store it inserts the received blocks into.
+    pub fn new(
+        inner: Bitswap<P>,
+        max_queued: Option<usize>,
+        served: Served,
+        ledgers: Writer<FnvHashMap<PeerId, Ledger>>,
+        wants: Writer<RecentWants>,
+        max_pending: Option<usize>,
+        pending: PendingBlocks,
+    ) -> Self {
+        Self {
+            inner,
+            queues: ServeQueues::new(max_queued),
+            draining: false,
+            overloaded: false,
+            served,
+            block_requests: Default::default(),
+            ledgers,
+            wants,
+            max_pending,
+            pending,
+            requests: Default::default(),
+            held: Default::default(),
+            expiry: None,
+        }
+    }
+
+    /// Stops accepting requests, the queued ones are still answered.
+    pub fn drain(&mut self) {
+        self.draining = true;
+    }
+
+    /// Refuses new requests until called with `false`.
+    pub fn set_overloaded(&mut self, overloaded: bool) {
+        self.overloaded = overloaded;
+    }
+
+    /// Number of requests that have not been answered yet.
+    pub fn in_flight(&self) -> usize {
+        self.queues.in_flight()
+    }
+
+    /// Hands an admitted event of a connection to bitswap.
+    fn serve(&mut self, peer: PeerId, conn: ConnectionId, event: BitswapHandlerEvent<P>) {
+        self.account(peer, conn, &event);
+        self.inner.on_connection_handler_event(peer, conn, event)
+    }
+
+    /// Whether another request may be sent. Until its response arrives,
+    /// every request in flight counts as a block of `MAX_BLOCK_SIZE` bytes,
+    /// so the blocks waiting to be stored stay within `max_pending`. A single
+    /// request is always sent once nothing is pending.
+    fn may_request(&mut self, cx: &mut Context) -> bool {
+        let max = match self.max_pending {
+            Some(max) => max,
+            None => return true,
+        };
+        // registered first so that a block stored meanwhile wakes the task
+        self.pending.register(cx.waker());
+        let (pending, expiry) = self.pending.bytes(Instant::now());
+        let in_flight: usize = self.requests.values().sum();
+        if (pending == 0 && in_flight == 0) || pending + (in_flight + 1) * P::MAX_BLOCK_SIZE <= max
+        {
+            return true;
+        }
+        if let Some(expiry) = expiry {
+            let mut delay = Delay::new(expiry.saturating_duration_since(Instant::now()));
+            if delay.poll_unpin(cx).is_ready() {
+                cx.waker().wake_by_ref();
+            }
+            self.expiry = Some(delay);
+        }
+        false
+    }
+
+    /// Counts a request that is handed to a connection handler.
+    fn sent(&mut self, action: &BitswapAction<P>) {
+        if let NetworkBehaviourAction::NotifyHandler {
+            handler: NotifyHandler::One(conn),
+            ..
+        } = action
+        {
+            *self.requests.entry(*conn).or_default() += 1;
+        }
+    }
+
+    /// Counts a response or failure of a request sent on `conn`.
+    fn answered(&mut self, conn: ConnectionId) {
+        if let Some(requests) = self.requests.get_mut(&conn) {
+            *requests = requests.saturating_sub(1);
+            if *requests == 0 {
+                self.requests.remove(&conn);
+            }
+        }
+    }
+
+    /// Updates the ledger of `peer` with an admitted event of its connection.
+    fn account(&mut self, peer: PeerId, conn: ConnectionId, event: &BitswapHandlerEvent<P>) {
+        let event = match event.request_response() {
+            Some(event) => event,
+            None => return,
+        };
+        match event {
+            RequestResponseHandlerEvent::Request {
+                request_id,
+                request,
+                ..
+            } => {
+                self.wants.write().push(request.cid, peer);
+                let mut msg = MessageLen::default();
+                request.write_to(&mut msg).ok();
+                if msg.ty == Some(MessageLen::BLOCK) {
+                    self.served.requested(request.cid);
+                    self.block_requests.insert((peer, *request_id), request.cid);
+                }
+            }
+            RequestResponseHandlerEvent::Response { response, .. } => {
+                self.answered(conn);
+                let mut msg = MessageLen::default();
+                response.write_to(&mut msg).ok();
+                if msg.ty == Some(MessageLen::BLOCK) {
+                    self.pending.received(msg.len - 1);
+                    let mut ledgers = self.ledgers.write();
+                    let ledger = ledgers.entry(peer).or_default();
+                    ledger.blocks_received += 1;
+                    ledger.bytes_received += msg.len as u64 - 1;
+                }
+            }
+            RequestResponseHandlerEvent::OutboundTimeout(_)
+            | RequestResponseHandlerEvent::OutboundUnsupportedProtocols(_) => {
+                self.answered(conn);
+            }
+            RequestResponseHandlerEvent::ResponseSent(id) => {
+                let len = self
+                    .block_requests
+                    .remove(&(peer, *id))
+                    .and_then(|cid| self.served.done(&cid));
+                if let Some(len) = len {
+                    let mut ledgers = self.ledgers.write();
+                    let ledger = ledgers.entry(peer).or_default();
+                    ledger.blocks_sent += 1;
+                    ledger.bytes_sent += len as u64;
+                }
+            }
+            RequestResponseHandlerEvent::ResponseOmission(id)
+            | RequestResponseHandlerEvent::InboundTimeout(id) => {
+                if let Some(cid) = self.block_requests.remove(&(peer, *id)) {
+                    self.served.done(&cid);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<P: StoreParams> Deref for FairBitswap<P> {
+    type Target = Bitswap<P>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<P: StoreParams> DerefMut for FairBitswap<P> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+impl<P: StoreParams> NetworkBehaviour for FairBitswap<P> {
+    type ConnectionHandler = BitswapHandler<P>;
+    type OutEvent = BitswapEvent;
+
+    fn new_handler(&mut self) -> Self::ConnectionHandler {
+        self.inner.new_handler()
+    }
+
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.inner.addresses_of_peer(peer_id)
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm<Self::ConnectionHandler>) {
+        if let FromSwarm::ConnectionClosed(ConnectionClosed {
+            peer_id,
+            connection_id,
+            ..
+        }) = &event
+        {
+            self.queues.remove_connection(peer_id, *connection_id);
+            self.requests.remove(connection_id);
+            // bitswap fails the requests of the connection itself
+            self.held.retain(|action| {
+                !matches!(
+                    action,
+                    NetworkBehaviourAction::NotifyHandler {
+                        handler: NotifyHandler::One(conn),
+                        ..
+                    } if conn == connection_id
+                )
+            });
+        }
+        if let FromSwarm::ConnectionClosed(ConnectionClosed {
+            peer_id,
+            remaining_established: 0,
+            ..
+        }) = &event
+        {
+            let served = &self.served;
+            self.block_requests.retain(|(peer, _), cid| {
+                if peer == peer_id {
+                    served.done(cid);
+                }
+                peer != peer_id
+            });
+            self.ledgers.write().remove(peer_id);
+            self.wants.write().remove_peer(peer_id);
+        }
+        self.inner.on_swarm_event(event)
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        conn: ConnectionId,
+        event: <<Self::ConnectionHandler as IntoConnectionHandler>::Handler as ConnectionHandler>::OutEvent,
+    ) {
+        let event = match event.inbound() {
+            Some(Inbound::Request(_)) if self.draining => {
+                tracing::debug!(peer = %peer_id, "draining, dropping bitswap request");
+                return;
+            }
+            Some(Inbound::Request(_)) if self.overloaded => {
+                tracing::debug!(peer = %peer_id, "overloaded, dropping bitswap request");
+                return;
+            }
+            Some(Inbound::Request(id)) => match self.queues.admit(peer_id, conn, id, event) {
+                Admission::Admitted(event) => event,
+                Admission::Deferred => {
+                    tracing::trace!(peer = %peer_id, "bitswap serve queue full, deferring request");
+                    return;
+                }
+                Admission::Rejected => {
+                    // dropping the event drops the response channel, which
+                    // fails the request
+                    tracing::debug!(peer = %peer_id, "bitswap serve queue full, dropping request");
+                    SERVE_QUEUE_FULL.inc();
+                    return;
+                }
+            },
+            Some(Inbound::Done(id)) => {
+                self.queues.done(&peer_id, conn, &id);
+                event
+            }
+            None => event,
+        };
+        self.serve(peer_id, conn, event)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context,
+        params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<Self::OutEvent, Self::ConnectionHandler>> {
+        while let Some((peer, conn, event)) = self.queues.next() {
+            self.serve(peer, conn, event);
+        }
+        if !self.held.is_empty() && self.may_request(cx) {
+            if let Some(action) = self.held.pop_front() {
+                self.sent(&action);
+                return Poll::Ready(action);
+            }
+        }
+        loop {
+            let action = match self.inner.poll(cx, params) {
+                Poll::Ready(action) => action,
+                Poll::Pending => return Poll::Pending,
+            };
+            let is_request = matches!(
+                &action,
+                NetworkBehaviourAction::NotifyHandler { event, .. } if event.is_request()
+            );
+            if is_request {
+                if !self.held.is_empty() || !self.may_request(cx) {
+                    tracing::trace!("too many received blocks pending, holding back request");
+                    self.held.push_back(action);
+                    continue;
+                }
+                self.sent(&action);
+            }
+            return Poll::Ready(action);
+        }
+    }
+}
@@ -1,5 +1,14 @@
-use super::{address_handler::IntoAddressHandler, *};
-use crate::net::{peer_info::ConnectionFailureKind, peers::AddressBook};
+use super::{
+    address_handler::IntoAddressHandler,
+    redial::{Redial, Redials},
+    *,
+};
+use crate::net::{
+    ledger::{PendingBlocks, PENDING_BLOCK_EXPIRY},
+    peer_info::ConnectionFailureKind,
+    peers::{AddressBook, AddressBookConfig},
+    serve_queue::{Admission, ServeQueues},
+};
 use async_executor::LocalExecutor;
 use futures::{future::ready, stream::StreamExt};
 use libp2p::{
@@ -8,11 +17,15 @@ use libp2p::{
     identity::ed25519::Keypair,
     multiaddr::Protocol,
     swarm::{
-        derive_prelude::{ConnectionEstablished as CE, FromSwarm},
-        DialError, NetworkBehaviour, NetworkBehaviourAction,
+        derive_prelude::{
+            AddressChange as AC, ConnectionEstablished as CE, FromSwarm, ListenerError as LE,
+            NewExternalAddr as NEA, NewListenAddr as NLA,
+        },
+        CloseConnection, DialError, NetworkBehaviour, NetworkBehaviourAction,
     },
     TransportError,
 };
+use prometheus::IntCounterVec;
 use std::{cell::RefCell, collections::HashMap, io::ErrorKind};
 use tracing_subscriber::EnvFilter;
 use Event::*;
@@ -47,19 +60,11 @@ fn test_dial_basic() {
         .try_init()
         .ok();
 
-    let mut book = AddressBook::new(
-        PeerId::random(),
-        false,
-        false,
-        false,
-        Writer::new(HashSet::default()),
-        Writer::new(HashMap::default()),
-        Writer::new(vec![]),
-    );
+    let mut book = AddressBook::new(Default::default());
 
     let events = Default::default();
     let (tx, rx) = mpsc::unbounded();
-    book.swarm_events(tx);
+    book.swarm_events(tx).unwrap();
     let events = Events::new(SwarmEvents::new(rx), &events);
 
     let peer_a = PeerId::random();
@@ -219,18 +224,13 @@ fn from_docker_host() {
     let addr_a_1: Multiaddr = "/ip4/10.0.0.2/tcp/4001".parse().unwrap();
     let addr_a_1p = addr_a_1.clone().with(Protocol::P2p(peer_a.into()));
 
-    let mut book = AddressBook::new(
-        peer_a,
-        false,
-        false,
-        false,
-        Writer::new(HashSet::default()),
-        Writer::new(HashMap::default()),
-        Writer::new(vec![]),
-    );
+    let mut book = AddressBook::new(AddressBookConfig {
+        local_peer_id: peer_a,
+        ..Default::default()
+    });
     let events = Default::default();
     let (tx, rx) = mpsc::unbounded();
-    book.swarm_events(tx);
+    book.swarm_events(tx).unwrap();
     let events = Events::new(SwarmEvents::new(rx), &events);
 
     let key_b = libp2p::identity::PublicKey::Ed25519(Keypair::generate().public());
@@ -355,18 +355,13 @@ fn from_docker_container() {
     let addr_a_1: Multiaddr = "/ip4/10.0.0.2/tcp/4001".parse().unwrap();
     let addr_a_1p = addr_a_1.clone().with(Protocol::P2p(peer_a.into()));
 
-    let mut book = AddressBook::new(
-        peer_a,
-        false,
-        false,
-        false,
-        Writer::new(HashSet::default()),
-        Writer::new(HashMap::default()),
-        Writer::new(vec![]),
-    );
+    let mut book = AddressBook::new(AddressBookConfig {
+        local_peer_id: peer_a,
+        ..Default::default()
+    });
     let events = Default::default();
     let (tx, rx) = mpsc::unbounded();
-    book.swarm_events(tx);
+    book.swarm_events(tx).unwrap();
     let events = Events::new(SwarmEvents::new(rx), &events);
 
     let key_b = libp2p::identity::PublicKey::Ed25519(Keypair::generate().public());
@@ -550,6 +545,669 @@ fn from_docker_container() {
     );
 }
 
+#[test]
+fn listen_addr_kind() {
+    let mut book = AddressBook::new(Default::default());
+    let events = Default::default();
+    let (tx, rx) = mpsc::unbounded();
+    book.swarm_events(tx).unwrap();
+    let events = Events::new(SwarmEvents::new(rx), &events);
+
+    let relay = PeerId::random();
+    let direct: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+    let public: Multiaddr = "/ip4/1.2.3.4/tcp/4001".parse().unwrap();
+    let circuit = public
+        .clone()
+        .with(Protocol::P2p(relay.into()))
+        .with(Protocol::P2pCircuit);
+    let id = ListenerId::new();
+    for addr in [&direct, &circuit, &public] {
+        book.on_swarm_event(FromSwarm::NewListenAddr(NLA {
+            listener_id: id,
+            addr,
+        }));
+    }
+    assert_eq!(
+        events.next(),
+        vec![
+            NewListenAddr(id, direct, ListenAddrKind::Direct),
+            NewListenAddr(id, circuit, ListenAddrKind::Relay),
+            NewListenAddr(id, public, ListenAddrKind::External),
+        ]
+    );
+}
+
+#[test]
+fn external_port_map() {
+    let mut book = AddressBook::new(AddressBookConfig {
+        external_port_map: std::iter::once((4001, 40001)).collect(),
+        ..Default::default()
+    });
+    let id = ListenerId::new();
+    let mapped: Multiaddr = "/ip4/10.0.0.2/tcp/4001".parse().unwrap();
+    let unmapped: Multiaddr = "/ip4/10.0.0.2/tcp/4002".parse().unwrap();
+    for addr in [&mapped, &unmapped] {
+        book.on_swarm_event(FromSwarm::NewListenAddr(NLA {
+            listener_id: id,
+            addr,
+        }));
+    }
+    let reported = book
+        .actions
+        .drain(..)
+        .filter_map(|a| match a {
+            NetworkBehaviourAction::ReportObservedAddr { address, score } => {
+                assert_eq!(score, AddressScore::Infinite);
+                Some(address)
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        reported,
+        vec!["/ip4/10.0.0.2/tcp/40001".parse::<Multiaddr>().unwrap()]
+    );
+}
+
+#[test]
+fn listener_error_addr() {
+    let mut book = AddressBook::new(Default::default());
+    let events = Default::default();
+    let (tx, rx) = mpsc::unbounded();
+    book.swarm_events(tx).unwrap();
+    let events = Events::new(SwarmEvents::new(rx), &events);
+
+    let v4 = ListenerId::new();
+    let v4_addr: Multiaddr = "/ip4/0.0.0.0/tcp/4001".parse().unwrap();
+    let v6 = ListenerId::new();
+    let v6_addr: Multiaddr = "/ip6/::/tcp/4001".parse().unwrap();
+    book.listening_on(v4, v4_addr);
+    book.listening_on(v6, v6_addr.clone());
+    let bound: Multiaddr = "/ip4/10.0.0.2/tcp/4001".parse().unwrap();
+    book.on_swarm_event(FromSwarm::NewListenAddr(NLA {
+        listener_id: v4,
+        addr: &bound,
+    }));
+    let err = std::io::Error::new(ErrorKind::AddrNotAvailable, "no ipv6");
+    book.on_swarm_event(FromSwarm::ListenerError(LE {
+        listener_id: v6,
+        err: &err,
+    }));
+    book.on_swarm_event(FromSwarm::ListenerError(LE {
+        listener_id: ListenerId::new(),
+        err: &err,
+    }));
+    let events = events.next();
+    assert_eq!(events[0], NewListenAddr(v4, bound, ListenAddrKind::Direct));
+    assert!(
+        matches!(&events[1], ListenerError(id, Some(addr), _) if *id == v6 && *addr == v6_addr)
+    );
+    assert!(matches!(&events[2], ListenerError(_, None, _)));
+}
+
+#[test]
+fn swarm_events_for_peers() {
+    let mut book = AddressBook::new(Default::default());
+    let peer_a = PeerId::random();
+    let peer_b = PeerId::random();
+    let peer_c = PeerId::random();
+    let events = Default::default();
+    let tracked = [peer_a, peer_b].iter().copied().collect();
+    let events = Events::new(
+        book.swarm_events_for_peers(tracked, false).unwrap(),
+        &events,
+    );
+
+    let addr: Multiaddr = "/ip4/1.1.1.1/tcp/3333".parse().unwrap();
+    for peer in [&peer_a, &peer_b, &peer_c] {
+        book.add_address(peer, addr.clone(), AddressSource::Mdns);
+    }
+    book.on_swarm_event(FromSwarm::NewListenAddr(NLA {
+        listener_id: ListenerId::new(),
+        addr: &addr,
+    }));
+    assert_eq!(events.next(), vec![NewInfo(peer_a), NewInfo(peer_b)]);
+}
+
+#[test]
+fn max_event_subscribers() {
+    let mut book = AddressBook::new(AddressBookConfig {
+        max_subscribers: Some(2),
+        ..Default::default()
+    });
+    let clock = MockClock::default();
+    book.set_clock(std::sync::Arc::new(clock.clone()));
+    let (tx1, rx1) = mpsc::unbounded();
+    let (tx2, _rx2) = mpsc::unbounded();
+    let (tx3, rx3) = mpsc::unbounded();
+    book.swarm_events(tx1).unwrap();
+    book.swarm_events(tx2).unwrap();
+    let err = book.swarm_events(tx3.clone()).unwrap_err();
+    assert_eq!(err.0, 2);
+    assert!(book
+        .swarm_events_for_peers(Default::default(), true)
+        .is_err());
+
+    // a dropped subscriber makes room for a new one
+    drop(rx1);
+    book.swarm_events(tx3).unwrap();
+    assert_eq!(book.subscriber_count(), 2);
+
+    // closed subscribers are dropped periodically even without events
+    drop(rx3);
+    let waker = futures::task::noop_waker();
+    let mut cx = std::task::Context::from_waker(&waker);
+    book.poll_compact_subscribers(&mut cx);
+    assert_eq!(book.subscriber_count(), 2);
+    clock.advance(Duration::from_secs(60));
+    book.poll_compact_subscribers(&mut cx);
+    assert_eq!(book.subscriber_count(), 1);
+}
+
+#[test]
+fn local_address_for() {
+    let peer_a = PeerId::random();
+    let mut book = AddressBook::new(AddressBookConfig {
+        local_peer_id: peer_a,
+        enable_loopback: true,
+        ..Default::default()
+    });
+    let listener: Multiaddr = "/ip4/10.0.0.2/tcp/4001".parse().unwrap();
+    book.on_swarm_event(FromSwarm::NewListenAddr(NLA {
+        listener_id: ListenerId::new(),
+        addr: &listener,
+    }));
+
+    let peer_b = PeerId::random();
+    let remote = "/ip4/10.0.0.10/tcp/57634"
+        .parse::<Multiaddr>()
+        .unwrap()
+        .with(Protocol::P2p(peer_b.into()));
+    assert_eq!(book.local_address_for(&peer_b), None);
+    let cp = ConnectedPoint::Listener {
+        local_addr: listener.clone(),
+        send_back_addr: remote.clone(),
+    };
+    book.on_swarm_event(FromSwarm::ConnectionEstablished(CE {
+        peer_id: peer_b,
+        connection_id: ConnectionId::new(1),
+        endpoint: &cp,
+        failed_addresses: &[],
+        other_established: 0,
+    }));
+    assert_eq!(book.local_address_for(&peer_b), Some(listener));
+
+    book.connection_closed(peer_b, ConnectionId::new(1), cp, 0, None);
+    assert_eq!(book.local_address_for(&peer_b), None);
+}
+
+#[test]
+fn redial_backoff() {
+    let ms = std::time::Duration::from_millis;
+    let mut redials = Redials::new(ms(100), 3);
+    let peer = PeerId::random();
+
+    assert_eq!(redials.schedule(peer), Redial::After(ms(100)));
+    // only one redial is queued at a time
+    assert_eq!(redials.schedule(peer), Redial::Pending);
+    redials.fired(&peer);
+    assert_eq!(redials.schedule(peer), Redial::After(ms(200)));
+    redials.fired(&peer);
+    assert_eq!(redials.schedule(peer), Redial::After(ms(400)));
+    redials.fired(&peer);
+    assert_eq!(redials.schedule(peer), Redial::GiveUp);
+
+    // a new failure after giving up starts over, as does a connection
+    assert_eq!(redials.schedule(peer), Redial::After(ms(100)));
+    redials.fired(&peer);
+    redials.reset(&peer);
+    assert_eq!(redials.schedule(peer), Redial::After(ms(100)));
+}
+
+/// Appends a protobuf field, `value` is a varint for wire type 0.
+fn proto_field(buf: &mut Vec<u8>, field: u64, wire_type: u64, value: &[u8]) {
+    fn varint(buf: &mut Vec<u8>, mut n: u64) {
+        while n >= 0x80 {
+            buf.push(n as u8 | 0x80);
+            n >>= 7;
+        }
+        buf.push(n as u8);
+    }
+    varint(buf, field << 3 | wire_type);
+    if wire_type == 2 {
+        varint(buf, value.len() as u64);
+    }
+    buf.extend_from_slice(value);
+}
+
+fn identify_message() -> (Vec<u8>, libp2p::identity::PublicKey) {
+    let key = libp2p::identity::Keypair::generate_ed25519().public();
+    let addr: Multiaddr = "/ip4/1.2.3.4/tcp/4001".parse().unwrap();
+    let mut buf = vec![];
+    proto_field(&mut buf, 5, 2, b"ipfs/0.1.0");
+    proto_field(&mut buf, 6, 2, b"test");
+    proto_field(&mut buf, 1, 2, &key.to_protobuf_encoding());
+    proto_field(&mut buf, 2, 2, &addr.to_vec());
+    proto_field(&mut buf, 3, 2, b"/ipfs/id/1.0.0");
+    proto_field(&mut buf, 4, 2, &addr.to_vec());
+    (buf, key)
+}
+
+#[test]
+fn identify_decode_unknown_fields() {
+    use super::identify_request::decode_info;
+    let (mut buf, key) = identify_message();
+    // fields of later versions of the message are skipped
+    proto_field(&mut buf, 7, 2, b"signed peer record");
+    proto_field(&mut buf, 9, 0, &[0x96, 0x01]);
+    proto_field(&mut buf, 10, 1, &[0; 8]);
+    proto_field(&mut buf, 11, 5, &[0; 4]);
+    let info = decode_info(&buf).unwrap();
+    let addr: Multiaddr = "/ip4/1.2.3.4/tcp/4001".parse().unwrap();
+    assert_eq!(info.public_key, key);
+    assert_eq!(info.protocol_version, "ipfs/0.1.0");
+    assert_eq!(info.agent_version, "test");
+    assert_eq!(info.listen_addrs, vec![addr.clone()]);
+    assert_eq!(info.protocols, vec!["/ipfs/id/1.0.0".to_owned()]);
+    assert_eq!(info.observed_addr, addr);
+
+    // but wire types that can't be skipped are rejected
+    let (mut buf, _) = identify_message();
+    proto_field(&mut buf, 12, 3, &[]);
+    assert!(decode_info(&buf).is_err());
+}
+
+#[test]
+fn identify_decode_truncated() {
+    use super::identify_request::decode_info;
+    let key = libp2p::identity::Keypair::generate_ed25519().public();
+    let mut buf = vec![];
+    proto_field(&mut buf, 1, 2, &key.to_protobuf_encoding());
+    // every prefix lacks the public key, which is required
+    for len in 0..buf.len() {
+        let err = decode_info(&buf[..len]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData, "length {}", len);
+    }
+    assert_eq!(decode_info(&buf).unwrap().public_key, key);
+    // a field that claims more bytes than follow
+    let mut buf = vec![];
+    proto_field(&mut buf, 6, 2, b"agent");
+    buf.truncate(buf.len() - 1);
+    assert!(decode_info(&buf).is_err());
+    // a varint cut short
+    assert!(decode_info(&[0x0a, 0x80]).is_err());
+}
+
+#[test]
+fn identify_decode_oversized() {
+    use super::identify_request::{decode_info, IdentifyCodec, IdentifyProtocol};
+    use libp2p::{core::upgrade::write_length_prefixed, request_response::RequestResponseCodec};
+    // a length that doesn't fit the message or even the address space
+    let mut buf = vec![6 << 3 | 2];
+    buf.extend_from_slice(&[0xff; 9]);
+    buf.push(0x01);
+    assert!(decode_info(&buf).is_err());
+    // a varint of more than 64 bits
+    assert!(decode_info(&[0x80; 11]).is_err());
+
+    // messages above the identify size limit are not read
+    let (mut msg, _) = identify_message();
+    proto_field(&mut msg, 7, 2, &[0; 5000]);
+    let mut io = vec![];
+    futures::executor::block_on(write_length_prefixed(&mut io, &msg)).unwrap();
+    let res = futures::executor::block_on(
+        IdentifyCodec.read_response(&IdentifyProtocol, &mut futures::io::Cursor::new(io)),
+    );
+    assert!(res.is_err());
+}
+
+#[test]
+fn upnp_maps_lan_addresses() {
+    use super::upnp::is_mappable;
+    let mappable = |addr: &str| is_mappable(&addr.parse().unwrap());
+    assert!(mappable("192.168.1.10:4001"));
+    assert!(mappable("10.0.0.10:4001"));
+    assert!(!mappable("127.0.0.1:4001"));
+    assert!(!mappable("169.254.1.1:4001"));
+    assert!(!mappable("0.0.0.0:4001"));
+    assert!(!mappable("[fe80::1]:4001"));
+}
+
+/// The error a dial fails with when both sides dialed each other at once.
+fn sim_open_error() -> TransportError<std::io::Error> {
+    use libp2p::{
+        core::{either::EitherError, transport::timeout::TransportTimeoutError, upgrade},
+        dns::DnsErr,
+        noise::NoiseError,
+    };
+    let noise = std::io::Error::new(ErrorKind::InvalidData, "both sides initiated");
+    let error: super::TransportError = TransportTimeoutError::Other(EitherError::A(
+        EitherError::B(upgrade::UpgradeError::Apply(NoiseError::Io(noise))),
+    ));
+    let error = DnsErr::Transport(std::io::Error::new(ErrorKind::Other, error));
+    TransportError::Other(std::io::Error::new(ErrorKind::Other, error))
+}
+
+#[test]
+fn redial_after_sim_open() {
+    let mut book = AddressBook::new(Default::default());
+    let clock = MockClock::default();
+    book.set_clock(std::sync::Arc::new(clock.clone()));
+    let waker = futures::task::noop_waker();
+    let mut cx = std::task::Context::from_waker(&waker);
+    let peer = PeerId::random();
+    let addr: Multiaddr = "/ip4/10.0.0.10/tcp/4001".parse().unwrap();
+    let addr_p = addr.clone().with(Protocol::P2p(peer.into()));
+    book.add_address(&peer, addr, AddressSource::User);
+    dials(&mut book);
+    let sim_open = |book: &mut AddressBook| {
+        book.dial_failure(
+            IntoAddressHandler(None, false),
+            Some(peer),
+            &DialError::Transport(vec![(addr_p.clone(), sim_open_error())]),
+        )
+    };
+
+    // the address is redialed once the backoff has passed
+    sim_open(&mut book);
+    book.poll_deferred(&mut cx);
+    assert_eq!(dials(&mut book), vec![]);
+    clock.advance(Duration::from_secs(2));
+    book.poll_deferred(&mut cx);
+    assert_eq!(dials(&mut book), vec![Dial::A(addr_p.clone())]);
+    assert_eq!(book.redialed_peers(), 1);
+
+    // a redial failing for another reason ends the redials
+    let error = std::io::Error::new(ErrorKind::TimedOut, "timeout");
+    book.dial_failure(
+        IntoAddressHandler(Some((addr_p.clone(), 3)), false),
+        Some(peer),
+        &DialError::ConnectionIo(error),
+    );
+    assert_eq!(book.redialed_peers(), 0);
+
+    // as do a disconnect and a ban
+    sim_open(&mut book);
+    assert_eq!(book.redialed_peers(), 1);
+    let cp = ConnectedPoint::Dialer {
+        address: addr_p.clone(),
+        role_override: Endpoint::Dialer,
+    };
+    book.connection_closed(peer, ConnectionId::new(1), cp, 0, None);
+    assert_eq!(book.redialed_peers(), 0);
+    sim_open(&mut book);
+    assert_eq!(book.redialed_peers(), 1);
+    book.remove_static_peer(&peer);
+    assert_eq!(book.redialed_peers(), 0);
+}
+
+#[test]
+fn external_addresses_stable() {
+    let peer = PeerId::random();
+    let mut book = AddressBook::new(AddressBookConfig {
+        local_peer_id: peer,
+        external_quiet_period: Duration::from_millis(50),
+        ..Default::default()
+    });
+    let clock = MockClock::default();
+    book.set_clock(std::sync::Arc::new(clock.clone()));
+
+    let events = Default::default();
+    let (tx, rx) = mpsc::unbounded();
+    book.swarm_events(tx).unwrap();
+    let events = Events::new(SwarmEvents::new(rx), &events);
+    let stable = |events: Vec<Event>| {
+        events
+            .into_iter()
+            .filter_map(|e| match e {
+                ExternalAddressesStable(addrs) => Some(addrs),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+    let waker = futures::task::noop_waker();
+    let mut cx = std::task::Context::from_waker(&waker);
+
+    let addr_1: Multiaddr = "/ip4/1.1.1.1/tcp/4001".parse().unwrap();
+    let addr_2: Multiaddr = "/ip4/2.2.2.2/tcp/4001".parse().unwrap();
+    book.on_swarm_event(FromSwarm::NewExternalAddr(NEA { addr: &addr_1 }));
+    clock.advance(Duration::from_millis(20));
+    book.poll_external_stable(&mut cx);
+    book.on_swarm_event(FromSwarm::NewExternalAddr(NEA { addr: &addr_2 }));
+    // the quiet period starts over with the second address
+    clock.advance(Duration::from_millis(40));
+    book.poll_external_stable(&mut cx);
+    assert_eq!(stable(events.next()), Vec::<Vec<Multiaddr>>::new());
+
+    clock.advance(Duration::from_millis(10));
+    book.poll_external_stable(&mut cx);
+    book.poll_external_stable(&mut cx);
+    let addr_1p = addr_1.with(Protocol::P2p(peer.into()));
+    let addr_2p = addr_2.with(Protocol::P2p(peer.into()));
+    assert_eq!(stable(events.next()), vec![vec![addr_1p, addr_2p]]);
+}
+
+#[test]
+fn external_addr_threshold() {
+    let local = PeerId::random();
+    let mut book = AddressBook::new(AddressBookConfig {
+        local_peer_id: local,
+        ..Default::default()
+    });
+    book.set_external_addr_policy(std::sync::Arc::new(ConfirmAfter(2)));
+    let events = Default::default();
+    let (tx, rx) = mpsc::unbounded();
+    book.swarm_events(tx).unwrap();
+    let events = Events::new(SwarmEvents::new(rx), &events);
+    let confirmed = |events: Vec<Event>| {
+        events
+            .into_iter()
+            .filter_map(|e| match e {
+                NewExternalAddr(addr) => Some(addr),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+    let addr: Multiaddr = "/ip4/1.1.1.1/tcp/4001".parse().unwrap();
+    let observe = |book: &mut AddressBook| {
+        let key = libp2p::identity::PublicKey::Ed25519(Keypair::generate().public());
+        let info = identify::Info {
+            public_key: key.clone(),
+            protocol_version: "my protocol".to_owned(),
+            agent_version: "my agent".to_owned(),
+            listen_addrs: vec![],
+            protocols: vec![],
+            observed_addr: addr.clone(),
+        };
+        let peer = PeerId::from(&key);
+        book.set_info(&peer, info.clone());
+        (peer, info)
+    };
+
+    let (peer_a, info_a) = observe(&mut book);
+    book.on_swarm_event(FromSwarm::NewExternalAddr(NEA { addr: &addr }));
+    assert_eq!(confirmed(events.next()), Vec::<Multiaddr>::new());
+    // the same peer observing the address again doesn't count
+    book.set_info(&peer_a, info_a);
+    assert_eq!(confirmed(events.next()), Vec::<Multiaddr>::new());
+
+    observe(&mut book);
+    assert_eq!(
+        confirmed(events.next()),
+        vec![addr.with(Protocol::P2p(local.into()))]
+    );
+}
+
+#[test]
+fn external_addr_observations_bounded() {
+    let mut book = AddressBook::new(AddressBookConfig {
+        local_peer_id: PeerId::random(),
+        ..Default::default()
+    });
+    let clock = MockClock::default();
+    book.set_clock(std::sync::Arc::new(clock.clone()));
+    book.set_external_addr_policy(std::sync::Arc::new(ConfirmAfter(2)));
+    let events = Default::default();
+    let (tx, rx) = mpsc::unbounded();
+    book.swarm_events(tx).unwrap();
+    let events = Events::new(SwarmEvents::new(rx), &events);
+    let confirmed = |events: Vec<Event>| {
+        events
+            .into_iter()
+            .filter(|e| matches!(e, NewExternalAddr(_)))
+            .count()
+    };
+    let observe = |book: &mut AddressBook, addr: &Multiaddr| {
+        let key = libp2p::identity::PublicKey::Ed25519(Keypair::generate().public());
+        let info = identify::Info {
+            public_key: key.clone(),
+            protocol_version: "my protocol".to_owned(),
+            agent_version: "my agent".to_owned(),
+            listen_addrs: vec![],
+            protocols: vec![],
+            observed_addr: addr.clone(),
+        };
+        book.set_info(&PeerId::from(&key), info);
+    };
+    let addr = |n: u16| -> Multiaddr { format!("/ip4/1.1.1.1/tcp/{}", n).parse().unwrap() };
+
+    observe(&mut book, &addr(0));
+    book.on_swarm_event(FromSwarm::NewExternalAddr(NEA { addr: &addr(0) }));
+    assert_eq!(confirmed(events.next()), 0);
+
+    // only so many addresses are remembered, the oldest are forgotten first
+    for n in 1..=100 {
+        clock.advance(Duration::from_secs(1));
+        observe(&mut book, &addr(n));
+    }
+    assert_eq!(book.observed_addrs(), 64);
+    observe(&mut book, &addr(0));
+    assert_eq!(confirmed(events.next()), 0);
+    observe(&mut book, &addr(0));
+    assert_eq!(confirmed(events.next()), 1);
+
+    // observations of unconfirmed addresses expire
+    book.on_swarm_event(FromSwarm::NewExternalAddr(NEA { addr: &addr(100) }));
+    assert_eq!(confirmed(events.next()), 0);
+    let waker = futures::task::noop_waker();
+    let mut cx = std::task::Context::from_waker(&waker);
+    book.poll_prune_observations(&mut cx);
+    assert_eq!(book.observed_addrs(), 64);
+    clock.advance(Duration::from_secs(11 * 60));
+    book.poll_prune_observations(&mut cx);
+    assert_eq!(book.observed_addrs(), 1);
+    observe(&mut book, &addr(100));
+    assert_eq!(confirmed(events.next()), 0);
+    observe(&mut book, &addr(100));
+    assert_eq!(confirmed(events.next()), 1);
+}
+
+#[test]
+fn prune_stale_peers() {
+    let peers = Writer::new(HashMap::default());
+    let mut book = AddressBook::new(AddressBookConfig {
+        peers: peers.clone(),
+        ..Default::default()
+    });
+    let events = Default::default();
+    let (tx, rx) = mpsc::unbounded();
+    book.swarm_events(tx).unwrap();
+    let events = Events::new(SwarmEvents::new(rx), &events);
+
+    let stale = PeerId::random();
+    let mixed = PeerId::random();
+    let fresh = PeerId::random();
+    let connected = PeerId::random();
+    let addr = |n: u8| -> Multiaddr { format!("/ip4/1.1.1.{}/tcp/4001", n).parse().unwrap() };
+    book.add_address(&stale, addr(1), AddressSource::User);
+    book.add_address(&mixed, addr(2), AddressSource::User);
+    book.add_address(&mixed, addr(3), AddressSource::User);
+    book.add_address(&fresh, addr(4), AddressSource::User);
+    book.add_address(&connected, addr(5), AddressSource::User);
+    book.on_swarm_event(FromSwarm::ConnectionEstablished(CE {
+        peer_id: connected,
+        connection_id: ConnectionId::new(1),
+        endpoint: &ConnectedPoint::Dialer {
+            address: addr(5).with(Protocol::P2p(connected.into())),
+            role_override: Endpoint::Dialer,
+        },
+        failed_addresses: &[],
+        other_established: 0,
+    }));
+
+    let age = |peer: &PeerId, addr: Multiaddr, secs: i64| {
+        let mut peers = peers.write();
+        let info = peers.get_mut(peer).unwrap();
+        let addr = addr.with(Protocol::P2p((*peer).into()));
+        info.addresses.get_mut(&addr).unwrap().1 = Utc::now() - chrono::Duration::seconds(secs);
+    };
+    age(&stale, addr(1), 7200);
+    age(&mixed, addr(2), 7200);
+    age(&mixed, addr(3), 60);
+    age(&fresh, addr(4), 60);
+    age(&connected, addr(5), 7200);
+    events.next();
+
+    book.prune_stale_peers(Duration::from_secs(3600));
+    assert!(book.info(&stale).is_none());
+    assert!(book.info(&mixed).is_some());
+    assert!(book.info(&fresh).is_some());
+    assert!(book.info(&connected).is_some());
+    assert_eq!(events.next(), vec![Unreachable(stale), NewInfo(stale)]);
+}
+
+#[test]
+fn unnormalized_address() {
+    let mut book = AddressBook::new(AddressBookConfig {
+        enable_loopback: true,
+        normalize: |addr| !addr.iter().any(|p| matches!(p, Protocol::Memory(_))),
+        ..Default::default()
+    });
+    let peer = PeerId::random();
+    let memory: Multiaddr = "/memory/1234".parse().unwrap();
+    let tcp: Multiaddr = "/ip4/1.1.1.1/tcp/4001".parse().unwrap();
+    book.add_address(&peer, memory.clone(), AddressSource::User);
+    book.add_address(&peer, tcp.clone(), AddressSource::User);
+    let addrs = addrs(&book, peer);
+    assert_eq!(addrs.len(), 2);
+    assert!(addrs.contains(&(memory.clone(), AddressSource::User)));
+    assert!(addrs.contains(&(tcp.with(Protocol::P2p(peer.into())), AddressSource::User)));
+
+    book.remove_address(&peer, &memory);
+    assert_eq!(book.info(&peer).unwrap().addresses().count(), 1);
+}
+
+#[test]
+fn webtransport_address() {
+    let mut book = AddressBook::new(AddressBookConfig {
+        enable_loopback: true,
+        ..Default::default()
+    });
+    let peer = PeerId::random();
+    let addr: Multiaddr = "/ip4/1.1.1.1/udp/4001/quic-v1/webtransport\
+        /certhash/uEiAkH5a4DPGKUuOBjYw0CgwjvcJCJMD2K_1aluKR_tpevQ\
+        /certhash/uEiB8XzF3Tgqc5e3AfDFxmRtFGTL1bleJ69AHsyedwWSoyQ"
+        .parse()
+        .unwrap();
+    book.add_address(&peer, addr.clone(), AddressSource::User);
+    // the peer id goes after the certhashes, which are kept as they are
+    let normalized = addr.clone().with(Protocol::P2p(peer.into()));
+    assert_eq!(
+        addrs(&book, peer),
+        vec![(normalized.clone(), AddressSource::User)]
+    );
+    assert_eq!(
+        normalized
+            .iter()
+            .filter(|p| matches!(p, Protocol::Certhash(_)))
+            .count(),
+        2
+    );
+    // adding the normalized address again doesn't duplicate it
+    book.add_address(&peer, normalized, AddressSource::User);
+    assert_eq!(book.info(&peer).unwrap().addresses().count(), 1);
+}
+
 fn addrs(book: &AddressBook, peer_id: PeerId) -> Vec<(Multiaddr, AddressSource)> {
     let mut v = book
         .info(&peer_id)
@@ -586,8 +1244,1416 @@ fn dials(book: &mut AddressBook) -> Vec<Dial> {
     for d in &mut v {
         match d {
             Dial::A(_) => {}
-            Dial::P(peer, addrs) => addrs.extend(book.addresses_of_peer(peer)),
+            Dial::P(peer, addrs) => addrs.extend(book.dial_addresses(peer)),
         }
     }
     v
 }
+
+#[test]
+fn load_saved_peers() {
+    let new_book = || {
+        AddressBook::new(AddressBookConfig {
+            local_peer_id: PeerId::random(),
+            ..Default::default()
+        })
+    };
+    let key = libp2p::identity::PublicKey::Ed25519(Keypair::generate().public());
+    let peer = PeerId::from(&key);
+    let addr: Multiaddr = "/ip4/1.1.1.1/tcp/4001".parse().unwrap();
+    let mut book = new_book();
+    book.add_address(&peer, addr.clone(), AddressSource::User);
+    book.set_info(
+        &peer,
+        identify::Info {
+            public_key: key.clone(),
+            protocol_version: "my protocol".to_owned(),
+            agent_version: "my agent".to_owned(),
+            listen_addrs: vec![addr.clone()],
+            protocols: vec!["/ipfs/bitswap/1.2.0".to_owned()],
+            observed_addr: addr.clone(),
+        },
+    );
+    let saved = book.save_peers();
+
+    let mut book = new_book();
+    let events = Default::default();
+    let (tx, rx) = mpsc::unbounded();
+    book.swarm_events(tx).unwrap();
+    let events = Events::new(SwarmEvents::new(rx), &events);
+    book.load_peers(peer_info::load_peers(&saved).unwrap());
+    assert_eq!(events.next(), vec![Discovered(peer), NewInfo(peer)]);
+    let info = book.info(&peer).unwrap();
+    assert!(info.is_stale());
+    assert_eq!(
+        info.protocols().collect::<Vec<_>>(),
+        vec!["/ipfs/bitswap/1.2.0"]
+    );
+    assert_eq!(info.agent_version(), Some("my agent"));
+    assert_eq!(
+        addrs(&book, peer),
+        vec![(
+            addr.clone().with(Protocol::P2p(peer.into())),
+            AddressSource::User
+        )]
+    );
+
+    book.set_info(
+        &peer,
+        identify::Info {
+            public_key: key,
+            protocol_version: "my protocol".to_owned(),
+            agent_version: "my agent".to_owned(),
+            listen_addrs: vec![addr.clone()],
+            protocols: vec![
+                "/ipfs/bitswap/1.2.0".to_owned(),
+                "/ipfs/kad/1.0.0".to_owned(),
+            ],
+            observed_addr: addr,
+        },
+    );
+    let info = book.info(&peer).unwrap();
+    assert!(!info.is_stale());
+    assert_eq!(info.protocols().count(), 2);
+}
+
+#[test]
+fn load_saved_peers_filters_addresses() {
+    let peer = PeerId::random();
+    let loopback: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+    let public: Multiaddr = "/ip4/1.1.1.1/tcp/4001".parse().unwrap();
+    let mut book = AddressBook::new(AddressBookConfig {
+        local_peer_id: PeerId::random(),
+        enable_loopback: true,
+        ..Default::default()
+    });
+    book.add_address(&peer, loopback, AddressSource::User);
+    book.add_address(&peer, public.clone(), AddressSource::Kad);
+    assert_eq!(addrs(&book, peer).len(), 2);
+    let saved = book.save_peers();
+
+    // loopback addresses are ignored by this book, also when loaded
+    let mut book = AddressBook::new(AddressBookConfig {
+        local_peer_id: PeerId::random(),
+        ..Default::default()
+    });
+    book.load_peers(peer_info::load_peers(&saved).unwrap());
+    assert_eq!(
+        addrs(&book, peer),
+        vec![(public.with(Protocol::P2p(peer.into())), AddressSource::Kad)]
+    );
+}
+
+#[test]
+fn oversized_identify() {
+    let mut book = AddressBook::new(Default::default());
+    let key = libp2p::identity::PublicKey::Ed25519(Keypair::generate().public());
+    let peer = PeerId::from(&key);
+    let addr: Multiaddr = "/ip4/1.1.1.1/tcp/4001".parse().unwrap();
+    book.add_address(&peer, addr.clone(), AddressSource::User);
+    let truncated = super::peers::OVERSIZED_IDENTIFY.get();
+    book.set_info(
+        &peer,
+        identify::Info {
+            public_key: key,
+            protocol_version: "v".repeat(100_000),
+            agent_version: "é".repeat(100_000),
+            listen_addrs: vec![addr.clone(); 10_000],
+            protocols: (0..100_000).map(|i| format!("/proto/{}", i)).collect(),
+            observed_addr: addr,
+        },
+    );
+    let info = book.info(&peer).unwrap();
+    assert_eq!(info.protocols().count(), 128);
+    assert_eq!(info.protocols().next(), Some("/proto/0"));
+    assert_eq!(info.protocol_version().unwrap().len(), 256);
+    assert!(info.agent_version().unwrap().len() <= 256);
+    assert_eq!(info.listen_addresses().count(), 64);
+    assert!(super::peers::OVERSIZED_IDENTIFY.get() > truncated);
+}
+
+#[test]
+fn interactions_use_clock() {
+    let mut book = AddressBook::new(AddressBookConfig {
+        local_peer_id: PeerId::random(),
+        ..Default::default()
+    });
+    let clock = MockClock::default();
+    book.set_clock(std::sync::Arc::new(clock.clone()));
+    let peer = PeerId::random();
+    let addr: Multiaddr = "/ip4/1.1.1.1/tcp/4001".parse().unwrap();
+    book.add_address(&peer, addr.clone(), AddressSource::User);
+    book.set_rtt(&peer, Some(Duration::from_millis(10)));
+    let measured = clock.now();
+
+    clock.advance(Duration::from_secs(60));
+    let error = std::io::Error::new(ErrorKind::Other, "my error");
+    book.dial_failure(
+        IntoAddressHandler(Some((addr, 3)), false),
+        Some(peer),
+        &DialError::ConnectionIo(error),
+    );
+    let info = book.info(&peer).unwrap();
+    assert_eq!(info.reputation().last_interaction(), Some(clock.now()));
+    assert_eq!(info.recent_failures().next().unwrap().time(), clock.now());
+    assert_eq!(info.full_rtt().unwrap().updated(), measured);
+
+    clock.advance(Duration::from_secs(60));
+    book.set_rtt(&peer, None);
+    let rtt = book.info(&peer).unwrap().full_rtt().unwrap();
+    assert_eq!(rtt.failures(), 1);
+    assert_eq!(rtt.updated(), clock.now());
+}
+
+#[test]
+fn reputation_survives_restart() {
+    let new_book = || {
+        AddressBook::new(AddressBookConfig {
+            local_peer_id: PeerId::random(),
+            ..Default::default()
+        })
+    };
+    let failing = PeerId::random();
+    let unknown = PeerId::random();
+    let addr: Multiaddr = "/ip4/1.1.1.1/tcp/4001".parse().unwrap();
+    let mut book = new_book();
+    book.add_address(&failing, addr.clone(), AddressSource::User);
+    book.add_address(&unknown, addr.clone(), AddressSource::User);
+    for _ in 0..2 {
+        let error = std::io::Error::new(ErrorKind::Other, "my error");
+        book.dial_failure(
+            IntoAddressHandler(Some((addr.clone(), 3)), false),
+            Some(failing),
+            &DialError::ConnectionIo(error),
+        );
+    }
+    let reputation = book.info(&failing).unwrap().reputation();
+    assert_eq!(reputation.failures(), 2);
+    assert!(reputation.is_bad());
+    let saved = book.save_peers();
+
+    let mut book = new_book();
+    book.load_peers(peer_info::load_peers(&saved).unwrap());
+    assert_eq!(book.info(&failing).unwrap().reputation(), reputation);
+    assert!(!book.info(&unknown).unwrap().reputation().is_bad());
+    let order = crate::sync::order_providers(
+        &[failing, unknown],
+        crate::ProviderStrategy::LowestRtt,
+        0,
+        |peer| book.info(peer),
+    );
+    assert_eq!(order, vec![unknown, failing]);
+}
+
+/// Establishes an outbound and an inbound connection from `local` to `remote`
+/// in the given order and returns whether the outbound one survives.
+fn outbound_survives(local: PeerId, remote: PeerId, outbound_first: bool) -> bool {
+    let mut book = AddressBook::new(AddressBookConfig {
+        local_peer_id: local,
+        tie_break: true,
+        ..Default::default()
+    });
+    let events = Default::default();
+    let (tx, rx) = mpsc::unbounded();
+    book.swarm_events(tx).unwrap();
+    let events = Events::new(SwarmEvents::new(rx), &events);
+
+    let outbound = ConnectedPoint::Dialer {
+        address: "/ip4/1.1.1.1/tcp/4001"
+            .parse::<Multiaddr>()
+            .unwrap()
+            .with(Protocol::P2p(remote.into())),
+        role_override: Endpoint::Dialer,
+    };
+    let inbound = ConnectedPoint::Listener {
+        local_addr: "/ip4/2.2.2.2/tcp/4001".parse().unwrap(),
+        send_back_addr: "/ip4/1.1.1.1/tcp/53000".parse().unwrap(),
+    };
+    let mut conns = vec![
+        (ConnectionId::new(1), &outbound),
+        (ConnectionId::new(2), &inbound),
+    ];
+    if !outbound_first {
+        conns.reverse();
+    }
+    for (other_established, (connection_id, endpoint)) in conns.into_iter().enumerate() {
+        book.on_swarm_event(FromSwarm::ConnectionEstablished(CE {
+            peer_id: remote,
+            connection_id,
+            endpoint,
+            failed_addresses: &[],
+            other_established,
+        }));
+    }
+
+    let closed = book
+        .actions
+        .drain(..)
+        .filter_map(|a| match a {
+            NetworkBehaviourAction::CloseConnection {
+                peer_id,
+                connection: CloseConnection::One(id),
+            } => {
+                assert_eq!(peer_id, remote);
+                Some(id)
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let closed_events = events
+        .next()
+        .into_iter()
+        .filter(|e| matches!(e, ConnectionClosed(..)))
+        .count();
+    assert_eq!(closed_events, 1);
+    match closed[..] {
+        [id] if id == ConnectionId::new(2) => true,
+        [id] if id == ConnectionId::new(1) => false,
+        _ => panic!("expected one closed connection, got {:?}", closed),
+    }
+}
+
+#[test]
+fn tie_break_simultaneous_connect() {
+    let a = PeerId::random();
+    let b = PeerId::random();
+    let (small, large) = if a.to_bytes() < b.to_bytes() {
+        (a, b)
+    } else {
+        (b, a)
+    };
+    for outbound_first in [true, false] {
+        // on both sides the connection dialed by the smaller peer survives
+        assert!(outbound_survives(small, large, outbound_first));
+        assert!(!outbound_survives(large, small, outbound_first));
+    }
+}
+
+#[test]
+fn peer_id_mismatch() {
+    let mut book = AddressBook::new(Default::default());
+    let events = Default::default();
+    let (tx, rx) = mpsc::unbounded();
+    book.swarm_events(tx).unwrap();
+    let events = Events::new(SwarmEvents::new(rx), &events);
+
+    let expected = PeerId::random();
+    let presented = PeerId::random();
+    let addr: Multiaddr = "/ip4/1.1.1.1/tcp/3333".parse().unwrap();
+    let addr_p = addr.clone().with(Protocol::P2p(expected.into()));
+    book.add_address(&expected, addr.clone(), AddressSource::User);
+    events.next();
+    let endpoint = ConnectedPoint::Dialer {
+        address: addr_p.clone(),
+        role_override: Endpoint::Dialer,
+    };
+    let mismatches = |events: Vec<Event>| {
+        events
+            .into_iter()
+            .filter(|e| matches!(e, PeerIdMismatch(..)))
+            .collect::<Vec<_>>()
+    };
+
+    // validation dial
+    book.dial_failure(
+        IntoAddressHandler(Some((addr_p.clone(), 3)), false),
+        Some(expected),
+        &DialError::WrongPeerId {
+            obtained: presented,
+            endpoint: endpoint.clone(),
+        },
+    );
+    assert_eq!(
+        mismatches(events.next()),
+        vec![PeerIdMismatch(expected, presented, addr_p.clone())]
+    );
+    // the address answered for someone else, so it is removed
+    assert_eq!(addrs(&book, expected), vec![]);
+
+    // any other dial
+    book.dial_failure(
+        IntoAddressHandler(None, false),
+        Some(expected),
+        &DialError::WrongPeerId {
+            obtained: presented,
+            endpoint,
+        },
+    );
+    assert_eq!(
+        mismatches(events.next()),
+        vec![PeerIdMismatch(expected, presented, addr_p)]
+    );
+}
+
+#[test]
+fn mdns_interface_filter() {
+    let mut book = AddressBook::new(AddressBookConfig {
+        mdns_filter: Some(vec!["192.168.1.0/24".parse().unwrap()]),
+        ..Default::default()
+    });
+    let peer = PeerId::random();
+    let addr = |s: &str| s.parse::<Multiaddr>().unwrap();
+    let p2p = |a: Multiaddr| a.with(Protocol::P2p(peer.into()));
+    book.add_address(
+        &peer,
+        addr("/ip4/192.168.1.7/tcp/4001"),
+        AddressSource::Mdns,
+    );
+    book.add_address(&peer, addr("/ip4/10.0.0.7/tcp/4001"), AddressSource::Mdns);
+    book.add_address(
+        &peer,
+        addr("/ip4/192.168.1.8/tcp/4001"),
+        AddressSource::Mdns,
+    );
+    // other sources are not filtered
+    book.add_address(&peer, addr("/ip4/10.0.0.8/tcp/4001"), AddressSource::User);
+    assert_eq!(
+        addrs(&book, peer),
+        vec![
+            (p2p(addr("/ip4/10.0.0.8/tcp/4001")), AddressSource::User),
+            (p2p(addr("/ip4/192.168.1.7/tcp/4001")), AddressSource::Mdns),
+            (p2p(addr("/ip4/192.168.1.8/tcp/4001")), AddressSource::Mdns),
+        ]
+    );
+}
+
+#[test]
+fn inbound_endpoint() {
+    let local_peer = PeerId::random();
+    let events = Default::default();
+    let (tx, rx) = mpsc::unbounded();
+    let mut book = AddressBook::new(AddressBookConfig {
+        local_peer_id: local_peer,
+        ..Default::default()
+    });
+    book.swarm_events(tx).unwrap();
+    let events = Events::new(SwarmEvents::new(rx), &events);
+
+    let peer = PeerId::random();
+    let local: Multiaddr = "/ip4/10.0.0.2/tcp/4001".parse().unwrap();
+    let remote: Multiaddr = "/ip4/10.0.0.10/tcp/57634".parse().unwrap();
+    let cp = ConnectedPoint::Listener {
+        local_addr: local.clone(),
+        send_back_addr: remote.clone(),
+    };
+    book.on_swarm_event(FromSwarm::ConnectionEstablished(CE {
+        peer_id: peer,
+        connection_id: ConnectionId::new(1),
+        endpoint: &cp,
+        failed_addresses: &[],
+        other_established: 0,
+    }));
+    let endpoints = events
+        .next()
+        .iter()
+        .filter_map(Event::endpoint)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        endpoints,
+        vec![peer_info::Endpoint {
+            addr: remote.with(Protocol::P2p(peer.into())),
+            direction: Direction::Inbound,
+            local_addr: Some(local.with(Protocol::P2p(local_peer.into()))),
+        }]
+    );
+}
+
+#[test]
+fn discovery_batch() {
+    let mut book = AddressBook::new(AddressBookConfig {
+        discovery_batch: Some(Duration::from_millis(50)),
+        ..Default::default()
+    });
+    let clock = MockClock::default();
+    book.set_clock(std::sync::Arc::new(clock.clone()));
+    let events = Default::default();
+    let (tx, rx) = mpsc::unbounded();
+    book.swarm_events(tx).unwrap();
+    let events = Events::new(SwarmEvents::new(rx), &events);
+    let discovered = |events: Vec<Event>| {
+        events
+            .into_iter()
+            .filter_map(|e| match e {
+                Discovered(peer) => Some(vec![peer]),
+                DiscoveredBatch(peers) => Some(peers),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+    let waker = futures::task::noop_waker();
+    let mut cx = std::task::Context::from_waker(&waker);
+    let addr: Multiaddr = "/ip4/10.0.0.10/tcp/4001".parse().unwrap();
+
+    let peers = (0..20).map(|_| PeerId::random()).collect::<Vec<_>>();
+    for peer in &peers[..10] {
+        book.add_address(peer, addr.clone(), AddressSource::User);
+        book.poll_discovered(&mut cx);
+    }
+    assert_eq!(discovered(events.next()), Vec::<Vec<PeerId>>::new());
+
+    // the interval is jittered by at most a quarter
+    clock.advance(Duration::from_millis(100));
+    book.poll_discovered(&mut cx);
+    for peer in &peers[10..] {
+        book.add_address(peer, addr.clone(), AddressSource::User);
+        book.poll_discovered(&mut cx);
+    }
+    assert_eq!(discovered(events.next()), vec![peers[..10].to_vec()]);
+
+    clock.advance(Duration::from_millis(100));
+    book.poll_discovered(&mut cx);
+    assert_eq!(discovered(events.next()), vec![peers[10..].to_vec()]);
+}
+
+#[test]
+fn first_connection_event_order() {
+    for batch in [None, Some(Duration::from_secs(10))] {
+        let mut book = AddressBook::new(AddressBookConfig {
+            local_peer_id: PeerId::random(),
+            discovery_batch: batch,
+            ..Default::default()
+        });
+        let peer = PeerId::random();
+        let all = Default::default();
+        let (tx, rx) = mpsc::unbounded();
+        book.swarm_events(tx).unwrap();
+        let all = Events::new(SwarmEvents::new(rx), &all);
+        let filtered = Default::default();
+        let rx = book
+            .swarm_events_for_peers(std::iter::once(peer).collect(), true)
+            .unwrap();
+        let filtered = Events::new(rx, &filtered);
+
+        book.on_swarm_event(FromSwarm::ConnectionEstablished(CE {
+            peer_id: peer,
+            connection_id: ConnectionId::new(1),
+            endpoint: &ConnectedPoint::Dialer {
+                address: "/ip4/10.0.0.10/tcp/4001".parse().unwrap(),
+                role_override: Endpoint::Dialer,
+            },
+            failed_addresses: &[],
+            other_established: 0,
+        }));
+        for events in [all.next(), filtered.next()] {
+            let discovered = match batch {
+                None => Discovered(peer),
+                Some(_) => DiscoveredBatch(vec![peer]),
+            };
+            assert_eq!(events.len(), 4, "{:?}", events);
+            assert_eq!(events[..3], [discovered, NewInfo(peer), Connected(peer)]);
+            assert!(matches!(&events[3], ConnectionEstablished(p, _) if *p == peer));
+        }
+    }
+}
+
+#[test]
+fn pending_dials() {
+    let mut book = AddressBook::new(Default::default());
+    let peer_a = PeerId::random();
+    let peer_b = PeerId::random();
+    let addr: Multiaddr = "/ip4/10.0.0.10/tcp/4001".parse().unwrap();
+    book.dial(&peer_a);
+    book.dial_address(&peer_b, addr.clone());
+    let mut pending = book.pending_dials().copied().collect::<Vec<_>>();
+    pending.sort();
+    let mut expected = vec![peer_a, peer_b];
+    expected.sort();
+    assert_eq!(pending, expected);
+
+    book.on_swarm_event(FromSwarm::ConnectionEstablished(CE {
+        peer_id: peer_a,
+        connection_id: ConnectionId::new(1),
+        endpoint: &ConnectedPoint::Dialer {
+            address: addr.clone(),
+            role_override: Endpoint::Dialer,
+        },
+        failed_addresses: &[],
+        other_established: 0,
+    }));
+    assert_eq!(book.pending_dials().collect::<Vec<_>>(), vec![&peer_b]);
+
+    let error = std::io::Error::new(ErrorKind::Other, "my error");
+    book.dial_failure(
+        IntoAddressHandler(None, false),
+        Some(peer_b),
+        &DialError::Transport(vec![(addr.clone(), TransportError::Other(error))]),
+    );
+    assert_eq!(book.pending_dials().next(), None);
+
+    // a dial that is gated or whose connection is gated is over as well
+    #[derive(Debug)]
+    struct RejectAll;
+
+    impl ConnectionGate for RejectAll {
+        fn intercept_dial(&self, _: Option<&PeerId>, _: Option<&Multiaddr>) -> bool {
+            false
+        }
+
+        fn intercept_peer_id(&self, _: &PeerId, _: &ConnectedPoint) -> bool {
+            false
+        }
+    }
+
+    book.set_connection_gate(std::sync::Arc::new(RejectAll));
+    book.actions.clear();
+    book.dial(&peer_b);
+    book.dial(&peer_b);
+    let dial = book.actions.pop_front().unwrap();
+    assert!(book.dial_gated(&dial));
+    assert_eq!(book.pending_dials().collect::<Vec<_>>(), vec![&peer_b]);
+    book.on_swarm_event(FromSwarm::ConnectionEstablished(CE {
+        peer_id: peer_b,
+        connection_id: ConnectionId::new(2),
+        endpoint: &ConnectedPoint::Dialer {
+            address: addr,
+            role_override: Endpoint::Dialer,
+        },
+        failed_addresses: &[],
+        other_established: 0,
+    }));
+    assert_eq!(book.pending_dials().next(), None);
+}
+
+#[test]
+fn mock_clock_address_expiry() {
+    let mut book = AddressBook::new(Default::default());
+    let clock = MockClock::default();
+    book.set_clock(std::sync::Arc::new(clock.clone()));
+    let events = Default::default();
+    let (tx, rx) = mpsc::unbounded();
+    book.swarm_events(tx).unwrap();
+    let events = Events::new(SwarmEvents::new(rx), &events);
+    let max_age = Duration::from_secs(3600);
+
+    let old = PeerId::random();
+    let new = PeerId::random();
+    let addr: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+    book.add_address(&old, addr.clone(), AddressSource::User);
+    clock.advance(Duration::from_secs(1800));
+    book.add_address(&new, addr, AddressSource::User);
+    book.prune_stale_peers(max_age);
+    assert!(book.info(&old).is_some());
+    events.next();
+
+    // the first address is now older than the maximum age, the second isn't
+    clock.advance(Duration::from_secs(2400));
+    book.prune_stale_peers(max_age);
+    assert!(book.info(&old).is_none());
+    assert!(book.info(&new).is_some());
+    assert_eq!(events.next(), vec![Unreachable(old), NewInfo(old)]);
+}
+
+#[test]
+fn dial_budget() {
+    let budget = Duration::from_millis(200);
+    let mut book = AddressBook::new(AddressBookConfig {
+        dial_budget: Some(budget),
+        ..Default::default()
+    });
+    let clock = MockClock::default();
+    book.set_clock(std::sync::Arc::new(clock.clone()));
+    let events = Default::default();
+    let (tx, rx) = mpsc::unbounded();
+    book.swarm_events(tx).unwrap();
+    let events = Events::new(SwarmEvents::new(rx), &events);
+    let waker = futures::task::noop_waker();
+    let mut cx = std::task::Context::from_waker(&waker);
+
+    let peer = PeerId::random();
+    let addrs = (1..=3)
+        .map(|i| format!("/ip4/10.0.0.{}/tcp/4001", i).parse().unwrap())
+        .collect::<Vec<Multiaddr>>();
+    for addr in &addrs {
+        book.add_address(&peer, addr.clone(), AddressSource::User);
+    }
+    events.next();
+
+    // none of the slow addresses answers before the budget is used up
+    book.dial(&peer);
+    book.poll_dial_budgets(&mut cx);
+    assert_eq!(events.next(), vec![]);
+    clock.advance(budget - Duration::from_millis(1));
+    book.poll_dial_budgets(&mut cx);
+    assert_eq!(events.next(), vec![]);
+    clock.advance(Duration::from_millis(1));
+    book.poll_dial_budgets(&mut cx);
+    assert_eq!(events.next(), vec![Unreachable(peer)]);
+    assert_eq!(book.pending_dials().next(), None);
+
+    // the dials failing later on don't make the peer unreachable again
+    let errors = addrs
+        .iter()
+        .map(|addr| {
+            let error = std::io::Error::new(ErrorKind::TimedOut, "timeout");
+            (addr.clone(), TransportError::Other(error))
+        })
+        .collect();
+    book.dial_failure(
+        IntoAddressHandler(None, false),
+        Some(peer),
+        &DialError::Transport(errors),
+    );
+    let events = events.next();
+    assert_eq!(events.len(), 4);
+    assert!(events
+        .iter()
+        .all(|e| matches!(e, DialFailure(..) | NewInfo(_))));
+}
+
+#[test]
+fn no_dial_to_own_address() {
+    let mut book = AddressBook::new(Default::default());
+    // a listener on 0.0.0.0 reports the concrete addresses it is bound to
+    let own: Multiaddr = "/ip4/10.0.0.2/tcp/4001".parse().unwrap();
+    let later: Multiaddr = "/ip4/10.0.0.3/tcp/4001".parse().unwrap();
+    let id = ListenerId::new();
+    book.on_swarm_event(FromSwarm::NewListenAddr(NLA {
+        listener_id: id,
+        addr: &own,
+    }));
+
+    let peer_a = PeerId::random();
+    let peer_b = PeerId::random();
+    let other: Multiaddr = "/ip4/10.0.0.10/tcp/4001".parse().unwrap();
+    book.add_address(&peer_a, own.clone(), AddressSource::Kad);
+    book.dial_address(&peer_a, own.clone());
+    book.add_address(&peer_b, later.clone(), AddressSource::User);
+    book.add_address(&peer_b, other.clone(), AddressSource::User);
+    // the interface shows up after its address was handed out as peer_b's
+    book.on_swarm_event(FromSwarm::NewListenAddr(NLA {
+        listener_id: id,
+        addr: &later,
+    }));
+    book.dial(&peer_b);
+    assert_eq!(
+        dials(&mut book),
+        vec![Dial::P(
+            peer_b,
+            vec![other.with(Protocol::P2p(peer_b.into()))]
+        )]
+    );
+}
+
+#[test]
+fn inbound_filter() {
+    let local_peer = PeerId::random();
+    let mut book = AddressBook::new(AddressBookConfig {
+        local_peer_id: local_peer,
+        ..Default::default()
+    });
+    let banned: ipnet::IpNet = "10.0.0.0/8".parse().unwrap();
+    book.set_inbound_filter(move |addr| {
+        !addr.iter().any(|p| match p {
+            Protocol::Ip4(ip) => banned.contains(&std::net::IpAddr::V4(ip)),
+            _ => false,
+        })
+    });
+    let events = Default::default();
+    let (tx, rx) = mpsc::unbounded();
+    book.swarm_events(tx).unwrap();
+    let events = Events::new(SwarmEvents::new(rx), &events);
+
+    let local: Multiaddr = "/ip4/192.168.1.2/tcp/4001".parse().unwrap();
+    let bad: Multiaddr = "/ip4/10.0.0.10/tcp/57634".parse().unwrap();
+    let good_peer = PeerId::random();
+    let good: Multiaddr = "/ip4/192.168.1.10/tcp/57634".parse().unwrap();
+    // the bad connection is denied before the handshake
+    assert!(book
+        .handle_pending_inbound_connection(ConnectionId::new(1), &local, &bad)
+        .is_err());
+    assert!(book
+        .handle_pending_inbound_connection(ConnectionId::new(2), &local, &good)
+        .is_ok());
+    book.on_swarm_event(FromSwarm::ConnectionEstablished(CE {
+        peer_id: good_peer,
+        connection_id: ConnectionId::new(2),
+        endpoint: &ConnectedPoint::Listener {
+            local_addr: local.clone(),
+            send_back_addr: good.clone(),
+        },
+        failed_addresses: &[],
+        other_established: 0,
+    }));
+
+    assert!(book.actions.is_empty());
+    let events = events.next();
+    assert!(events.contains(&ConnectionRefused(bad)));
+    assert!(events.contains(&Connected(good_peer)));
+}
+
+#[test]
+fn connection_gate_peer_id() {
+    #[derive(Debug)]
+    struct RejectPeer(PeerId);
+
+    impl ConnectionGate for RejectPeer {
+        fn intercept_peer_id(&self, peer: &PeerId, _: &ConnectedPoint) -> bool {
+            *peer != self.0
+        }
+    }
+
+    let mut book = AddressBook::new(Default::default());
+    let bad_peer = PeerId::random();
+    book.set_connection_gate(std::sync::Arc::new(RejectPeer(bad_peer)));
+    let events = Default::default();
+    let (tx, rx) = mpsc::unbounded();
+    book.swarm_events(tx).unwrap();
+    let events = Events::new(SwarmEvents::new(rx), &events);
+
+    let good_peer = PeerId::random();
+    let addr: Multiaddr = "/ip4/10.0.0.10/tcp/4001".parse().unwrap();
+    book.dial(&bad_peer);
+    for (id, peer) in [(1, bad_peer), (2, good_peer)] {
+        book.on_swarm_event(FromSwarm::ConnectionEstablished(CE {
+            peer_id: peer,
+            connection_id: ConnectionId::new(id),
+            endpoint: &ConnectedPoint::Dialer {
+                address: addr.clone(),
+                role_override: Endpoint::Dialer,
+            },
+            failed_addresses: &[],
+            other_established: 0,
+        }));
+    }
+
+    let closed = book
+        .actions
+        .drain(..)
+        .filter_map(|a| match a {
+            NetworkBehaviourAction::CloseConnection {
+                peer_id,
+                connection: CloseConnection::One(id),
+            } => Some((peer_id, id)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(closed, vec![(bad_peer, ConnectionId::new(1))]);
+    let events = events.next();
+    let bad_p2p = addr.with(Protocol::P2p(bad_peer.into()));
+    assert!(events.contains(&ConnectionGated(
+        Some(bad_peer),
+        Some(bad_p2p),
+        GateStage::PeerId
+    )));
+    assert!(!events.contains(&Connected(bad_peer)));
+    assert!(events.contains(&Connected(good_peer)));
+    assert_eq!(book.pending_dials().next(), None);
+}
+
+#[test]
+fn connection_gate_inbound_and_dial() {
+    #[derive(Debug)]
+    struct RejectAddr(Multiaddr);
+
+    impl ConnectionGate for RejectAddr {
+        fn intercept_dial(&self, _: Option<&PeerId>, addr: Option<&Multiaddr>) -> bool {
+            addr != Some(&self.0)
+        }
+
+        fn intercept_inbound(&self, remote_addr: &Multiaddr) -> bool {
+            *remote_addr != self.0
+        }
+    }
+
+    let mut book = AddressBook::new(Default::default());
+    let bad: Multiaddr = "/ip4/10.0.0.10/tcp/4001".parse().unwrap();
+    book.set_connection_gate(std::sync::Arc::new(RejectAddr(bad.clone())));
+    let events = Default::default();
+    let (tx, rx) = mpsc::unbounded();
+    book.swarm_events(tx).unwrap();
+    let events = Events::new(SwarmEvents::new(rx), &events);
+
+    let local: Multiaddr = "/ip4/10.0.0.2/tcp/4001".parse().unwrap();
+    assert!(book
+        .handle_pending_inbound_connection(ConnectionId::new(1), &local, &bad)
+        .is_err());
+
+    // dials of an address without peer id pass the gate as well
+    let dial = NetworkBehaviourAction::Dial {
+        opts: libp2p::swarm::dial_opts::DialOpts::unknown_peer_id()
+            .address(bad.clone())
+            .build(),
+        handler: IntoAddressHandler(Some((bad.clone(), 1)), false),
+    };
+    assert!(book.dial_gated(&dial));
+
+    let events = events.next();
+    assert!(events.contains(&ConnectionGated(
+        None,
+        Some(bad.clone()),
+        GateStage::Inbound
+    )));
+    assert!(events.contains(&ConnectionGated(None, Some(bad), GateStage::Dial)));
+}
+
+#[test]
+fn fd_soft_limit_trims_connections() {
+    let mut book = AddressBook::new(Default::default());
+    let clock = MockClock::default();
+    book.set_clock(Arc::new(clock.clone()));
+    book.set_fd_soft_limit(Some(5));
+    book.reserve_fds(1);
+    let events = Default::default();
+    let (tx, rx) = mpsc::unbounded();
+    book.swarm_events(tx).unwrap();
+    let events = Events::new(SwarmEvents::new(rx), &events);
+
+    let peers = (0..4).map(|_| PeerId::random()).collect::<Vec<_>>();
+    let connect = |book: &mut AddressBook, id: usize| {
+        let addr: Multiaddr = format!("/ip4/10.0.0.{}/tcp/4001", id).parse().unwrap();
+        book.on_swarm_event(FromSwarm::ConnectionEstablished(CE {
+            peer_id: peers[id],
+            connection_id: ConnectionId::new(id),
+            endpoint: &ConnectedPoint::Dialer {
+                address: addr,
+                role_override: Endpoint::Dialer,
+            },
+            failed_addresses: &[],
+            other_established: 0,
+        }));
+    };
+    let closed = |book: &mut AddressBook| {
+        book.actions
+            .drain(..)
+            .filter_map(|a| match a {
+                NetworkBehaviourAction::CloseConnection { peer_id, .. } => Some(peer_id),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+    for id in 0..3 {
+        connect(&mut book, id);
+    }
+    for (id, rtt) in [(0, 10), (1, 300), (2, 50)] {
+        book.set_rtt(&peers[id], Some(Duration::from_millis(rtt)));
+    }
+    assert_eq!(book.file_descriptors(), 4);
+    assert!(closed(&mut book).is_empty());
+    assert!(!events
+        .next()
+        .contains(&ResourceLimitApproaching(Resource::FileDescriptors)));
+
+    // of equally idle peers, the connections with the highest rtt are closed
+    // first
+    book.set_fd_soft_limit(Some(3));
+    assert_eq!(closed(&mut book), vec![peers[1], peers[2]]);
+    assert_eq!(book.file_descriptors(), 2);
+    let events = events.next();
+    assert!(events.contains(&ResourceLimitApproaching(Resource::FileDescriptors)));
+
+    // a new connection isn't closed in favour of the most idle one
+    clock.advance(Duration::from_secs(1));
+    connect(&mut book, 3);
+    assert_eq!(closed(&mut book), vec![peers[0]]);
+    assert_eq!(book.file_descriptors(), 2);
+    assert!(book.is_connected(&peers[3]));
+}
+
+#[test]
+fn connections_are_tracked_by_id() {
+    let mut book = AddressBook::new(Default::default());
+    let peer = PeerId::random();
+    let dialer = |port: u16| ConnectedPoint::Dialer {
+        address: format!("/ip4/10.0.0.10/tcp/{}", port)
+            .parse::<Multiaddr>()
+            .unwrap()
+            .with(Protocol::P2p(peer.into())),
+        role_override: Endpoint::Dialer,
+    };
+    for (id, other_established) in [(1, 0), (2, 1)] {
+        book.on_swarm_event(FromSwarm::ConnectionEstablished(CE {
+            peer_id: peer,
+            connection_id: ConnectionId::new(id),
+            endpoint: &dialer(4001),
+            failed_addresses: &[],
+            other_established,
+        }));
+    }
+    assert_eq!(book.file_descriptors(), 2);
+
+    // the connection that moved is closed under its new address
+    book.on_swarm_event(FromSwarm::AddressChange(AC {
+        peer_id: peer,
+        connection_id: ConnectionId::new(1),
+        old: &dialer(4001),
+        new: &dialer(4002),
+    }));
+    book.connection_closed(peer, ConnectionId::new(1), dialer(4002), 1, None);
+    assert_eq!(book.file_descriptors(), 1);
+
+    // a second connection on the same address is closed separately
+    book.connection_closed(peer, ConnectionId::new(2), dialer(4001), 0, None);
+    assert_eq!(book.file_descriptors(), 0);
+}
+
+#[test]
+fn static_peers() {
+    let mut book = AddressBook::new(Default::default());
+    let clock = MockClock::default();
+    book.set_clock(std::sync::Arc::new(clock.clone()));
+    let waker = futures::task::noop_waker();
+    let mut cx = std::task::Context::from_waker(&waker);
+    let peer = PeerId::random();
+    let addr: Multiaddr = "/ip4/10.0.0.10/tcp/4001".parse().unwrap();
+    let addr_p = addr.clone().with(Protocol::P2p(peer.into()));
+
+    // dialed on startup
+    book.add_static_peer(peer, vec![addr], ReconnectPolicy::default());
+    assert_eq!(dials(&mut book), vec![Dial::P(peer, vec![addr_p.clone()])]);
+
+    let cp = ConnectedPoint::Dialer {
+        address: addr_p.clone(),
+        role_override: Endpoint::Dialer,
+    };
+    book.on_swarm_event(FromSwarm::ConnectionEstablished(CE {
+        peer_id: peer,
+        connection_id: ConnectionId::new(1),
+        endpoint: &cp,
+        failed_addresses: &[],
+        other_established: 0,
+    }));
+    book.connection_closed(peer, ConnectionId::new(1), cp, 0, None);
+
+    // redialed once the redial delay has passed
+    book.poll_static_redials(&mut cx);
+    assert_eq!(dials(&mut book), vec![]);
+    clock.advance(Duration::from_secs(6));
+    book.poll_static_redials(&mut cx);
+    assert_eq!(dials(&mut book), vec![Dial::P(peer, vec![addr_p.clone()])]);
+
+    // and again after failing to connect
+    let error = std::io::Error::new(ErrorKind::TimedOut, "timeout");
+    book.dial_failure(
+        IntoAddressHandler(None, true),
+        Some(peer),
+        &DialError::Transport(vec![(addr_p.clone(), TransportError::Other(error))]),
+    );
+    clock.advance(Duration::from_secs(12));
+    book.poll_static_redials(&mut cx);
+    assert_eq!(dials(&mut book), vec![Dial::P(peer, vec![addr_p])]);
+
+    // other peers are not redialed
+    let other = PeerId::random();
+    book.connection_closed(
+        other,
+        ConnectionId::new(2),
+        ConnectedPoint::Dialer {
+            address: "/ip4/10.0.0.11/tcp/4001".parse().unwrap(),
+            role_override: Endpoint::Dialer,
+        },
+        0,
+        None,
+    );
+    clock.advance(Duration::from_secs(600));
+    book.poll_static_redials(&mut cx);
+    assert_eq!(dials(&mut book), vec![]);
+}
+
+#[test]
+fn gossipsub_mesh() {
+    use crate::config::GossipsubConfig;
+
+    let mesh = GossipsubMesh {
+        mesh_n: 3,
+        mesh_n_low: 2,
+        mesh_n_high: 20,
+        gossip_factor: 0.5,
+        heartbeat_interval: Duration::from_millis(300),
+    };
+    let config = mesh.apply(GossipsubConfig::default()).unwrap();
+    assert_eq!(config.mesh_n(), 3);
+    assert_eq!(config.mesh_n_low(), 2);
+    assert_eq!(config.mesh_n_high(), 20);
+    assert_eq!(config.gossip_factor(), 0.5);
+    assert_eq!(config.heartbeat_interval(), Duration::from_millis(300));
+    assert!(config.mesh_outbound_min() * 2 <= 3);
+
+    let invalid = [
+        GossipsubMesh {
+            mesh_n_low: 7,
+            ..Default::default()
+        },
+        GossipsubMesh {
+            mesh_n_high: 5,
+            ..Default::default()
+        },
+        GossipsubMesh {
+            gossip_factor: 1.5,
+            ..Default::default()
+        },
+    ];
+    for mesh in &invalid {
+        assert!(mesh.apply(GossipsubConfig::default()).is_err());
+    }
+}
+
+#[test]
+fn serve_queue_per_peer() {
+    let mut queues = ServeQueues::new(Some(4));
+    let flooder = PeerId::random();
+    let other = PeerId::random();
+    let conn = ConnectionId::new(0);
+    let admitted = (0..100u64)
+        .filter(|id| queues.admit(flooder, conn, *id, *id) == Admission::Admitted(*id))
+        .count();
+    assert_eq!(admitted, 4);
+    assert_eq!(queues.queued(&flooder), 4);
+    // as many again are deferred, the rest is rejected
+    assert_eq!(queues.deferred(&flooder), 4);
+    assert_eq!(queues.admit(flooder, conn, 100, 100), Admission::Rejected);
+    assert_eq!(queues.next(), None);
+
+    // the flooding peer doesn't use up the queue of the others
+    let conn2 = ConnectionId::new(1);
+    assert_eq!(
+        queues.admit(other, conn2, 1000, 1000),
+        Admission::Admitted(1000)
+    );
+    assert_eq!(queues.queued(&other), 1);
+
+    // answering a request hands the next deferred one to bitswap
+    queues.done(&flooder, conn, &0);
+    assert_eq!(queues.next(), Some((flooder, conn, 4)));
+    assert_eq!(queues.next(), None);
+    assert_eq!(queues.queued(&flooder), 4);
+    assert_eq!(queues.deferred(&flooder), 3);
+    // a deferred request that timed out is forgotten
+    queues.done(&flooder, conn, &5);
+    assert_eq!(queues.deferred(&flooder), 2);
+    assert_eq!(queues.in_flight(), 7);
+
+    queues.remove_connection(&flooder, conn);
+    assert_eq!(queues.queued(&flooder), 0);
+    assert_eq!(queues.deferred(&flooder), 0);
+    assert_eq!(queues.queued(&other), 1);
+
+    let mut unlimited = ServeQueues::new(None);
+    assert!((0..100u64).all(|id| matches!(
+        unlimited.admit(flooder, conn, id, ()),
+        Admission::Admitted(_)
+    )));
+}
+
+#[test]
+fn serve_queue_round_robin() {
+    let mut queues = ServeQueues::new(Some(1));
+    let peers = [PeerId::random(), PeerId::random()];
+    let conn = ConnectionId::new(0);
+    for (i, peer) in peers.iter().enumerate() {
+        let id = i as u64 * 10;
+        assert_eq!(queues.admit(*peer, conn, id, id), Admission::Admitted(id));
+        assert_eq!(
+            queues.admit(*peer, conn, id + 1, id + 1),
+            Admission::Deferred
+        );
+    }
+    queues.done(&peers[0], conn, &0);
+    queues.done(&peers[1], conn, &10);
+    // each peer gets a turn
+    let mut served = vec![queues.next().unwrap(), queues.next().unwrap()];
+    served.sort_by_key(|(_, _, id)| *id);
+    assert_eq!(served, vec![(peers[0], conn, 1), (peers[1], conn, 11)]);
+    assert_eq!(queues.next(), None);
+}
+
+#[test]
+fn pending_blocks() {
+    let pending = PendingBlocks::default();
+    pending.received(10);
+    pending.received(20);
+    pending.received(30);
+    let now = std::time::Instant::now();
+    assert_eq!(pending.bytes(now).0, 60);
+    // the block received first was dropped by bitswap
+    pending.stored(20);
+    assert_eq!(pending.bytes(now).0, 30);
+    // a block that wasn't counted, e.g. received via the legacy protocol
+    pending.stored(5);
+    assert_eq!(pending.bytes(now).0, 30);
+    // a block that is never stored is forgotten eventually
+    assert_eq!(pending.bytes(now + PENDING_BLOCK_EXPIRY), (0, None));
+}
+
+#[test]
+fn event_handler() {
+    let mut book = AddressBook::new(AddressBookConfig {
+        enable_loopback: true,
+        ..Default::default()
+    });
+    let handled = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let handled2 = handled.clone();
+    book.set_event_handler(Box::new(move |event| {
+        handled2.lock().unwrap().push(event.clone())
+    }));
+    let (tx, _rx) = mpsc::unbounded();
+    book.swarm_events(tx).unwrap();
+
+    let peer = PeerId::random();
+    book.add_address(
+        &peer,
+        "/ip4/1.1.1.1/tcp/4001".parse().unwrap(),
+        AddressSource::User,
+    );
+    // seen before add_address returns, without polling anything
+    assert_eq!(handled.lock().unwrap().first(), Some(&Discovered(peer)));
+}
+
+#[test]
+fn dial_metrics_by_source() {
+    use crate::net::peers::{
+        ADDRESS_DIAL_FAILURE, ADDRESS_REACH_FAILURE, ADDRESS_UNREACHABLE, DIAL_FAILURE,
+        DIAL_SUCCESS,
+    };
+
+    let mut book = AddressBook::new(AddressBookConfig {
+        enable_loopback: true,
+        ..Default::default()
+    });
+    let peer = PeerId::random();
+    let mdns = "/ip4/10.0.0.1/tcp/4001".parse::<Multiaddr>().unwrap();
+    let mdns2 = "/ip4/10.0.0.2/tcp/4001".parse::<Multiaddr>().unwrap();
+    let kad = "/ip4/1.1.1.1/tcp/4001".parse::<Multiaddr>().unwrap();
+    let kad2 = "/ip4/1.1.1.2/tcp/4001".parse::<Multiaddr>().unwrap();
+    book.add_address(&peer, mdns.clone(), AddressSource::Mdns);
+    book.add_address(&peer, mdns2.clone(), AddressSource::Mdns);
+    book.add_address(&peer, kad.clone(), AddressSource::Kad);
+    book.add_address(&peer, kad2.clone(), AddressSource::Kad);
+
+    let count = |counter: &IntCounterVec, source: &str| counter.with_label_values(&[source]).get();
+    let before = [
+        count(&ADDRESS_UNREACHABLE, "Mdns"),
+        count(&ADDRESS_UNREACHABLE, "Kad"),
+        count(&ADDRESS_DIAL_FAILURE, "Mdns"),
+        count(&DIAL_SUCCESS, "Kad"),
+    ];
+    let dial_failures = DIAL_FAILURE.get();
+    let reach_failures = ADDRESS_REACH_FAILURE.get();
+    let error = || TransportError::Other(std::io::Error::new(ErrorKind::Other, "my error"));
+    book.dial_failure(
+        IntoAddressHandler(None, false),
+        Some(peer),
+        &DialError::Transport(vec![(mdns, error()), (mdns2, error()), (kad, error())]),
+    );
+    // the failed addresses are gone, the other one connects
+    let cp = ConnectedPoint::Dialer {
+        address: kad2.with(Protocol::P2p(peer.into())),
+        role_override: Endpoint::Dialer,
+    };
+    book.on_swarm_event(FromSwarm::ConnectionEstablished(CE {
+        peer_id: peer,
+        connection_id: ConnectionId::new(1),
+        endpoint: &cp,
+        failed_addresses: &[],
+        other_established: 0,
+    }));
+
+    // other tests may count failures at the same time
+    assert!(count(&ADDRESS_UNREACHABLE, "Mdns") >= before[0] + 2);
+    assert!(count(&ADDRESS_UNREACHABLE, "Kad") > before[1]);
+    assert!(count(&ADDRESS_DIAL_FAILURE, "Mdns") >= before[2] + 2);
+    assert!(count(&DIAL_SUCCESS, "Kad") > before[3]);
+    // the unlabelled counters count the dial once and each address
+    assert!(DIAL_FAILURE.get() > dial_failures);
+    assert!(ADDRESS_REACH_FAILURE.get() >= reach_failures + 3);
+}
+
+#[test]
+fn remote_loopback_addresses_are_ignored() {
+    let mut book = AddressBook::new(AddressBookConfig {
+        enable_loopback: true,
+        ..Default::default()
+    });
+    book.set_trust_remote_loopback(false);
+    let peer = PeerId::random();
+    let loopback = "/ip4/127.0.0.1/tcp/4001".parse::<Multiaddr>().unwrap();
+    book.add_address(&peer, loopback.clone(), AddressSource::Listen);
+    book.add_address(&peer, loopback.clone(), AddressSource::Kad);
+    assert!(book.info(&peer).is_none());
+
+    // loopback addresses from mDNS or the user are still trusted
+    book.add_address(&peer, loopback.clone(), AddressSource::User);
+    assert_eq!(book.info(&peer).unwrap().addresses().count(), 1);
+
+    book.set_trust_remote_loopback(true);
+    let other = PeerId::random();
+    book.add_address(&other, loopback, AddressSource::Kad);
+    assert_eq!(book.info(&other).unwrap().addresses().count(), 1);
+}
+
+#[test]
+fn introductions_are_limited() {
+    let mut book = AddressBook::new(AddressBookConfig {
+        enable_loopback: true,
+        ..Default::default()
+    });
+    book.set_trust_remote_loopback(false);
+    let from = PeerId::random();
+    let peer = PeerId::random();
+    let addrs = (0..40)
+        .map(|i| format!("/ip4/10.0.0.{}/tcp/4001", i).parse().unwrap())
+        .collect::<Vec<Multiaddr>>();
+
+    // refused by default
+    book.add_introduced(&from, &peer, addrs.clone());
+    assert!(book.info(&peer).is_none());
+
+    book.set_introductions(IntroductionPolicy::Any);
+    let loopback = "/ip4/127.0.0.1/tcp/4001".parse::<Multiaddr>().unwrap();
+    book.add_introduced(&from, &peer, vec![loopback]);
+    assert!(book.info(&peer).is_none());
+    book.add_introduced(&from, &peer, addrs);
+    let info = book.info(&peer).unwrap();
+    // the loopback address counts towards the limit
+    assert_eq!(info.addresses().count(), 31);
+    assert!(info
+        .addresses()
+        .all(|(_, source, _)| source == AddressSource::Introduced));
+
+    let other = PeerId::random();
+    let addr = "/ip4/10.0.1.1/tcp/4001".parse().unwrap();
+    book.add_introduced(&from, &other, vec![addr]);
+    assert!(book.info(&other).is_none());
+}
+
+#[test]
+fn static_peer_reconnect_backoff() {
+    let mut book = AddressBook::new(Default::default());
+    let clock = MockClock::default();
+    book.set_clock(std::sync::Arc::new(clock.clone()));
+    let waker = futures::task::noop_waker();
+    let mut cx = std::task::Context::from_waker(&waker);
+    let peer = PeerId::random();
+    let addr: Multiaddr = "/ip4/10.0.0.10/tcp/4001".parse().unwrap();
+    let addr_p = addr.clone().with(Protocol::P2p(peer.into()));
+    let reconnect = ReconnectPolicy {
+        backoff: Duration::from_secs(1),
+        max_backoff: Duration::from_secs(2),
+        max_attempts: Some(3),
+    };
+    book.add_static_peer(peer, vec![addr], reconnect);
+    assert_eq!(dials(&mut book), vec![Dial::P(peer, vec![addr_p.clone()])]);
+
+    let cp = ConnectedPoint::Dialer {
+        address: addr_p.clone(),
+        role_override: Endpoint::Dialer,
+    };
+    let connect = |book: &mut AddressBook| {
+        book.on_swarm_event(FromSwarm::ConnectionEstablished(CE {
+            peer_id: peer,
+            connection_id: ConnectionId::new(1),
+            endpoint: &cp,
+            failed_addresses: &[],
+            other_established: 0,
+        }))
+    };
+    let fail = |book: &mut AddressBook| {
+        let error = std::io::Error::new(ErrorKind::TimedOut, "timeout");
+        book.dial_failure(
+            IntoAddressHandler(None, true),
+            Some(peer),
+            &DialError::Transport(vec![(addr_p.clone(), TransportError::Other(error))]),
+        )
+    };
+    let mut redialed_after = |book: &mut AddressBook, secs: u64| {
+        clock.advance(Duration::from_millis(secs * 1000 - 1));
+        book.poll_static_redials(&mut cx);
+        assert_eq!(dials(book), vec![]);
+        clock.advance(Duration::from_millis(1));
+        book.poll_static_redials(&mut cx);
+        assert_eq!(dials(book), vec![Dial::P(peer, vec![addr_p.clone()])]);
+    };
+    connect(&mut book);
+    book.connection_closed(peer, ConnectionId::new(1), cp.clone(), 0, None);
+
+    // the delay doubles up to the maximum
+    redialed_after(&mut book, 1);
+    fail(&mut book);
+    redialed_after(&mut book, 2);
+    fail(&mut book);
+    redialed_after(&mut book, 2);
+
+    // and the peer is given up on after the maximum number of redials
+    fail(&mut book);
+    clock.advance(Duration::from_secs(60));
+    book.poll_static_redials(&mut cx);
+    assert_eq!(dials(&mut book), vec![]);
+
+    // connecting starts over
+    connect(&mut book);
+    book.connection_closed(peer, ConnectionId::new(1), cp.clone(), 0, None);
+    redialed_after(&mut book, 1);
+
+    // a peer that is no longer static, e.g. because it was banned, is not
+    // redialed
+    connect(&mut book);
+    book.remove_static_peer(&peer);
+    book.connection_closed(peer, ConnectionId::new(1), cp.clone(), 0, None);
+    clock.advance(Duration::from_secs(60));
+    book.poll_static_redials(&mut cx);
+    assert_eq!(dials(&mut book), vec![]);
+}
+
+#[test]
+fn jitter_desynchronizes_periodic_tasks() {
+    let interval = Duration::from_secs(60);
+    assert_eq!(Jitter::NONE.apply(interval), interval);
+
+    assert_eq!(Jitter::new(1.0).fraction(), Jitter::MAX_FRACTION);
+
+    // the clocks of two nodes started at the same time, advanced a second at
+    // a time
+    let jitter = Jitter::default();
+    let start = Utc::now();
+    let clocks = [MockClock::new(start), MockClock::new(start)];
+    let mut timers = clocks
+        .iter()
+        .map(|clock| clock.tick(interval, jitter))
+        .collect::<Vec<_>>();
+    let mut ticks = [vec![], vec![]];
+    let waker = futures::task::noop_waker();
+    let mut cx = std::task::Context::from_waker(&waker);
+    for _ in 0..100 * 66 {
+        for (i, clock) in clocks.iter().enumerate() {
+            clock.advance(Duration::from_secs(1));
+            if timers[i].poll_unpin(&mut cx).is_ready() {
+                ticks[i].push(clock.now());
+                timers[i] = clock.tick(interval, jitter);
+            }
+        }
+    }
+    for ticks in &ticks {
+        assert!(ticks.len() >= 100);
+        for pair in ticks.windows(2) {
+            let delay = (pair[1] - pair[0]).to_std().unwrap();
+            assert!(delay >= Duration::from_secs(54) && delay <= Duration::from_secs(66));
+        }
+    }
+    let apart = ticks[0]
+        .iter()
+        .zip(&ticks[1])
+        .filter(|(a, b)| a != b)
+        .count();
+    assert!(apart > 90, "ticks stayed together {} times", 100 - apart);
+}
+
+#[test]
+fn transport_of_listen_address() {
+    let transport = |addr: &str| Transport::of(&addr.parse().unwrap());
+    assert_eq!(
+        transport("/ip4/1.1.1.1/tcp/4001"),
+        Some((4001, Transport::Tcp))
+    );
+    assert_eq!(
+        transport("/ip4/1.1.1.1/tcp/4001/ws"),
+        Some((4001, Transport::WebSocket))
+    );
+    assert_eq!(
+        transport("/ip4/1.1.1.1/udp/4001/quic"),
+        Some((4001, Transport::Quic))
+    );
+    assert_eq!(
+        transport("/ip6/::1/udp/4001/quic-v1"),
+        Some((4001, Transport::Quic))
+    );
+    assert_eq!(transport("/ip4/1.1.1.1/udp/4001"), None);
+}
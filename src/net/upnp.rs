@@ -0,0 +1,206 @@
+use super::{
+    clock::{Clock, Jitter},
+    peers::normalize_addr,
+    NetworkCommand,
+};
+use crate::executor::{Executor, JoinHandle};
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::{channel::mpsc::Sender, SinkExt};
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use std::{
+    fmt::Debug,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Lease duration requested for each port mapping; mappings are renewed after
+/// half of this time.
+const LEASE: Duration = Duration::from_secs(3600);
+
+/// Pause before trying again after a renewal failed for a mapping that was
+/// established before.
+const RETRY: Duration = Duration::from_secs(60);
+
+/// A gateway that can forward a port from the outside to a local socket.
+#[async_trait]
+pub trait PortMapper: Debug + Send + Sync + 'static {
+    /// Requests a mapping of a TCP port to `local` for the given lease time,
+    /// returning the external socket address.
+    async fn map_port(&self, local: SocketAddr, lease: Duration) -> Result<SocketAddr>;
+
+    /// Removes the mapping of the TCP port of `local` again.
+    async fn unmap_port(&self, local: SocketAddr) -> Result<()>;
+}
+
+/// Port mapping via the UPnP IGD protocol of the local router.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Upnp;
+
+#[async_trait]
+impl PortMapper for Upnp {
+    async fn map_port(&self, local: SocketAddr, lease: Duration) -> Result<SocketAddr> {
+        #[cfg(feature = "async_global")]
+        let gateway = igd_next::aio::async_std::search_gateway(Default::default()).await?;
+        #[cfg(all(feature = "tokio", not(feature = "async_global")))]
+        let gateway = igd_next::aio::tokio::search_gateway(Default::default()).await?;
+        let ip = gateway.get_external_ip().await?;
+        gateway
+            .add_port(
+                igd_next::PortMappingProtocol::TCP,
+                local.port(),
+                local,
+                lease.as_secs() as u32,
+                "ipfs-embed",
+            )
+            .await?;
+        Ok(SocketAddr::new(ip, local.port()))
+    }
+
+    async fn unmap_port(&self, local: SocketAddr) -> Result<()> {
+        #[cfg(feature = "async_global")]
+        let gateway = igd_next::aio::async_std::search_gateway(Default::default()).await?;
+        #[cfg(all(feature = "tokio", not(feature = "async_global")))]
+        let gateway = igd_next::aio::tokio::search_gateway(Default::default()).await?;
+        gateway
+            .remove_port(igd_next::PortMappingProtocol::TCP, local.port())
+            .await?;
+        Ok(())
+    }
+}
+
+/// Extracts the socket address of a TCP listen address.
+pub(crate) fn socket_addr(addr: &Multiaddr) -> Option<SocketAddr> {
+    let mut iter = addr.iter();
+    let ip = match iter.next()? {
+        Protocol::Ip4(ip) => IpAddr::V4(ip),
+        Protocol::Ip6(ip) => IpAddr::V6(ip),
+        _ => return None,
+    };
+    match iter.next()? {
+        Protocol::Tcp(port) => Some(SocketAddr::new(ip, port)),
+        _ => None,
+    }
+}
+
+/// Whether the gateway can forward a port to `local`. IGD gateways only
+/// forward to IPv4 hosts of their local network, so loopback, link-local,
+/// unspecified and broadcast addresses are left out, as is IPv6.
+pub(crate) fn is_mappable(local: &SocketAddr) -> bool {
+    match local.ip() {
+        IpAddr::V4(ip) => {
+            !(ip.is_loopback() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast())
+        }
+        IpAddr::V6(_) => false,
+    }
+}
+
+/// A port mapping for a listen address, kept alive by a background task and
+/// removed from the gateway again when dropped.
+pub(crate) struct PortMapping {
+    task: Option<JoinHandle<()>>,
+    mapper: Arc<dyn PortMapper>,
+    local: SocketAddr,
+    mapped: Arc<AtomicBool>,
+    executor: Executor,
+}
+
+impl PortMapping {
+    pub fn new(
+        executor: &Executor,
+        mapper: Arc<dyn PortMapper>,
+        local: SocketAddr,
+        peer_id: PeerId,
+        cmd: Sender<NetworkCommand>,
+        clock: Arc<dyn Clock>,
+        jitter: Jitter,
+    ) -> Self {
+        let mapped = Arc::new(AtomicBool::new(false));
+        let task = executor.spawn(port_mapping(
+            mapper.clone(),
+            local,
+            peer_id,
+            cmd,
+            clock,
+            jitter,
+            mapped.clone(),
+        ));
+        Self {
+            task: Some(task),
+            mapper,
+            local,
+            mapped,
+            executor: executor.clone(),
+        }
+    }
+}
+
+impl Drop for PortMapping {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        if !self.mapped.load(Ordering::Relaxed) {
+            return;
+        }
+        let mapper = self.mapper.clone();
+        let local = self.local;
+        self.executor
+            .spawn(async move {
+                match mapper.unmap_port(local).await {
+                    Ok(()) => tracing::debug!("removed port mapping for {}", local),
+                    Err(err) => {
+                        tracing::debug!("removing port mapping for {} failed: {:#}", local, err)
+                    }
+                }
+            })
+            .detach();
+    }
+}
+
+/// Keeps a port mapping for `local` alive and reports the resulting external
+/// address to the swarm.
+///
+/// If the very first attempt fails the gateway is assumed to not support port
+/// mapping and the task ends, otherwise failed renewals are retried.
+async fn port_mapping(
+    mapper: Arc<dyn PortMapper>,
+    local: SocketAddr,
+    peer_id: PeerId,
+    mut cmd: Sender<NetworkCommand>,
+    clock: Arc<dyn Clock>,
+    jitter: Jitter,
+    mapped: Arc<AtomicBool>,
+) {
+    loop {
+        let delay = match mapper.map_port(local, LEASE).await {
+            Ok(external) => {
+                let mut addr = Multiaddr::from(external.ip()).with(Protocol::Tcp(external.port()));
+                normalize_addr(&mut addr, &peer_id);
+                tracing::debug!("port mapping {} -> {}", local, addr);
+                if cmd
+                    .send(NetworkCommand::AddExternalAddress(addr))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+                mapped.store(true, Ordering::Relaxed);
+                LEASE / 2
+            }
+            Err(err) if mapped.load(Ordering::Relaxed) => {
+                tracing::warn!("renewing port mapping for {} failed: {:#}", local, err);
+                RETRY
+            }
+            Err(err) => {
+                tracing::info!("port mapping for {} not available: {:#}", local, err);
+                return;
+            }
+        };
+        clock.tick(delay, jitter).await;
+    }
+}
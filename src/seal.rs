@@ -0,0 +1,255 @@
+//! The bytes the block store keeps for a block, see
+//! [`StorageConfig::encryption_key`](crate::StorageConfig::encryption_key) and
+//! [`StorageConfig::compression`](crate::StorageConfig::compression).
+//!
+//! Blocks are stored as they are, unless they are encrypted, compressed or
+//! their bytes start with [`MAGIC`]. Those are stored in an envelope that
+//! starts with [`MAGIC`], followed by a flags byte, the links of the block and
+//! the payload. The links stay readable, because the block store scrapes them
+//! from the stored bytes to track references for garbage collection, which
+//! then works without the key. The flags say how the payload was made, so
+//! blocks stored with different settings can be read side by side.
+use crate::{
+    db::Compression,
+    unixfs::{read_varint, varint},
+};
+use chacha20poly1305::{
+    aead::{Aead, Payload},
+    KeyInit, XChaCha20Poly1305, XNonce,
+};
+use libipld::{
+    codec::{Codec, References},
+    error::UnsupportedCodec,
+    store::StoreParams,
+    Block, Cid, Ipld, Result,
+};
+use std::{
+    collections::HashSet,
+    convert::TryFrom,
+    io::{Read, Seek},
+    marker::PhantomData,
+};
+use thiserror::Error;
+
+/// Starts every envelope. A dag-cbor, dag-pb or dag-json block can't start
+/// with it, blocks of other codecs that do are stored in an envelope.
+const MAGIC: [u8; 4] = [0xff, b'i', b'p', b'e'];
+
+/// The payload of the envelope is encrypted.
+const ENCRYPTED: u8 = 1;
+
+/// The payload of the envelope is compressed, before it is encrypted.
+const COMPRESSED: u8 = 2;
+
+const NONCE_LEN: usize = 24;
+
+/// Error returned when reading a block that can't be decrypted, because the
+/// block store was opened with a different or without an encryption key.
+#[derive(Debug, Error)]
+#[error("block {0} can't be decrypted with the configured encryption key")]
+pub struct DecryptionFailed(pub Cid);
+
+/// Error returned when reading a block whose envelope can't be parsed.
+#[derive(Debug, Error)]
+#[error("block {0} is stored in an invalid envelope")]
+pub struct InvalidEnvelope(pub Cid);
+
+/// The parameters of the block store, whose blocks are those of `S` in the
+/// bytes [`Sealer::seal`] returns for them.
+#[derive(Debug)]
+pub(crate) struct Sealed<S>(PhantomData<S>);
+
+impl<S> Clone for Sealed<S> {
+    fn clone(&self) -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<S: StoreParams> StoreParams for Sealed<S> {
+    type Hashes = S::Hashes;
+    type Codecs = SealedCodec<S>;
+    const MAX_BLOCK_SIZE: usize = S::MAX_BLOCK_SIZE;
+}
+
+/// The codecs of `S`, which scrape the links of envelopes from the envelope.
+#[derive(Debug)]
+pub(crate) struct SealedCodec<S: StoreParams>(S::Codecs, PhantomData<S>);
+
+impl<S: StoreParams> Clone for SealedCodec<S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<S: StoreParams> Copy for SealedCodec<S> {}
+
+impl<S: StoreParams> TryFrom<u64> for SealedCodec<S> {
+    type Error = UnsupportedCodec;
+
+    fn try_from(code: u64) -> std::result::Result<Self, Self::Error> {
+        Ok(Self(S::Codecs::try_from(code)?, PhantomData))
+    }
+}
+
+impl<S: StoreParams> From<SealedCodec<S>> for u64 {
+    fn from(codec: SealedCodec<S>) -> Self {
+        codec.0.into()
+    }
+}
+
+impl<S: StoreParams> Codec for SealedCodec<S> {}
+
+impl<S: StoreParams> References<SealedCodec<S>> for Ipld
+where
+    Ipld: References<S::Codecs>,
+{
+    fn references<R: Read + Seek, E: Extend<Cid>>(
+        c: SealedCodec<S>,
+        r: &mut R,
+        set: &mut E,
+    ) -> Result<()> {
+        let mut bytes = Vec::new();
+        r.read_to_end(&mut bytes)?;
+        if !bytes.starts_with(&MAGIC) {
+            return c.0.references::<Ipld, E>(&bytes, set);
+        }
+        let links = bytes
+            .get(MAGIC.len() + 1..)
+            .and_then(|mut rest| read_links(&mut rest))
+            .ok_or_else(|| anyhow::anyhow!("invalid envelope"))?;
+        set.extend(links);
+        Ok(())
+    }
+}
+
+fn read_links(buf: &mut &[u8]) -> Option<Vec<Cid>> {
+    let n = read_varint(buf)?;
+    let mut links = Vec::with_capacity(n.min(buf.len() as u64) as usize);
+    for _ in 0..n {
+        links.push(Cid::read_bytes(&mut *buf).ok()?);
+    }
+    Some(links)
+}
+
+/// Turns blocks into the bytes stored for them and back.
+#[derive(Clone)]
+pub(crate) struct Sealer {
+    cipher: Option<XChaCha20Poly1305>,
+    compression: Option<Compression>,
+    /// the most bytes a compressed payload may expand to
+    max_block_size: usize,
+}
+
+impl Sealer {
+    pub fn new(
+        encryption_key: Option<&[u8; 32]>,
+        compression: Option<Compression>,
+        max_block_size: usize,
+    ) -> Self {
+        Self {
+            cipher: encryption_key.map(|key| XChaCha20Poly1305::new(key.into())),
+            compression,
+            max_block_size,
+        }
+    }
+
+    /// Returns the block with the bytes to store for it, compressed if that
+    /// makes them smaller and then encrypted with a fresh nonce if there is a
+    /// key. The CID is the ciphertext's associated data, so the bytes of one
+    /// block can't be passed off as another's.
+    pub fn seal<S: StoreParams>(&self, block: Block<S>) -> Result<Block<Sealed<S>>>
+    where
+        Ipld: References<S::Codecs>,
+    {
+        let compressed = self
+            .compression
+            .map(|compression| compression.compress(block.data()))
+            .filter(|compressed| compressed.len() < block.data().len());
+        if self.cipher.is_none() && compressed.is_none() && !block.data().starts_with(&MAGIC) {
+            let (cid, data) = block.into_inner();
+            return Ok(Block::new_unchecked(cid, data));
+        }
+        let mut links = HashSet::new();
+        block.references(&mut links)?;
+        let (cid, data) = block.into_inner();
+        let mut flags = 0;
+        if self.cipher.is_some() {
+            flags |= ENCRYPTED;
+        }
+        if compressed.is_some() {
+            flags |= COMPRESSED;
+        }
+        let data = compressed.unwrap_or(data);
+        let mut sealed = Vec::with_capacity(data.len() + 64);
+        sealed.extend_from_slice(&MAGIC);
+        sealed.push(flags);
+        varint(&mut sealed, links.len() as u64);
+        for link in &links {
+            link.write_bytes(&mut sealed)?;
+        }
+        match &self.cipher {
+            Some(cipher) => {
+                let nonce = rand::random::<[u8; NONCE_LEN]>();
+                let aad = cid.to_bytes();
+                let payload = Payload {
+                    msg: &data,
+                    aad: &aad,
+                };
+                let ciphertext = cipher
+                    .encrypt(XNonce::from_slice(&nonce), payload)
+                    .map_err(|_| anyhow::anyhow!("failed to encrypt block {}", cid))?;
+                sealed.extend_from_slice(&nonce);
+                sealed.extend_from_slice(&ciphertext);
+            }
+            None => sealed.extend_from_slice(&data),
+        }
+        Ok(Block::new_unchecked(cid, sealed))
+    }
+
+    /// Returns the bytes of the block stored as `data`.
+    pub fn open(&self, cid: &Cid, data: Vec<u8>) -> Result<Vec<u8>> {
+        if !data.starts_with(&MAGIC) {
+            return Ok(data);
+        }
+        let (flags, mut rest) = data[MAGIC.len()..]
+            .split_first()
+            .ok_or(InvalidEnvelope(*cid))?;
+        read_links(&mut rest).ok_or(InvalidEnvelope(*cid))?;
+        if flags & !(ENCRYPTED | COMPRESSED) != 0 {
+            return Err(InvalidEnvelope(*cid).into());
+        }
+        let data = if flags & ENCRYPTED != 0 {
+            let cipher = self.cipher.as_ref().ok_or(DecryptionFailed(*cid))?;
+            if rest.len() < NONCE_LEN {
+                return Err(InvalidEnvelope(*cid).into());
+            }
+            let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+            let aad = cid.to_bytes();
+            let payload = Payload {
+                msg: ciphertext,
+                aad: &aad,
+            };
+            cipher
+                .decrypt(XNonce::from_slice(nonce), payload)
+                .map_err(|_| DecryptionFailed(*cid))?
+        } else {
+            rest.to_vec()
+        };
+        if flags & COMPRESSED != 0 {
+            return Ok(miniz_oxide::inflate::decompress_to_vec_with_limit(
+                &data,
+                self.max_block_size,
+            )
+            .map_err(|_| InvalidEnvelope(*cid))?);
+        }
+        Ok(data)
+    }
+}
+
+impl Compression {
+    fn compress(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Deflate { level } => miniz_oxide::deflate::compress_to_vec(data, level),
+        }
+    }
+}
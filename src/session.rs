@@ -0,0 +1,234 @@
+//! Fetch sessions that share the providers discovered for their blocks.
+//!
+//! Every open session keeps up to 256 providers found in the DHT for the
+//! blocks it fetched, and the number of open sessions is bounded by
+//! [`NetworkConfig::max_sessions`](crate::NetworkConfig::max_sessions). When
+//! the limit is reached, opening a session closes the least recently used one
+//! that has no fetch running and forgets its providers.
+use crate::{sync, FetchSource, Ipfs, ProviderStrategy};
+use fnv::{FnvHashMap, FnvHashSet};
+use lazy_static::lazy_static;
+use libipld::{codec::References, store::StoreParams, Block, Cid, Ipld, Result};
+use libp2p::PeerId;
+use parking_lot::Mutex;
+use prometheus::{IntGauge, Registry};
+use std::sync::Arc;
+use thiserror::Error;
+
+lazy_static! {
+    pub static ref SESSIONS: IntGauge =
+        IntGauge::new("sessions_open", "Number of open fetch sessions.").unwrap();
+}
+
+/// Maximum number of providers a session, or a batch of fetches, keeps.
+pub(crate) const MAX_PROVIDERS: usize = 256;
+
+type Providers = Arc<Mutex<FnvHashSet<PeerId>>>;
+
+/// Error returned when opening a session while
+/// [`NetworkConfig::max_sessions`](crate::NetworkConfig::max_sessions)
+/// sessions are fetching blocks.
+#[derive(Debug, Error)]
+#[error("too many fetch sessions, at most {0} are allowed")]
+pub struct TooManySessions(pub usize);
+
+/// Error returned when fetching with a session that was closed to make room
+/// for a new one.
+#[derive(Debug, Error)]
+#[error("the fetch session was evicted")]
+pub struct SessionEvicted;
+
+#[derive(Debug)]
+struct SessionState {
+    last_used: u64,
+    fetching: usize,
+    providers: Providers,
+}
+
+#[derive(Debug, Default)]
+struct SessionTable {
+    next_id: u64,
+    tick: u64,
+    open: FnvHashMap<u64, SessionState>,
+}
+
+/// The open sessions of a node.
+#[derive(Clone, Debug)]
+pub(crate) struct Sessions {
+    max: usize,
+    registry: Arc<Mutex<SessionTable>>,
+}
+
+impl Sessions {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            registry: Default::default(),
+        }
+    }
+
+    /// Registers a new session, evicting the least recently used idle one if
+    /// `max` are open.
+    fn open(&self) -> Result<(u64, Providers), TooManySessions> {
+        let mut registry = self.registry.lock();
+        if registry.open.len() >= self.max {
+            let lru = registry
+                .open
+                .iter()
+                .filter(|(_, state)| state.fetching == 0)
+                .min_by_key(|(_, state)| state.last_used)
+                .map(|(id, _)| *id);
+            match lru {
+                Some(id) => {
+                    tracing::debug!("evicting fetch session {}", id);
+                    if let Some(state) = registry.open.remove(&id) {
+                        state.providers.lock().clear();
+                        SESSIONS.dec();
+                    }
+                }
+                None => return Err(TooManySessions(self.max)),
+            }
+        }
+        let id = registry.next_id;
+        registry.next_id += 1;
+        registry.tick += 1;
+        let providers = Providers::default();
+        let state = SessionState {
+            last_used: registry.tick,
+            fetching: 0,
+            providers: providers.clone(),
+        };
+        registry.open.insert(id, state);
+        SESSIONS.inc();
+        Ok((id, providers))
+    }
+
+    /// Marks the session as used and fetching, unless it was evicted.
+    fn start(&self, id: u64) -> Result<(), SessionEvicted> {
+        let mut registry = self.registry.lock();
+        registry.tick += 1;
+        let tick = registry.tick;
+        let state = registry.open.get_mut(&id).ok_or(SessionEvicted)?;
+        state.last_used = tick;
+        state.fetching += 1;
+        Ok(())
+    }
+
+    fn done(&self, id: u64) {
+        if let Some(state) = self.registry.lock().open.get_mut(&id) {
+            state.fetching -= 1;
+        }
+    }
+
+    fn close(&self, id: u64) {
+        if self.registry.lock().open.remove(&id).is_some() {
+            SESSIONS.dec();
+        }
+    }
+
+    fn is_open(&self, id: u64) -> bool {
+        self.registry.lock().open.contains_key(&id)
+    }
+}
+
+/// Ends a fetch of a session when dropped, also if the fetch is cancelled.
+struct Fetching<'a> {
+    sessions: &'a Sessions,
+    id: u64,
+}
+
+impl Drop for Fetching<'_> {
+    fn drop(&mut self) {
+        self.sessions.done(self.id);
+    }
+}
+
+/// A group of related fetches, e.g. of the blocks shown by one view of an
+/// app, see [`Ipfs::session`](crate::Ipfs::session). Providers found in the
+/// DHT for one block are asked for the others first. Dropping the session
+/// closes it.
+pub struct Session<P: StoreParams> {
+    ipfs: Ipfs<P>,
+    id: u64,
+    providers: Providers,
+}
+
+impl<P: StoreParams> std::fmt::Debug for Session<P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Session").field("id", &self.id).finish()
+    }
+}
+
+impl<P: StoreParams> Session<P>
+where
+    Ipld: References<P::Codecs>,
+{
+    pub(crate) fn new(ipfs: Ipfs<P>) -> Result<Self> {
+        let (id, providers) = ipfs.sessions.open()?;
+        Ok(Self {
+            ipfs,
+            id,
+            providers,
+        })
+    }
+
+    /// Adds a peer known to have blocks of this session, it is asked for the
+    /// blocks not found at the connected peers before the DHT is. Ignored
+    /// once the session keeps 256 providers or was closed.
+    pub fn add_provider(&self, peer: PeerId) {
+        if !self.is_open() {
+            return;
+        }
+        let mut providers = self.providers.lock();
+        if providers.len() < MAX_PROVIDERS {
+            providers.insert(peer);
+        }
+    }
+
+    /// The providers found so far.
+    pub fn providers(&self) -> Vec<PeerId> {
+        self.providers.lock().iter().copied().collect()
+    }
+
+    /// Whether the session is still open, it is closed when evicted to make
+    /// room for a new one.
+    pub fn is_open(&self) -> bool {
+        self.ipfs.sessions.is_open(self.id)
+    }
+
+    /// Returns a block from the block store or fetches it from the connected
+    /// peers, then from the providers of the session and the DHT. Fails with
+    /// [`SessionEvicted`] once the session was closed.
+    ///
+    /// Use a temp pin to keep the block around once it is returned.
+    pub async fn fetch(&self, cid: &Cid) -> Result<Block<P>> {
+        self.ipfs.sessions.start(self.id)?;
+        let _fetching = Fetching {
+            sessions: &self.ipfs.sessions,
+            id: self.id,
+        };
+        let connected = self.ipfs.network.connected_peers();
+        let providers = sync::order_providers(&connected, ProviderStrategy::LowestRtt, 0, |peer| {
+            self.ipfs.peer_info(peer)
+        });
+        let chain = [
+            FetchSource::Local,
+            FetchSource::ConnectedPeers,
+            FetchSource::Dht,
+        ];
+        self.ipfs
+            .fetch_from(cid, providers, &chain, Some(&*self.providers))
+            .await
+    }
+}
+
+impl<P: StoreParams> Drop for Session<P> {
+    fn drop(&mut self) {
+        self.ipfs.sessions.close(self.id);
+    }
+}
+
+pub fn register_metrics(registry: &Registry) -> Result<()> {
+    registry.register(Box::new(SESSIONS.clone()))?;
+    Ok(())
+}
@@ -0,0 +1,760 @@
+//! Retrieval of DAGs with a bounded number of concurrent block requests.
+use crate::PeerInfo;
+use fnv::{FnvHashMap, FnvHashSet};
+use futures::{
+    stream::{self, FuturesUnordered, Stream, StreamExt},
+    Future, FutureExt,
+};
+use libipld::{codec::References, store::StoreParams, Block, Cid, Ipld, Result};
+use libp2p::PeerId;
+use std::{collections::VecDeque, time::Duration};
+use thiserror::Error;
+use url::Url;
+
+/// Options for [`Ipfs::sync_with_opts`](crate::Ipfs::sync_with_opts).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SyncOpts {
+    /// Maximum number of blocks requested at the same time. This bounds the
+    /// memory taken up by blocks in flight; zero is treated as one.
+    pub traversal_concurrency: usize,
+    /// Which provider is sent the want-block for each block. The others are
+    /// only asked whether they have it, and are sent the want-block after a
+    /// HAVE response if the first one doesn't deliver in time.
+    pub provider_strategy: ProviderStrategy,
+    /// Fail with [`LimitExceeded`] instead of fetching more than this many
+    /// blocks. The missing blocks below a stored block are counted before
+    /// any of them is requested, but looking them up in the block store
+    /// isn't bounded. Unlimited if `None`, which is the default.
+    pub max_blocks: Option<usize>,
+}
+
+impl Default for SyncOpts {
+    fn default() -> Self {
+        Self {
+            traversal_concurrency: 16,
+            provider_strategy: ProviderStrategy::LowestRtt,
+            max_blocks: None,
+        }
+    }
+}
+
+/// Error returned when a block links to one of its ancestors. Such a DAG
+/// can't be hashed, but blocks stored with a wrong CID or decoded by a
+/// misbehaving codec can form one.
+#[derive(Debug, Error)]
+#[error("The DAG has a cycle through block {0}.")]
+pub struct CycleDetected(pub Cid);
+
+/// Error returned when a traversal reaches the maximum number of blocks, see
+/// [`FetchOpts::max_blocks`] and [`SyncOpts::max_blocks`].
+#[derive(Debug, Error)]
+#[error("The DAG has more than {0} blocks.")]
+pub struct LimitExceeded(pub usize);
+
+/// Guards a depth-first traversal against cycles and against visiting more
+/// than `max_blocks` blocks. Blocks are entered in preorder with their depth,
+/// so the path from the root to the current block is known.
+///
+/// Only the traversals of this crate are guarded. Pinning, resolving aliases,
+/// garbage collection and looking up the missing blocks of a sync walk the
+/// DAG in the block store, whose recursive queries visit every block once,
+/// so a cycle ends them, but they aren't bounded by `max_blocks`.
+#[derive(Debug, Default)]
+pub(crate) struct DagGuard {
+    path: Vec<Cid>,
+    max_blocks: Option<usize>,
+    blocks: usize,
+}
+
+impl DagGuard {
+    pub fn new(max_blocks: Option<usize>) -> Self {
+        Self {
+            max_blocks,
+            ..Default::default()
+        }
+    }
+
+    /// Fails if `cid`, linked at `depth` below the root, is its own
+    /// ancestor. Must be called before a block that was visited already is
+    /// skipped, since the ancestors have all been visited.
+    pub fn check_path(&mut self, cid: &Cid, depth: usize) -> Result<()> {
+        self.path.truncate(depth);
+        if self.path.contains(cid) {
+            return Err(CycleDetected(*cid).into());
+        }
+        Ok(())
+    }
+
+    /// Visits `cid` after `check_path`.
+    pub fn enter(&mut self, cid: Cid) -> Result<()> {
+        self.blocks += 1;
+        if let Some(max) = self.max_blocks {
+            if self.blocks > max {
+                return Err(LimitExceeded(max).into());
+            }
+        }
+        self.path.push(cid);
+        Ok(())
+    }
+}
+
+/// Fetches all blocks of the DAG below `root`.
+///
+/// `missing` returns the blocks below a cid that are not stored yet, `fetch`
+/// retrieves and stores a single block from the `providers`, which are
+/// ordered by `opts.provider_strategy` for every block. After each fetched
+/// block its missing descendants are queued, so the traversal proceeds
+/// breadth first.
+pub(crate) async fn traverse<M, F, Fut>(
+    root: Cid,
+    opts: SyncOpts,
+    providers: &[PeerId],
+    info: impl Fn(&PeerId) -> Option<PeerInfo>,
+    missing: M,
+    fetch: F,
+) -> Result<()>
+where
+    M: Fn(&Cid) -> Result<Vec<Cid>>,
+    F: Fn(Cid, Vec<PeerId>) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let concurrency = opts.traversal_concurrency.max(1);
+    let mut queue = VecDeque::new();
+    let mut seen = FnvHashSet::default();
+    enqueue(&mut queue, &mut seen, missing(&root)?, opts.max_blocks)?;
+    let mut in_flight = FuturesUnordered::new();
+    let mut round = 0;
+    loop {
+        while in_flight.len() < concurrency {
+            let cid = match queue.pop_front() {
+                Some(cid) => cid,
+                None => break,
+            };
+            let providers = order_providers(providers, opts.provider_strategy, round, &info);
+            round += 1;
+            in_flight.push(fetch(cid, providers).map(move |res| res.map(|_| cid)));
+        }
+        match in_flight.next().await {
+            Some(res) => enqueue(&mut queue, &mut seen, missing(&res?)?, opts.max_blocks)?,
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Queues the blocks that weren't queued before. They are counted against
+/// `max_blocks` right away, so that a DAG over the limit fails before its
+/// blocks are requested.
+fn enqueue(
+    queue: &mut VecDeque<Cid>,
+    seen: &mut FnvHashSet<Cid>,
+    cids: Vec<Cid>,
+    max_blocks: Option<usize>,
+) -> Result<()> {
+    for cid in cids {
+        if seen.insert(cid) {
+            if let Some(max) = max_blocks {
+                if seen.len() > max {
+                    return Err(LimitExceeded(max).into());
+                }
+            }
+            queue.push_back(cid);
+        }
+    }
+    Ok(())
+}
+
+/// Options for [`Ipfs::fetch_with`](crate::Ipfs::fetch_with).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FetchOpts {
+    /// Deliver the blocks in depth-first, left-to-right order instead of in
+    /// the order they arrive. A block reachable via several paths is then
+    /// delivered once for every path, and a block linking to one of its
+    /// ancestors fails the fetch with [`CycleDetected`].
+    pub ordered: bool,
+    /// Maximum number of blocks requested or waiting for delivery at the same
+    /// time.
+    pub max_buffered: usize,
+    /// Which provider is asked for a block first; the others are only asked
+    /// whether they have it.
+    pub provider_strategy: ProviderStrategy,
+    /// Dial the providers that are not connected right away, so that the
+    /// connections are warm by the time the first blocks are wanted.
+    pub eager_connect: bool,
+    /// Where each block is looked for, in this order, until it is found.
+    pub strategy_chain: Vec<FetchSource>,
+    /// Fail with [`LimitExceeded`] instead of delivering more than this many
+    /// blocks. Unlimited if `None`, which is the default.
+    pub max_blocks: Option<usize>,
+}
+
+impl Default for FetchOpts {
+    fn default() -> Self {
+        Self {
+            ordered: false,
+            max_buffered: 32,
+            provider_strategy: ProviderStrategy::default(),
+            eager_connect: false,
+            strategy_chain: vec![FetchSource::Local, FetchSource::ConnectedPeers],
+            max_blocks: None,
+        }
+    }
+}
+
+/// Options for [`Ipfs::get_or_fetch`](crate::Ipfs::get_or_fetch).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct GetOpts {
+    /// The peers asked for the block if it isn't stored locally, all
+    /// connected peers if empty.
+    pub providers: Vec<PeerId>,
+    /// Give up fetching after this long, failing with [`FetchTimeout`].
+    pub timeout: Option<Duration>,
+    /// Wait until a fetched block is written to disk, like
+    /// [`Ipfs::flush`](crate::Ipfs::flush), before returning it.
+    pub persist: bool,
+}
+
+/// Error returned when a block wasn't fetched within
+/// [`GetOpts::timeout`].
+#[derive(Debug, Error)]
+#[error("Fetching block {0} timed out.")]
+pub struct FetchTimeout(pub Cid);
+
+/// A place to look for a block, see [`FetchOpts::strategy_chain`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FetchSource {
+    /// The local block store.
+    Local,
+    /// The providers given to the fetch, or all connected peers if none were
+    /// given.
+    ConnectedPeers,
+    /// The providers found in the DHT or via the delegated router.
+    Dht,
+    /// An HTTP gateway, asked for the raw block with `?format=raw`. The
+    /// response is only accepted if it matches the CID and is at most
+    /// `StoreParams::MAX_BLOCK_SIZE` bytes.
+    Gateway(Url),
+}
+
+/// How [`Ipfs::fetch_with_report`](crate::Ipfs::fetch_with_report) got a
+/// block.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FetchReport {
+    /// Where the block was found, [`FetchSource::Local`] or
+    /// [`FetchSource::ConnectedPeers`].
+    pub source: FetchSource,
+    /// Number of providers the block was requested from.
+    pub providers: usize,
+    /// Number of providers that were only asked whether they have the block.
+    /// Bitswap asks the first provider for the block right away and the
+    /// others for a HAVE or DONT_HAVE, requesting the block from one that
+    /// answered HAVE if the first one doesn't have it.
+    pub have_requests: usize,
+}
+
+/// Order in which the providers of a block are tried.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ProviderStrategy {
+    /// Keep the order in which the providers were given.
+    FirstResponder,
+    /// Prefer connected peers with the lowest round trip time, trying peers
+    /// that failed more often than not last (see
+    /// [`Reputation::is_bad`](crate::Reputation::is_bad)).
+    LowestRtt,
+    /// Start with the next provider for every block, spreading the load.
+    RoundRobin,
+}
+
+impl Default for ProviderStrategy {
+    fn default() -> Self {
+        Self::FirstResponder
+    }
+}
+
+/// Orders `providers` for the `round`th block request according to `strategy`.
+pub(crate) fn order_providers(
+    providers: &[PeerId],
+    strategy: ProviderStrategy,
+    round: usize,
+    info: impl Fn(&PeerId) -> Option<PeerInfo>,
+) -> Vec<PeerId> {
+    let mut providers = providers.to_vec();
+    match strategy {
+        ProviderStrategy::FirstResponder => {}
+        ProviderStrategy::LowestRtt => {
+            // the sort is stable, so peers without rtt keep their order
+            providers.sort_by_cached_key(|peer| {
+                let info = info(peer);
+                let connected = info
+                    .as_ref()
+                    .map_or(false, |info| info.connections().next().is_some());
+                let bad = info
+                    .as_ref()
+                    .map_or(false, |info| info.reputation().is_bad());
+                let rtt = info.and_then(|info| info.rtt()).unwrap_or(Duration::MAX);
+                (!connected, bad, rtt)
+            });
+        }
+        ProviderStrategy::RoundRobin => {
+            if !providers.is_empty() {
+                let len = providers.len();
+                providers.rotate_left(round % len);
+            }
+        }
+    }
+    providers
+}
+
+struct DagFetch<P: StoreParams, F, Fut> {
+    opts: FetchOpts,
+    fetch: F,
+    /// blocks to request with their depth; when ordered also the delivery
+    /// order
+    pending: VecDeque<(Cid, usize)>,
+    requested: FnvHashSet<Cid>,
+    in_flight: FuturesUnordered<Fut>,
+    /// blocks that arrived before their turn
+    arrived: FnvHashMap<Cid, Block<P>>,
+    /// detects cycles when ordered, which would otherwise be delivered
+    /// forever since blocks are delivered once per path
+    guard: DagGuard,
+    delivered: usize,
+}
+
+impl<P, F, Fut> DagFetch<P, F, Fut>
+where
+    P: StoreParams,
+    Ipld: References<P::Codecs>,
+    F: Fn(Cid) -> Fut,
+    Fut: Future<Output = Result<Block<P>>>,
+{
+    /// Requests the next pending blocks within the buffer bound. When ordered,
+    /// the next block to deliver is always requested so that a buffer full of
+    /// later blocks can't stall the fetch.
+    fn schedule(&mut self) {
+        let Self {
+            opts,
+            fetch,
+            pending,
+            requested,
+            in_flight,
+            arrived,
+            ..
+        } = self;
+        let bound = opts.max_buffered.max(1);
+        if opts.ordered {
+            for (i, (cid, _)) in pending.iter().enumerate() {
+                if i > 0 && in_flight.len() + arrived.len() >= bound {
+                    break;
+                }
+                if requested.insert(*cid) {
+                    in_flight.push(fetch(*cid));
+                }
+            }
+        } else {
+            while in_flight.len() < bound {
+                let cid = match pending.pop_front() {
+                    Some((cid, _)) => cid,
+                    None => break,
+                };
+                if requested.insert(cid) {
+                    in_flight.push(fetch(cid));
+                }
+            }
+        }
+    }
+
+    fn links(block: &Block<P>) -> Result<Vec<Cid>> {
+        let mut links = vec![];
+        block.references(&mut links)?;
+        Ok(links)
+    }
+
+    async fn next(&mut self) -> Option<Result<Block<P>>> {
+        loop {
+            if let Some(block) = self
+                .pending
+                .front()
+                .and_then(|(cid, _)| self.arrived.remove(cid))
+            {
+                let (_, depth) = self.pending.pop_front().unwrap();
+                // the same block may appear again further down the DAG
+                self.requested.remove(block.cid());
+                let res = self
+                    .guard
+                    .check_path(block.cid(), depth)
+                    .and_then(|_| self.guard.enter(*block.cid()))
+                    .and_then(|_| Self::links(&block));
+                match res {
+                    Ok(links) => {
+                        for cid in links.into_iter().rev() {
+                            self.pending.push_front((cid, depth + 1));
+                        }
+                    }
+                    Err(err) => return Some(Err(self.fail(err))),
+                }
+                return Some(Ok(block));
+            }
+            self.schedule();
+            match self.in_flight.next().await? {
+                Ok(block) if self.opts.ordered => {
+                    self.arrived.insert(*block.cid(), block);
+                }
+                Ok(block) => {
+                    self.delivered += 1;
+                    if let Some(max) = self.opts.max_blocks {
+                        if self.delivered > max {
+                            return Some(Err(self.fail(LimitExceeded(max).into())));
+                        }
+                    }
+                    match Self::links(&block) {
+                        Ok(links) => self.pending.extend(links.into_iter().map(|cid| (cid, 0))),
+                        Err(err) => return Some(Err(self.fail(err))),
+                    }
+                    return Some(Ok(block));
+                }
+                Err(err) => return Some(Err(self.fail(err))),
+            }
+        }
+    }
+
+    /// Ends the fetch after reporting `err`.
+    fn fail(&mut self, err: anyhow::Error) -> anyhow::Error {
+        self.pending.clear();
+        self.arrived.clear();
+        self.in_flight = FuturesUnordered::new();
+        err
+    }
+}
+
+/// Streams the blocks of the DAG below `root`, retrieving each with `fetch`.
+pub(crate) fn fetch_dag<P, F, Fut>(
+    root: Cid,
+    opts: FetchOpts,
+    fetch: F,
+) -> impl Stream<Item = Result<Block<P>>>
+where
+    P: StoreParams,
+    Ipld: References<P::Codecs>,
+    F: Fn(Cid) -> Fut,
+    Fut: Future<Output = Result<Block<P>>>,
+{
+    let guard = DagGuard::new(opts.max_blocks);
+    let state = DagFetch {
+        opts,
+        fetch,
+        pending: std::iter::once((root, 0)).collect(),
+        requested: Default::default(),
+        in_flight: FuturesUnordered::new(),
+        arrived: Default::default(),
+        guard,
+        delivered: 0,
+    };
+    stream::unfold(state, |mut state| async move {
+        let block = state.next().await?;
+        Some((block, state))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChunkOpts;
+    use libipld::{multihash::Code, raw::RawCodec, store::DefaultParams, Block};
+    use parking_lot::Mutex;
+    use std::{
+        collections::HashSet,
+        time::{Duration, Instant},
+    };
+
+    /// A connected peer with the given rtt in milliseconds.
+    fn connected(rtt: u64) -> PeerInfo {
+        let addr: libp2p::Multiaddr = "/ip4/1.1.1.1/tcp/4001".parse().unwrap();
+        let mut info = PeerInfo::default();
+        let now = chrono::Utc::now();
+        info.connections
+            .insert(addr, (now, crate::net::Direction::Outbound));
+        info.set_rtt(Some(Duration::from_millis(rtt)), now);
+        info
+    }
+
+    fn cid(n: u32) -> Cid {
+        *Block::<DefaultParams>::encode(RawCodec, Code::Blake3_256, &n.to_be_bytes()[..])
+            .unwrap()
+            .cid()
+    }
+
+    #[derive(Default)]
+    struct Peer {
+        stored: HashSet<Cid>,
+        in_flight: usize,
+        max_in_flight: usize,
+    }
+
+    /// Syncs a root with 32 children, each taking 20ms to fetch.
+    async fn sync_wide_dag(traversal_concurrency: usize) -> (usize, Duration) {
+        let root = cid(0);
+        let children = (1..=32).map(cid).collect::<Vec<_>>();
+        let peer = Mutex::new(Peer::default());
+        let missing = |c: &Cid| -> Result<Vec<Cid>> {
+            let peer = peer.lock();
+            Ok(if !peer.stored.contains(c) {
+                vec![*c]
+            } else if *c == root {
+                children
+                    .iter()
+                    .filter(|c| !peer.stored.contains(c))
+                    .copied()
+                    .collect()
+            } else {
+                vec![]
+            })
+        };
+        let fetch = |c: Cid, _| {
+            let peer = &peer;
+            async move {
+                {
+                    let mut peer = peer.lock();
+                    peer.in_flight += 1;
+                    peer.max_in_flight = peer.max_in_flight.max(peer.in_flight);
+                }
+                async_std::task::sleep(Duration::from_millis(20)).await;
+                let mut peer = peer.lock();
+                peer.in_flight -= 1;
+                peer.stored.insert(c);
+                Ok(())
+            }
+        };
+        let start = Instant::now();
+        let opts = SyncOpts {
+            traversal_concurrency,
+            ..Default::default()
+        };
+        traverse(root, opts, &[], |_| None, missing, fetch)
+            .await
+            .unwrap();
+        let elapsed = start.elapsed();
+        let peer = peer.lock();
+        assert_eq!(peer.stored.len(), 33);
+        (peer.max_in_flight, elapsed)
+    }
+
+    #[async_std::test]
+    async fn test_traversal_concurrency() {
+        let (serial, serial_time) = sync_wide_dag(1).await;
+        let (parallel, parallel_time) = sync_wide_dag(8).await;
+        assert_eq!(serial, 1);
+        assert_eq!(parallel, 8);
+        assert!(serial_time >= Duration::from_millis(33 * 20));
+        assert!(
+            parallel_time * 2 < serial_time,
+            "{:?} vs {:?}",
+            parallel_time,
+            serial_time
+        );
+    }
+
+    /// Returns the depth-first, left-to-right order of the blocks below `cid`.
+    fn dfs(blocks: &FnvHashMap<Cid, Block<DefaultParams>>, cid: &Cid, order: &mut Vec<Cid>) {
+        order.push(*cid);
+        let mut links = vec![];
+        blocks[cid].references(&mut links).unwrap();
+        for link in &links {
+            dfs(blocks, link, order);
+        }
+    }
+
+    #[async_std::test]
+    async fn test_fetch_ordered() {
+        let tmp = tempdir::TempDir::new("fetch").unwrap();
+        let path = tmp.path().join("file");
+        let data = (0..400u32).map(|i| i as u8).collect::<Vec<_>>();
+        std::fs::write(&path, &data).unwrap();
+        let chunk = ChunkOpts {
+            chunk_size: 1,
+            ..Default::default()
+        };
+        let mut blocks = FnvHashMap::default();
+        let root = crate::unixfs::import::<DefaultParams>(&path, &chunk, &mut |block| {
+            blocks.insert(*block.cid(), block);
+            Ok(())
+        })
+        .unwrap();
+        let mut expected = vec![];
+        dfs(&blocks, &root, &mut expected);
+        // 400 leaves below three intermediate nodes
+        assert_eq!(expected.len(), 404);
+
+        let buffered = Mutex::new((0, 0));
+        let fetch = |cid: Cid| {
+            let block = blocks[&cid].clone();
+            let buffered = &buffered;
+            async move {
+                {
+                    let mut buffered = buffered.lock();
+                    buffered.0 += 1;
+                    buffered.1 = buffered.1.max(buffered.0);
+                }
+                // scramble the arrival order
+                let delay = cid.hash().digest()[0] as u64 % 7;
+                async_std::task::sleep(Duration::from_millis(delay)).await;
+                buffered.lock().0 -= 1;
+                Ok(block)
+            }
+        };
+        let opts = FetchOpts {
+            ordered: true,
+            max_buffered: 8,
+            ..Default::default()
+        };
+        let delivered = fetch_dag(root, opts.clone(), fetch)
+            .map(|block| *block.unwrap().cid())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(delivered, expected);
+        assert!(buffered.lock().1 <= 9);
+
+        let opts = FetchOpts {
+            ordered: false,
+            ..opts
+        };
+        let fetch = |cid: Cid| futures::future::ready(Ok(blocks[&cid].clone()));
+        let delivered = fetch_dag(root, opts, fetch)
+            .map(|block| *block.unwrap().cid())
+            .collect::<HashSet<_>>()
+            .await;
+        assert_eq!(delivered, expected.into_iter().collect());
+    }
+
+    #[async_std::test]
+    async fn test_fetch_cycle() {
+        use libipld::{cbor::DagCborCodec, codec::Codec, ipld};
+
+        // two blocks linking each other, which needs CIDs that don't match
+        // the data
+        let (a, b) = (cid(1), cid(2));
+        let a = Cid::new_v1(DagCborCodec.into(), *a.hash());
+        let b = Cid::new_v1(DagCborCodec.into(), *b.hash());
+        let block = |cid: Cid, link: Cid| {
+            let data = DagCborCodec.encode(&ipld!([link])).unwrap();
+            Block::<DefaultParams>::new_unchecked(cid, data)
+        };
+        let blocks = vec![(a, block(a, b)), (b, block(b, a))]
+            .into_iter()
+            .collect::<FnvHashMap<_, _>>();
+        let fetch = |cid: Cid| futures::future::ready(Ok(blocks[&cid].clone()));
+
+        let opts = FetchOpts {
+            ordered: true,
+            ..Default::default()
+        };
+        let res = fetch_dag(a, opts.clone(), fetch).collect::<Vec<_>>().await;
+        assert_eq!(res.len(), 3);
+        let err = res[2].as_ref().unwrap_err();
+        assert_eq!(err.downcast_ref::<CycleDetected>().unwrap().0, a);
+
+        // unordered fetches deliver every block once, so they end anyway
+        let opts = FetchOpts {
+            ordered: false,
+            ..opts
+        };
+        let res = fetch_dag(a, opts.clone(), fetch).collect::<Vec<_>>().await;
+        assert_eq!(res.len(), 2);
+        assert!(res.iter().all(|res| res.is_ok()));
+
+        let opts = FetchOpts {
+            max_blocks: Some(1),
+            ..opts
+        };
+        let res = fetch_dag(a, opts, fetch).collect::<Vec<_>>().await;
+        assert_eq!(res.len(), 2);
+        assert!(res[1].as_ref().unwrap_err().is::<LimitExceeded>());
+    }
+
+    #[test]
+    fn test_lowest_rtt_first() {
+        let slow = PeerId::random();
+        let fast = PeerId::random();
+        let unknown = PeerId::random();
+        let infos = vec![(slow, connected(200)), (fast, connected(20))]
+            .into_iter()
+            .collect::<FnvHashMap<_, _>>();
+        let info = |peer: &PeerId| infos.get(peer).cloned();
+        let providers = [unknown, slow, fast];
+
+        let order = |strategy, round| order_providers(&providers, strategy, round, info);
+        assert_eq!(order(ProviderStrategy::FirstResponder, 0), providers);
+        assert_eq!(
+            order(ProviderStrategy::LowestRtt, 0),
+            vec![fast, slow, unknown]
+        );
+        assert_eq!(
+            order(ProviderStrategy::RoundRobin, 4),
+            vec![slow, fast, unknown]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_sync_wants_block_from_lowest_rtt() {
+        // both peers hold every block, the slow one is listed first
+        let slow = PeerId::random();
+        let fast = PeerId::random();
+        let infos = vec![(slow, connected(300)), (fast, connected(10))]
+            .into_iter()
+            .collect::<FnvHashMap<_, _>>();
+        let root = cid(0);
+        let children = vec![cid(1), cid(2)];
+        let stored = Mutex::new(HashSet::new());
+        let want_block = Mutex::new(vec![]);
+        let missing = |c: &Cid| -> Result<Vec<Cid>> {
+            let stored = stored.lock();
+            Ok(if !stored.contains(c) {
+                vec![*c]
+            } else if *c == root {
+                children.clone()
+            } else {
+                vec![]
+            })
+        };
+        let fetch = |c: Cid, providers: Vec<PeerId>| {
+            // the want-block goes to the first provider
+            want_block.lock().push(providers[0]);
+            stored.lock().insert(c);
+            futures::future::ready(Ok(()))
+        };
+        traverse(
+            root,
+            SyncOpts::default(),
+            &[slow, fast],
+            |peer| infos.get(peer).cloned(),
+            missing,
+            fetch,
+        )
+        .await
+        .unwrap();
+        assert_eq!(*want_block.lock(), vec![fast; 3]);
+    }
+
+    #[async_std::test]
+    async fn test_sync_max_blocks_before_fetching() {
+        // the root is stored, its three children are missing
+        let root = cid(0);
+        let children = vec![cid(1), cid(2), cid(3)];
+        let missing = |c: &Cid| -> Result<Vec<Cid>> {
+            Ok(if *c == root { children.clone() } else { vec![] })
+        };
+        let fetched = Mutex::new(vec![]);
+        let fetch = |c: Cid, _| {
+            fetched.lock().push(c);
+            futures::future::ready(Ok(()))
+        };
+        let opts = SyncOpts {
+            max_blocks: Some(2),
+            ..Default::default()
+        };
+        let err = traverse(root, opts, &[], |_| None, missing, fetch)
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<LimitExceeded>().unwrap().0, 2);
+        assert!(fetched.lock().is_empty());
+    }
+}
@@ -0,0 +1,256 @@
+//! Import of files and directory trees as UnixFS DAGs.
+use anyhow::anyhow;
+use libipld::{
+    error::{BlockTooLarge, UnsupportedMultihash},
+    multihash::MultihashDigest,
+    pb::{PbLink, PbNode},
+    store::StoreParams,
+    Block, Cid, Result,
+};
+use std::{convert::TryFrom, fs::File, io::Read, path::Path};
+
+const RAW: u64 = 0x55;
+const DAG_PB: u64 = 0x70;
+const SHA2_256: u64 = 0x12;
+
+/// Maximum number of links in an intermediate file node, as used by go-ipfs.
+const MAX_LINKS: usize = 174;
+
+/// UnixFS node types, see the `Data.DataType` protobuf enum.
+const DIRECTORY: u64 = 1;
+const FILE: u64 = 2;
+
+/// Options for importing files.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ChunkOpts {
+    /// Size in bytes of the leaf blocks files are split into, at least one.
+    pub chunk_size: usize,
+    /// Multihash code used for all blocks.
+    pub hash: u64,
+    /// Names of files and directories to skip, matched against each path
+    /// component.
+    pub ignore: Vec<String>,
+}
+
+impl Default for ChunkOpts {
+    fn default() -> Self {
+        Self {
+            chunk_size: 256 * 1024,
+            hash: SHA2_256,
+            ignore: vec![],
+        }
+    }
+}
+
+/// A DAG that has been handed to the store.
+struct Node {
+    cid: Cid,
+    /// Size of the file contents.
+    filesize: u64,
+    /// Cumulative size of all blocks in the DAG.
+    tsize: u64,
+}
+
+/// Imports the file or directory at `path`, passing every block to `insert`
+/// and returning the root. Symbolic links below `path` are skipped, so the
+/// import can't loop through a link to a parent directory.
+pub(crate) fn import<P: StoreParams>(
+    path: &Path,
+    opts: &ChunkOpts,
+    insert: &mut impl FnMut(Block<P>) -> Result<()>,
+) -> Result<Cid> {
+    if opts.chunk_size == 0 {
+        return Err(anyhow!("chunk size must be at least one byte"));
+    }
+    Ok(import_path(path, opts, insert)?.cid)
+}
+
+fn import_path<P: StoreParams>(
+    path: &Path,
+    opts: &ChunkOpts,
+    insert: &mut impl FnMut(Block<P>) -> Result<()>,
+) -> Result<Node> {
+    if !path.is_dir() {
+        return import_file(path, opts, insert);
+    }
+    let mut names = vec![];
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_symlink() {
+            tracing::debug!("skipping symbolic link {}", entry.path().display());
+            continue;
+        }
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|name| anyhow!("file name {:?} is not valid UTF-8", name))?;
+        names.push(name);
+    }
+    names.sort();
+    let mut links = vec![];
+    let mut tsize = 0;
+    for name in names {
+        if opts.ignore.contains(&name) {
+            continue;
+        }
+        let child = import_path(&path.join(&name), opts, insert)?;
+        tsize += child.tsize;
+        links.push(PbLink {
+            cid: child.cid,
+            name,
+            size: child.tsize,
+        });
+    }
+    let node = PbNode {
+        links,
+        data: unixfs_data(DIRECTORY, None, &[]).into_boxed_slice(),
+    };
+    let block = create_block::<P>(DAG_PB, node.into_bytes().into_vec(), opts)?;
+    let node = Node {
+        cid: *block.cid(),
+        filesize: 0,
+        tsize: tsize + block.data().len() as u64,
+    };
+    insert(block)?;
+    Ok(node)
+}
+
+fn import_file<P: StoreParams>(
+    path: &Path,
+    opts: &ChunkOpts,
+    insert: &mut impl FnMut(Block<P>) -> Result<()>,
+) -> Result<Node> {
+    let mut file = File::open(path)?;
+    let mut layer = vec![];
+    loop {
+        let mut chunk = Vec::with_capacity(opts.chunk_size);
+        (&mut file)
+            .take(opts.chunk_size as u64)
+            .read_to_end(&mut chunk)?;
+        if chunk.is_empty() && !layer.is_empty() {
+            break;
+        }
+        let len = chunk.len() as u64;
+        let block = create_block::<P>(RAW, chunk, opts)?;
+        layer.push(Node {
+            cid: *block.cid(),
+            filesize: len,
+            tsize: len,
+        });
+        insert(block)?;
+        if len < opts.chunk_size as u64 {
+            break;
+        }
+    }
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(MAX_LINKS)
+            .map(|children| -> Result<Node> {
+                let filesize = children.iter().map(|c| c.filesize).sum();
+                let blocksizes = children.iter().map(|c| c.filesize).collect::<Vec<_>>();
+                let tsize: u64 = children.iter().map(|c| c.tsize).sum();
+                let node = PbNode {
+                    links: children
+                        .iter()
+                        .map(|c| PbLink {
+                            cid: c.cid,
+                            name: String::new(),
+                            size: c.tsize,
+                        })
+                        .collect(),
+                    data: unixfs_data(FILE, Some(filesize), &blocksizes).into_boxed_slice(),
+                };
+                let block = create_block::<P>(DAG_PB, node.into_bytes().into_vec(), opts)?;
+                let node = Node {
+                    cid: *block.cid(),
+                    filesize,
+                    tsize: tsize + block.data().len() as u64,
+                };
+                insert(block)?;
+                Ok(node)
+            })
+            .collect::<Result<_>>()?;
+    }
+    Ok(layer.pop().unwrap())
+}
+
+/// Returns the file size of a UnixFS file node or the cumulative size of the
+/// entries of a UnixFS directory node.
+pub(crate) fn size<P: StoreParams>(block: &Block<P>) -> Option<u64> {
+    if block.cid().codec() != DAG_PB {
+        return None;
+    }
+    let node = PbNode::from_bytes(block.data()).ok()?;
+    let mut data = &*node.data;
+    let mut ty = None;
+    let mut filesize = None;
+    while !data.is_empty() {
+        let tag = read_varint(&mut data)?;
+        match tag & 7 {
+            0 => {
+                let value = read_varint(&mut data)?;
+                match tag >> 3 {
+                    1 => ty = Some(value),
+                    3 => filesize = Some(value),
+                    _ => {}
+                }
+            }
+            2 => {
+                let len = read_varint(&mut data)? as usize;
+                data = data.get(len..)?;
+            }
+            _ => return None,
+        }
+    }
+    match ty? {
+        DIRECTORY => Some(node.links.iter().map(|l| l.size).sum()),
+        FILE => Some(filesize.unwrap_or_default()),
+        _ => None,
+    }
+}
+
+fn create_block<P: StoreParams>(codec: u64, data: Vec<u8>, opts: &ChunkOpts) -> Result<Block<P>> {
+    if data.len() > P::MAX_BLOCK_SIZE {
+        return Err(BlockTooLarge(data.len()).into());
+    }
+    let hash = P::Hashes::try_from(opts.hash)
+        .map_err(|_| UnsupportedMultihash(opts.hash))?
+        .digest(&data);
+    Ok(Block::new_unchecked(Cid::new_v1(codec, hash), data))
+}
+
+/// Encodes the UnixFS `Data` protobuf message.
+fn unixfs_data(ty: u64, filesize: Option<u64>, blocksizes: &[u64]) -> Vec<u8> {
+    let mut buf = vec![0x08];
+    varint(&mut buf, ty);
+    if let Some(filesize) = filesize {
+        buf.push(0x18);
+        varint(&mut buf, filesize);
+    }
+    for size in blocksizes {
+        buf.push(0x20);
+        varint(&mut buf, *size);
+    }
+    buf
+}
+
+pub(crate) fn varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+pub(crate) fn read_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut n = 0;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = buf.split_first()?;
+        *buf = rest;
+        n |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(n);
+        }
+    }
+    None
+}